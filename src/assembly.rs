@@ -6,7 +6,15 @@
 // This came from the Rust book documenting global_asm!. 
 // They show using include_str! with it to
 // import a full assembly file, which is what I want here.
+// Under OpenSBI (the sbi feature) the kernel boots and takes traps in supervisor mode
+// instead, with sboot.S and strap.S.
+#[cfg(not(feature = "sbi"))]
 global_asm!(include_str!("asm/boot.S"));
+#[cfg(feature = "sbi")]
+global_asm!(include_str!("asm/sboot.S"));
 global_asm!(include_str!("asm/mem.S"));
+#[cfg(not(feature = "sbi"))]
 global_asm!(include_str!("asm/trap.S"));
+#[cfg(feature = "sbi")]
+global_asm!(include_str!("asm/strap.S"));
 
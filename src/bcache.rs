@@ -0,0 +1,339 @@
+// Block buffer cache - keeps recently used disk blocks in memory and writes changes back lazily
+use crate::block::BlockErrors;
+use crate::buffer::Buffer;
+use crate::cpu::memcpy;
+use crate::lock::Mutex;
+use crate::syscall::{read_block, write_block, yield_process};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+// Size of each cached block. Larger filesystem blocks span several cache blocks.
+pub const CACHE_BLOCK_SIZE: u32 = 1024;
+// Blocks kept in memory before the least recently used one is evicted
+pub const CACHE_BLOCKS: usize = 256;
+
+struct CacheBlock {
+    data: Buffer,
+    dirty: bool,
+    // Someone is copying in or out of the block, or doing I/O on it, with the cache unlocked
+    busy: bool,
+    // Key in BlockCache::lru
+    last_used: u64,
+}
+
+struct BlockCache {
+    // Keyed by block device and block number
+    blocks: BTreeMap<(usize, u64), CacheBlock>,
+    // Blocks by when they were last used, oldest first
+    lru: BTreeMap<u64, (usize, u64)>,
+    // Bumped on every access
+    tick: u64,
+}
+
+static mut CACHE: Option<BlockCache> = None;
+static mut CACHE_MUTEX: Mutex = Mutex::new();
+
+pub fn init() {
+    unsafe {
+        CACHE.replace(BlockCache {
+            blocks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+        });
+    }
+}
+
+impl BlockCache {
+    // Makes a block the most recently used
+    fn touch(&mut self, key: (usize, u64)) {
+        self.tick += 1;
+        if let Some(cached) = self.blocks.get_mut(&key) {
+            self.lru.remove(&cached.last_used);
+            cached.last_used = self.tick;
+            self.lru.insert(self.tick, key);
+        }
+    }
+
+    fn remove(&mut self, key: (usize, u64)) {
+        if let Some(cached) = self.blocks.remove(&key) {
+            self.lru.remove(&cached.last_used);
+        }
+    }
+
+    // The least recently used block nobody is using
+    fn oldest_idle(&self) -> Option<(usize, u64)> {
+        self.lru
+            .values()
+            .find(|key| !self.blocks[key].busy)
+            .copied()
+    }
+}
+
+// Where claiming a block has got to
+enum Step {
+    // The block is ours, and holds what's on the disk
+    Claimed(*mut u8),
+    // The block is ours, but still has to be read from the disk
+    Fill(*mut u8),
+    // The cache is full, and this idle block has to be written back to make room
+    WriteBack((usize, u64), *const u8),
+    // Someone else is using it
+    Wait,
+    // It isn't cached
+    Gone,
+}
+
+// Runs f with the cache locked. The lock is only held while the cache is looked at, never
+// across I/O.
+// don't run inside interrupt context - will block
+fn with_cache<T>(
+    f: impl FnOnce(&mut BlockCache) -> Result<T, BlockErrors>,
+) -> Result<T, BlockErrors> {
+    unsafe {
+        CACHE_MUTEX.sleep_lock();
+        let ret = match CACHE.take() {
+            Some(mut cache) => {
+                let ret = f(&mut cache);
+                CACHE.replace(cache);
+                ret
+            }
+            // Not initialized yet
+            None => Err(BlockErrors::NoDevice),
+        };
+        CACHE_MUTEX.unlock();
+        ret
+    }
+}
+
+// Marks a block busy and returns its data, which can then be used with the cache unlocked until
+// release. Reads it from disk if needed - fill is false when the caller is about to overwrite
+// all of it. Waits for anyone else using it.
+fn claim(dev: usize, block: u64, fill: bool) -> Result<*mut u8, BlockErrors> {
+    let key = (dev, block);
+    loop {
+        let step = with_cache(|cache| {
+            if let Some(cached) = cache.blocks.get_mut(&key) {
+                if cached.busy {
+                    return Ok(Step::Wait);
+                }
+                cached.busy = true;
+                let data = cached.data.get_mut();
+                cache.touch(key);
+                return Ok(Step::Claimed(data));
+            }
+            if cache.blocks.len() >= CACHE_BLOCKS {
+                let oldest = match cache.oldest_idle() {
+                    Some(oldest) => oldest,
+                    // Everything is in use
+                    None => return Ok(Step::Wait),
+                };
+                let cached = cache.blocks.get_mut(&oldest).unwrap();
+                if cached.dirty {
+                    cached.busy = true;
+                    return Ok(Step::WriteBack(oldest, cached.data.get()));
+                }
+                cache.remove(oldest);
+            }
+            let mut data = Buffer::new(CACHE_BLOCK_SIZE as usize);
+            let ptr = data.get_mut();
+            cache.blocks.insert(
+                key,
+                CacheBlock {
+                    data,
+                    dirty: false,
+                    busy: true,
+                    last_used: 0,
+                },
+            );
+            cache.touch(key);
+            Ok(if fill {
+                Step::Fill(ptr)
+            } else {
+                Step::Claimed(ptr)
+            })
+        })?;
+        match step {
+            Step::Claimed(data) => return Ok(data),
+            Step::Fill(data) => {
+                let offset = block * CACHE_BLOCK_SIZE as u64;
+                if let Err(err) = read_block(dev, data, CACHE_BLOCK_SIZE, offset) {
+                    // Nobody else can see it yet, so just forget it
+                    with_cache(|cache| {
+                        cache.remove(key);
+                        Ok(())
+                    })?;
+                    return Err(err);
+                }
+                return Ok(data);
+            }
+            Step::WriteBack(oldest, data) => {
+                let offset = oldest.1 * CACHE_BLOCK_SIZE as u64;
+                let written = write_block(oldest.0, data, CACHE_BLOCK_SIZE, offset);
+                // A block that can't be written back stays cached
+                with_cache(|cache| {
+                    match written {
+                        Ok(()) => cache.remove(oldest),
+                        Err(_) => cache.blocks.get_mut(&oldest).unwrap().busy = false,
+                    }
+                    Ok(())
+                })?;
+                written?;
+            }
+            Step::Wait => {
+                yield_process();
+            }
+            Step::Gone => unreachable!(),
+        }
+    }
+}
+
+// Gives back a claimed block, dirty if it's been changed
+fn release(dev: usize, block: u64, dirty: bool) {
+    let _ = with_cache(|cache| {
+        if let Some(cached) = cache.blocks.get_mut(&(dev, block)) {
+            cached.dirty |= dirty;
+            cached.busy = false;
+        }
+        Ok(())
+    });
+}
+
+// Writes back dirty blocks, from every device if dev is None. Blocks that fail stay dirty,
+// and the first error is returned once the rest have been tried.
+fn write_back(dev: Option<usize>) -> Result<(), BlockErrors> {
+    let dirty: Vec<(usize, u64)> = with_cache(|cache| {
+        Ok(cache
+            .blocks
+            .iter()
+            .filter(|(key, cached)| cached.dirty && dev.map_or(true, |dev| dev == key.0))
+            .map(|(key, _)| *key)
+            .collect())
+    })?;
+    let mut ret = Ok(());
+    for (block_dev, block) in dirty {
+        let step = loop {
+            let step = with_cache(|cache| {
+                Ok(match cache.blocks.get_mut(&(block_dev, block)) {
+                    Some(cached) if cached.busy => Step::Wait,
+                    Some(cached) if cached.dirty => {
+                        cached.busy = true;
+                        cached.dirty = false;
+                        Step::Claimed(cached.data.get_mut())
+                    }
+                    // Written back or dropped since
+                    _ => Step::Gone,
+                })
+            })?;
+            match step {
+                Step::Wait => {
+                    yield_process();
+                }
+                step => break step,
+            }
+        };
+        if let Step::Claimed(data) = step {
+            let offset = block * CACHE_BLOCK_SIZE as u64;
+            let written = write_block(block_dev, data, CACHE_BLOCK_SIZE, offset);
+            release(block_dev, block, written.is_err());
+            ret = ret.and(written);
+        }
+    }
+    ret
+}
+
+// Reads size bytes at a byte offset of the device, through the cache. Same arguments as
+// syscall::read_block.
+// don't run inside interrupt context - will block
+pub fn read(dev: usize, buffer: *mut u8, size: u32, offset: u64) -> Result<(), BlockErrors> {
+    let mut done = 0;
+    while done < size {
+        let pos = offset + done as u64;
+        let block = pos / CACHE_BLOCK_SIZE as u64;
+        let block_offset = (pos % CACHE_BLOCK_SIZE as u64) as u32;
+        let amount = (CACHE_BLOCK_SIZE - block_offset).min(size - done);
+        let data = claim(dev, block, true)?;
+        unsafe {
+            memcpy(
+                buffer.add(done as usize),
+                data.add(block_offset as usize),
+                amount as usize,
+            );
+        }
+        release(dev, block, false);
+        done += amount;
+    }
+    Ok(())
+}
+
+// Writes size bytes at a byte offset of the device into the cache. They reach the disk on the
+// next write-back, sync or eviction.
+// don't run inside interrupt context - will block
+pub fn write(dev: usize, buffer: *const u8, size: u32, offset: u64) -> Result<(), BlockErrors> {
+    let mut done = 0;
+    while done < size {
+        let pos = offset + done as u64;
+        let block = pos / CACHE_BLOCK_SIZE as u64;
+        let block_offset = (pos % CACHE_BLOCK_SIZE as u64) as u32;
+        let amount = (CACHE_BLOCK_SIZE - block_offset).min(size - done);
+        // No need to read a block we're about to overwrite completely
+        let data = claim(dev, block, amount < CACHE_BLOCK_SIZE)?;
+        unsafe {
+            memcpy(
+                data.add(block_offset as usize),
+                buffer.add(done as usize),
+                amount as usize,
+            );
+        }
+        release(dev, block, true);
+        done += amount;
+    }
+    Ok(())
+}
+
+// Forgets cached blocks inside a byte range without writing them back, for space that's been
+// freed. Blocks only partly inside the range are kept.
+// don't run inside interrupt context - will block
+pub fn invalidate(dev: usize, offset: u64, size: u64) -> Result<(), BlockErrors> {
+    let block_size = CACHE_BLOCK_SIZE as u64;
+    let first = (offset + block_size - 1) / block_size;
+    let end = (offset + size) / block_size;
+    if first >= end {
+        // No whole block inside the range - an empty range would panic
+        return Ok(());
+    }
+    loop {
+        // Blocks in use are waited for, so a write that's still copying in isn't lost later
+        let waiting = with_cache(|cache| {
+            let cached: Vec<((usize, u64), bool)> = cache
+                .blocks
+                .range((dev, first)..(dev, end))
+                .map(|(key, cached)| (*key, cached.busy))
+                .collect();
+            let mut waiting = false;
+            for (key, busy) in cached {
+                if busy {
+                    waiting = true;
+                } else {
+                    cache.remove(key);
+                }
+            }
+            Ok(waiting)
+        })?;
+        if !waiting {
+            return Ok(());
+        }
+        yield_process();
+    }
+}
+
+// Writes every dirty block back to disk
+// don't run inside interrupt context - will block
+pub fn sync() -> Result<(), BlockErrors> {
+    write_back(None)
+}
+
+// Writes back one device's dirty blocks
+// don't run inside interrupt context - will block
+pub fn sync_dev(dev: usize) -> Result<(), BlockErrors> {
+    write_back(Some(dev))
+}
@@ -1,9 +1,13 @@
 use crate::kmem::{kfree, kmalloc};
-use crate::page::{zalloc, PAGE_SIZE};
+use crate::lock::Mutex;
 use crate::process;
+use crate::syscall::{discard_block, flush_block, write_zeroes_block};
 use crate::virtio;
-use crate::virtio::{Descriptor, MmioOffsets, Queue, StatusField, VIRTIO_RING_SIZE};
+use crate::virtio::{Buf, MmioOffsets, Virtqueue};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use core::mem::size_of;
+use core::ptr::null_mut;
 
 #[repr(C)]
 pub struct Header {
@@ -15,6 +19,7 @@ pub struct Header {
 #[repr(C)]
 pub struct Data {
     data: *mut u8,
+    len: u32,
 }
 
 #[repr(C)]
@@ -22,24 +27,65 @@ pub struct Status {
     status: u8,
 }
 
+// The data of a discard or write-zeroes request
+#[repr(C)]
+pub struct Segment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
 #[repr(C)]
 pub struct Request {
     header: Header,
     data: Data,
     status: Status,
-    head: u16,
     waiting_pid: u16, // pid of waiting task
+    segment: Segment,
+    // Some when data is a kernel copy of the waiting process' memory at that address, which the
+    // request owns
+    user_buffer: Option<usize>,
+}
+
+// What a device says about itself in its config space. Fields whose feature the device didn't
+// offer are 0, except blk_size which defaults to SECTOR_SIZE.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct BlockInfo {
+    // Size of the disk in bytes
+    pub capacity: u64,
+    pub read_only: bool,
+    pub blk_size: u32,
+    // Largest data segment in bytes, and most segments in one request
+    pub size_max: u32,
+    pub seg_max: u32,
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors: u8,
+    // Logical blocks per physical block, as a power of two
+    pub physical_block_exp: u8,
+    pub alignment_offset: u8,
+    // In logical blocks
+    pub min_io_size: u16,
+    pub opt_io_size: u32,
 }
 
 pub struct BlockDevice {
-    queue: *mut Queue,
-    dev: *mut u32,
-    idx: u16,
-    ack_used_idx: u16,
-    read_only: bool,
+    vq: Virtqueue,
+    info: BlockInfo,
+    // Feature bits both sides agreed on
+    features: u64,
+    // Largest discard and write-zeroes requests the device takes
+    max_discard_sectors: u32,
+    max_write_zeroes_sectors: u32,
+    // Requests waiting for free descriptors, oldest first
+    backlog: VecDeque<*mut Request>,
 }
 
 pub const SECTOR_SIZE: u32 = 512;
+// Largest read or write a process with its own address space can ask for in one syscall, since
+// it's copied through the kernel heap
+pub const MAX_USER_IO: u32 = 32 * 1024;
 
 // Type values
 pub const VIRTIO_BLK_T_IN: u32 = 0;
@@ -48,6 +94,22 @@ pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
 pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
 pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
 
+// Status values written back by the device
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// Config space offsets, in 32 bit words
+const CONFIG_CAPACITY: usize = 0; // 64 bits, in sectors
+const CONFIG_SIZE_MAX: usize = 2;
+const CONFIG_SEG_MAX: usize = 3;
+const CONFIG_GEOMETRY: usize = 4; // cylinders u16, heads u8, sectors u8
+const CONFIG_BLK_SIZE: usize = 5;
+const CONFIG_TOPOLOGY: usize = 6; // physical_block_exp u8, alignment_offset u8, min_io_size u16
+const CONFIG_OPT_IO_SIZE: usize = 7;
+const CONFIG_MAX_DISCARD_SECTORS: usize = 9;
+const CONFIG_MAX_WRITE_ZEROES_SECTORS: usize = 12;
+
 // Feature bits
 pub const VIRTIO_BLK_F_SIZE_MAX: u32 = 1;
 pub const VIRTIO_BLK_F_SEG_MAX: u32 = 2;
@@ -60,186 +122,552 @@ pub const VIRTIO_BLK_F_CONFIG_WCE: u32 = 11;
 pub const VIRTIO_BLK_F_DISCARD: u32 = 13;
 pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BlockErrors {
+    // No block device with that number
+    NoDevice,
+    ReadOnly,
+    // Misaligned or too large
+    Invalid,
+    // The device reported VIRTIO_BLK_S_IOERR
+    IoError,
+    // The device reported VIRTIO_BLK_S_UNSUPP
+    Unsupported,
+    // A buffer the process can't access
+    Fault,
+    // No kernel memory to copy the process' data through
+    NoMemory,
+}
+
+impl BlockErrors {
+    // Negated Linux errno, which is what block syscalls return on failure
+    pub fn errno(self) -> usize {
+        let errno: isize = match self {
+            BlockErrors::NoDevice => 19,    // ENODEV
+            BlockErrors::ReadOnly => 30,    // EROFS
+            BlockErrors::Invalid => 22,     // EINVAL
+            BlockErrors::IoError => 5,      // EIO
+            BlockErrors::Unsupported => 95, // EOPNOTSUPP
+            BlockErrors::Fault => 14,       // EFAULT
+            BlockErrors::NoMemory => 12,    // ENOMEM
+        };
+        -errno as usize
+    }
+
+    fn from_status(status: u8) -> Result<(), BlockErrors> {
+        match status {
+            VIRTIO_BLK_S_OK => Ok(()),
+            VIRTIO_BLK_S_UNSUPP => Err(BlockErrors::Unsupported),
+            // including a status the device never wrote
+            _ => Err(BlockErrors::IoError),
+        }
+    }
+}
+
+// Return value of a block syscall: 0 or a negated errno
+pub fn to_syscall_ret(result: Result<(), BlockErrors>) -> usize {
+    match result {
+        Ok(()) => 0,
+        Err(err) => err.errno(),
+    }
+}
+
+// Turns a block syscall's return value back into a Result
+pub fn from_syscall_ret(ret: usize) -> Result<(), BlockErrors> {
+    let errors = [
+        BlockErrors::NoDevice,
+        BlockErrors::ReadOnly,
+        BlockErrors::Invalid,
+        BlockErrors::IoError,
+        BlockErrors::Unsupported,
+        BlockErrors::Fault,
+        BlockErrors::NoMemory,
+    ];
+    match errors.iter().find(|err| err.errno() == ret) {
+        Some(err) => Err(*err),
+        None if ret == 0 => Ok(()),
+        None => Err(BlockErrors::IoError),
+    }
+}
+
 static mut BLOCK_DEVICES: [Option<BlockDevice>; 8] =
     [None, None, None, None, None, None, None, None];
+// The rings and backlogs are shared between whoever queues requests and the interrupt handler,
+// on any hart
+static mut BLOCK_LOCK: Mutex = Mutex::new();
 
 pub fn setup_block_device(ptr: *mut u32) -> bool {
     unsafe {
-        let index = (ptr as usize - virtio::MMIO_VIRTIO_START) >> 12;
-        // 1. Reset by writing 0 to status
-        ptr.add(MmioOffsets::Status.scale32()).write_volatile(0);
-        // 2. Set ACKNOWLEDGE status bit
-        let mut status_bits = StatusField::Acknowledge.val32();
-        ptr.add(MmioOffsets::Status.scale32())
-            .write_volatile(status_bits);
-        // 3. Set the DRIVER status bit
-        status_bits |= StatusField::DriverOk.val32();
-        ptr.add(MmioOffsets::Status.scale32())
-            .write_volatile(status_bits);
-        // 4. Read device feature bits, write subset of feature bits understood by OS and driver to the device
-        let host_features = ptr.add(MmioOffsets::HostFeatures.scale32()).read_volatile();
-        let guest_features = host_features & !(1 << VIRTIO_BLK_F_RO);
-        let ro = host_features & (1 << VIRTIO_BLK_F_RO) != 0;
-        ptr.add(MmioOffsets::GuestFeatures.scale32())
-            .write_volatile(guest_features);
-        // 5. Set the FEATURES_OK status bit
-        status_bits |= StatusField::FeaturesOk.val32();
-        ptr.add(MmioOffsets::Status.scale32())
-            .write_volatile(status_bits);
-        // 6. Re-read status to ensure FEATURES_OK is still set.
-        // Otherwise, it doesn't support our features.
-        let status_ok = ptr.add(MmioOffsets::Status.scale32()).read_volatile();
-        if false == StatusField::features_ok(status_ok) {
-            print!("features fail...");
-            ptr.add(MmioOffsets::Status.scale32())
-                .write_volatile(StatusField::Failed.val32());
-            return false;
-        }
+        let index = match virtio::slot(ptr) {
+            Some(index) => index,
+            None => return false,
+        };
+        // 1 - 6. Reset, acknowledge and agree on features
+        let supported = virtio::RING_FEATURES
+            | (1 << VIRTIO_BLK_F_SIZE_MAX)
+            | (1 << VIRTIO_BLK_F_SEG_MAX)
+            | (1 << VIRTIO_BLK_F_GEOMETRY)
+            | (1 << VIRTIO_BLK_F_RO)
+            | (1 << VIRTIO_BLK_F_BLK_SIZE)
+            | (1 << VIRTIO_BLK_F_FLUSH)
+            | (1 << VIRTIO_BLK_F_TOPOLOGY)
+            | (1 << VIRTIO_BLK_F_DISCARD)
+            | (1 << VIRTIO_BLK_F_WRITE_ZEROES);
+        let guest_features = match virtio::negotiate_features(ptr, supported) {
+            Some(features) => features,
+            None => return false,
+        };
 
         // 7. Perform device-specific setup
-        let qnmax = ptr.add(MmioOffsets::QueueNumMax.scale32()).read_volatile();
-        // Max sure the queue size is valid
-        if VIRTIO_RING_SIZE as u32 > qnmax {
-            print!("queue size fail...");
-            return false;
-        }
-        // Set queue num
-        ptr.add(MmioOffsets::QueueNum.scale32())
-            .write_volatile(VIRTIO_RING_SIZE as u32);
-        let num_pages = (size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
-        ptr.add(MmioOffsets::QueueSel.scale32()).write_volatile(0);
-        let queue_ptr = zalloc(num_pages) as *mut Queue;
-        let queue_pfn = queue_ptr as u32;
-        ptr.add(MmioOffsets::GuestPageSize.scale32())
-            .write_volatile(PAGE_SIZE as u32);
-        ptr.add(MmioOffsets::QueuePfn.scale32())
-            .write_volatile(queue_pfn / PAGE_SIZE as u32);
+        let vq = match Virtqueue::new(ptr, 0, guest_features) {
+            Some(vq) => vq,
+            None => return false,
+        };
+        let config = ptr.add(MmioOffsets::Config.scale32());
+        // Retry if the device changes its config while we read it
+        let info = loop {
+            let generation = virtio::config_generation(ptr);
+            let info = read_config(config, guest_features);
+            if generation == virtio::config_generation(ptr) {
+                break info;
+            }
+        };
+        println!(
+            "block device {}: {} sectors{}",
+            index + 1,
+            info.capacity / SECTOR_SIZE as u64,
+            if info.read_only { ", read-only" } else { "" }
+        );
         let bd = BlockDevice {
-            queue: queue_ptr,
-            dev: ptr,
-            idx: 0,
-            ack_used_idx: 0,
-            read_only: ro,
+            vq,
+            info,
+            features: guest_features,
+            max_discard_sectors: config.add(CONFIG_MAX_DISCARD_SECTORS).read_volatile(),
+            max_write_zeroes_sectors: config.add(CONFIG_MAX_WRITE_ZEROES_SECTORS).read_volatile(),
+            backlog: VecDeque::new(),
         };
         BLOCK_DEVICES[index] = Some(bd);
         // 8. Set the DRIVER_OK status bit. Device is now 'live'
-        status_bits |= StatusField::DriverOk.val32();
-        ptr.add(MmioOffsets::Status.scale32())
-            .write_volatile(status_bits);
+        virtio::driver_ok(ptr);
 
         true
     }
 }
 
-pub fn fill_next_descriptor(bd: &mut BlockDevice, desc: Descriptor) -> u16 {
+// Reads the config space. Only the fields of negotiated features are valid.
+unsafe fn read_config(config: *mut u32, features: u64) -> BlockInfo {
+    let has = |feature: u32| features & (1 << feature) != 0;
+    let word = |offset: usize| config.add(offset).read_volatile();
+    let sectors = word(CONFIG_CAPACITY) as u64 | (word(CONFIG_CAPACITY + 1) as u64) << 32;
+    let mut info = BlockInfo {
+        capacity: sectors * SECTOR_SIZE as u64,
+        read_only: has(VIRTIO_BLK_F_RO),
+        blk_size: SECTOR_SIZE,
+        ..BlockInfo::default()
+    };
+    if has(VIRTIO_BLK_F_SIZE_MAX) {
+        info.size_max = word(CONFIG_SIZE_MAX);
+    }
+    if has(VIRTIO_BLK_F_SEG_MAX) {
+        info.seg_max = word(CONFIG_SEG_MAX);
+    }
+    if has(VIRTIO_BLK_F_GEOMETRY) {
+        let geometry = word(CONFIG_GEOMETRY);
+        info.cylinders = geometry as u16;
+        info.heads = (geometry >> 16) as u8;
+        info.sectors = (geometry >> 24) as u8;
+    }
+    if has(VIRTIO_BLK_F_BLK_SIZE) {
+        info.blk_size = word(CONFIG_BLK_SIZE);
+    }
+    if has(VIRTIO_BLK_F_TOPOLOGY) {
+        let topology = word(CONFIG_TOPOLOGY);
+        info.physical_block_exp = topology as u8;
+        info.alignment_offset = (topology >> 8) as u8;
+        info.min_io_size = (topology >> 16) as u16;
+        info.opt_io_size = word(CONFIG_OPT_IO_SIZE);
+    }
+    info
+}
+
+// Numbers of the block devices that were set up, in probe order
+pub fn devices() -> Vec<usize> {
+    let mut ret = Vec::new();
     unsafe {
-        bd.idx = (bd.idx + 1) % VIRTIO_RING_SIZE as u16;
-        (*bd.queue).desc[bd.idx as usize] = desc;
-        if (*bd.queue).desc[bd.idx as usize].flags & virtio::VIRTIO_DESC_F_NEXT != 0 {
-            (*bd.queue).desc[bd.idx as usize].next = (bd.idx + 1) % VIRTIO_RING_SIZE as u16;
+        for (i, bdev) in BLOCK_DEVICES.iter().enumerate() {
+            if bdev.is_some() {
+                ret.push(i + 1);
+            }
         }
-        bd.idx
     }
+    ret
 }
 
-pub fn read(dev: usize, buffer: *mut u8, size: u32, offset: u64) {
-    block_op(dev, buffer, size, offset, false, 0);
+// Queues a read without waiting for it
+pub fn read(dev: usize, buffer: *mut u8, size: u32, offset: u64) -> Result<(), BlockErrors> {
+    block_op(dev, buffer, size, offset, false, 0)
 }
 
-pub fn write(dev: usize, buffer: *mut u8, size: u32, offset: u64) {
-    block_op(dev, buffer, size, offset, true, 0);
+// Queues a write without waiting for it
+pub fn write(dev: usize, buffer: *mut u8, size: u32, offset: u64) -> Result<(), BlockErrors> {
+    block_op(dev, buffer, size, offset, true, 0)
 }
 
-pub fn process_read(pid: u16, dev: usize, buffer: *mut u8, size: u32, offset: u64) {
-    process::set_waiting(pid);
-    block_op(dev, buffer, size, offset, false, pid);
+// The process waits until the read completes, and gets the result in A0
+pub fn process_read(
+    pid: u16,
+    dev: usize,
+    buffer: *mut u8,
+    size: u32,
+    offset: u64,
+) -> Result<(), BlockErrors> {
+    process_op(pid, dev, buffer, size, offset, false)
+}
+
+// The process waits until the write completes, and gets the result in A0
+pub fn process_write(
+    pid: u16,
+    dev: usize,
+    buffer: *mut u8,
+    size: u32,
+    offset: u64,
+) -> Result<(), BlockErrors> {
+    process_op(pid, dev, buffer, size, offset, true)
 }
 
-pub fn process_write(pid: u16, dev: usize, buffer: *mut u8, size: u32, offset: u64) {
+fn process_op(
+    pid: u16,
+    dev: usize,
+    buffer: *mut u8,
+    size: u32,
+    offset: u64,
+    write: bool,
+) -> Result<(), BlockErrors> {
     process::set_waiting(pid);
-    block_op(dev, buffer, size, offset, true, pid);
+    let ret = block_op(dev, buffer, size, offset, write, pid);
+    if ret.is_err() {
+        // Nothing was queued, so nothing will wake it
+        process::set_running(pid);
+    }
+    ret
+}
+
+// Queues a read or write. If pid isn't 0 that process is woken when it completes, with the
+// result in its A0. When the ring is full the request waits in the device's backlog until
+// descriptors are freed. Errors here mean nothing was queued.
+pub fn block_op(
+    dev: usize,
+    buffer: *mut u8,
+    size: u32,
+    offset: u64,
+    write: bool,
+    pid: u16,
+) -> Result<(), BlockErrors> {
+    let blktype = if true == write {
+        VIRTIO_BLK_T_OUT
+    } else {
+        VIRTIO_BLK_T_IN
+    };
+    request(dev, blktype, buffer, size as u64, offset, pid, None)
+}
+
+// Queues a read or write like block_op, for a process with its own address space - buffer is
+// an address in its memory. The device gets a kernel copy instead, since the process' pages
+// needn't be physically contiguous and may be gone by the time the request completes. A read is
+// copied back into the process when it does.
+pub fn user_op(
+    dev: usize,
+    buffer: usize,
+    size: u32,
+    offset: u64,
+    write: bool,
+    pid: u16,
+) -> Result<(), BlockErrors> {
+    if size > MAX_USER_IO {
+        return Err(BlockErrors::Invalid);
+    }
+    let bounce = kmalloc(size as usize);
+    if bounce.is_null() {
+        return Err(BlockErrors::NoMemory);
+    }
+    let (blktype, copied) = if true == write {
+        let copied = process::with_process(pid, |proc| {
+            proc.copy_from_user(bounce, buffer, size as usize)
+        });
+        (VIRTIO_BLK_T_OUT, copied.unwrap_or(0))
+    } else {
+        (VIRTIO_BLK_T_IN, size as usize)
+    };
+    let ret = if copied == size as usize {
+        request(dev, blktype, bounce, size as u64, offset, pid, Some(buffer))
+    } else {
+        Err(BlockErrors::Fault)
+    };
+    if ret.is_err() {
+        kfree(bounce);
+    }
+    ret
+}
+
+// Queues a flush of the device's write cache, like block_op
+pub fn flush(dev: usize, pid: u16) -> Result<(), BlockErrors> {
+    request(dev, VIRTIO_BLK_T_FLUSH, null_mut(), 0, 0, pid, None)
 }
 
-pub fn block_op(dev: usize, buffer: *mut u8, size: u32, offset: u64, write: bool, pid: u16) {
+// Queues a discard of a sector aligned byte range, like block_op. The device may then read it
+// back as anything.
+pub fn discard(dev: usize, offset: u64, size: u64, pid: u16) -> Result<(), BlockErrors> {
+    request(
+        dev,
+        VIRTIO_BLK_T_DISCARD,
+        null_mut(),
+        size,
+        offset,
+        pid,
+        None,
+    )
+}
+
+// Queues zeroing a sector aligned byte range, like block_op
+pub fn write_zeroes(dev: usize, offset: u64, size: u64, pid: u16) -> Result<(), BlockErrors> {
+    request(
+        dev,
+        VIRTIO_BLK_T_WRITE_ZEROES,
+        null_mut(),
+        size,
+        offset,
+        pid,
+        None,
+    )
+}
+
+fn request(
+    dev: usize,
+    blktype: u32,
+    buffer: *mut u8,
+    size: u64,
+    offset: u64,
+    pid: u16,
+    user_buffer: Option<usize>,
+) -> Result<(), BlockErrors> {
     unsafe {
-        if let Some(bdev) = BLOCK_DEVICES[dev - 1].as_mut() {
-            if true == bdev.read_only && true == write {
-                println!("Trying to write to read-only device!");
-                return;
+        let bdev = match BLOCK_DEVICES.get_mut(dev.wrapping_sub(1)) {
+            Some(Some(bdev)) => bdev,
+            _ => return Err(BlockErrors::NoDevice),
+        };
+        if true == bdev.info.read_only
+            && blktype != VIRTIO_BLK_T_IN
+            && blktype != VIRTIO_BLK_T_FLUSH
+        {
+            println!("Trying to write to read-only device!");
+            return Err(BlockErrors::ReadOnly);
+        }
+        let (feature, max_sectors) = match blktype {
+            VIRTIO_BLK_T_FLUSH => (Some(VIRTIO_BLK_F_FLUSH), 0),
+            VIRTIO_BLK_T_DISCARD => (Some(VIRTIO_BLK_F_DISCARD), bdev.max_discard_sectors),
+            VIRTIO_BLK_T_WRITE_ZEROES => (
+                Some(VIRTIO_BLK_F_WRITE_ZEROES),
+                bdev.max_write_zeroes_sectors,
+            ),
+            _ => (None, 0),
+        };
+        if let Some(feature) = feature {
+            if bdev.features & (1 << feature) == 0 {
+                return Err(BlockErrors::Unsupported);
+            }
+        }
+        let sector = offset / SECTOR_SIZE as u64;
+        let segment = blktype == VIRTIO_BLK_T_DISCARD || blktype == VIRTIO_BLK_T_WRITE_ZEROES;
+        if blktype != VIRTIO_BLK_T_FLUSH {
+            let aligned = offset % SECTOR_SIZE as u64 == 0 && size % SECTOR_SIZE as u64 == 0;
+            let in_range = offset
+                .checked_add(size)
+                .map_or(false, |end| end <= bdev.info.capacity);
+            if !aligned || !in_range {
+                return Err(BlockErrors::Invalid);
             }
-            let sector = offset / SECTOR_SIZE as u64;
-            // allocate request on the heap
-            let blk_request = kmalloc(size_of::<Request>()) as *mut Request;
-            let desc = Descriptor {
-                addr: &(*blk_request).header as *const Header as u64,
-                len: size_of::<Header>() as u32,
-                flags: virtio::VIRTIO_DESC_F_NEXT,
-                next: 0,
+        }
+        // 0 means the device didn't say
+        if segment && max_sectors != 0 && size / SECTOR_SIZE as u64 > max_sectors as u64 {
+            return Err(BlockErrors::Invalid);
+        }
+        if !segment && bdev.info.size_max != 0 && size > bdev.info.size_max as u64 {
+            return Err(BlockErrors::Invalid);
+        }
+
+        // allocate request on the heap
+        let blk_request = kmalloc(size_of::<Request>()) as *mut Request;
+        (*blk_request).header.blktype = blktype;
+        (*blk_request).header.reserved = 0;
+        if segment {
+            // The range goes in the data, which the device reads
+            (*blk_request).header.sector = 0;
+            (*blk_request).segment = Segment {
+                sector,
+                num_sectors: (size / SECTOR_SIZE as u64) as u32,
+                flags: 0,
             };
-            let head_idx = fill_next_descriptor(bdev, desc);
+            (*blk_request).data.data = &mut (*blk_request).segment as *mut Segment as *mut u8;
+            (*blk_request).data.len = size_of::<Segment>() as u32;
+        } else {
             (*blk_request).header.sector = sector;
-            (*blk_request).header.blktype = if true == write {
-                VIRTIO_BLK_T_OUT
-            } else {
-                VIRTIO_BLK_T_IN
-            };
-            (*blk_request).header.reserved = 0;
             (*blk_request).data.data = buffer;
-            (*blk_request).status.status = 111; // arbitrary status, we'll read it back to see if the device has changed it
-            (*blk_request).waiting_pid = pid;
-            let desc = Descriptor {
-                addr: buffer as u64,
-                len: size,
-                flags: virtio::VIRTIO_DESC_F_NEXT
-                    | if false == write {
-                        virtio::VIRTIO_DESC_F_WRITE
-                    } else {
-                        0
-                    },
-                next: 0,
-            };
-            let _data_idx = fill_next_descriptor(bdev, desc);
-            let desc = Descriptor {
-                addr: &(*blk_request).status as *const Status as u64,
-                len: size_of::<Status>() as u32,
-                flags: virtio::VIRTIO_DESC_F_WRITE,
-                next: 0,
-            };
-            let _status_idx = fill_next_descriptor(bdev, desc);
-            (*bdev.queue).avail.ring[(*bdev.queue).avail.idx as usize % virtio::VIRTIO_RING_SIZE] =
-                head_idx;
-            (*bdev.queue).avail.idx = (*bdev.queue).avail.idx.wrapping_add(1);
-            bdev.dev
-                .add(MmioOffsets::QueueNotify.scale32())
-                .write_volatile(0);
+            (*blk_request).data.len = size as u32;
         }
+        (*blk_request).status.status = 111; // arbitrary status, we'll read it back to see if the device has changed it
+        (*blk_request).waiting_pid = pid;
+        (*blk_request).user_buffer = user_buffer;
+
+        BLOCK_LOCK.with(|| {
+            // Anything already waiting goes first
+            if !(bdev.backlog.is_empty() && submit(bdev, blk_request)) {
+                bdev.backlog.push_back(blk_request);
+            }
+        });
+        Ok(())
+    }
+}
+
+// Writes the device's cache out, if it has one
+// don't run inside interrupt context - will block
+pub fn flush_dev(dev: usize) -> Result<(), BlockErrors> {
+    let features = device_info(dev, |bdev| bdev.features).ok_or(BlockErrors::NoDevice)?;
+    if features & (1 << VIRTIO_BLK_F_FLUSH) == 0 {
+        // Without VIRTIO_BLK_F_FLUSH writes are durable once they complete
+        return Ok(());
     }
+    flush_block(dev)
+}
+
+// Discards a byte range, in as many requests as the device needs. Only the whole sectors inside
+// the range are discarded.
+// don't run inside interrupt context - will block
+pub fn discard_range(dev: usize, offset: u64, size: u64) -> Result<(), BlockErrors> {
+    let sector_size = SECTOR_SIZE as u64;
+    let start = (offset + sector_size - 1) / sector_size * sector_size;
+    let end = (offset + size) / sector_size * sector_size;
+    let max_sectors =
+        device_info(dev, |bdev| bdev.max_discard_sectors).ok_or(BlockErrors::NoDevice)?;
+    range_op(start, end, max_sectors, |offset, size| {
+        discard_block(dev, offset, size)
+    })
+}
+
+// Zeroes a sector aligned byte range, in as many requests as the device needs
+// don't run inside interrupt context - will block
+pub fn write_zeroes_range(dev: usize, offset: u64, size: u64) -> Result<(), BlockErrors> {
+    let max_sectors =
+        device_info(dev, |bdev| bdev.max_write_zeroes_sectors).ok_or(BlockErrors::NoDevice)?;
+    range_op(offset, offset + size, max_sectors, |offset, size| {
+        write_zeroes_block(dev, offset, size)
+    })
+}
+
+// What the device reported about itself at probe time
+pub fn info(dev: usize) -> Option<BlockInfo> {
+    device_info(dev, |bdev| bdev.info)
+}
+
+// Splits start..end into pieces of at most max_sectors and calls f on each
+fn range_op(
+    start: u64,
+    end: u64,
+    max_sectors: u32,
+    f: impl Fn(u64, u64) -> Result<(), BlockErrors>,
+) -> Result<(), BlockErrors> {
+    let max_size = if max_sectors == 0 {
+        end.saturating_sub(start)
+    } else {
+        max_sectors as u64 * SECTOR_SIZE as u64
+    };
+    let mut pos = start;
+    while pos < end {
+        let size = max_size.min(end - pos);
+        f(pos, size)?;
+        pos += size;
+    }
+    Ok(())
+}
+
+// Something read off a device, None if there's no such device
+fn device_info<T>(dev: usize, f: impl FnOnce(&BlockDevice) -> T) -> Option<T> {
+    unsafe {
+        match BLOCK_DEVICES.get(dev.wrapping_sub(1)) {
+            Some(Some(bdev)) => Some(f(bdev)),
+            _ => None,
+        }
+    }
+}
+
+// Puts a request on the available ring and tells the device. Returns false if there's no room
+// for it yet.
+unsafe fn submit(bd: &mut BlockDevice, rq: *mut Request) -> bool {
+    let header = Buf::new(&(*rq).header, size_of::<Header>() as u32, false);
+    let status = Buf::new(&(*rq).status, size_of::<Status>() as u32, true);
+    // Everything but a read hands the device data to read
+    let read = (*rq).header.blktype == VIRTIO_BLK_T_IN;
+    let data = Buf::new((*rq).data.data, (*rq).data.len, read);
+    let added = if (*rq).data.len > 0 {
+        bd.vq.add_buf(&[header, data, status], rq as usize)
+    } else {
+        bd.vq.add_buf(&[header, status], rq as usize)
+    };
+    if added {
+        bd.vq.kick();
+    }
+    added
+}
+
+// Hands a finished request's result to whoever is waiting for it
+unsafe fn complete(rq: *mut Request) {
+    let waiting_pid = (*rq).waiting_pid;
+    let result = BlockErrors::from_status((*rq).status.status);
+    let read = (*rq).header.blktype == VIRTIO_BLK_T_IN;
+    if waiting_pid > 0 {
+        process::finish_syscall(waiting_pid, |proc| match (*rq).user_buffer {
+            Some(vaddr) if read && result.is_ok() => {
+                let len = (*rq).data.len as usize;
+                if proc.copy_to_user(vaddr, (*rq).data.data, len) == len {
+                    to_syscall_ret(result)
+                } else {
+                    BlockErrors::Fault.errno()
+                }
+            }
+            _ => to_syscall_ret(result),
+        });
+    } else if let Err(err) = result {
+        println!("Block request failed: {:?}", err);
+    }
+    if (*rq).user_buffer.is_some() {
+        kfree((*rq).data.data);
+    }
+    kfree(rq as *mut u8);
 }
 
 pub fn pending(bd: &mut BlockDevice) {
     unsafe {
-        // check the used ring and free descriptor memory
-        let ref queue = *bd.queue;
-        while bd.ack_used_idx != queue.used.idx {
-            let ref elem = queue.used.ring[bd.ack_used_idx as usize];
-            bd.ack_used_idx = (bd.ack_used_idx + 1) % VIRTIO_RING_SIZE as u16;
-            let rq = queue.desc[elem.id as usize].addr as *const Request;
-            let waiting_pid = (*rq).waiting_pid;
-            if waiting_pid > 0 {
-                process::set_running(waiting_pid);
+        bd.vq
+            .process_used(|token, _| complete(token as *mut Request));
+
+        // Room may have opened up for requests that were waiting
+        while let Some(&rq) = bd.backlog.front() {
+            if false == submit(bd, rq) {
+                break;
             }
-            kfree(rq as *mut u8);
+            bd.backlog.pop_front();
         }
     }
 }
 
 pub fn handle_interrupt(dev_id: usize) {
     unsafe {
-        // println!("handling block interrupt");
-        if let Some(bdev) = BLOCK_DEVICES[dev_id].as_mut() {
-            pending(bdev);
-        } else {
-            println!("Invalid block device for interrupt {}", dev_id + 1);
-        }
+        BLOCK_LOCK.with(|| {
+            // println!("handling block interrupt");
+            if let Some(bdev) = BLOCK_DEVICES[dev_id].as_mut() {
+                pending(bdev);
+            } else {
+                println!("Invalid block device for interrupt {}", dev_id + 1);
+            }
+        });
     }
 }
@@ -1,7 +1,7 @@
 // BlockBuffer
 use crate::{
     cpu::memcpy,
-    kmem::{kfree, kmalloc},
+    kmem::{kfree, kmalloc, kzmalloc},
 };
 use core::{
     ops::{Index, IndexMut},
@@ -21,6 +21,13 @@ impl Buffer {
         }
     }
 
+    pub fn zeroed(size: usize) -> Self {
+        Self {
+            buffer: kzmalloc(size),
+            len: size,
+        }
+    }
+
     pub fn get_mut(&mut self) -> *mut u8 {
         self.buffer
     }
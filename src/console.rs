@@ -1,7 +1,12 @@
 // Console - stdin/out etc.
-use alloc::collections::VecDeque;
-use crate::lock::Mutex;
+use crate::fbcon;
+use crate::fdt;
+use crate::lock::{Mutex, RecursiveMutex};
 use crate::process;
+use crate::uart::Uart;
+use crate::vconsole;
+use alloc::collections::VecDeque;
+use core::fmt::{Error, Write};
 
 pub static mut IN_BUFFER: Option<VecDeque<u8>> = None;
 pub static mut OUT_BUFFER: Option<VecDeque<u8>> = None;
@@ -14,6 +19,57 @@ pub const DEFAULT_OUT_BUFFER_SIZE: usize = 10_000;
 
 pub static mut CONSOLE_QUEUE: Option<VecDeque<u16>> = None;
 
+// Held while printing. The display and virtio console drivers print themselves, so their state
+// is behind it as well rather than a lock of their own that print! would be taken inside.
+static mut OUTPUT_LOCK: RecursiveMutex = RecursiveMutex::new();
+// See panicked
+static mut PANICKED: bool = false;
+
+// What print! writes to - the UART, and the display and virtio console when there are ones
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        if unsafe { PANICKED } {
+            write_uart(s);
+            return Ok(());
+        }
+        with_output(|| {
+            write_uart(s);
+            fbcon::write_str(s);
+            vconsole::write_log(s);
+        });
+        Ok(())
+    }
+}
+
+// Runs f with the output lock held, for the drivers print! writes to
+pub fn with_output<T>(f: impl FnOnce() -> T) -> T {
+    unsafe { OUTPUT_LOCK.with(f) }
+}
+
+// Called by the panic handler. From then on print! only writes to the UART and takes no locks,
+// since whatever held one when the kernel panicked won't let go of it.
+pub fn panicked() {
+    unsafe {
+        PANICKED = true;
+    }
+}
+
+fn write_uart(s: &str) {
+    let base = fdt::platform().uart.base;
+    if base != 0 {
+        let _ = Uart::new(base).write_str(s);
+    }
+    // Only under the firmware is there no UART, its console is used instead
+    #[cfg(feature = "sbi")]
+    {
+        if base == 0 {
+            crate::sbi::console_write(s);
+        }
+    }
+}
+
 pub fn init() {
     unsafe {
         IN_BUFFER.replace(VecDeque::with_capacity(DEFAULT_IN_BUFFER_SIZE));
@@ -70,6 +126,15 @@ pub fn push_stdin(c: u8) {
     }
 }
 
+// Shows what was typed. A backspace has to rub out the last character.
+pub fn echo(c: u8) {
+    match c {
+        8 => print!("{} {}", 8 as char, 8 as char),
+        10 | 13 => println!(),
+        _ => print!("{}", c as char),
+    }
+}
+
 pub fn pop_stdin() -> u8 {
     let mut ret = None;
     unsafe {
@@ -90,4 +155,4 @@ pub fn push_queue(pid: u16) {
             CONSOLE_QUEUE.replace(q);
         }
     }
-}
\ No newline at end of file
+}
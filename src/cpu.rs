@@ -1,4 +1,6 @@
+use crate::fdt;
 use alloc::{format, string::String};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 #[repr(usize)]
 pub enum SatpMode {
@@ -78,7 +80,10 @@ impl TrapFrame {
 	}
 }
 
-pub static mut KERNEL_TRAP_FRAME: [TrapFrame; 8] = [TrapFrame::zero(); 8];
+// Harts there's a trap frame and a slice of the kernel stack for, the rest stay parked
+pub const MAX_HARTS: usize = 8;
+
+pub static mut KERNEL_TRAP_FRAME: [TrapFrame; MAX_HARTS] = [TrapFrame::zero(); MAX_HARTS];
 
 pub fn wfi() {
 	unsafe {
@@ -98,6 +103,24 @@ pub fn mhartid_read() -> usize {
 	}
 }
 
+pub fn tp_read() -> usize {
+	unsafe {
+		let rval;
+		llvm_asm!("mv $0, tp" :"=r"(rval));
+		rval
+	}
+}
+
+// Hart this is running on. Supervisor mode can't read mhartid, under the firmware the boot
+// code and the trap vector keep it in tp instead.
+pub fn hartid() -> usize {
+	if cfg!(feature = "sbi") {
+		tp_read()
+	} else {
+		mhartid_read()
+	}
+}
+
 pub fn mstatus_write(val: usize) {
 	unsafe {
 		llvm_asm!("csrw	mstatus, $0" ::"r"(val));
@@ -112,6 +135,47 @@ pub fn mstatus_read() -> usize {
 	}
 }
 
+// Machine interrupt enable bit of mstatus
+pub const MSTATUS_MIE: usize = 1 << 3;
+
+pub fn sstatus_write(val: usize) {
+	unsafe {
+		llvm_asm!("csrw	sstatus, $0" ::"r"(val));
+	}
+}
+
+pub fn sstatus_read() -> usize {
+	unsafe {
+		let rval;
+		llvm_asm!("csrr	$0, sstatus":"=r"(rval));
+		rval
+	}
+}
+
+// Supervisor interrupt enable bit of sstatus
+pub const SSTATUS_SIE: usize = 1 << 1;
+
+// Runs f with this hart's machine interrupts off. The other harts carry on, so whatever an
+// interrupt handler shares with f needs a lock as well, see lock::Mutex::with.
+#[cfg(not(feature = "sbi"))]
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+	let mstatus = mstatus_read();
+	mstatus_write(mstatus & !MSTATUS_MIE);
+	let ret = f();
+	mstatus_write(mstatus_read() | (mstatus & MSTATUS_MIE));
+	ret
+}
+
+// The same with supervisor interrupts, which are all the kernel gets under the firmware
+#[cfg(feature = "sbi")]
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+	let sstatus = sstatus_read();
+	sstatus_write(sstatus & !SSTATUS_SIE);
+	let ret = f();
+	sstatus_write(sstatus_read() | (sstatus & SSTATUS_SIE));
+	ret
+}
+
 pub fn stvec_write(val: usize) {
 	unsafe {
 		llvm_asm!("csrw	stvec, $0" ::"r"(val));
@@ -210,6 +274,75 @@ pub fn satp_fence_asid(asid: usize) {
 	}
 }
 
+// Flushes vaddr's translations for every ASID on this hart
+pub fn satp_fence_vaddr(vaddr: usize) {
+	unsafe {
+		llvm_asm!("sfence.vma $0, zero" :: "r"(vaddr));
+	}
+}
+
+// Flushes every translation this hart has cached
+pub fn satp_fence_all() {
+	unsafe {
+		llvm_asm!("sfence.vma" :::: "volatile");
+	}
+}
+
+// TLB shootdowns. A hart asking for a flush bumps the other harts' request counts and interrupts
+// them, each answers by flushing and copying its request count to its ack count.
+static TLB_REQUESTS: [AtomicUsize; MAX_HARTS] = [
+	AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+	AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+static TLB_ACKS: [AtomicUsize; MAX_HARTS] = [
+	AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+	AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+// Harts that are scheduling and so can answer, one bit each
+static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+// Marks this hart as taking shootdowns. Returns false if it already was.
+pub fn set_online() -> bool {
+	let bit = 1 << hartid();
+	ONLINE_HARTS.fetch_or(bit, Ordering::SeqCst) & bit == 0
+}
+
+// Drops asid's translations on every hart, for when a page table has lost mappings or write
+// permission. Waits until the other harts have flushed, so nothing stale is used afterwards.
+// Don't hold a lock another hart may spin on with interrupts off.
+pub fn flush_asid_all_harts(asid: usize) {
+	satp_fence_asid(asid);
+	let me = hartid();
+	let others = ONLINE_HARTS.load(Ordering::SeqCst) & !(1 << me);
+	let mut wanted = [0; MAX_HARTS];
+	for hart in 0..MAX_HARTS {
+		if others & (1 << hart) != 0 {
+			wanted[hart] = TLB_REQUESTS[hart].fetch_add(1, Ordering::SeqCst) + 1;
+			send_software_interrupt(hart);
+		}
+	}
+	for hart in 0..MAX_HARTS {
+		if others & (1 << hart) != 0 {
+			while TLB_ACKS[hart].load(Ordering::SeqCst) < wanted[hart] {
+				// The other hart may be waiting on this one the same way
+				handle_tlb_flush();
+				core::hint::spin_loop();
+			}
+		}
+	}
+}
+
+// Answers the shootdowns asked of this hart, if there are any. Other harts' ASIDs are flushed
+// too, which is simpler than keeping a list.
+pub fn handle_tlb_flush() {
+	let me = hartid();
+	let requested = TLB_REQUESTS[me].load(Ordering::SeqCst);
+	if TLB_ACKS[me].load(Ordering::SeqCst) < requested {
+		satp_fence_all();
+		TLB_ACKS[me].store(requested, Ordering::SeqCst);
+	}
+}
+
 pub const MTIMER_TICKS_PER_MS: u64 = 10_000;
 pub const MTIMER_TICKS_PER_SEC: u64 = MTIMER_TICKS_PER_MS * 1000;
 pub const MTIMER_TICKS_PER_MIN: u64 = MTIMER_TICKS_PER_SEC * 60;
@@ -265,18 +398,72 @@ impl MachineTime {
 	}
 }
 
+// CLINT register offsets, msip (4 bytes a hart) and mtimecmp (8 bytes a hart) are hart 0's
+const CLINT_MSIP: usize = 0;
+#[cfg(not(feature = "sbi"))]
+const CLINT_MTIMECMP: usize = 0x4000;
+#[cfg(not(feature = "sbi"))]
+const CLINT_MTIME: usize = 0xbff8;
+
+// Raises a software interrupt (an IPI) on hart
+pub fn send_software_interrupt(hart: usize) {
+	#[cfg(feature = "sbi")]
+	crate::sbi::send_ipi(1, hart);
+	#[cfg(not(feature = "sbi"))]
+	{
+		let msip = (fdt::platform().clint.base + CLINT_MSIP) as *mut u32;
+		unsafe {
+			msip.add(hart).write_volatile(1);
+		}
+	}
+}
+
+// Clears a software interrupt (an IPI) on hart, or it would trap again straight away
+pub fn clear_software_interrupt(hart: usize) {
+	if cfg!(feature = "sbi") {
+		// Supervisor software interrupt pending (SSIP), which the firmware sets
+		unsafe {
+			llvm_asm!("csrc	sip, $0" ::"r"(1 << 1));
+		}
+	} else {
+		let msip = (fdt::platform().clint.base + CLINT_MSIP) as *mut u32;
+		unsafe {
+			msip.add(hart).write_volatile(0);
+		}
+	}
+}
+
+#[cfg(not(feature = "sbi"))]
 pub fn get_mtime() -> MachineTime {
-	let mtime = 0x0200_bff8 as *const u64;
+	let mtime = (fdt::platform().clint.base + CLINT_MTIME) as *const u64;
 	let mtime_u64;
 	unsafe { mtime_u64 = mtime.read_volatile() }
 	MachineTime::from_ticks(mtime_u64)
 }
 
+#[cfg(not(feature = "sbi"))]
 pub fn set_next_minterrupt(next_time: MachineTime) {
-	let mtimecmp = 0x0200_4000 as *mut u64;
+	// Every hart has its own timer
+	let mtimecmp = (fdt::platform().clint.base + CLINT_MTIMECMP) as *mut u64;
+	unsafe {
+		mtimecmp.add(hartid()).write_volatile(next_time.as_u64());
+	}
+}
+
+// Under the firmware the CLINT is machine mode's - mtime can be read through the time CSR, and
+// the timer is set with an SBI call, which raises a supervisor timer interrupt instead
+#[cfg(feature = "sbi")]
+pub fn get_mtime() -> MachineTime {
+	let mtime_u64;
 	unsafe {
-		mtimecmp.write_volatile(next_time.as_u64());
+		llvm_asm!("rdtime	$0" :"=r"(mtime_u64));
 	}
+	MachineTime::from_ticks(mtime_u64)
+}
+
+#[cfg(feature = "sbi")]
+pub fn set_next_minterrupt(next_time: MachineTime) {
+	crate::sbi::set_timer(next_time.as_u64());
 }
 
 pub unsafe fn memcpy(dest: *mut u8, src: *const u8, bytes: usize) {
@@ -290,7 +477,7 @@ pub unsafe fn memcpy(dest: *mut u8, src: *const u8, bytes: usize) {
 
 	let bytes_completed = bytes_as_8 * 8;
 	let bytes_remaining = bytes - bytes_completed;
-	for i in bytes_completed..bytes_remaining {
-		*dest_as_8.add(i) = *src_as_8.add(i);
+	for i in bytes_completed..bytes_completed + bytes_remaining {
+		*dest.add(i) = *src.add(i);
 	}
 }
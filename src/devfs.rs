@@ -0,0 +1,168 @@
+// Device filesystem - the device nodes, mounted at /dev. Nodes come from the fixed table below
+// and from drivers registering theirs as they find devices.
+use crate::random;
+use crate::vfs::{DirEntry, FileSystem, Stat, S_IFCHR, S_IFDIR, S_IFMT};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const ROOT_INODE: u32 = 1;
+
+// A node's handlers get its minor number, then the same arguments as FileSystem::read and write
+pub struct Device {
+    // Path below /dev, with the minor number appended if there is one. Nodes in a subdirectory
+    // need the directory's node too.
+    pub name: &'static str,
+    pub minor: Option<usize>,
+    pub mode: u16,
+    pub read: fn(usize, *mut u8, u32, u32) -> u32,
+    pub write: Option<fn(usize, *const u8, u32, u32) -> Option<u32>>,
+    // Physical address of the node's memory from offset on, for nodes that are memory
+    pub mmap: Option<fn(usize, u32, u32) -> Option<usize>>,
+}
+
+// Inode numbers follow the root, in this order and then in the order nodes were registered
+const DEVICES: &[Device] = &[
+    Device {
+        name: "random",
+        minor: None,
+        mode: S_IFCHR | 0o444,
+        read: read_random,
+        write: None,
+        mmap: None,
+    },
+    Device {
+        name: "urandom",
+        minor: None,
+        mode: S_IFCHR | 0o444,
+        read: read_random,
+        write: None,
+        mmap: None,
+    },
+    Device {
+        name: "input",
+        minor: None,
+        mode: S_IFDIR | 0o755,
+        read: read_nothing,
+        write: None,
+        mmap: None,
+    },
+];
+
+static mut REGISTERED: Vec<Device> = Vec::new();
+
+// Adds a node. Drivers call it while probing, before anything can look at /dev.
+pub fn register(dev: Device) {
+    unsafe {
+        REGISTERED.push(dev);
+    }
+}
+
+// Both block until the entropy pool has enough, like /dev/random does since Linux 5.6
+fn read_random(_minor: usize, buffer: *mut u8, size: u32, _offset: u32) -> u32 {
+    let buf = unsafe { core::slice::from_raw_parts_mut(buffer, size as usize) };
+    random::fill(buf);
+    size
+}
+
+fn read_nothing(_minor: usize, _buffer: *mut u8, _size: u32, _offset: u32) -> u32 {
+    0
+}
+
+impl Device {
+    fn path(&self) -> String {
+        match self.minor {
+            Some(minor) => format!("{}{}", self.name, minor),
+            None => String::from(self.name),
+        }
+    }
+}
+
+pub struct DevFileSystem;
+
+impl DevFileSystem {
+    pub fn new() -> Self {
+        DevFileSystem
+    }
+
+    fn devices() -> impl Iterator<Item = &'static Device> {
+        unsafe { DEVICES.iter().chain(REGISTERED.iter()) }
+    }
+
+    fn device(inode_num: u32) -> Option<&'static Device> {
+        Self::devices().nth(inode_num.checked_sub(ROOT_INODE + 1)? as usize)
+    }
+}
+
+impl FileSystem for DevFileSystem {
+    fn lookup(&self, path: &str) -> Option<u32> {
+        let name = path.trim_matches('/');
+        if name.is_empty() {
+            return Some(ROOT_INODE);
+        }
+        Self::devices()
+            .position(|dev| dev.path() == name)
+            .map(|index| ROOT_INODE + 1 + index as u32)
+    }
+
+    fn stat(&self, inode_num: u32) -> Option<Stat> {
+        let mode = if inode_num == ROOT_INODE {
+            S_IFDIR | 0o755
+        } else {
+            Self::device(inode_num)?.mode
+        };
+        Some(Stat {
+            ino: inode_num as u64,
+            mode: mode as u32,
+            nlink: 1,
+            ..Stat::default()
+        })
+    }
+
+    fn read(&self, inode_num: u32, buffer: *mut u8, size: u32, offset: u32) -> u32 {
+        match Self::device(inode_num) {
+            Some(dev) => (dev.read)(dev.minor.unwrap_or(0), buffer, size, offset),
+            None => 0,
+        }
+    }
+
+    fn readdir(&self, inode_num: u32) -> Option<Vec<DirEntry>> {
+        // The directory's path, with a trailing slash unless it's the root
+        let prefix = if inode_num == ROOT_INODE {
+            String::new()
+        } else {
+            let dir = Self::device(inode_num)?;
+            if dir.mode & S_IFMT != S_IFDIR {
+                return None;
+            }
+            format!("{}/", dir.path())
+        };
+        let mut entries = Vec::new();
+        entries.push(DirEntry {
+            inode: inode_num,
+            name: String::from("."),
+        });
+        for (index, dev) in Self::devices().enumerate() {
+            let path = dev.path();
+            let name = match path.strip_prefix(prefix.as_str()) {
+                Some(name) if !name.contains('/') => name,
+                _ => continue,
+            };
+            entries.push(DirEntry {
+                inode: ROOT_INODE + 1 + index as u32,
+                name: String::from(name),
+            });
+        }
+        Some(entries)
+    }
+
+    fn write(&self, inode_num: u32, buffer: *const u8, size: u32, offset: u32) -> Option<u32> {
+        let dev = Self::device(inode_num)?;
+        dev.write?(dev.minor.unwrap_or(0), buffer, size, offset)
+    }
+
+    fn mmap(&self, inode_num: u32, offset: u32, len: u32) -> Option<usize> {
+        let dev = Self::device(inode_num)?;
+        dev.mmap?(dev.minor.unwrap_or(0), offset, len)
+    }
+}
@@ -0,0 +1,400 @@
+// ELF64 executable loader
+use crate::buffer::Buffer;
+use crate::cpu::{CpuMode, MachineTime, Registers, TrapFrame};
+use crate::fdt;
+use crate::mmu::{copy_to_virt, get_leaf, map, EntryBits, Table};
+use crate::page::{align_val, zalloc, PAGE_SIZE};
+use crate::process::{Process, ProcessData, ProcessState, STACK_ADDR, STACK_PAGES};
+use crate::random;
+use crate::{HEAP_START, TEXT_START};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::{align_of, size_of};
+use core::ptr::{copy_nonoverlapping, null_mut};
+
+pub const MAGIC: u32 = 0x464c_457f; // 0x7f 'E' 'L' 'F'
+pub const CLASS_64: u8 = 2;
+pub const DATA_LITTLE_ENDIAN: u8 = 1;
+pub const MACHINE_RISCV: u16 = 0xf3;
+pub const TYPE_EXEC: u16 = 2;
+
+// Program header segment types
+pub const PH_SEG_TYPE_NULL: u32 = 0;
+pub const PH_SEG_TYPE_LOAD: u32 = 1;
+pub const PH_SEG_TYPE_DYNAMIC: u32 = 2;
+pub const PH_SEG_TYPE_INTERP: u32 = 3;
+pub const PH_SEG_TYPE_NOTE: u32 = 4;
+
+// Program header flags
+pub const PROG_EXECUTE: u32 = 1;
+pub const PROG_WRITE: u32 = 2;
+pub const PROG_READ: u32 = 4;
+
+// Auxiliary vector types
+pub const AT_NULL: usize = 0;
+pub const AT_PHDR: usize = 3;
+pub const AT_PHENT: usize = 4;
+pub const AT_PHNUM: usize = 5;
+pub const AT_PAGESZ: usize = 6;
+pub const AT_ENTRY: usize = 9;
+pub const AT_RANDOM: usize = 25;
+
+// Most the top of the stack is moved down by, so its addresses differ between runs
+pub const STACK_RANDOM_GAP: usize = 4 * PAGE_SIZE;
+// Pages mapped for a program's stack - the gap comes on top of STACK_PAGES, so a program always
+// has at least those
+pub const USER_STACK_PAGES: usize = STACK_PAGES + STACK_RANDOM_GAP / PAGE_SIZE;
+
+#[repr(C)]
+pub struct Header {
+    pub magic: u32,
+    pub bitsize: u8,
+    pub endian: u8,
+    pub ident_abi_version: u8,
+    pub target_platform: u8,
+    pub abi_version: u8,
+    pub padding: [u8; 7],
+    pub obj_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry_addr: usize,
+    pub phoff: usize,
+    pub shoff: usize,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct ProgramHeader {
+    pub seg_type: u32,
+    pub flags: u32,
+    pub off: usize,
+    pub vaddr: usize,
+    pub paddr: usize,
+    pub filesz: usize,
+    pub memsz: usize,
+    pub align: usize,
+}
+
+#[derive(Debug)]
+pub enum LoadErrors {
+    TooSmall,
+    Magic,
+    Class,
+    Machine,
+    TypeExec,
+    ProgramHeaders,
+    // A loadable segment that's out of bounds or overlaps memory the kernel maps itself
+    BadSegment,
+    OutOfMemory,
+    // The executable couldn't be found or read from disk
+    NotFound,
+    Read,
+    // argv/envp don't fit on the stack
+    Arguments,
+}
+
+impl LoadErrors {
+    // Negated Linux errno, which is what execv returns on failure
+    pub fn errno(&self) -> usize {
+        let errno: isize = match self {
+            LoadErrors::NotFound => 2,     // ENOENT
+            LoadErrors::Read => 5,         // EIO
+            LoadErrors::Arguments => 7,    // E2BIG
+            LoadErrors::OutOfMemory => 12, // ENOMEM
+            _ => 8,                        // ENOEXEC
+        };
+        -errno as usize
+    }
+}
+
+pub struct File<'a> {
+    pub header: &'a Header,
+    pub program_headers: &'a [ProgramHeader],
+    buffer: &'a Buffer,
+}
+
+impl<'a> File<'a> {
+    // Validates the ELF header and program headers inside the buffer
+    pub fn load(buffer: &'a Buffer) -> Result<Self, LoadErrors> {
+        if buffer.len() < size_of::<Header>() {
+            return Err(LoadErrors::TooSmall);
+        }
+        let header = unsafe { &*(buffer.get() as *const Header) };
+        if header.magic != MAGIC {
+            return Err(LoadErrors::Magic);
+        }
+        if header.bitsize != CLASS_64 || header.endian != DATA_LITTLE_ENDIAN {
+            return Err(LoadErrors::Class);
+        }
+        if header.machine != MACHINE_RISCV {
+            return Err(LoadErrors::Machine);
+        }
+        if header.obj_type != TYPE_EXEC {
+            return Err(LoadErrors::TypeExec);
+        }
+        let ph_end = (header.phnum as usize * size_of::<ProgramHeader>()).checked_add(header.phoff);
+        if header.phentsize as usize != size_of::<ProgramHeader>()
+            || header.phoff % align_of::<ProgramHeader>() != 0
+            || ph_end.map_or(true, |ph_end| ph_end > buffer.len())
+        {
+            return Err(LoadErrors::ProgramHeaders);
+        }
+        let program_headers = unsafe {
+            core::slice::from_raw_parts(
+                buffer.get().add(header.phoff) as *const ProgramHeader,
+                header.phnum as usize,
+            )
+        };
+        for ph in program_headers.iter() {
+            if ph.seg_type != PH_SEG_TYPE_LOAD {
+                continue;
+            }
+            let file_end = ph.off.checked_add(ph.filesz);
+            // The end is rounded up to a page when it's mapped
+            let mem_end = ph
+                .vaddr
+                .checked_add(ph.memsz)
+                .and_then(|end| end.checked_add(PAGE_SIZE - 1));
+            if ph.filesz > ph.memsz
+                || file_end.map_or(true, |file_end| file_end > buffer.len())
+                || mem_end.is_none()
+            {
+                return Err(LoadErrors::BadSegment);
+            }
+            let start = ph.vaddr & !(PAGE_SIZE - 1);
+            let end = mem_end.unwrap() & !(PAGE_SIZE - 1);
+            if reserved_ranges()
+                .iter()
+                .any(|&(r_start, r_end)| start < r_end && r_start < end)
+            {
+                return Err(LoadErrors::BadSegment);
+            }
+        }
+
+        Ok(File {
+            header,
+            program_headers,
+            buffer,
+        })
+    }
+
+    // Maps every PT_LOAD segment into the given table. Each page is allocated on its own and
+    // marked as owned by the table, so it's freed when the table is unmapped.
+    pub fn map_segments(&self, table: &mut Table) -> Result<(), LoadErrors> {
+        for ph in self.program_headers.iter() {
+            if ph.seg_type != PH_SEG_TYPE_LOAD || ph.memsz == 0 {
+                continue;
+            }
+            let mut bits = EntryBits::User.val() | EntryBits::Owned.val();
+            if ph.flags & PROG_READ != 0 {
+                bits |= EntryBits::Read.val();
+            }
+            if ph.flags & PROG_WRITE != 0 {
+                bits |= EntryBits::Write.val();
+            }
+            if ph.flags & PROG_EXECUTE != 0 {
+                bits |= EntryBits::Execute.val();
+            }
+            if bits & EntryBits::ReadWriteExecute.val() == 0 {
+                // A loadable segment with no permissions is still readable
+                bits |= EntryBits::Read.val();
+            }
+
+            let seg_start = ph.vaddr & !(PAGE_SIZE - 1);
+            let seg_end = align_val(ph.vaddr + ph.memsz, 12);
+            for page_vaddr in (seg_start..seg_end).step_by(PAGE_SIZE) {
+                // Segments aren't required to be page aligned, so two of them can share a page
+                let mut page_bits = bits;
+                let page = match get_leaf(table, page_vaddr) {
+                    Some(entry) => {
+                        page_bits |= entry.get_entry() & 0x3ff & !EntryBits::Valid.val();
+                        ((entry.get_entry() & !0x3ff) << 2) as *mut u8
+                    }
+                    None => zalloc(1),
+                };
+                if page.is_null() {
+                    return Err(LoadErrors::OutOfMemory);
+                }
+
+                // Copy the part of the file image that lands in this page - the rest stays zeroed
+                let copy_start = if page_vaddr > ph.vaddr {
+                    page_vaddr
+                } else {
+                    ph.vaddr
+                };
+                let file_end = ph.vaddr + ph.filesz;
+                let copy_end = if page_vaddr + PAGE_SIZE < file_end {
+                    page_vaddr + PAGE_SIZE
+                } else {
+                    file_end
+                };
+                if copy_start < copy_end {
+                    unsafe {
+                        copy_nonoverlapping(
+                            self.buffer.get().add(ph.off + (copy_start - ph.vaddr)),
+                            page.add(copy_start - page_vaddr),
+                            copy_end - copy_start,
+                        );
+                    }
+                }
+                map(table, page_vaddr, page as usize, page_bits, 0);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Auxiliary vector entries describing this image, for the program's startup code
+    pub fn auxv(&self) -> Vec<(usize, usize)> {
+        let mut auxv = Vec::new();
+        // The program headers are visible if they're inside a loaded segment
+        for ph in self.program_headers.iter() {
+            if ph.seg_type == PH_SEG_TYPE_LOAD
+                && self.header.phoff >= ph.off
+                && self.header.phoff < ph.off + ph.filesz
+            {
+                auxv.push((AT_PHDR, ph.vaddr + (self.header.phoff - ph.off)));
+                break;
+            }
+        }
+        auxv.push((AT_PHENT, size_of::<ProgramHeader>()));
+        auxv.push((AT_PHNUM, self.program_headers.len()));
+        auxv.push((AT_PAGESZ, PAGE_SIZE));
+        auxv.push((AT_ENTRY, self.header.entry_addr));
+        auxv
+    }
+
+    // Builds a user-mode process from the ELF image. The caller assigns the PID.
+    pub fn load_proc(&self) -> Result<Process, LoadErrors> {
+        let proc = Process {
+            frame: zalloc(1) as *mut TrapFrame,
+            stack: null_mut(),
+            pid: 0,
+            root_table: zalloc(1) as *mut Table,
+            state: ProcessState::Running,
+            data: ProcessData::zero(),
+            sleep_until: MachineTime::zero(),
+            program: null_mut(),
+            brk: 0,
+            parent: 0,
+            exit_status: 0,
+            wait_for: None,
+            hart: None,
+        };
+        let table = unsafe { &mut *proc.root_table };
+        self.map_segments(table)?;
+        map_stack(table)?;
+
+        unsafe {
+            (*proc.frame).pc = self.header.entry_addr;
+            (*proc.frame).regs[Registers::Sp as usize] = STACK_ADDR + PAGE_SIZE * USER_STACK_PAGES;
+            (*proc.frame).mode = CpuMode::User as usize;
+        }
+
+        Ok(proc)
+    }
+}
+
+// Address ranges a program's segments mustn't cover: its stack, and the kernel from its text to
+// the end of the heap - under the firmware the trampoline and trap frame are mapped in there
+fn reserved_ranges() -> [(usize, usize); 2] {
+    let stack_end = STACK_ADDR + USER_STACK_PAGES * PAGE_SIZE;
+    let kernel = unsafe { (TEXT_START, fdt::platform().heap_end(HEAP_START)) };
+    [(STACK_ADDR, stack_end), kernel]
+}
+
+// Allocates and maps USER_STACK_PAGES of user stack starting at STACK_ADDR
+pub fn map_stack(table: &mut Table) -> Result<(), LoadErrors> {
+    for i in 0..USER_STACK_PAGES {
+        let page = zalloc(1);
+        if page.is_null() {
+            return Err(LoadErrors::OutOfMemory);
+        }
+        map(
+            table,
+            STACK_ADDR + i * PAGE_SIZE,
+            page as usize,
+            EntryBits::UserReadWrite.val() | EntryBits::Owned.val(),
+            0,
+        );
+    }
+
+    Ok(())
+}
+
+// Lays out argc, argv, envp and the auxiliary vector at the top of the stack as described by the
+// RISC-V psABI, with the strings themselves above them. Returns the new stack pointer.
+// don't run inside interrupt context - will block
+pub fn init_stack(
+    table: &mut Table,
+    argv: &[String],
+    envp: &[String],
+    auxv: &[(usize, usize)],
+) -> Result<usize, LoadErrors> {
+    let gap = random::random_usize() % STACK_RANDOM_GAP & !0xf;
+    let mut sp = STACK_ADDR + PAGE_SIZE * USER_STACK_PAGES - gap;
+
+    // 16 random bytes for the program's stack canary and the like, found through AT_RANDOM
+    let mut seed = [0u8; 16];
+    random::fill(&mut seed);
+    sp -= seed.len();
+    copy_to_virt(table, sp, seed.as_ptr(), seed.len());
+    let seed_addr = sp;
+
+    // Strings go next, each NUL terminated
+    let envp_addrs = push_strings(table, envp, &mut sp)?;
+    let argv_addrs = push_strings(table, argv, &mut sp)?;
+
+    // argc, argv pointers + NULL, envp pointers + NULL, auxv pairs + AT_NULL
+    let mut words = Vec::with_capacity(3 + argv.len() + envp.len() + 2 * (auxv.len() + 2));
+    words.push(argv.len());
+    words.extend_from_slice(&argv_addrs);
+    words.push(0);
+    words.extend_from_slice(&envp_addrs);
+    words.push(0);
+    for (key, val) in auxv.iter() {
+        words.push(*key);
+        words.push(*val);
+    }
+    words.push(AT_RANDOM);
+    words.push(seed_addr);
+    words.push(AT_NULL);
+    words.push(0);
+
+    // The stack pointer must be 16-byte aligned at entry
+    let size = words.len() * size_of::<usize>();
+    if sp - STACK_ADDR < size + 16 {
+        return Err(LoadErrors::Arguments);
+    }
+    sp = (sp - size) & !0xf;
+    copy_to_virt(table, sp, words.as_ptr() as *const u8, size);
+
+    Ok(sp)
+}
+
+// Copies strings below the stack pointer, returning the address of each one
+fn push_strings(
+    table: &mut Table,
+    strings: &[String],
+    sp: &mut usize,
+) -> Result<Vec<usize>, LoadErrors> {
+    let mut addrs = Vec::with_capacity(strings.len());
+    for string in strings.iter() {
+        let len = string.len() + 1;
+        if *sp - STACK_ADDR < len {
+            return Err(LoadErrors::Arguments);
+        }
+        *sp -= len;
+        copy_to_virt(table, *sp, string.as_ptr(), string.len());
+        copy_to_virt(table, *sp + string.len(), [0u8].as_ptr(), 1);
+        addrs.push(*sp);
+    }
+    Ok(addrs)
+}
@@ -0,0 +1,325 @@
+// Flattened device tree - what the machine has, as handed over in a1 by QEMU or the firmware.
+// It's parsed once at boot, before there's an allocator, into the fixed tables below. Without a
+// tree the layout of QEMU's virt machine with 128M is assumed.
+use crate::TEXT_START;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+// Structure block tokens
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// Header fields, in u32s
+const HEADER_TOTALSIZE: usize = 1;
+const HEADER_OFF_DT_STRUCT: usize = 2;
+const HEADER_OFF_DT_STRINGS: usize = 3;
+const HEADER_OFF_MEM_RSVMAP: usize = 4;
+
+// Virtio slots there's room for, every driver keeps this many devices
+pub const MAX_VIRTIO: usize = 8;
+// Deepest node that's looked at, QEMU's trees go 3 deep
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Mmio {
+    pub base: usize,
+    pub size: usize,
+    // PLIC interrupt, 0 if there isn't one
+    pub irq: u32,
+}
+
+impl Mmio {
+    const fn new(base: usize, size: usize, irq: u32) -> Self {
+        Mmio { base, size, irq }
+    }
+}
+
+pub struct Platform {
+    // The RAM the kernel was loaded into
+    pub memory: Mmio,
+    pub harts: usize,
+    pub uart: Mmio,
+    pub plic: Mmio,
+    pub clint: Mmio,
+    // By address, which is the order QEMU numbers them in
+    virtio: [Mmio; MAX_VIRTIO],
+    num_virtio: usize,
+    // The tree itself and the reservations it lists, which the heap has to stay below
+    reserved: [Mmio; 4],
+    num_reserved: usize,
+}
+
+static mut PLATFORM: Platform = Platform {
+    memory: Mmio::new(0x8000_0000, 128 << 20, 0),
+    harts: 1,
+    uart: Mmio::new(0x1000_0000, 0x100, 10),
+    plic: Mmio::new(0x0c00_0000, 0x60_0000, 0),
+    clint: Mmio::new(0x0200_0000, 0x1_0000, 0),
+    virtio: [
+        Mmio::new(0x1000_1000, 0x1000, 1),
+        Mmio::new(0x1000_2000, 0x1000, 2),
+        Mmio::new(0x1000_3000, 0x1000, 3),
+        Mmio::new(0x1000_4000, 0x1000, 4),
+        Mmio::new(0x1000_5000, 0x1000, 5),
+        Mmio::new(0x1000_6000, 0x1000, 6),
+        Mmio::new(0x1000_7000, 0x1000, 7),
+        Mmio::new(0x1000_8000, 0x1000, 8),
+    ],
+    num_virtio: MAX_VIRTIO,
+    reserved: [Mmio::new(0, 0, 0); 4],
+    num_reserved: 0,
+};
+
+pub fn platform() -> &'static Platform {
+    unsafe { &PLATFORM }
+}
+
+impl Platform {
+    pub fn virtio(&self) -> &[Mmio] {
+        &self.virtio[..self.num_virtio]
+    }
+
+    // Slot of the virtio device raising a PLIC interrupt
+    pub fn virtio_slot(&self, irq: u32) -> Option<usize> {
+        self.virtio().iter().position(|slot| slot.irq == irq)
+    }
+
+    // Where the heap starting at heap_start has to end - the end of RAM, or the first thing
+    // after heap_start that has to be left alone
+    pub fn heap_end(&self, heap_start: usize) -> usize {
+        self.reserved[..self.num_reserved]
+            .iter()
+            .map(|r| r.base)
+            .filter(|&base| base >= heap_start)
+            .fold(self.memory.base + self.memory.size, usize::min)
+    }
+}
+
+// What's been seen of a node so far. Properties come before child nodes, so everything's known
+// by the node's end.
+#[derive(Copy, Clone)]
+struct Node {
+    // Cells of the node's own reg, which its parent decides
+    address_cells: u32,
+    size_cells: u32,
+    // What its children's reg uses
+    child_address_cells: u32,
+    child_size_cells: u32,
+    compatible: &'static [u8],
+    device_type: &'static [u8],
+    reg: &'static [u8],
+    interrupts: &'static [u8],
+    disabled: bool,
+}
+
+impl Node {
+    const fn new(address_cells: u32, size_cells: u32) -> Self {
+        Node {
+            address_cells,
+            size_cells,
+            // What the spec says to assume when a node doesn't say
+            child_address_cells: 2,
+            child_size_cells: 1,
+            compatible: &[],
+            device_type: &[],
+            reg: &[],
+            interrupts: &[],
+            disabled: false,
+        }
+    }
+
+    // compatible is a list of NUL terminated strings
+    fn is_compatible(&self, names: &[&str]) -> bool {
+        self.compatible
+            .split(|&b| b == 0)
+            .any(|c| names.iter().any(|name| name.as_bytes() == c))
+    }
+
+    // First address and size in reg
+    fn reg(&self) -> Option<(usize, usize)> {
+        let (a, s) = (self.address_cells as usize, self.size_cells as usize);
+        // More than two cells doesn't fit in a usize
+        if a > 2 || s > 2 || self.reg.len() < (a + s) * 4 {
+            return None;
+        }
+        Some((
+            cells(&self.reg[..a * 4]),
+            cells(&self.reg[a * 4..(a + s) * 4]),
+        ))
+    }
+
+    fn mmio(&self) -> Option<Mmio> {
+        let (base, size) = self.reg()?;
+        let irq = if self.interrupts.len() >= 4 {
+            cells(&self.interrupts[..4]) as u32
+        } else {
+            0
+        };
+        Some(Mmio::new(base, size, irq))
+    }
+}
+
+// A big-endian number made of any number of cells
+fn cells(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |acc, &b| acc << 8 | b as usize)
+}
+
+unsafe fn be32(ptr: *const u8) -> u32 {
+    u32::from_be((ptr as *const u32).read_unaligned())
+}
+
+unsafe fn be64(ptr: *const u8) -> u64 {
+    u64::from_be((ptr as *const u64).read_unaligned())
+}
+
+// Length of a NUL terminated string
+unsafe fn strlen(ptr: *const u8) -> usize {
+    let mut len = 0;
+    while ptr.add(len).read() != 0 {
+        len += 1;
+    }
+    len
+}
+
+// Reads the tree at fdt, if that's what it is. Returns false and keeps the defaults otherwise.
+// Runs first thing in kinit, with nothing else set up.
+pub fn init(fdt: usize) -> bool {
+    unsafe {
+        let base = fdt as *const u8;
+        if fdt == 0 || be32(base) != FDT_MAGIC {
+            return false;
+        }
+        let header = |field: usize| be32(base.add(field * 4)) as usize;
+        let mut found = Platform {
+            memory: PLATFORM.memory,
+            harts: 0,
+            uart: PLATFORM.uart,
+            plic: PLATFORM.plic,
+            clint: PLATFORM.clint,
+            virtio: [Mmio::new(0, 0, 0); MAX_VIRTIO],
+            num_virtio: 0,
+            reserved: [Mmio::new(0, 0, 0); 4],
+            num_reserved: 0,
+        };
+        found.reserved[0] = Mmio::new(fdt, header(HEADER_TOTALSIZE), 0);
+        found.num_reserved = 1;
+        // The memory reservation block ends with an empty entry
+        let mut rsv = base.add(header(HEADER_OFF_MEM_RSVMAP));
+        while found.num_reserved < found.reserved.len() {
+            let (addr, size) = (be64(rsv) as usize, be64(rsv.add(8)) as usize);
+            if size == 0 {
+                break;
+            }
+            found.reserved[found.num_reserved] = Mmio::new(addr, size, 0);
+            found.num_reserved += 1;
+            rsv = rsv.add(16);
+        }
+
+        let strings = base.add(header(HEADER_OFF_DT_STRINGS));
+        let mut p = base.add(header(HEADER_OFF_DT_STRUCT));
+        let mut stack = [Node::new(2, 1); MAX_DEPTH];
+        let mut depth = 0;
+        let mut seen_uart = false;
+        loop {
+            let token = be32(p);
+            p = p.add(4);
+            match token {
+                FDT_BEGIN_NODE => {
+                    // The name isn't needed, compatible says what the node is
+                    p = p.add((strlen(p) + 1 + 3) & !3);
+                    let parent = stack[depth.min(MAX_DEPTH - 1)];
+                    depth += 1;
+                    if depth < MAX_DEPTH {
+                        stack[depth] =
+                            Node::new(parent.child_address_cells, parent.child_size_cells);
+                    }
+                }
+                FDT_PROP => {
+                    let len = be32(p) as usize;
+                    let name = strings.add(be32(p.add(4)) as usize);
+                    let name = core::slice::from_raw_parts(name, strlen(name));
+                    let value = core::slice::from_raw_parts(p.add(8), len);
+                    p = p.add((8 + len + 3) & !3);
+                    if depth >= MAX_DEPTH {
+                        continue;
+                    }
+                    let node = &mut stack[depth];
+                    match name {
+                        b"#address-cells" => node.child_address_cells = cells(value) as u32,
+                        b"#size-cells" => node.child_size_cells = cells(value) as u32,
+                        b"compatible" => node.compatible = value,
+                        b"device_type" => node.device_type = value,
+                        b"reg" => node.reg = value,
+                        b"interrupts" => node.interrupts = value,
+                        b"status" => node.disabled = value.starts_with(b"disabled"),
+                        _ => {}
+                    }
+                }
+                FDT_END_NODE => {
+                    if depth < MAX_DEPTH && !stack[depth].disabled {
+                        add_node(&mut found, &stack[depth], &mut seen_uart);
+                    }
+                    if depth == 0 {
+                        return false;
+                    }
+                    depth -= 1;
+                }
+                FDT_NOP => {}
+                FDT_END => break,
+                _ => return false,
+            }
+        }
+
+        // Under the firmware a tree without a UART means there isn't one, and its console stands
+        // in for it
+        if cfg!(feature = "sbi") && !seen_uart {
+            found.uart = Mmio::new(0, 0, 0);
+        }
+        // QEMU lists them highest address first
+        found.virtio[..found.num_virtio].sort_unstable_by_key(|slot| slot.base);
+        found.harts = found.harts.max(1);
+        PLATFORM = found;
+        true
+    }
+}
+
+fn add_node(found: &mut Platform, node: &Node, seen_uart: &mut bool) {
+    let type_is = |t: &str| node.device_type.split(|&b| b == 0).next() == Some(t.as_bytes());
+    if type_is("cpu") {
+        found.harts += 1;
+    } else if type_is("memory") {
+        // Only the bank the kernel is in, the page allocator wants one range
+        let (base, size) = match node.reg() {
+            Some(reg) => reg,
+            None => return,
+        };
+        let text = unsafe { TEXT_START };
+        if base <= text && text < base + size {
+            found.memory = Mmio::new(base, size, 0);
+        }
+    } else if node.is_compatible(&["ns16550a", "ns16550"]) {
+        // The first one is the console
+        if !*seen_uart {
+            if let Some(mmio) = node.mmio() {
+                found.uart = mmio;
+                *seen_uart = true;
+            }
+        }
+    } else if node.is_compatible(&["riscv,plic0", "sifive,plic-1.0.0"]) {
+        if let Some(mmio) = node.mmio() {
+            found.plic = mmio;
+        }
+    } else if node.is_compatible(&["riscv,clint0", "sifive,clint0"]) {
+        if let Some(mmio) = node.mmio() {
+            found.clint = mmio;
+        }
+    } else if node.is_compatible(&["virtio,mmio"]) && found.num_virtio < MAX_VIRTIO {
+        if let Some(mmio) = node.mmio() {
+            found.virtio[found.num_virtio] = mmio;
+            found.num_virtio += 1;
+        }
+    }
+}
@@ -1,21 +1,34 @@
-// File system
-use crate::block::SECTOR_SIZE;
+// Minix file system - versions 1, 2 and 3
+use crate::bcache;
+use crate::block::{self, BlockErrors, SECTOR_SIZE};
 use crate::buffer::Buffer;
-use crate::cpu::{memcpy, Registers};
+use crate::cpu::MTIMER_TICKS_PER_SEC;
+use crate::lock::Mutex;
 use crate::process;
-use crate::syscall::read_block;
+use crate::syscall::get_time;
+use crate::vfs::{self, FileSystem, Stat, S_IFDIR, S_IFMT};
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::mem::size_of;
 
-pub const MAGIC: u16 = 0x4d5a;
-pub const BLOCK_SIZE: u32 = 1024;
-pub const NUM_IPTRS: usize = BLOCK_SIZE as usize / 4;
-
-// mode types
-pub const S_IFDIR: u16 = 0o040_000;
-pub const S_IFREG: u16 = 0o100_000;
+// Superblock magic numbers. v1 and v2 come with 14 or 30 character names, v3 always has 60.
+pub const MAGIC_V1: u16 = 0x137f;
+pub const MAGIC_V1_30: u16 = 0x138f;
+pub const MAGIC_V2: u16 = 0x2468;
+pub const MAGIC_V2_30: u16 = 0x2478;
+pub const MAGIC_V3: u16 = 0x4d5a;
+// The superblock is right after the 1K boot block, whatever the block size
+pub const SUPER_BLOCK_OFFSET: u64 = 1024;
+// v1 and v2 blocks are always 1K, v3 gives its block size in the superblock
+pub const V1_BLOCK_SIZE: u32 = 1024;
+// Inode number of the root directory
+pub const ROOT_INODE: u32 = 1;
 
+// Minix 3 superblock
 #[repr(C)]
+#[derive(Copy, Clone)]
 pub struct SuperBlock {
     pub ninodes: u32,
     pub pad0: u16,
@@ -32,6 +45,23 @@ pub struct SuperBlock {
     pub version: u8,
 }
 
+// Minix 1 and 2 superblock. v1 counts zones in nzones, v2 in zones.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SuperBlockV1 {
+    pub ninodes: u16,
+    pub nzones: u16,
+    pub imap_blocks: u16,
+    pub zmap_blocks: u16,
+    pub first_data_zone: u16,
+    pub log_zone_size: u16,
+    pub max_size: u32,
+    pub magic: u16,
+    pub state: u16,
+    pub zones: u32,
+}
+
+// Minix 2 and 3 inode. v1 inodes are converted to this when they're read.
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct Inode {
@@ -46,259 +76,1157 @@ pub struct Inode {
     pub zones: [u32; 10],
 }
 
+// Minix 1 inode - one timestamp, 16 bit zone numbers and no triply indirect zone
 #[repr(C)]
-pub struct DirEntry {
-    pub inode: u32,
-    pub name: [u8; 60],
+#[derive(Copy, Clone)]
+pub struct InodeV1 {
+    pub mode: u16,
+    pub uid: u16,
+    pub size: u32,
+    pub time: u32,
+    pub gid: u8,
+    pub hard_links: u8,
+    pub zones: [u16; 9],
+}
+
+impl InodeV1 {
+    pub fn to_inode(&self) -> Inode {
+        let mut zones = [0; 10];
+        for (zone, zone_v1) in zones.iter_mut().zip(self.zones.iter()) {
+            *zone = *zone_v1 as u32;
+        }
+        Inode {
+            mode: self.mode,
+            hard_links: self.hard_links as u16,
+            uid: self.uid,
+            gid: self.gid as u16,
+            size: self.size,
+            accessed_time: self.time,
+            modified_time: self.time,
+            creation_time: self.time,
+            zones,
+        }
+    }
+
+    pub fn from_inode(inode: &Inode) -> Self {
+        let mut zones = [0; 9];
+        for (zone_v1, zone) in zones.iter_mut().zip(inode.zones.iter()) {
+            *zone_v1 = *zone as u16;
+        }
+        InodeV1 {
+            mode: inode.mode,
+            uid: inode.uid,
+            size: inode.size,
+            time: inode.modified_time,
+            gid: inode.gid as u8,
+            hard_links: inode.hard_links as u8,
+            zones,
+        }
+    }
 }
 
-pub struct MinixFileSystem;
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Version {
+    V1,
+    V2,
+    V3,
+}
+
+// How a mounted disk is laid out, taken from whichever superblock it has
+#[derive(Copy, Clone)]
+pub struct Layout {
+    pub version: Version,
+    pub block_size: u32,
+    // Data is allocated in zones of 2^log_zone_size blocks
+    pub zone_size: u32,
+    pub ninodes: u32,
+    pub zones: u32,
+    pub imap_blocks: u32,
+    pub zmap_blocks: u32,
+    pub first_data_zone: u32,
+    pub max_size: u32,
+    // Longest name a directory entry holds
+    pub name_len: usize,
+}
+
+// Bytes in a zone of 2^log_zone_size blocks, None if that doesn't fit in a u32
+fn zone_size(block_size: u32, log_zone_size: u16) -> Option<u32> {
+    1u32.checked_shl(log_zone_size as u32)?
+        .checked_mul(block_size)
+}
+
+impl Layout {
+    // Works out the layout from the bytes at SUPER_BLOCK_OFFSET, None if it isn't a Minix disk
+    // or the super block doesn't add up
+    pub fn parse(buffer: &Buffer) -> Option<Layout> {
+        let layout = Self::parse_super_block(buffer)?;
+        // The zone bitmap only covers zones - first_data_zone
+        if layout.first_data_zone > layout.zones {
+            return None;
+        }
+        Some(layout)
+    }
+
+    fn parse_super_block(buffer: &Buffer) -> Option<Layout> {
+        let super_block = unsafe { *(buffer.get() as *const SuperBlock) };
+        if super_block.magic == MAGIC_V3 {
+            let block_size = super_block.block_size as u32;
+            if block_size < 1024 || !block_size.is_power_of_two() {
+                return None;
+            }
+            return Some(Layout {
+                version: Version::V3,
+                block_size,
+                zone_size: zone_size(block_size, super_block.log_zone_size)?,
+                ninodes: super_block.ninodes,
+                zones: super_block.zones,
+                imap_blocks: super_block.imap_blocks as u32,
+                zmap_blocks: super_block.zmap_blocks as u32,
+                first_data_zone: super_block.first_data_zone as u32,
+                max_size: super_block.max_size,
+                name_len: 60,
+            });
+        }
+
+        let super_block = unsafe { *(buffer.get() as *const SuperBlockV1) };
+        let (version, name_len) = match super_block.magic {
+            MAGIC_V1 => (Version::V1, 14),
+            MAGIC_V1_30 => (Version::V1, 30),
+            MAGIC_V2 => (Version::V2, 14),
+            MAGIC_V2_30 => (Version::V2, 30),
+            _ => return None,
+        };
+        Some(Layout {
+            version,
+            block_size: V1_BLOCK_SIZE,
+            zone_size: zone_size(V1_BLOCK_SIZE, super_block.log_zone_size)?,
+            ninodes: super_block.ninodes as u32,
+            zones: if version == Version::V1 {
+                super_block.nzones as u32
+            } else {
+                super_block.zones
+            },
+            imap_blocks: super_block.imap_blocks as u32,
+            zmap_blocks: super_block.zmap_blocks as u32,
+            first_data_zone: super_block.first_data_zone as u32,
+            max_size: super_block.max_size,
+            name_len,
+        })
+    }
+
+    // Byte offset of a block on the disk
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.block_size as u64
+    }
+
+    // Byte offset of a zone on the disk
+    fn zone_offset(&self, zone: u32) -> u64 {
+        zone as u64 * self.zone_size as u64
+    }
+
+    fn inode_size(&self) -> u32 {
+        match self.version {
+            Version::V1 => size_of::<InodeV1>() as u32,
+            _ => size_of::<Inode>() as u32,
+        }
+    }
+
+    // Bytes taken by a zone number in inodes and indirect zones
+    fn zone_ptr_size(&self) -> u32 {
+        match self.version {
+            Version::V1 => 2,
+            _ => 4,
+        }
+    }
+
+    // Zone numbers held by an indirect zone. Only its first block is used.
+    fn ptrs_per_zone(&self) -> usize {
+        (self.block_size / self.zone_ptr_size()) as usize
+    }
+
+    // Bytes taken by the inode number at the start of a directory entry
+    fn dirent_inode_size(&self) -> usize {
+        match self.version {
+            Version::V3 => 4,
+            _ => 2,
+        }
+    }
+
+    fn dirent_size(&self) -> usize {
+        self.dirent_inode_size() + self.name_len
+    }
+
+    // Inode number and name, without the NUL padding, of the directory entry at the start of
+    // entry
+    fn parse_entry<'a>(&self, entry: &'a [u8]) -> (u32, &'a [u8]) {
+        let inode_size = self.dirent_inode_size();
+        let mut inode_num = 0;
+        for (i, byte) in entry[..inode_size].iter().enumerate() {
+            inode_num |= (*byte as u32) << (8 * i);
+        }
+        let name = &entry[inode_size..self.dirent_size()];
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        (inode_num, &name[..len])
+    }
+
+    // A directory entry in the disk's format
+    fn make_entry(&self, inode_num: u32, name: &[u8]) -> Vec<u8> {
+        let mut entry = Vec::new();
+        entry.resize(self.dirent_size(), 0);
+        let inode_size = self.dirent_inode_size();
+        for (i, byte) in entry[..inode_size].iter_mut().enumerate() {
+            *byte = (inode_num >> (8 * i)) as u8;
+        }
+        entry[inode_size..inode_size + name.len()].copy_from_slice(name);
+        entry
+    }
+}
+
+// Minix timestamps - we don't have a clock, so seconds since boot
+fn current_time() -> u32 {
+    (get_time().as_u64() / MTIMER_TICKS_PER_SEC) as u32
+}
+
+// Splits an absolute path into its parent directory and final name
+fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("/", path),
+    }
+}
+
+// What zone_for does about the zone it finds
+#[derive(Copy, Clone, PartialEq)]
+enum ZoneOp {
+    Find,
+    // Missing zones, including indirect ones on the way, are allocated
+    Alloc,
+    // The zone is unlinked from the file, and returned so the caller can free it
+    Clear,
+}
+
+// The in-memory copy of an inode, shared by open files and directory walks
+struct CachedInode {
+    inode: Inode,
+    refs: usize,
+    // Changed since it was read from the inode table
+    dirty: bool,
+}
+
+struct MountState {
+    // Read on first use, since mounting happens before processes can do I/O
+    layout: Option<Layout>,
+    inodes: BTreeMap<u32, CachedInode>,
+    // Guards inodes. Never held across I/O, so it can be taken in interrupt context.
+    inode_lock: Mutex,
+    // Only one kernel process may modify the filesystem at a time
+    write_lock: Mutex,
+}
+
+// A mounted Minix filesystem on a block device
+pub struct MinixFileSystem {
+    bdev: usize,
+    state: *mut MountState,
+}
 
 impl MinixFileSystem {
-    pub fn get_inode(bdev: usize, inode_num: u32) -> Option<Inode> {
-        let mut buffer = Buffer::new(BLOCK_SIZE as usize);
-        let super_block = unsafe { &*(buffer.get_mut() as *mut SuperBlock) };
-        let inode = buffer.get_mut() as *mut Inode;
-        read_block(bdev, buffer.get_mut(), SECTOR_SIZE, BLOCK_SIZE); // super block is immediately after boot block
-        if super_block.magic == MAGIC {
-            // this is a valid disk
-            // need to skip boot block, super block and imap/zmap blocks (1024 each)
-            let inode_offset = (2 + super_block.imap_blocks + super_block.zmap_blocks) as usize
-                * BLOCK_SIZE as usize
-                + ((inode_num as usize - 1) / (BLOCK_SIZE as usize / size_of::<Inode>()))
-                    * BLOCK_SIZE as usize;
-
-            read_block(bdev, buffer.get_mut(), BLOCK_SIZE, inode_offset as u32);
-
-            let offset = (inode_num as usize - 1) % (BLOCK_SIZE as usize / size_of::<Inode>());
-            return unsafe { Some(*inode.add(offset)) }; // Copy across
+    pub fn new(bdev: usize) -> Self {
+        let state = MountState {
+            layout: None,
+            inodes: BTreeMap::new(),
+            inode_lock: Mutex::new(),
+            write_lock: Mutex::new(),
+        };
+        MinixFileSystem {
+            bdev,
+            state: Box::into_raw(Box::new(state)),
         }
-        None
     }
 
-    pub fn read(bdev: usize, inode: &Inode, buffer: *mut u8, size: u32, offset: u32) -> u32 {
-        let mut blocks_seen: u32 = 0;
-        let offset_block = offset / BLOCK_SIZE;
-        let mut offset_byte = offset % BLOCK_SIZE;
-        let mut bytes_left = if size > inode.size { inode.size } else { size };
+    // Layout of the mounted disk, None if it isn't a Minix disk
+    // don't run inside interrupt context - will block
+    pub fn layout(&self) -> Option<Layout> {
+        let state = unsafe { &mut *self.state };
+        if state.layout.is_none() {
+            let size = size_of::<SuperBlock>().max(size_of::<SuperBlockV1>());
+            let mut buffer = Buffer::new(size);
+            bcache::read(self.bdev, buffer.get_mut(), size as u32, SUPER_BLOCK_OFFSET).ok()?;
+            state.layout = Layout::parse(&buffer);
+        }
+        state.layout
+    }
 
-        let mut bytes_read: u32 = 0;
-        // block buffer
-        let mut block_buffer = Buffer::new(BLOCK_SIZE as usize);
-        // triply indirect zones
-        let mut indirect_buffer = Buffer::new(BLOCK_SIZE as usize);
-        let mut iindirect_buffer = Buffer::new(BLOCK_SIZE as usize);
-        let mut iiindirect_buffer = Buffer::new(BLOCK_SIZE as usize);
-        let izones = indirect_buffer.get() as *const u32;
-        let iizones = iindirect_buffer.get() as *const u32;
-        let iiizones = iiindirect_buffer.get() as *const u32;
-
-        // Read 7 direct zones
-        for i in 0..7 {
-            if inode.zones[i] == 0 {
-                continue;
+    // Byte offset of an inode in the inode table
+    fn inode_offset(layout: &Layout, inode_num: u32) -> u64 {
+        // need to skip boot block, super block and imap/zmap blocks
+        let table_start = layout.block_offset(2 + layout.imap_blocks + layout.zmap_blocks);
+        table_start + (inode_num - 1) as u64 * layout.inode_size() as u64
+    }
+
+    // Reads an inode from the inode table
+    fn load_inode(&self, inode_num: u32) -> Option<Inode> {
+        let layout = self.layout()?;
+        if inode_num == 0 || inode_num > layout.ninodes {
+            return None;
+        }
+        let offset = Self::inode_offset(&layout, inode_num);
+        let size = layout.inode_size();
+        let mut buffer = Buffer::new(size as usize);
+        bcache::read(self.bdev, buffer.get_mut(), size, offset).ok()?;
+        // Copy across
+        Some(match layout.version {
+            Version::V1 => unsafe { (*(buffer.get() as *const InodeV1)).to_inode() },
+            _ => unsafe { *(buffer.get() as *const Inode) },
+        })
+    }
+
+    // Writes an inode to the inode table. Only this inode's bytes are written, so inodes sharing
+    // a block can be written at the same time.
+    fn store_inode(&self, inode_num: u32, inode: &Inode) -> Result<(), BlockErrors> {
+        let layout = self.layout().ok_or(BlockErrors::IoError)?;
+        let offset = Self::inode_offset(&layout, inode_num);
+        match layout.version {
+            Version::V1 => {
+                let inode_v1 = InodeV1::from_inode(inode);
+                let inode_ptr = &inode_v1 as *const InodeV1 as *const u8;
+                bcache::write(self.bdev, inode_ptr, layout.inode_size(), offset)
+            }
+            _ => {
+                let inode_ptr = inode as *const Inode as *const u8;
+                bcache::write(self.bdev, inode_ptr, layout.inode_size(), offset)
             }
+        }
+    }
 
-            if offset_block <= blocks_seen {
-                let zone_offset = inode.zones[i] * BLOCK_SIZE;
-                read_block(bdev, block_buffer.get_mut(), BLOCK_SIZE, zone_offset);
+    // Takes a reference to an inode, loading it into the cache if nobody else is using it.
+    // Returns a copy of the shared inode - changes go back through update_inode.
+    // don't run inside interrupt context - will block
+    fn get_inode(&self, inode_num: u32) -> Option<Inode> {
+        let state = unsafe { &mut *self.state };
+        state.inode_lock.spin_lock();
+        if let Some(cached) = state.inodes.get_mut(&inode_num) {
+            cached.refs += 1;
+            let inode = cached.inode;
+            state.inode_lock.unlock();
+            return Some(inode);
+        }
+        state.inode_lock.unlock();
+
+        let loaded = self.load_inode(inode_num)?;
+        state.inode_lock.spin_lock();
+        // Someone else may have loaded it while we were reading
+        let cached = state.inodes.entry(inode_num).or_insert(CachedInode {
+            inode: loaded,
+            refs: 0,
+            dirty: false,
+        });
+        cached.refs += 1;
+        let inode = cached.inode;
+        state.inode_lock.unlock();
+        Some(inode)
+    }
+
+    // Takes another reference to an inode that is already referenced
+    fn dup_inode(&self, inode_num: u32) {
+        let state = unsafe { &mut *self.state };
+        state.inode_lock.spin_lock();
+        if let Some(cached) = state.inodes.get_mut(&inode_num) {
+            cached.refs += 1;
+        }
+        state.inode_lock.unlock();
+    }
+
+    // Copy of the shared inode, without taking a reference
+    fn cached_inode(&self, inode_num: u32) -> Option<Inode> {
+        let state = unsafe { &mut *self.state };
+        state.inode_lock.spin_lock();
+        let ret = state.inodes.get(&inode_num).map(|cached| cached.inode);
+        state.inode_lock.unlock();
+        ret
+    }
+
+    // Replaces the shared copy of a referenced inode
+    fn update_inode(&self, inode_num: u32, inode: &Inode) {
+        let state = unsafe { &mut *self.state };
+        state.inode_lock.spin_lock();
+        if let Some(cached) = state.inodes.get_mut(&inode_num) {
+            cached.inode = *inode;
+            cached.dirty = true;
+        }
+        state.inode_lock.unlock();
+    }
 
-                let amount_to_read = if BLOCK_SIZE - offset_byte > bytes_left {
-                    bytes_left
+    // Drops a reference. The last one out writes the inode back if it changed.
+    // don't run inside interrupt context - will block
+    fn put_inode(&self, inode_num: u32) {
+        let state = unsafe { &mut *self.state };
+        state.inode_lock.spin_lock();
+        let mut write_back = None;
+        if let Some(cached) = state.inodes.get_mut(&inode_num) {
+            cached.refs -= 1;
+            if cached.refs == 0 {
+                if cached.dirty {
+                    cached.dirty = false;
+                    write_back = Some(cached.inode);
                 } else {
-                    BLOCK_SIZE - offset_byte
-                };
-                unsafe {
-                    // copy from buffer to final destination
-                    memcpy(
-                        buffer.add(bytes_read as usize),
-                        block_buffer.get().add(offset_byte as usize),
-                        amount_to_read as usize,
-                    );
+                    state.inodes.remove(&inode_num);
                 }
-                offset_byte = 0;
-                bytes_read += amount_to_read;
-                bytes_left -= amount_to_read;
-                if bytes_left == 0 {
-                    // no more data to read, we're done
-                    return bytes_read;
+            }
+        }
+        state.inode_lock.unlock();
+
+        if let Some(inode) = write_back {
+            let stored = self.store_inode(inode_num, &inode).is_ok();
+            // It stays cached until it's written, so nobody reads a stale copy in the meantime
+            state.inode_lock.spin_lock();
+            if let Some(cached) = state.inodes.get_mut(&inode_num) {
+                if !stored {
+                    // Left for the next sync to try again
+                    cached.dirty = true;
+                } else if cached.refs == 0 && !cached.dirty {
+                    state.inodes.remove(&inode_num);
                 }
             }
-            blocks_seen += 1;
+            state.inode_lock.unlock();
         }
+    }
 
-        // Singly indirect zones
-        if inode.zones[7] != 0 {
-            read_block(
-                bdev,
-                indirect_buffer.get_mut(),
-                BLOCK_SIZE,
-                BLOCK_SIZE * inode.zones[7],
-            );
-            // each indirect zone is list of pointers, 4 bytes each
-            for i in 0..NUM_IPTRS {
-                unsafe {
-                    if izones.add(i).read() != 0 {
-                        if offset_block <= blocks_seen {
-                            read_block(
-                                bdev,
-                                block_buffer.get_mut(),
-                                BLOCK_SIZE,
-                                BLOCK_SIZE * izones.add(i).read(),
-                            );
-                            let amount_to_read = if BLOCK_SIZE - offset_byte > bytes_left {
-                                bytes_left
-                            } else {
-                                BLOCK_SIZE - offset_byte
-                            };
-                            // copy from buffer to final destination
-                            memcpy(
-                                buffer.add(bytes_read as usize),
-                                block_buffer.get().add(offset_byte as usize),
-                                amount_to_read as usize,
-                            );
-                            offset_byte = 0;
-                            bytes_read += amount_to_read;
-                            bytes_left -= amount_to_read;
-                            if bytes_left == 0 {
-                                return bytes_read;
-                            }
-                        }
-                        blocks_seen += 1;
+    // Copy of an inode, for when it isn't kept around
+    // don't run inside interrupt context - will block
+    fn inode(&self, inode_num: u32) -> Option<Inode> {
+        let inode = self.get_inode(inode_num)?;
+        self.put_inode(inode_num);
+        Some(inode)
+    }
+
+    // Writes back every changed inode, including ones that are still in use
+    // don't run inside interrupt context - will block
+    fn sync_inodes(&self) -> Result<(), BlockErrors> {
+        let state = unsafe { &mut *self.state };
+        state.inode_lock.spin_lock();
+        let mut dirty = Vec::new();
+        for (inode_num, cached) in state.inodes.iter_mut() {
+            if cached.dirty {
+                cached.dirty = false;
+                dirty.push((*inode_num, cached.inode));
+            }
+        }
+        state.inode_lock.unlock();
+        let mut ret = Ok(());
+        for (inode_num, inode) in dirty.iter() {
+            if let Err(err) = self.store_inode(*inode_num, inode) {
+                ret = Err(err);
+                state.inode_lock.spin_lock();
+                if let Some(cached) = state.inodes.get_mut(inode_num) {
+                    cached.dirty = true;
+                }
+                state.inode_lock.unlock();
+            }
+        }
+        ret
+    }
+
+    fn write_lock(&self) -> &mut Mutex {
+        unsafe { &mut (*self.state).write_lock }
+    }
+
+    // Resolves a path to an inode number by walking directory entries from the root
+    // don't run inside interrupt context - will block
+    fn lookup_path(&self, path: &str) -> Option<u32> {
+        let mut inode_num = ROOT_INODE;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let dir = self.get_inode(inode_num)?;
+            let next = if dir.mode & S_IFMT == S_IFDIR {
+                self.find_entry(&dir, name.as_bytes())
+            } else {
+                None
+            };
+            self.put_inode(inode_num);
+            inode_num = next?;
+        }
+        Some(inode_num)
+    }
+
+    // Finds the inode number of a named entry in a directory
+    fn find_entry(&self, dir: &Inode, name: &[u8]) -> Option<u32> {
+        self.find_slot(dir, name).map(|(_, inode_num)| inode_num)
+    }
+
+    // Finds a named entry in a directory, returns its index and inode number
+    fn find_slot(&self, dir: &Inode, name: &[u8]) -> Option<(usize, u32)> {
+        let layout = self.layout()?;
+        let entries = self.read_entries(&layout, dir);
+        let entry_size = layout.dirent_size();
+        for i in 0..entries.len() / entry_size {
+            let (inode_num, entry_name) = layout.parse_entry(&entries[i * entry_size..]);
+            if inode_num != 0 && entry_name == name {
+                return Some((i, inode_num));
+            }
+        }
+        None
+    }
+
+    // Raw contents of a directory, a whole number of entries
+    fn read_entries(&self, layout: &Layout, dir: &Inode) -> Vec<u8> {
+        let mut entries = Vec::new();
+        entries.resize(dir.size as usize, 0);
+        let bytes_read = self.read_data(dir, entries.as_mut_ptr(), dir.size, 0) as usize;
+        entries.truncate(bytes_read - bytes_read % layout.dirent_size());
+        entries
+    }
+
+    // Reads file data given its inode. Zones that were never allocated read as zeroes. Stops
+    // short if the disk fails.
+    fn read_data(&self, inode: &Inode, buffer: *mut u8, size: u32, offset: u32) -> u32 {
+        let layout = match self.layout() {
+            Some(layout) => layout,
+            None => return 0,
+        };
+        if offset >= inode.size {
+            return 0;
+        }
+        // don't read past the end of the file
+        let size = size.min(inode.size - offset);
+        // zone_for only changes the inode when it allocates or clears
+        let mut inode = *inode;
+
+        let mut bytes_read: u32 = 0;
+        while bytes_read < size {
+            let pos = offset + bytes_read;
+            let offset_byte = pos % layout.zone_size;
+            let amount_to_read = (layout.zone_size - offset_byte).min(size - bytes_read);
+            let dest = unsafe { buffer.add(bytes_read as usize) };
+            match self.zone_for(&layout, &mut inode, pos / layout.zone_size, ZoneOp::Find) {
+                Some(zone) => {
+                    let zone_offset = layout.zone_offset(zone) + offset_byte as u64;
+                    if bcache::read(self.bdev, dest, amount_to_read, zone_offset).is_err() {
+                        break;
                     }
                 }
+                None => unsafe { dest.write_bytes(0, amount_to_read as usize) },
             }
+            bytes_read += amount_to_read;
         }
+        bytes_read
+    }
 
-        // Doubly indirect zones
-        if inode.zones[8] != 0 {
-            read_block(
-                bdev,
-                indirect_buffer.get_mut(),
-                BLOCK_SIZE,
-                BLOCK_SIZE * inode.zones[8],
-            );
-            unsafe {
-                for i in 0..NUM_IPTRS {
-                    if izones.add(i).read() != 0 {
-                        read_block(
-                            bdev,
-                            iindirect_buffer.get_mut(),
-                            BLOCK_SIZE,
-                            BLOCK_SIZE * izones.add(i).read(),
-                        );
-                        for j in 0..NUM_IPTRS {
-                            if iizones.add(j).read() != 0 {
-                                if offset_block <= blocks_seen {
-                                    read_block(
-                                        bdev,
-                                        block_buffer.get_mut(),
-                                        BLOCK_SIZE,
-                                        BLOCK_SIZE * iizones.add(j).read(),
-                                    );
-                                    let amount_to_read = if BLOCK_SIZE - offset_byte > bytes_left {
-                                        bytes_left
-                                    } else {
-                                        BLOCK_SIZE - offset_byte
-                                    };
-                                    // copy from buffer to final destination
-                                    memcpy(
-                                        buffer.add(bytes_read as usize),
-                                        block_buffer.get().add(offset_byte as usize),
-                                        amount_to_read as usize,
-                                    );
-                                    offset_byte = 0;
-                                    bytes_read += amount_to_read;
-                                    bytes_left -= amount_to_read;
-                                    if bytes_left == 0 {
-                                        return bytes_read;
-                                    }
-                                }
-                                blocks_seen += 1;
-                            }
-                        }
+    // Finds a clear bit in the bitmap starting at first_block, sets it and returns its number
+    fn alloc_bit(
+        &self,
+        layout: &Layout,
+        first_block: u32,
+        num_blocks: u32,
+        max_bits: u32,
+    ) -> Option<u32> {
+        let block_size = layout.block_size;
+        let mut buffer = Buffer::new(block_size as usize);
+        let bits_per_block = block_size * 8;
+        for block in 0..num_blocks {
+            let offset = layout.block_offset(first_block + block);
+            bcache::read(self.bdev, buffer.get_mut(), block_size, offset).ok()?;
+            for byte in 0..block_size as usize {
+                if buffer[byte] == 0xff {
+                    continue;
+                }
+                for bit in 0..8 {
+                    let num = block * bits_per_block + byte as u32 * 8 + bit;
+                    if num >= max_bits {
+                        return None;
                     }
+                    if buffer[byte] & (1 << bit) == 0 {
+                        buffer[byte] |= 1 << bit;
+                        // Only the changed byte
+                        let byte_ptr = unsafe { buffer.get().add(byte) };
+                        bcache::write(self.bdev, byte_ptr, 1, offset + byte as u64).ok()?;
+                        return Some(num);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // Clears a bit in the bitmap starting at first_block. If the disk fails the bit stays set,
+    // which only leaks what it stands for.
+    fn free_bit(&self, layout: &Layout, first_block: u32, num: u32) {
+        let offset = layout.block_offset(first_block) + (num / 8) as u64;
+        let mut byte: u8 = 0;
+        if bcache::read(self.bdev, &mut byte, 1, offset).is_ok() {
+            byte &= !(1 << (num % 8));
+            let _ = bcache::write(self.bdev, &byte, 1, offset);
+        }
+    }
+
+    // Allocates an inode number from the inode bitmap
+    fn alloc_inode(&self, layout: &Layout) -> Option<u32> {
+        self.alloc_bit(layout, 2, layout.imap_blocks, layout.ninodes + 1)
+    }
+
+    fn free_inode(&self, layout: &Layout, inode_num: u32) {
+        self.free_bit(layout, 2, inode_num);
+    }
+
+    // Allocates a zeroed zone from the zone bitmap
+    fn alloc_zone(&self, layout: &Layout) -> Option<u32> {
+        let zmap_start = 2 + layout.imap_blocks;
+        // bit 0 is reserved, bit n is zone first_data_zone + n - 1
+        let max_bits = layout.zones - layout.first_data_zone + 1;
+        let bit = self.alloc_bit(layout, zmap_start, layout.zmap_blocks, max_bits)?;
+        let zone = layout.first_data_zone + bit - 1;
+        let buffer = Buffer::zeroed(layout.zone_size as usize);
+        let zone_offset = layout.zone_offset(zone);
+        if bcache::write(self.bdev, buffer.get(), layout.zone_size, zone_offset).is_err() {
+            self.free_zone(layout, zone);
+            return None;
+        }
+        Some(zone)
+    }
+
+    fn free_zone(&self, layout: &Layout, zone: u32) {
+        let zmap_start = 2 + layout.imap_blocks;
+        self.free_bit(layout, zmap_start, zone - layout.first_data_zone + 1);
+    }
+
+    // Zone holding the given zone-sized piece of a file, see ZoneOp for what else happens
+    fn zone_for(&self, layout: &Layout, inode: &mut Inode, index: u32, op: ZoneOp) -> Option<u32> {
+        let per_zone = layout.ptrs_per_zone();
+        let mut index = index as usize;
+        if index < 7 {
+            let zone = inode.zones[index];
+            return match op {
+                ZoneOp::Alloc if zone == 0 => {
+                    inode.zones[index] = self.alloc_zone(layout)?;
+                    Some(inode.zones[index])
                 }
+                ZoneOp::Clear => {
+                    inode.zones[index] = 0;
+                    Some(zone).filter(|&zone| zone != 0)
+                }
+                _ => Some(zone).filter(|&zone| zone != 0),
+            };
+        }
+        index -= 7;
+        if index < per_zone {
+            let path = [index];
+            return self.indirect_zone(layout, &mut inode.zones[7], &path, op);
+        }
+        index -= per_zone;
+        if index < per_zone * per_zone {
+            let path = [index / per_zone, index % per_zone];
+            return self.indirect_zone(layout, &mut inode.zones[8], &path, op);
+        }
+        index -= per_zone * per_zone;
+        // v1 inodes have no triply indirect zone
+        if layout.version != Version::V1 && index < per_zone * per_zone * per_zone {
+            let path = [
+                index / (per_zone * per_zone),
+                (index / per_zone) % per_zone,
+                index % per_zone,
+            ];
+            return self.indirect_zone(layout, &mut inode.zones[9], &path, op);
+        }
+        None
+    }
+
+    // Follows a chain of indirect zones, path holds the index to take at each level
+    fn indirect_zone(
+        &self,
+        layout: &Layout,
+        table_zone: &mut u32,
+        path: &[usize],
+        op: ZoneOp,
+    ) -> Option<u32> {
+        if *table_zone == 0 {
+            if op != ZoneOp::Alloc {
+                return None;
+            }
+            *table_zone = self.alloc_zone(layout)?;
+        }
+        // Just the one zone number we need
+        let ptr_size = layout.zone_ptr_size();
+        let offset = layout.zone_offset(*table_zone) + (path[0] as u32 * ptr_size) as u64;
+        let mut old_entry: u32 = 0;
+        bcache::read(
+            self.bdev,
+            &mut old_entry as *mut u32 as *mut u8,
+            ptr_size,
+            offset,
+        )
+        .ok()?;
+        let mut entry = old_entry;
+        let ret = if path.len() == 1 {
+            match op {
+                ZoneOp::Alloc if entry == 0 => entry = self.alloc_zone(layout).unwrap_or(0),
+                ZoneOp::Clear => entry = 0,
+                _ => (),
+            }
+            let zone = if op == ZoneOp::Clear {
+                old_entry
+            } else {
+                entry
+            };
+            Some(zone).filter(|&zone| zone != 0)
+        } else {
+            self.indirect_zone(layout, &mut entry, &path[1..], op)
+        };
+        if entry != old_entry {
+            bcache::write(
+                self.bdev,
+                &entry as *const u32 as *const u8,
+                ptr_size,
+                offset,
+            )
+            .ok()?;
+        }
+        ret
+    }
+
+    // Writes to a file, growing it as needed, and updates the shared inode. The caller holds a
+    // reference to it. Returns bytes written, which is short if the disk fills up.
+    fn write_data(
+        &self,
+        inode_num: u32,
+        inode: &mut Inode,
+        buffer: *const u8,
+        size: u32,
+        offset: u32,
+    ) -> u32 {
+        let layout = match self.layout() {
+            Some(layout) => layout,
+            None => return 0,
+        };
+        if offset >= layout.max_size {
+            return 0;
+        }
+        let size = size.min(layout.max_size - offset);
+        let zone_size = layout.zone_size;
+
+        // Writing past the end fills the gap with zeroed zones, only punch_hole makes holes
+        for index in inode.size / zone_size..offset / zone_size {
+            if self
+                .zone_for(&layout, inode, index, ZoneOp::Alloc)
+                .is_none()
+            {
+                self.update_inode(inode_num, inode);
+                return 0;
             }
         }
 
-        // Triply indirect zones
-        if inode.zones[9] != 0 {
-            read_block(
-                bdev,
-                indirect_buffer.get_mut(),
-                BLOCK_SIZE,
-                BLOCK_SIZE * inode.zones[9],
+        let mut bytes_written: u32 = 0;
+        while bytes_written < size {
+            let pos = offset + bytes_written;
+            let offset_byte = pos % zone_size;
+            let amount_to_write = (zone_size - offset_byte).min(size - bytes_written);
+            let zone = match self.zone_for(&layout, inode, pos / zone_size, ZoneOp::Alloc) {
+                Some(zone) => zone,
+                None => break, // disk is full
+            };
+            let written = bcache::write(
+                self.bdev,
+                unsafe { buffer.add(bytes_written as usize) },
+                amount_to_write,
+                layout.zone_offset(zone) + offset_byte as u64,
             );
-            for i in 0..NUM_IPTRS {
-                unsafe {
-                    if izones.add(i).read() != 0 {
-                        read_block(
-                            bdev,
-                            iindirect_buffer.get_mut(),
-                            BLOCK_SIZE,
-                            BLOCK_SIZE * izones.add(i).read(),
-                        );
-                        for j in 0..NUM_IPTRS {
-                            if iizones.add(j).read() != 0 {
-                                read_block(
-                                    bdev,
-                                    iiindirect_buffer.get_mut(),
-                                    BLOCK_SIZE,
-                                    BLOCK_SIZE * iizones.add(i).read(),
-                                );
-                                for k in 0..NUM_IPTRS {
-                                    if iiizones.add(k).read() != 0 {
-                                        if offset_block <= blocks_seen {
-                                            read_block(
-                                                bdev,
-                                                block_buffer.get_mut(),
-                                                BLOCK_SIZE,
-                                                BLOCK_SIZE * iiizones.add(k).read(),
-                                            );
-                                            let amount_to_read =
-                                                if BLOCK_SIZE - offset_byte > bytes_left {
-                                                    bytes_left
-                                                } else {
-                                                    BLOCK_SIZE - offset_byte
-                                                };
-                                            // copy from buffer to final destination
-                                            memcpy(
-                                                buffer.add(bytes_read as usize),
-                                                block_buffer.get().add(offset_byte as usize),
-                                                amount_to_read as usize,
-                                            );
-                                            offset_byte = 0;
-                                            bytes_read += amount_to_read;
-                                            bytes_left -= amount_to_read;
-                                            if bytes_left == 0 {
-                                                return bytes_read;
-                                            }
-                                        }
-                                        blocks_seen += 1;
-                                    }
-                                }
-                            }
-                        }
+            if written.is_err() {
+                break;
+            }
+            bytes_written += amount_to_write;
+        }
+
+        if offset + bytes_written > inode.size {
+            inode.size = offset + bytes_written;
+        }
+        inode.modified_time = current_time();
+        self.update_inode(inode_num, inode);
+        bytes_written
+    }
+
+    // Frees every zone of a file and sets its size to 0. The caller holds a reference to it.
+    fn truncate_data(&self, inode_num: u32, inode: &mut Inode) {
+        let layout = match self.layout() {
+            Some(layout) => layout,
+            None => return,
+        };
+        for i in 0..7 {
+            if inode.zones[i] != 0 {
+                self.free_zone(&layout, inode.zones[i]);
+                inode.zones[i] = 0;
+            }
+        }
+        // singly, doubly and triply indirect zones
+        for (i, depth) in [(7, 1), (8, 2), (9, 3)].iter() {
+            if inode.zones[*i] != 0 {
+                self.free_indirect(&layout, inode.zones[*i], *depth);
+                inode.zones[*i] = 0;
+            }
+        }
+        inode.size = 0;
+        inode.modified_time = current_time();
+        self.update_inode(inode_num, inode);
+    }
+
+    // Frees the zones wholly inside a byte range of a file and zeroes the rest of it. The caller
+    // holds a reference to the inode.
+    fn punch_hole_data(
+        &self,
+        inode_num: u32,
+        inode: &mut Inode,
+        offset: u32,
+        len: u32,
+    ) -> Result<(), BlockErrors> {
+        let layout = self.layout().ok_or(BlockErrors::IoError)?;
+        let zone_size = layout.zone_size;
+        let end = offset.saturating_add(len).min(inode.size);
+        if offset >= end {
+            return Ok(());
+        }
+        let first_whole = offset / zone_size + (offset % zone_size != 0) as u32;
+        let end_whole = end / zone_size;
+        if first_whole >= end_whole {
+            return self.zero_range(&layout, inode, offset, end);
+        }
+        self.zero_range(&layout, inode, offset, first_whole * zone_size)?;
+        self.zero_range(&layout, inode, end_whole * zone_size, end)?;
+
+        // Runs of adjacent zones are discarded together
+        let mut run: Option<(u32, u32)> = None;
+        for index in first_whole..end_whole {
+            let zone = match self.zone_for(&layout, inode, index, ZoneOp::Clear) {
+                Some(zone) => zone,
+                None => continue,
+            };
+            self.free_zone(&layout, zone);
+            run = match run {
+                Some((first, count)) if first + count == zone => Some((first, count + 1)),
+                _ => {
+                    if let Some((first, count)) = run {
+                        self.discard_zones(&layout, first, count);
                     }
+                    Some((zone, 1))
                 }
+            };
+        }
+        if let Some((first, count)) = run {
+            self.discard_zones(&layout, first, count);
+        }
+        inode.modified_time = current_time();
+        self.update_inode(inode_num, inode);
+        Ok(())
+    }
+
+    // Zeroes a byte range of a file, skipping zones it doesn't have
+    fn zero_range(
+        &self,
+        layout: &Layout,
+        inode: &mut Inode,
+        start: u32,
+        end: u32,
+    ) -> Result<(), BlockErrors> {
+        let zone_size = layout.zone_size;
+        let zeroes = Buffer::zeroed(zone_size as usize);
+        let mut pos = start;
+        while pos < end {
+            let offset_byte = pos % zone_size;
+            let amount = (zone_size - offset_byte).min(end - pos);
+            if let Some(zone) = self.zone_for(layout, inode, pos / zone_size, ZoneOp::Find) {
+                let zone_offset = layout.zone_offset(zone) + offset_byte as u64;
+                bcache::write(self.bdev, zeroes.get(), amount, zone_offset)?;
             }
+            pos += amount;
         }
+        Ok(())
+    }
 
-        bytes_read
+    // Tells the device that freed zones aren't used. That only gives space back to the host, so
+    // it's fine if the device can't.
+    fn discard_zones(&self, layout: &Layout, first: u32, count: u32) {
+        let offset = layout.zone_offset(first);
+        let size = layout.zone_offset(count);
+        // Cached copies would be written back over the discarded space
+        let _ = bcache::invalidate(self.bdev, offset, size);
+        let _ = block::discard_range(self.bdev, offset, size);
+    }
+
+    // Frees an indirect zone and everything it points to. If it can't be read, it and what it
+    // points to are leaked rather than guessed at.
+    fn free_indirect(&self, layout: &Layout, zone: u32, depth: u32) {
+        let block_size = layout.block_size;
+        let mut buffer = Buffer::new(block_size as usize);
+        let zone_offset = layout.zone_offset(zone);
+        if bcache::read(self.bdev, buffer.get_mut(), block_size, zone_offset).is_err() {
+            return;
+        }
+        for i in 0..layout.ptrs_per_zone() {
+            let entry = match layout.version {
+                Version::V1 => unsafe { (buffer.get() as *const u16).add(i).read() as u32 },
+                _ => unsafe { (buffer.get() as *const u32).add(i).read() },
+            };
+            if entry == 0 {
+                continue;
+            }
+            if depth > 1 {
+                self.free_indirect(layout, entry, depth - 1);
+            } else {
+                self.free_zone(layout, entry);
+            }
+        }
+        self.free_zone(layout, zone);
+    }
+
+    // Frees an inode nothing links to, unless an open file still holds it - then the last one
+    // to let go frees it. The caller holds a reference to it.
+    fn free_if_orphaned(&self, inode_num: u32, inode: &mut Inode) {
+        let state = unsafe { &mut *self.state };
+        state.inode_lock.spin_lock();
+        let refs = state.inodes.get(&inode_num).map_or(0, |cached| cached.refs);
+        state.inode_lock.unlock();
+        if inode.hard_links == 0 && refs == 1 {
+            self.truncate_data(inode_num, inode);
+            if let Some(layout) = self.layout() {
+                self.free_inode(&layout, inode_num);
+            }
+        }
+    }
+
+    // Adds a name to a directory, reusing an empty slot if there is one
+    fn add_entry(&self, dir_num: u32, name: &[u8], inode_num: u32) -> bool {
+        let layout = match self.layout() {
+            Some(layout) => layout,
+            None => return false,
+        };
+        let mut dir = match self.get_inode(dir_num) {
+            Some(dir) => dir,
+            None => return false,
+        };
+        let entry = layout.make_entry(inode_num, name);
+
+        let entries = self.read_entries(&layout, &dir);
+        let entry_size = layout.dirent_size();
+        let mut offset = dir.size;
+        for i in 0..entries.len() / entry_size {
+            if layout.parse_entry(&entries[i * entry_size..]).0 == 0 {
+                offset = (i * entry_size) as u32;
+                break;
+            }
+        }
+        let size = entry_size as u32;
+        let ret = self.write_data(dir_num, &mut dir, entry.as_ptr(), size, offset) == size;
+        self.put_inode(dir_num);
+        ret
+    }
+
+    // Clears the directory entry at the given index
+    fn remove_entry(&self, dir_num: u32, index: usize) -> bool {
+        let layout = match self.layout() {
+            Some(layout) => layout,
+            None => return false,
+        };
+        let mut dir = match self.get_inode(dir_num) {
+            Some(dir) => dir,
+            None => return false,
+        };
+        let entry = layout.make_entry(0, b"");
+        let size = entry.len() as u32;
+        let offset = index as u32 * size;
+        let ret = self.write_data(dir_num, &mut dir, entry.as_ptr(), size, offset) == size;
+        self.put_inode(dir_num);
+        ret
+    }
+
+    // Creates an empty inode and links it into a directory, returns its number
+    fn create_in(&self, dir_num: u32, name: &[u8], mode: u16) -> Option<u32> {
+        let layout = self.layout()?;
+        if name.is_empty() || name.len() > layout.name_len {
+            return None;
+        }
+        let dir = self.inode(dir_num)?;
+        if dir.mode & S_IFMT != S_IFDIR || self.find_entry(&dir, name).is_some() {
+            return None;
+        }
+
+        let inode_num = self.alloc_inode(&layout)?;
+        let now = current_time();
+        let inode = Inode {
+            mode,
+            hard_links: 1,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            accessed_time: now,
+            modified_time: now,
+            creation_time: now,
+            zones: [0; 10],
+        };
+        if self.store_inode(inode_num, &inode).is_err() || !self.add_entry(dir_num, name, inode_num)
+        {
+            self.free_inode(&layout, inode_num);
+            return None;
+        }
+        Some(inode_num)
+    }
+
+    // Looks up the parent directory of a path and the named entry in it. Returns the parent's
+    // inode number, the entry's index and the entry's inode number.
+    fn find_path_entry(&self, path: &str) -> Option<(u32, usize, u32)> {
+        let (parent, name) = split_path(path);
+        let parent_num = self.lookup_path(parent)?;
+        let parent_dir = self.inode(parent_num)?;
+        let (index, inode_num) = self.find_slot(&parent_dir, name.as_bytes())?;
+        Some((parent_num, index, inode_num))
+    }
+
+    // Removes a file's directory entry, freeing it once nothing links to it
+    fn unlink_path(&self, path: &str) -> bool {
+        let (dir_num, index, inode_num) = match self.find_path_entry(path) {
+            Some(found) => found,
+            None => return false,
+        };
+        let mut inode = match self.get_inode(inode_num) {
+            Some(inode) => inode,
+            None => return false,
+        };
+        let ret = inode.mode & S_IFMT != S_IFDIR && self.remove_entry(dir_num, index);
+        if ret {
+            inode.hard_links = inode.hard_links.saturating_sub(1);
+            self.update_inode(inode_num, &inode);
+            self.free_if_orphaned(inode_num, &mut inode);
+        }
+        self.put_inode(inode_num);
+        ret
+    }
+
+    // Creates a directory holding "." and "..". If any step fails, the new entry and inode are
+    // removed again and the parent is left as it was.
+    fn mkdir_path(&self, path: &str, mode: u16) -> bool {
+        let (parent, name) = split_path(path);
+        let parent_num = match self.lookup_path(parent) {
+            Some(parent_num) => parent_num,
+            None => return false,
+        };
+        let layout = match self.layout() {
+            Some(layout) => layout,
+            None => return false,
+        };
+        let inode_num = match self.create_in(parent_num, name.as_bytes(), S_IFDIR | mode) {
+            Some(inode_num) => inode_num,
+            None => return false,
+        };
+        let mut inode = match self.get_inode(inode_num) {
+            Some(inode) => inode,
+            None => {
+                self.remove_name(parent_num, name.as_bytes());
+                self.free_inode(&layout, inode_num);
+                return false;
+            }
+        };
+
+        // The parent's entry and "." both link to the new directory
+        inode.hard_links = 2;
+        let mut entries = layout.make_entry(inode_num, b".");
+        entries.extend(layout.make_entry(parent_num, b".."));
+        let size = entries.len() as u32;
+        let mut ret = self.write_data(inode_num, &mut inode, entries.as_ptr(), size, 0) == size;
+
+        // The new ".." links to the parent. This is the last step, so a failure never has to
+        // take it back.
+        if ret {
+            match self.get_inode(parent_num) {
+                Some(mut parent) => {
+                    parent.hard_links += 1;
+                    self.update_inode(parent_num, &parent);
+                    self.put_inode(parent_num);
+                }
+                None => ret = false,
+            }
+        }
+        if !ret {
+            self.remove_name(parent_num, name.as_bytes());
+            inode.hard_links = 0;
+            self.update_inode(inode_num, &inode);
+            self.free_if_orphaned(inode_num, &mut inode);
+        }
+        self.put_inode(inode_num);
+        ret
+    }
+
+    // Clears the named entry from a directory, if it's there
+    fn remove_name(&self, dir_num: u32, name: &[u8]) {
+        let index = self
+            .inode(dir_num)
+            .and_then(|dir| self.find_slot(&dir, name))
+            .map(|(index, _)| index);
+        if let Some(index) = index {
+            self.remove_entry(dir_num, index);
+        }
+    }
+
+    // Removes an empty directory
+    fn rmdir_path(&self, path: &str) -> bool {
+        let (_, name) = split_path(path);
+        if name.is_empty() || name == "." || name == ".." {
+            return false;
+        }
+        let (parent_num, index, inode_num) = match self.find_path_entry(path) {
+            Some(found) => found,
+            None => return false,
+        };
+        let mut inode = match self.get_inode(inode_num) {
+            Some(inode) => inode,
+            None => return false,
+        };
+        let ret = inode.mode & S_IFMT == S_IFDIR
+            && self.is_empty_dir(&inode)
+            && self.remove_entry(parent_num, index);
+        if ret {
+            inode.hard_links = 0;
+            self.update_inode(inode_num, &inode);
+            self.free_if_orphaned(inode_num, &mut inode);
+            if let Some(mut parent) = self.get_inode(parent_num) {
+                parent.hard_links = parent.hard_links.saturating_sub(1);
+                self.update_inode(parent_num, &parent);
+                self.put_inode(parent_num);
+            }
+        }
+        self.put_inode(inode_num);
+        ret
+    }
+
+    // Only "." and ".." are left
+    fn is_empty_dir(&self, dir: &Inode) -> bool {
+        let layout = match self.layout() {
+            Some(layout) => layout,
+            None => return false,
+        };
+        let entries = self.read_entries(&layout, dir);
+        let entry_size = layout.dirent_size();
+        for i in 0..entries.len() / entry_size {
+            let (inode_num, name) = layout.parse_entry(&entries[i * entry_size..]);
+            if inode_num != 0 && name != b"." && name != b".." {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Drop for MinixFileSystem {
+    fn drop(&mut self) {
+        unsafe {
+            drop(Box::from_raw(self.state));
+        }
     }
 }
 
 // reads contents of a specified inode to memory
 // don't run inside interrupt context - will block
 pub fn read_inode(bdev: usize, node: u32, buffer: *mut u8, size: u32, offset: u32) -> u32 {
-    let inode_option = MinixFileSystem::get_inode(bdev, node);
-    if let Some(inode) = inode_option {
-        return MinixFileSystem::read(bdev, &inode, buffer, size, offset);
+    // Through the mounted filesystem, so changes still in its inode cache are seen. A disk that
+    // isn't mounted has nothing cached.
+    match vfs::mounted_bdev(bdev) {
+        Some(fs) => fs.read(node, buffer, size, offset),
+        None => MinixFileSystem::new(bdev).read(node, buffer, size, offset),
     }
-
-    // Invalid node - return 0
-    0
 }
 
 struct ProcArgs {
@@ -316,14 +1244,7 @@ fn read_proc(args_addr: usize) {
     let bytes = read_inode(args.dev, args.node, args.buffer, args.size, args.offset);
 
     // set return address
-    unsafe {
-        let ptr = process::get_by_pid(args.pid);
-        if !ptr.is_null() {
-            (*(*ptr).frame).regs[Registers::A0 as usize] = bytes as usize;
-        }
-    }
-
-    process::set_running(args.pid);
+    process::finish_syscall(args.pid, |_| bytes as usize);
 }
 
 // called by syscall - marks current process as waiting, and spawns a new process to read node
@@ -340,3 +1261,167 @@ pub fn process_read(pid: u16, dev: usize, node: u32, buffer: *mut u8, size: u32,
     process::set_waiting(pid);
     process::add_kernel_process_args(read_proc, Box::into_raw(boxed_args) as usize);
 }
+
+// The write lock is taken by the operations that change the filesystem, and never by the
+// internal helpers they're made of
+impl FileSystem for MinixFileSystem {
+    fn lookup(&self, path: &str) -> Option<u32> {
+        self.lookup_path(path)
+    }
+
+    fn bdev(&self) -> Option<usize> {
+        Some(self.bdev)
+    }
+
+    fn stat(&self, inode_num: u32) -> Option<Stat> {
+        let layout = self.layout()?;
+        let inode = self.inode(inode_num)?;
+        Some(Stat {
+            ino: inode_num as u64,
+            mode: inode.mode as u32,
+            nlink: inode.hard_links as u32,
+            uid: inode.uid as u32,
+            gid: inode.gid as u32,
+            size: inode.size as i64,
+            blksize: layout.block_size as i32,
+            blocks: ((inode.size + SECTOR_SIZE - 1) / SECTOR_SIZE) as i64,
+            atime: inode.accessed_time as i64,
+            mtime: inode.modified_time as i64,
+            ctime: inode.creation_time as i64,
+            ..Stat::default()
+        })
+    }
+
+    fn open(&self, inode_num: u32) -> bool {
+        self.get_inode(inode_num).is_some()
+    }
+
+    fn dup(&self, inode_num: u32) {
+        self.dup_inode(inode_num);
+    }
+
+    fn release(&self, inode_num: u32) {
+        let orphaned = self
+            .cached_inode(inode_num)
+            .map_or(false, |inode| inode.hard_links == 0);
+        if orphaned {
+            // Unlinked while it was open
+            self.write_lock().sleep_lock();
+            if let Some(mut inode) = self.cached_inode(inode_num) {
+                self.free_if_orphaned(inode_num, &mut inode);
+            }
+            self.put_inode(inode_num);
+            self.write_lock().unlock();
+        } else {
+            self.put_inode(inode_num);
+        }
+    }
+
+    fn read(&self, inode_num: u32, buffer: *mut u8, size: u32, offset: u32) -> u32 {
+        match self.inode(inode_num) {
+            Some(inode) => self.read_data(&inode, buffer, size, offset),
+            None => 0,
+        }
+    }
+
+    fn readdir(&self, inode_num: u32) -> Option<Vec<vfs::DirEntry>> {
+        let layout = self.layout()?;
+        let dir = self.inode(inode_num)?;
+        if dir.mode & S_IFMT != S_IFDIR {
+            return None;
+        }
+        let entries = self.read_entries(&layout, &dir);
+        let entry_size = layout.dirent_size();
+        let mut ret = Vec::new();
+        for i in 0..entries.len() / entry_size {
+            let (inode, name) = layout.parse_entry(&entries[i * entry_size..]);
+            if inode != 0 {
+                ret.push(vfs::DirEntry {
+                    inode,
+                    name: String::from_utf8_lossy(name).into_owned(),
+                });
+            }
+        }
+        Some(ret)
+    }
+
+    fn write(&self, inode_num: u32, buffer: *const u8, size: u32, offset: u32) -> Option<u32> {
+        self.write_lock().sleep_lock();
+        let ret = self.get_inode(inode_num).map(|mut inode| {
+            let bytes = self.write_data(inode_num, &mut inode, buffer, size, offset);
+            self.put_inode(inode_num);
+            bytes
+        });
+        self.write_lock().unlock();
+        ret
+    }
+
+    fn create(&self, path: &str, mode: u16) -> Option<u32> {
+        let (parent, name) = split_path(path);
+        self.write_lock().sleep_lock();
+        let ret = self
+            .lookup_path(parent)
+            .and_then(|dir_num| self.create_in(dir_num, name.as_bytes(), mode));
+        self.write_lock().unlock();
+        ret
+    }
+
+    fn truncate(&self, inode_num: u32) -> bool {
+        self.write_lock().sleep_lock();
+        let ret = match self.get_inode(inode_num) {
+            Some(mut inode) => {
+                self.truncate_data(inode_num, &mut inode);
+                self.put_inode(inode_num);
+                true
+            }
+            None => false,
+        };
+        self.write_lock().unlock();
+        ret
+    }
+
+    fn unlink(&self, path: &str) -> bool {
+        self.write_lock().sleep_lock();
+        let ret = self.unlink_path(path);
+        self.write_lock().unlock();
+        ret
+    }
+
+    fn mkdir(&self, path: &str, mode: u16) -> bool {
+        self.write_lock().sleep_lock();
+        let ret = self.mkdir_path(path, mode);
+        self.write_lock().unlock();
+        ret
+    }
+
+    fn rmdir(&self, path: &str) -> bool {
+        self.write_lock().sleep_lock();
+        let ret = self.rmdir_path(path);
+        self.write_lock().unlock();
+        ret
+    }
+
+    fn sync(&self) -> Result<(), BlockErrors> {
+        self.sync_inodes()
+    }
+
+    fn fsync(&self, _inode_num: u32) -> Result<(), BlockErrors> {
+        self.sync_inodes()?;
+        bcache::sync_dev(self.bdev)?;
+        block::flush_dev(self.bdev)
+    }
+
+    fn punch_hole(&self, inode_num: u32, offset: u32, len: u32) -> Result<(), BlockErrors> {
+        self.write_lock().sleep_lock();
+        let ret = match self.get_inode(inode_num) {
+            Some(mut inode) => {
+                let ret = self.punch_hole_data(inode_num, &mut inode, offset, len);
+                self.put_inode(inode_num);
+                ret
+            }
+            None => Err(BlockErrors::IoError),
+        };
+        self.write_lock().unlock();
+        ret
+    }
+}
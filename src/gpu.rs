@@ -0,0 +1,409 @@
+// Virtio GPU device - one 2D resource the size of the display, backed by a framebuffer in our
+// memory. Drawing goes into the framebuffer, and a kernel process copies it to the host and
+// flushes it to the screen whenever it changed. User programs get it as /dev/fb0.
+use crate::console;
+use crate::devfs::{self, Device};
+use crate::page::{zalloc, PAGE_SIZE};
+use crate::process;
+use crate::syscall::sleep;
+use crate::vfs::S_IFCHR;
+use crate::virtio::{self, Buf, Virtqueue};
+use alloc::boxed::Box;
+use core::mem::size_of;
+use core::sync::atomic::{fence, Ordering};
+
+// Control queue commands
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
+const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+
+// Responses. Anything from 0x1200 on is an error.
+const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+// Each pixel is a u32 holding 0x00RRGGBB
+const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;
+
+const RESOURCE_ID: u32 = 1;
+const SCANOUT_ID: u32 = 0;
+// Used when the device doesn't report a display, and the most we'll allocate for
+const DEFAULT_WIDTH: u32 = 640;
+const DEFAULT_HEIGHT: u32 = 480;
+const MAX_WIDTH: u32 = 1920;
+const MAX_HEIGHT: u32 = 1080;
+// How often changes are pushed to the screen, in ms
+const FLUSH_INTERVAL: usize = 30;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct CtrlHeader {
+    kind: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    padding: u32,
+}
+
+impl CtrlHeader {
+    fn new(kind: u32) -> Self {
+        CtrlHeader {
+            kind,
+            ..CtrlHeader::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct DisplayOne {
+    r: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct RespDisplayInfo {
+    hdr: CtrlHeader,
+    pmodes: [DisplayOne; VIRTIO_GPU_MAX_SCANOUTS],
+}
+
+#[repr(C)]
+struct ResourceCreate2d {
+    hdr: CtrlHeader,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+struct MemEntry {
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+// The framebuffer is one contiguous allocation, so a single entry covers it
+#[repr(C)]
+struct ResourceAttachBacking {
+    hdr: CtrlHeader,
+    resource_id: u32,
+    nr_entries: u32,
+    entry: MemEntry,
+}
+
+#[repr(C)]
+struct SetScanout {
+    hdr: CtrlHeader,
+    r: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct TransferToHost2d {
+    hdr: CtrlHeader,
+    r: Rect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct ResourceFlush {
+    hdr: CtrlHeader,
+    r: Rect,
+    resource_id: u32,
+    padding: u32,
+}
+
+// The two commands that put the framebuffer on screen, and where their responses go. They
+// live as long as the device, so only one update is in flight at a time.
+#[derive(Default)]
+struct Update {
+    transfer: TransferToHost2d,
+    flush: ResourceFlush,
+    transfer_resp: CtrlHeader,
+    flush_resp: CtrlHeader,
+}
+
+pub struct GpuDevice {
+    ctrl: Virtqueue,
+    index: usize,
+    pub fb: *mut u32,
+    pub width: u32,
+    pub height: u32,
+    update: Box<Update>,
+    // Commands of the current update the device hasn't finished
+    in_flight: usize,
+    // Whether the framebuffer changed since the last update
+    dirty: bool,
+    // Mapped into a process, which can change it without telling us
+    mapped: bool,
+}
+
+// The console only ever uses one display
+static mut GPU: Option<GpuDevice> = None;
+
+// Sends a command and spins until the device answers. Only for setup, before interrupts are
+// on. Returns the response type.
+unsafe fn command<C, R>(vq: &mut Virtqueue, cmd: &C, resp: &mut R) -> u32 {
+    let chain = [
+        Buf::new(cmd as *const C, size_of::<C>() as u32, false),
+        Buf::new(resp as *const R, size_of::<R>() as u32, true),
+    ];
+    if false == vq.add_buf(&chain, 0) {
+        return 0;
+    }
+    vq.kick();
+    while vq.poll_used().is_none() {
+        // The device moves used.idx behind our back
+        fence(Ordering::SeqCst);
+    }
+    (*(resp as *const R as *const CtrlHeader)).kind
+}
+
+pub fn setup_gpu_device(ptr: *mut u32) -> bool {
+    unsafe {
+        if GPU.is_some() {
+            print!("already have one...");
+            return false;
+        }
+        let index = match virtio::slot(ptr) {
+            Some(index) => index,
+            None => return false,
+        };
+        // 1 - 6. Reset, acknowledge and agree on features. We don't want 3D or EDID.
+        let features = match virtio::negotiate_features(ptr, virtio::RING_FEATURES) {
+            Some(features) => features,
+            None => return false,
+        };
+        // 7. Perform device-specific setup. The cursor queue isn't used.
+        let mut ctrl = match Virtqueue::new(ptr, 0, features) {
+            Some(vq) => vq,
+            None => return false,
+        };
+        // 8. Set the DRIVER_OK status bit. Device is now 'live'
+        virtio::driver_ok(ptr);
+
+        // Take the size of the first display that's on
+        let mut info = Box::new(RespDisplayInfo::default());
+        let cmd = CtrlHeader::new(VIRTIO_GPU_CMD_GET_DISPLAY_INFO);
+        let (mut width, mut height) = (DEFAULT_WIDTH, DEFAULT_HEIGHT);
+        if command(&mut ctrl, &cmd, &mut *info) == VIRTIO_GPU_RESP_OK_DISPLAY_INFO {
+            let mode = &info.pmodes[SCANOUT_ID as usize];
+            if mode.enabled != 0 && mode.r.width > 0 && mode.r.height > 0 {
+                width = mode.r.width.min(MAX_WIDTH);
+                height = mode.r.height.min(MAX_HEIGHT);
+            }
+        }
+
+        let fb_size = (width * height) as usize * size_of::<u32>();
+        let fb = zalloc((fb_size + PAGE_SIZE - 1) / PAGE_SIZE) as *mut u32;
+        if fb.is_null() {
+            print!("no memory for a framebuffer...");
+            return false;
+        }
+        let mut resp = Box::new(CtrlHeader::default());
+        let create = Box::new(ResourceCreate2d {
+            hdr: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D),
+            resource_id: RESOURCE_ID,
+            format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+            width,
+            height,
+        });
+        let attach = Box::new(ResourceAttachBacking {
+            hdr: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING),
+            resource_id: RESOURCE_ID,
+            nr_entries: 1,
+            entry: MemEntry {
+                addr: fb as u64,
+                length: fb_size as u32,
+                padding: 0,
+            },
+        });
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        let scanout = Box::new(SetScanout {
+            hdr: CtrlHeader::new(VIRTIO_GPU_CMD_SET_SCANOUT),
+            r: rect,
+            scanout_id: SCANOUT_ID,
+            resource_id: RESOURCE_ID,
+        });
+        if command(&mut ctrl, &*create, &mut *resp) != VIRTIO_GPU_RESP_OK_NODATA
+            || command(&mut ctrl, &*attach, &mut *resp) != VIRTIO_GPU_RESP_OK_NODATA
+            || command(&mut ctrl, &*scanout, &mut *resp) != VIRTIO_GPU_RESP_OK_NODATA
+        {
+            print!("device refused the framebuffer...");
+            return false;
+        }
+
+        let mut update = Box::new(Update::default());
+        update.transfer = TransferToHost2d {
+            hdr: CtrlHeader::new(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D),
+            r: rect,
+            resource_id: RESOURCE_ID,
+            ..TransferToHost2d::default()
+        };
+        update.flush = ResourceFlush {
+            hdr: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_FLUSH),
+            r: rect,
+            resource_id: RESOURCE_ID,
+            ..ResourceFlush::default()
+        };
+        GPU.replace(GpuDevice {
+            ctrl,
+            index,
+            fb,
+            width,
+            height,
+            update,
+            in_flight: 0,
+            dirty: true,
+            mapped: false,
+        });
+        devfs::register(Device {
+            name: "fb",
+            minor: Some(0),
+            mode: S_IFCHR | 0o666,
+            read: read_fb,
+            write: Some(write_fb),
+            mmap: Some(mmap_fb),
+        });
+        process::add_kernel_process(update_proc);
+        print!("{}x{}...", width, height);
+        true
+    }
+}
+
+// Runs f on the display with the output lock held, since print! draws on it, None if there
+// isn't one
+pub fn with_gpu<T>(f: impl FnOnce(&mut GpuDevice) -> T) -> Option<T> {
+    console::with_output(|| unsafe { GPU.as_mut().map(f) })
+}
+
+// Has the framebuffer put on screen soon
+pub fn mark_dirty() {
+    with_gpu(|gd| gd.dirty = true);
+}
+
+// Sends the whole framebuffer to the host and has it shown, unless an update is still going
+fn update(gd: &mut GpuDevice) {
+    if gd.in_flight > 0 || !(gd.dirty || gd.mapped) {
+        return;
+    }
+    let u = &mut *gd.update;
+    let transfer = [
+        Buf::new(
+            &u.transfer as *const TransferToHost2d,
+            size_of::<TransferToHost2d>() as u32,
+            false,
+        ),
+        Buf::new(
+            &u.transfer_resp as *const CtrlHeader,
+            size_of::<CtrlHeader>() as u32,
+            true,
+        ),
+    ];
+    let flush = [
+        Buf::new(
+            &u.flush as *const ResourceFlush,
+            size_of::<ResourceFlush>() as u32,
+            false,
+        ),
+        Buf::new(
+            &u.flush_resp as *const CtrlHeader,
+            size_of::<CtrlHeader>() as u32,
+            true,
+        ),
+    ];
+    // The device handles the control queue in order, so the flush sees the transferred pixels
+    if gd.ctrl.add_buf(&transfer, 0) && gd.ctrl.add_buf(&flush, 1) {
+        gd.in_flight = 2;
+        gd.dirty = false;
+    }
+    gd.ctrl.kick();
+}
+
+fn update_proc() {
+    loop {
+        with_gpu(update);
+        sleep(FLUSH_INTERVAL);
+    }
+}
+
+fn fb_len(gd: &GpuDevice) -> usize {
+    (gd.width * gd.height) as usize * size_of::<u32>()
+}
+
+fn read_fb(_minor: usize, buffer: *mut u8, size: u32, offset: u32) -> u32 {
+    with_gpu(|gd| {
+        let len = (size as usize).min(fb_len(gd).saturating_sub(offset as usize));
+        unsafe {
+            core::ptr::copy_nonoverlapping((gd.fb as *const u8).add(offset as usize), buffer, len);
+        }
+        len as u32
+    })
+    .unwrap_or(0)
+}
+
+fn write_fb(_minor: usize, buffer: *const u8, size: u32, offset: u32) -> Option<u32> {
+    with_gpu(|gd| {
+        let len = (size as usize).min(fb_len(gd).saturating_sub(offset as usize));
+        unsafe {
+            core::ptr::copy_nonoverlapping(buffer, (gd.fb as *mut u8).add(offset as usize), len);
+        }
+        gd.dirty = true;
+        len as u32
+    })
+}
+
+// From then on the screen is refreshed every FLUSH_INTERVAL, since we can't tell when the
+// process draws
+fn mmap_fb(_minor: usize, offset: u32, len: u32) -> Option<usize> {
+    with_gpu(|gd| {
+        // The pages the framebuffer was allocated in, mmap only maps whole ones
+        let pages_len = (fb_len(gd) + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        if offset as usize + len as usize > pages_len {
+            return None;
+        }
+        gd.mapped = true;
+        Some(gd.fb as usize + offset as usize)
+    })
+    .flatten()
+}
+
+pub fn handle_interrupt(index: usize) {
+    console::with_output(|| unsafe {
+        let gd = match GPU.as_mut() {
+            Some(gd) if gd.index == index => gd,
+            _ => {
+                println!("Invalid GPU device for interrupt {}", index + 1);
+                return;
+            }
+        };
+        let mut finished = 0;
+        gd.ctrl.process_used(|_, _| finished += 1);
+        gd.in_flight = gd.in_flight.saturating_sub(finished);
+    });
+}
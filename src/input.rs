@@ -0,0 +1,331 @@
+// Virtio input devices - keyboards and tablets. Every device's events can be read from
+// /dev/input/eventN as Linux struct input_event records, and keys also go to the console.
+use crate::console;
+use crate::cpu::{get_mtime, MTIMER_TICKS_PER_MS};
+use crate::devfs::{self, Device};
+use crate::lock::Mutex;
+use crate::syscall::sleep;
+use crate::vfs::S_IFCHR;
+use crate::virtio::{self, Buf, MmioOffsets, Virtqueue};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use core::mem::size_of;
+
+// Config space layout, in bytes
+const CONFIG_SELECT: usize = 0;
+const CONFIG_SUBSEL: usize = 1;
+const CONFIG_SIZE: usize = 2;
+const CONFIG_DATA: usize = 8;
+
+// What the config space shows, by select
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+// Event types
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+
+// Key codes with a meaning beyond the keymap
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_CAPSLOCK: u16 = 58;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_UP: u16 = 103;
+const KEY_LEFT: u16 = 105;
+const KEY_RIGHT: u16 = 106;
+const KEY_DOWN: u16 = 108;
+
+// US layout, indexed by key code. 0 means the key doesn't type anything.
+const KEYMAP: &[u8; 58] =
+    b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const KEYMAP_SHIFT: &[u8; 58] =
+    b"\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+// Event buffers kept posted on the event queue
+const EVENT_BUFFERS: usize = 64;
+// Events that haven't been read yet. The oldest are dropped past this, like evdev does.
+const EVENT_QUEUE_LIMIT: usize = 256;
+// How often a blocked read checks again, in ms
+const READ_INTERVAL: usize = 10;
+
+// What the device puts in each buffer
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct VirtioInputEvent {
+    kind: u16,
+    code: u16,
+    value: u32,
+}
+
+// struct input_event, as read from /dev/input/eventN on a 64-bit Linux
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct InputEvent {
+    pub sec: u64,
+    pub usec: u64,
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InputKind {
+    Keyboard,
+    // Absolute (tablet) or relative (mouse) pointer
+    Pointer,
+}
+
+pub struct InputDevice {
+    vq: Virtqueue,
+    pub kind: InputKind,
+    // N in /dev/input/eventN
+    pub minor: usize,
+    pub name: String,
+    // Range of ABS_X and ABS_Y, for absolute pointers
+    pub abs: [(u32, u32); 2],
+    buffers: Box<[VirtioInputEvent; EVENT_BUFFERS]>,
+    queue: VecDeque<InputEvent>,
+    shift: bool,
+    ctrl: bool,
+    caps_lock: bool,
+}
+
+static mut INPUT_DEVICES: [Option<InputDevice>; 8] =
+    [None, None, None, None, None, None, None, None];
+static mut NEXT_MINOR: usize = 0;
+// The devices' queues are shared between readers and the interrupt handler, on any hart
+static mut INPUT_LOCK: Mutex = Mutex::new();
+
+// Selects a config field, returns how many bytes of data it has
+unsafe fn select(config: *mut u8, select: u8, subsel: u8) -> usize {
+    config.add(CONFIG_SELECT).write_volatile(select);
+    config.add(CONFIG_SUBSEL).write_volatile(subsel);
+    config.add(CONFIG_SIZE).read_volatile() as usize
+}
+
+unsafe fn config_u32(config: *mut u8, at: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = config.add(CONFIG_DATA + at + i).read_volatile();
+    }
+    u32::from_le_bytes(bytes)
+}
+
+pub fn setup_input_device(ptr: *mut u32) -> bool {
+    unsafe {
+        let index = match virtio::slot(ptr) {
+            Some(index) => index,
+            None => return false,
+        };
+        // 1 - 6. Reset, acknowledge and agree on features. There are no device specific ones.
+        let features = match virtio::negotiate_features(ptr, virtio::RING_FEATURES) {
+            Some(features) => features,
+            None => return false,
+        };
+
+        // 7. Perform device-specific setup. Only the event queue is used, the status queue
+        // would set the keyboard's LEDs.
+        let vq = match Virtqueue::new(ptr, 0, features) {
+            Some(vq) => vq,
+            None => return false,
+        };
+        let config = ptr.add(MmioOffsets::Config.scale32()) as *mut u8;
+        let mut name = String::new();
+        let len = select(config, VIRTIO_INPUT_CFG_ID_NAME, 0);
+        for i in 0..len {
+            name.push(config.add(CONFIG_DATA + i).read_volatile() as char);
+        }
+        // Anything that reports positions is a pointer
+        let kind = if select(config, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8) > 0
+            || select(config, VIRTIO_INPUT_CFG_EV_BITS, EV_REL as u8) > 0
+        {
+            InputKind::Pointer
+        } else if select(config, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8) > 0 {
+            InputKind::Keyboard
+        } else {
+            print!("no keys or axes...");
+            return false;
+        };
+        let mut abs = [(0, 0); 2];
+        for (axis, range) in abs.iter_mut().enumerate() {
+            if select(config, VIRTIO_INPUT_CFG_ABS_INFO, axis as u8) >= 8 {
+                *range = (config_u32(config, 0), config_u32(config, 4));
+            }
+        }
+        let minor = NEXT_MINOR;
+        NEXT_MINOR += 1;
+        let mut id = InputDevice {
+            vq,
+            kind,
+            minor,
+            name,
+            abs,
+            buffers: Box::new([VirtioInputEvent::default(); EVENT_BUFFERS]),
+            queue: VecDeque::new(),
+            shift: false,
+            ctrl: false,
+            caps_lock: false,
+        };
+        for token in 0..EVENT_BUFFERS {
+            post_buffer(&mut id, token);
+        }
+        print!("{} ({:?}, event{})...", id.name, id.kind, minor);
+        INPUT_DEVICES[index] = Some(id);
+        devfs::register(Device {
+            name: "input/event",
+            minor: Some(minor),
+            mode: S_IFCHR | 0o444,
+            read: read_events,
+            write: None,
+            mmap: None,
+        });
+        // 8. Set the DRIVER_OK status bit. Device is now 'live'
+        virtio::driver_ok(ptr);
+        // and can be given somewhere to put events
+        if let Some(id) = INPUT_DEVICES[index].as_mut() {
+            id.vq.kick();
+        }
+        true
+    }
+}
+
+fn post_buffer(id: &mut InputDevice, token: usize) {
+    let buf = Buf::new(
+        &id.buffers[token] as *const VirtioInputEvent,
+        size_of::<VirtioInputEvent>() as u32,
+        true,
+    );
+    id.vq.add_buf(&[buf], token);
+}
+
+// Runs f on the device behind /dev/input/eventN with the lock held
+fn with_minor<T>(minor: usize, f: impl FnOnce(&mut InputDevice) -> T) -> Option<T> {
+    unsafe {
+        INPUT_LOCK.with(|| {
+            INPUT_DEVICES
+                .iter_mut()
+                .flatten()
+                .find(|id| id.minor == minor)
+                .map(f)
+        })
+    }
+}
+
+// Reads whole events, waiting until there's at least one. Returns 0 if the buffer can't hold
+// one.
+// don't run inside interrupt context - will block
+fn read_events(minor: usize, buffer: *mut u8, size: u32, _offset: u32) -> u32 {
+    let max = size as usize / size_of::<InputEvent>();
+    if max == 0 {
+        return 0;
+    }
+    loop {
+        let count = with_minor(minor, |id| {
+            let count = max.min(id.queue.len());
+            let events = buffer as *mut InputEvent;
+            for (i, event) in id.queue.drain(..count).enumerate() {
+                unsafe {
+                    events.add(i).write_unaligned(event);
+                }
+            }
+            count
+        });
+        match count {
+            Some(0) => {
+                sleep(READ_INTERVAL);
+            }
+            Some(count) => return (count * size_of::<InputEvent>()) as u32,
+            None => return 0,
+        }
+    }
+}
+
+// Turns a key press into what the console should see
+fn type_key(id: &mut InputDevice, code: u16) {
+    let arrow = match code {
+        KEY_UP => Some(b'A'),
+        KEY_DOWN => Some(b'B'),
+        KEY_RIGHT => Some(b'C'),
+        KEY_LEFT => Some(b'D'),
+        _ => None,
+    };
+    if let Some(c) = arrow {
+        for byte in [0x1b, b'[', c].iter() {
+            console::push_stdin(*byte);
+        }
+        return;
+    }
+    let mut c = match KEYMAP.get(code as usize) {
+        Some(0) | None => return,
+        Some(_) if id.shift => KEYMAP_SHIFT[code as usize],
+        Some(c) => *c,
+    };
+    if id.caps_lock && c.is_ascii_alphabetic() {
+        c ^= 0x20;
+    }
+    if id.ctrl && c.is_ascii_alphabetic() {
+        c = c.to_ascii_uppercase() - b'@';
+    }
+    console::push_stdin(c);
+    console::echo(c);
+}
+
+fn key(id: &mut InputDevice, code: u16, value: u32) {
+    // 0 is a release, 1 a press and 2 a repeat
+    let down = value != 0;
+    match code {
+        KEY_LEFTSHIFT | KEY_RIGHTSHIFT => id.shift = down,
+        KEY_LEFTCTRL | KEY_RIGHTCTRL => id.ctrl = down,
+        KEY_CAPSLOCK if value == 1 => id.caps_lock = !id.caps_lock,
+        _ if down => type_key(id, code),
+        _ => {}
+    }
+}
+
+pub fn handle_interrupt(index: usize) {
+    unsafe { INPUT_LOCK.with(|| handle_events(index)) }
+}
+
+unsafe fn handle_events(index: usize) {
+    let id = match INPUT_DEVICES[index].as_mut() {
+        Some(id) => id,
+        None => {
+            println!("Invalid input device for interrupt {}", index + 1);
+            return;
+        }
+    };
+    let ticks = get_mtime().as_u64();
+    let usec = ticks * 1000 / MTIMER_TICKS_PER_MS;
+    let mut tokens = [0usize; EVENT_BUFFERS];
+    let mut count = 0;
+    id.vq.process_used(|token, _| {
+        tokens[count] = token;
+        count += 1;
+    });
+    for &token in tokens[..count].iter() {
+        let event = id.buffers[token];
+        if event.kind == EV_KEY && id.kind == InputKind::Keyboard {
+            key(id, event.code, event.value);
+        }
+        if id.queue.len() >= EVENT_QUEUE_LIMIT {
+            id.queue.pop_front();
+        }
+        id.queue.push_back(InputEvent {
+            sec: usec / 1_000_000,
+            usec: usec % 1_000_000,
+            kind: event.kind,
+            code: event.code,
+            value: event.value as i32,
+        });
+        post_buffer(id, token);
+    }
+    id.vq.kick();
+}
@@ -1,6 +1,7 @@
 // kmem.rs
 // Sub-page allocation
 
+use crate::lock::Mutex;
 use crate::page::{align_val, zalloc, PAGE_SIZE};
 use crate::mmu::{Table};
 use core::{mem::size_of, ptr::null_mut};
@@ -54,6 +55,8 @@ impl AllocList {
 static mut KMEM_HEAD: *mut AllocList = null_mut();
 static mut KMEM_SIZE: usize = 0;
 static mut KMEM_PAGE_TABLE: *mut Table = null_mut();
+// Held while the list is walked or changed, every hart allocates
+static mut KMEM_LOCK: Mutex = Mutex::new();
 
 pub fn get_head() -> *mut u8 {
     unsafe { KMEM_HEAD as *mut u8 }
@@ -99,34 +102,36 @@ pub fn kzmalloc(size: usize) -> *mut u8 {
 
 /// Allocate sub-page level allocation
 pub fn kmalloc(size: usize) -> *mut u8 {
-    unsafe {
-        let size = align_val(size, 3) + size_of::<AllocList>();
-        let mut head = KMEM_HEAD;
-        let tail = (KMEM_HEAD as *mut u8).add(KMEM_SIZE * PAGE_SIZE) as *mut AllocList;
+    unsafe { KMEM_LOCK.with(|| kmalloc_locked(size)) }
+}
 
-        while head < tail {
-            if (*head).is_free() && size <= (*head).get_size() {
-                // Here's a spot available
-                let chunk_size = (*head).get_size();
-                let rem = chunk_size - size;
-                (*head).set_taken();
-                if rem > size_of::<AllocList>() {
-                    // There's some space left over - mark as available
-                    let next = (head as *mut u8).add(size) as *mut AllocList;
-                    (*next).set_free();
-                    (*next).set_size(rem);
-                    (*head).set_size(size);
-                }
-                else {
-                    // The space left over isn't big enough, take the entire chunk
-                    (*head).set_size(chunk_size);
-                }
-                return head.add(1) as *mut u8;
+unsafe fn kmalloc_locked(size: usize) -> *mut u8 {
+    let size = align_val(size, 3) + size_of::<AllocList>();
+    let mut head = KMEM_HEAD;
+    let tail = (KMEM_HEAD as *mut u8).add(KMEM_SIZE * PAGE_SIZE) as *mut AllocList;
+
+    while head < tail {
+        if (*head).is_free() && size <= (*head).get_size() {
+            // Here's a spot available
+            let chunk_size = (*head).get_size();
+            let rem = chunk_size - size;
+            (*head).set_taken();
+            if rem > size_of::<AllocList>() {
+                // There's some space left over - mark as available
+                let next = (head as *mut u8).add(size) as *mut AllocList;
+                (*next).set_free();
+                (*next).set_size(rem);
+                (*head).set_size(size);
             }
             else {
-                // Try next chunk
-                head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
+                // The space left over isn't big enough, take the entire chunk
+                (*head).set_size(chunk_size);
             }
+            return head.add(1) as *mut u8;
+        }
+        else {
+            // Try next chunk
+            head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
         }
     }
 
@@ -136,39 +141,43 @@ pub fn kmalloc(size: usize) -> *mut u8 {
 pub fn kfree(ptr: *mut u8) {
     unsafe {
         if !ptr.is_null() {
-            let p = (ptr as *mut AllocList).offset(-1);
-            if (*p).is_taken() {
-                (*p).set_free();
-            }
-            coalesce(); // See if we can merge with surrounding chunks to avoid fragmentation
+            KMEM_LOCK.with(|| {
+                let p = (ptr as *mut AllocList).offset(-1);
+                if (*p).is_taken() {
+                    (*p).set_free();
+                }
+                coalesce_locked(); // See if we can merge with surrounding chunks to avoid fragmentation
+            })
         }
     }
 }
 
 pub fn coalesce() {
-    unsafe {
-        let mut head = KMEM_HEAD;
-        let tail = (KMEM_HEAD as *mut u8).add(KMEM_SIZE * PAGE_SIZE) as *mut AllocList;
+    unsafe { KMEM_LOCK.with(|| coalesce_locked()) }
+}
 
-        while head < tail {
-            let next = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
-            if (*head).get_size() == 0 {
-                // Size is 0 for some reason - jump out to avoid an infinite loop
-                break;
-            }
-            else if next >= tail {
-                // The last block's size is incorrect - jump out to avoid page fault
-                break;
-            }
+unsafe fn coalesce_locked() {
+    let mut head = KMEM_HEAD;
+    let tail = (KMEM_HEAD as *mut u8).add(KMEM_SIZE * PAGE_SIZE) as *mut AllocList;
 
-            if (*head).is_free() && (*next).is_free() {
-                // Combine with next block
-                (*head).set_size((*head).get_size() + (*next).get_size());
-            }
+    while head < tail {
+        let next = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
+        if (*head).get_size() == 0 {
+            // Size is 0 for some reason - jump out to avoid an infinite loop
+            break;
+        }
+        else if next >= tail {
+            // The last block's size is incorrect - jump out to avoid page fault
+            break;
+        }
 
-            // Move to next block
-            head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
+        if (*head).is_free() && (*next).is_free() {
+            // Combine with next block
+            (*head).set_size((*head).get_size() + (*next).get_size());
         }
+
+        // Move to next block
+        head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
     }
 }
 
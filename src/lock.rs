@@ -1,4 +1,5 @@
 // Locking routines
+use crate::cpu::{self, without_interrupts};
 use crate::syscall;
 
 pub const DEFAULT_LOCK_SLEEP: usize = 1000;
@@ -49,6 +50,17 @@ impl<'a> Mutex {
         while !self.try_lock() {}
     }
 
+    // Runs f with the lock held and this hart's interrupts off, so a trap on the same hart
+    // can't end up spinning on it. Can be safely used in interrupt context.
+    pub fn with<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        without_interrupts(|| {
+            self.spin_lock();
+            let ret = f();
+            self.unlock();
+            ret
+        })
+    }
+
     // Unlocks mutex
     pub fn unlock(&mut self) {
         unsafe {
@@ -56,4 +68,43 @@ impl<'a> Mutex {
         }
     }
 
-}
\ No newline at end of file
+}
+
+// A Mutex the hart holding it can take again, for code that may end up back in itself - a
+// driver that prints while it holds the lock print! takes, for one
+pub struct RecursiveMutex {
+    mutex: Mutex,
+    // Hart holding it, and how many times over
+    owner: usize,
+    depth: usize,
+}
+
+impl RecursiveMutex {
+    pub const fn new() -> Self {
+        Self {
+            mutex: Mutex::new(),
+            owner: usize::MAX,
+            depth: 0,
+        }
+    }
+
+    // Runs f with the lock held, see Mutex::with. Only the holding hart ever sees its own id in
+    // owner, and its interrupts are off while it does.
+    pub fn with<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        without_interrupts(|| {
+            let hart = cpu::hartid();
+            if self.owner != hart {
+                self.mutex.spin_lock();
+                self.owner = hart;
+            }
+            self.depth += 1;
+            let ret = f();
+            self.depth -= 1;
+            if self.depth == 0 {
+                self.owner = usize::MAX;
+                self.mutex.unlock();
+            }
+            ret
+        })
+    }
+}
@@ -22,7 +22,7 @@ macro_rules! print
 {
     ($($args:tt)+) => ({
         use core::fmt::Write;
-        let _ = write!(crate::uart::Uart::new(0x1000_0000), $($args)+);
+        let _ = write!(crate::console::Console, $($args)+);
     });
 }
 
@@ -42,7 +42,6 @@ macro_rules! println
 
 extern "C" {
     pub static HEAP_START: usize;
-    pub static HEAP_SIZE: usize;
     pub static TEXT_START: usize;
     pub static TEXT_END: usize;
     pub static RODATA_START: usize;
@@ -56,7 +55,8 @@ extern "C" {
 }
 
 extern "C" {
-    pub fn switch_to_user(frame: usize) -> !;
+    // flush_asid is non-zero when the hart may have cached another table under the frame's ASID
+    pub fn switch_to_user(frame: usize, flush_asid: usize) -> !;
 }
 
 // ///////////////////////////////////
@@ -67,6 +67,7 @@ extern "C" fn eh_personality() {}
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    console::panicked();
     print!("Aborting: ");
     if let Some(_p) = info.location() {
         println!(
@@ -90,30 +91,64 @@ extern "C" fn abort() -> ! {
     }
 }
 
-// Runs in machine mode
+// Runs in machine mode, with the device tree's address from boot.S - or in supervisor mode
+// from sboot.S under the firmware
 #[no_mangle]
-extern "C" fn kinit() -> ! {
-    uart::Uart::new(0x1000_0000).init();
+extern "C" fn kinit(fdt: usize) -> ! {
+    let found_fdt = fdt::init(fdt);
+    let platform = fdt::platform();
+    if platform.uart.base != 0 {
+        uart::Uart::new(platform.uart.base).init();
+    }
     println!("Welcome to PeetOS");
+    if found_fdt {
+        println!(
+            "Device tree at 0x{:x}: {} MiB at 0x{:x}, {} harts",
+            fdt,
+            platform.memory.size >> 20,
+            platform.memory.base,
+            platform.harts
+        );
+    } else {
+        println!("No device tree, assuming QEMU's virt machine");
+    }
     page::init();
     kmem::init();
     process::init();
     plic::set_threshold(0);
-    // Enable PLIC interrupts
-    // VIRTIO = 1 -> 8
-    // UART0 = 10
-    for i in 1..=10 {
-        plic::enable(i);
-        plic::set_priority(i, 1);
+    // Enable PLIC interrupts for the UART and every virtio slot
+    if platform.uart.irq != 0 {
+        plic::enable(platform.uart.irq);
+        plic::set_priority(platform.uart.irq, 1);
+    }
+    for slot in platform.virtio() {
+        plic::enable(slot.irq);
+        plic::set_priority(slot.irq, 1);
     }
     virtio::probe();
+    fbcon::init();
+    bcache::init();
+    vfs::init();
+    tcpip::init();
 
     console::init();
+    // The other harts are still stopped in the firmware
+    #[cfg(feature = "sbi")]
+    sbi::start_harts();
 
     // let mut sh = shell::Shell::new();
     // sh.shell();
     test::init_processes();
 
+    // Wake the parked harts, each of them schedules from the same process list
+    cpu::set_online();
+    let boot_hart = cpu::hartid();
+    for hart in 0..platform.harts.min(cpu::MAX_HARTS) {
+        if hart != boot_hart {
+            cpu::send_software_interrupt(hart);
+        }
+    }
+
     // Schedule first process and switch
     trap::schedule_scheduler();
     scheduler::context_switch();
@@ -126,15 +161,16 @@ extern "C" fn kinit_hart(hartid: usize) {
         // We have to store the kernel's table. The tables will be moved
         // back and forth between the kernel's table and user
         // applicatons' tables.
-        cpu::mscratch_write((&mut cpu::KERNEL_TRAP_FRAME[hartid] as *mut cpu::TrapFrame) as usize);
+        let frame = (&mut cpu::KERNEL_TRAP_FRAME[hartid] as *mut cpu::TrapFrame) as usize;
+        // Supervisor mode can't touch mscratch, under the firmware only sscratch is used
+        #[cfg(not(feature = "sbi"))]
+        cpu::mscratch_write(frame);
         // Copy the same mscratch over to the supervisor version of the
         // same register.
-        cpu::sscratch_write(cpu::mscratch_read());
+        cpu::sscratch_write(frame);
         cpu::KERNEL_TRAP_FRAME[hartid].hartid = hartid;
-        // We can't do the following until zalloc() is locked, but we
-        // don't have locks, yet :( cpu::KERNEL_TRAP_FRAME[hartid].satp
-        // = cpu::KERNEL_TRAP_FRAME[0].satp;
-        // cpu::KERNEL_TRAP_FRAME[hartid].trap_stack = page::zalloc(1);
+        // Nothing else to set up - the kernel runs with paging off, and traps land on this
+        // hart's 64K of the kernel stack. It parks until kinit wakes it to start scheduling.
     }
 }
 
@@ -143,24 +179,38 @@ extern "C" fn kinit_hart(hartid: usize) {
 // ///////////////////////////////////
 
 pub mod assembly;
+pub mod bcache;
 pub mod block;
 pub mod buffer;
 pub mod console;
 pub mod cpu;
+pub mod devfs;
+pub mod elf;
+pub mod fbcon;
+pub mod fdt;
 pub mod fs;
+pub mod gpu;
+pub mod input;
 pub mod kmem;
 pub mod lock;
 pub mod mmu;
+pub mod net;
 pub mod page;
 pub mod plic;
 pub mod process;
 pub mod random;
+#[cfg(feature = "sbi")]
+pub mod sbi;
 pub mod scheduler;
 pub mod shell;
+pub mod socket;
 pub mod syscall;
+pub mod tcpip;
 pub mod test;
 pub mod trap;
 pub mod uart;
+pub mod vconsole;
+pub mod vfs;
 pub mod virtio;
 
 // ///////////////////////////////////
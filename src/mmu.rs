@@ -1,7 +1,8 @@
-use crate::{TEXT_START, TEXT_END, RODATA_START, RODATA_END, DATA_START, DATA_END, BSS_START, BSS_END, KERNEL_STACK_START, KERNEL_STACK_END, HEAP_START, HEAP_SIZE};
-use crate::page::{zalloc, dealloc, align_val, PAGE_SIZE};
+use crate::{TEXT_START, TEXT_END, RODATA_START, RODATA_END, DATA_START, DATA_END, BSS_START, BSS_END, KERNEL_STACK_START, KERNEL_STACK_END, HEAP_START};
+use crate::page::{alloc, zalloc, dealloc, release, share, is_shared, align_val, heap_size, PAGE_SIZE};
 use crate::kmem::{get_page_table, get_head, get_num_allocations};
 use crate::cpu;
+use crate::fdt;
 
 #[repr(u64)]
 #[derive(Copy, Clone)]
@@ -16,6 +17,12 @@ pub enum EntryBits {
     Access = 1 << 6,
     Dirty = 1 << 7,
 
+    // Reserved for software (RSW) bits
+    // Owned - the leaf page was allocated for this table and is freed when it's unmapped
+    Owned = 1 << 8,
+    // CopyOnWrite - the page is shared read-only and gets copied on the first store
+    CopyOnWrite = 1 << 9,
+
     ReadWrite = 1 << 1 | 1 << 2,
     ReadExecute = 1 << 1 | 1 << 3,
     ReadWriteExecute = 1 << 1 | 1 << 2 | 1 << 3,
@@ -123,6 +130,103 @@ pub fn map(root: &mut Table, vaddr: usize, paddr: usize, bits: i64, level: usize
     v.set_entry(entry); // Set the entry
 }
 
+// Returns the leaf entry mapping a virtual address, if there is one
+pub fn get_leaf(root: &mut Table, vaddr: usize) -> Option<&mut Entry> {
+    let vpn = [
+        (vaddr >> 12) & 0x1ff,
+        (vaddr >> 21) & 0x1ff,
+        (vaddr >> 30) & 0x1ff,
+    ];
+
+    let mut v = &mut root.entries[vpn[2]];
+    for i in (0..=2).rev() {
+        if v.is_invalid() {
+            break;
+        } else if v.is_leaf() {
+            return Some(v);
+        } else if i == 0 {
+            break;
+        }
+
+        let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
+        v = unsafe { entry.add(vpn[i - 1]).as_mut().unwrap() };
+    }
+
+    None
+}
+
+// Duplicates the user mappings of one table into another for fork. Owned pages are shared
+// rather than copied: writable ones become read-only copy-on-write in both tables.
+// Processes only map 4 KiB pages, so leaves above level 0 aren't cloned.
+pub fn clone_cow(parent: &mut Table, child: &mut Table) {
+    for lv2 in 0..Table::len() {
+        let entry_lv2 = &parent.entries[lv2];
+        if entry_lv2.is_invalid() || entry_lv2.is_leaf() {
+            continue;
+        }
+        let memaddr_lv1 = (entry_lv2.get_entry() & !0x3ff) << 2;
+        let table_lv1 = unsafe { (memaddr_lv1 as *mut Table).as_mut().unwrap() };
+        for lv1 in 0..Table::len() {
+            let entry_lv1 = &table_lv1.entries[lv1];
+            if entry_lv1.is_invalid() || entry_lv1.is_leaf() {
+                continue;
+            }
+            let memaddr_lv0 = (entry_lv1.get_entry() & !0x3ff) << 2;
+            let table_lv0 = unsafe { (memaddr_lv0 as *mut Table).as_mut().unwrap() };
+            for lv0 in 0..Table::len() {
+                let entry_lv0 = &mut table_lv0.entries[lv0];
+                if entry_lv0.is_invalid() {
+                    continue;
+                }
+                let vaddr = lv2 << 30 | lv1 << 21 | lv0 << 12;
+                let paddr = ((entry_lv0.get_entry() & !0x3ff) << 2) as usize;
+                let mut bits = entry_lv0.get_entry() & 0x3ff & !EntryBits::Valid.val();
+                if bits & EntryBits::Owned.val() != 0 {
+                    if bits & EntryBits::Write.val() != 0 {
+                        bits = (bits & !EntryBits::Write.val()) | EntryBits::CopyOnWrite.val();
+                        let shared = entry_lv0.get_entry() & !EntryBits::Write.val();
+                        entry_lv0.set_entry(shared | EntryBits::CopyOnWrite.val());
+                    }
+                    share(paddr as *mut u8);
+                }
+                map(child, vaddr, paddr, bits, 0);
+            }
+        }
+    }
+}
+
+// Gives the table its own writable copy of a copy-on-write page.
+// Returns false if the address isn't mapped copy-on-write.
+pub fn resolve_cow(root: &mut Table, vaddr: usize) -> bool {
+    let entry = match get_leaf(root, vaddr) {
+        Some(entry) => entry,
+        None => return false,
+    };
+    if entry.get_entry() & EntryBits::CopyOnWrite.val() == 0 {
+        return false;
+    }
+
+    let paddr = ((entry.get_entry() & !0x3ff) << 2) as *mut u8;
+    let bits =
+        (entry.get_entry() & 0x3ff & !EntryBits::CopyOnWrite.val()) | EntryBits::Write.val();
+    if is_shared(paddr) {
+        // Someone else still uses the page - take a private copy
+        let page = alloc(1);
+        if page.is_null() {
+            return false;
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(paddr, page, PAGE_SIZE);
+        }
+        release(paddr);
+        entry.set_entry(((page as i64) >> 2) | bits);
+    } else {
+        // We're the last one holding the page, it can just be made writable again
+        entry.set_entry(((paddr as i64) >> 2) | bits);
+    }
+    true
+}
+
 // Unmaps a virtual address
 pub fn unmap(root: &mut Table) {
     for lv2 in 0..Table::len() {
@@ -138,6 +242,17 @@ pub fn unmap(root: &mut Table) {
                 if entry_lv1.is_valid() && entry_lv1.is_branch() {
                     // Get LV0 table and deallocate its page
                     let memaddr_lv0 = (entry_lv1.get_entry() & !0x3ff) << 2;
+                    let table_lv0 = unsafe {
+                        (memaddr_lv0 as *mut Table).as_mut().unwrap()
+                    };
+                    for lv0 in 0..Table::len() {
+                        let ref entry_lv0 = table_lv0.entries[lv0];
+                        let owned = entry_lv0.get_entry() & EntryBits::Owned.val() != 0;
+                        if entry_lv0.is_valid() && owned {
+                            // Free pages that belong to this table, e.g. a loaded program
+                            release(((entry_lv0.get_entry() & !0x3ff) << 2) as *mut u8);
+                        }
+                    }
                     dealloc(memaddr_lv0 as *mut u8);
                 }
             }
@@ -165,6 +280,10 @@ pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
             break;
         }
         else if v.is_leaf() {
+            if v.get_entry() & EntryBits::User.val() == 0 {
+                // Not the process' own memory, like the trap trampoline
+                break;
+            }
             // We've found the leaf entry - get memory address
             let offset_mask = (1 << (12 + i * 9)) - 1;
             let vaddr_pgoffset = vaddr & offset_mask;
@@ -181,6 +300,71 @@ pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
     None
 }
 
+// Copies bytes from kernel memory into virtual memory mapped by the table.
+// Returns the number of bytes copied, which is short if an address isn't mapped writable -
+// the caller should fail with EFAULT.
+pub fn copy_to_virt(root: &mut Table, vaddr: usize, src: *const u8, len: usize) -> usize {
+    let mut copied = 0;
+    while copied < len {
+        let curr = vaddr + copied;
+        let chunk = core::cmp::min(PAGE_SIZE - (curr & (PAGE_SIZE - 1)), len - copied);
+        // The process couldn't store there itself, e.g. its text - which after fork is shared
+        let writable = EntryBits::Write.val() | EntryBits::CopyOnWrite.val();
+        match get_leaf(root, curr) {
+            Some(entry) if entry.get_entry() & writable != 0 => (),
+            _ => break,
+        }
+        // The kernel doesn't go through the MMU, so break copy-on-write sharing by hand. This
+        // hart may still have the read-only translation cached.
+        if resolve_cow(root, curr) {
+            cpu::satp_fence_vaddr(curr);
+        }
+        match virt_to_phys(root, curr) {
+            Some(paddr) => unsafe {
+                core::ptr::copy_nonoverlapping(src.add(copied), paddr as *mut u8, chunk);
+            },
+            None => break,
+        }
+        copied += chunk;
+    }
+    copied
+}
+
+// Copies bytes from virtual memory mapped by the table into kernel memory.
+// Returns the number of bytes copied, which is short if an address isn't mapped.
+pub fn copy_from_virt(root: &Table, dest: *mut u8, vaddr: usize, len: usize) -> usize {
+    let mut copied = 0;
+    while copied < len {
+        let curr = vaddr + copied;
+        let chunk = core::cmp::min(PAGE_SIZE - (curr & (PAGE_SIZE - 1)), len - copied);
+        match virt_to_phys(root, curr) {
+            Some(paddr) => unsafe {
+                core::ptr::copy_nonoverlapping(paddr as *const u8, dest.add(copied), chunk);
+            },
+            None => break,
+        }
+        copied += chunk;
+    }
+    copied
+}
+
+#[cfg(feature = "sbi")]
+extern "C" {
+    static TRAMPOLINE_START: usize;
+    static TRAMPOLINE_END: usize;
+}
+
+// Under the firmware traps come in on the process' satp, so the trap vector (strap.S) and the
+// process' trap frame are mapped at their physical addresses into its table, without the user
+// bit. Done on every switch to the process, as clone_cow leaves the frame as the parent's.
+#[cfg(feature = "sbi")]
+pub fn map_trampoline(root: &mut Table, frame: usize) {
+    let (start, end) = unsafe { (TRAMPOLINE_START, TRAMPOLINE_END) };
+    id_map_range(root, start, end, EntryBits::ReadExecute.val() | EntryBits::Global.val());
+    let frame_page = frame & !(PAGE_SIZE - 1);
+    map(root, frame_page, frame_page, EntryBits::Read.val(), 0);
+}
+
 /// Identity maps a physical memory range to virtual
 pub fn id_map_range(root: &mut Table, start: usize, end: usize, bits: i64) {
     let mut memaddr = start & !(PAGE_SIZE - 1);
@@ -212,7 +396,7 @@ pub fn map_kernel() {
 
     unsafe {
         // Map heap descriptors
-        let num_pages = HEAP_SIZE / PAGE_SIZE;
+        let num_pages = heap_size() / PAGE_SIZE;
         id_map_range(&mut root_pt, HEAP_START, HEAP_START + num_pages, EntryBits::ReadWrite.val());
 
         // Map executable section
@@ -231,15 +415,11 @@ pub fn map_kernel() {
         id_map_range(&mut root_pt, KERNEL_STACK_START, KERNEL_STACK_END, EntryBits::ReadWrite.val());
     }
 
-    // Identity map UART
-    id_map_range(&mut root_pt, 0x1000_0000, 0x1000_0100, EntryBits::ReadWrite.val());
-
-    // Identity map CLINT
-    id_map_range(&mut root_pt, 0x0200_0000, 0x0200_ffff, EntryBits::ReadWrite.val());
-
-    // Identity map PLIC
-    id_map_range(&mut root_pt, 0x0c00_0000, 0x0c00_2001, EntryBits::ReadWrite.val());
-    id_map_range(&mut root_pt, 0x0c20_0000, 0x0c20_8001, EntryBits::ReadWrite.val());
+    // Identity map UART, CLINT and PLIC
+    let platform = fdt::platform();
+    for mmio in [platform.uart, platform.clint, platform.plic].iter() {
+        id_map_range(&mut root_pt, mmio.base, mmio.base + mmio.size, EntryBits::ReadWrite.val());
+    }
 
 	// When we return from here, we'll go back to boot.S and switch into
 	// supervisor mode We will return the SATP register to be written when
@@ -0,0 +1,272 @@
+// Virtio network device - sends and receives raw Ethernet frames. Received frames wait in a
+// queue until the network stack picks them up.
+use crate::kmem::{kfree, kmalloc};
+use crate::lock::Mutex;
+use crate::virtio::{self, Buf, MmioOffsets, Virtqueue};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::copy_nonoverlapping;
+
+// Feature bits
+pub const VIRTIO_NET_F_MAC: u32 = 5;
+pub const VIRTIO_NET_F_STATUS: u32 = 16;
+
+// Config status bits
+pub const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+// Queue numbers
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+
+// Config space offsets, in bytes
+const CONFIG_MAC: usize = 0;
+const CONFIG_STATUS: usize = 6;
+
+// Ethernet header and payload, without the frame check sequence
+pub const MAX_FRAME_SIZE: usize = 1514;
+// Receive buffers kept posted on the RX queue
+const RX_BUFFERS: usize = 16;
+// Frames that haven't been picked up yet. Anything over is dropped, like a full NIC would.
+const RX_QUEUE_LIMIT: usize = 64;
+
+// Goes in front of every frame in both directions. Legacy devices without
+// VIRTIO_NET_F_MRG_RXBUF leave out num_buffers.
+#[repr(C)]
+pub struct Header {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+    num_buffers: u16,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum NetErrors {
+    NoDevice,
+    // Larger than MAX_FRAME_SIZE or smaller than an Ethernet header
+    Invalid,
+    // Every TX descriptor is in use
+    QueueFull,
+    OutOfMemory,
+}
+
+pub struct NetDevice {
+    rx: Virtqueue,
+    tx: Virtqueue,
+    ptr: *mut u32,
+    mac: [u8; 6],
+    features: u64,
+    // Bytes of Header the device uses
+    header_len: usize,
+    // Posted receive buffers, each header_len + MAX_FRAME_SIZE, by token
+    rx_buffers: Vec<*mut u8>,
+    received: VecDeque<Vec<u8>>,
+    dropped: usize,
+}
+
+static mut NET_DEVICES: [Option<NetDevice>; 8] = [None, None, None, None, None, None, None, None];
+// The queues are shared between the network stack and the interrupt handler, on any hart
+static mut NET_LOCK: Mutex = Mutex::new();
+
+pub fn setup_network_device(ptr: *mut u32) -> bool {
+    unsafe {
+        let index = match virtio::slot(ptr) {
+            Some(index) => index,
+            None => return false,
+        };
+        // 1 - 6. Reset, acknowledge and agree on features
+        let supported =
+            virtio::RING_FEATURES | (1 << VIRTIO_NET_F_MAC) | (1 << VIRTIO_NET_F_STATUS);
+        let features = match virtio::negotiate_features(ptr, supported) {
+            Some(features) => features,
+            None => return false,
+        };
+
+        // 7. Perform device-specific setup
+        let rx = match Virtqueue::new(ptr, RX_QUEUE, features) {
+            Some(vq) => vq,
+            None => return false,
+        };
+        let tx = match Virtqueue::new(ptr, TX_QUEUE, features) {
+            Some(vq) => vq,
+            None => return false,
+        };
+        let header_len = if features & (1 << virtio::VIRTIO_F_VERSION_1) != 0 {
+            size_of::<Header>()
+        } else {
+            size_of::<Header>() - 2
+        };
+        let config = ptr.add(MmioOffsets::Config.scale32()) as *mut u8;
+        let mut mac = [0u8; 6];
+        if features & (1 << VIRTIO_NET_F_MAC) != 0 {
+            for (i, byte) in mac.iter_mut().enumerate() {
+                *byte = config.add(CONFIG_MAC + i).read_volatile();
+            }
+        } else {
+            // Locally administered, unique per slot
+            mac = [0x52, 0x54, 0x00, 0x00, 0x00, index as u8];
+        }
+        let mut nd = NetDevice {
+            rx,
+            tx,
+            ptr,
+            mac,
+            features,
+            header_len,
+            rx_buffers: Vec::with_capacity(RX_BUFFERS),
+            received: VecDeque::new(),
+            dropped: 0,
+        };
+        for token in 0..RX_BUFFERS {
+            let buffer = kmalloc(header_len + MAX_FRAME_SIZE);
+            if buffer.is_null() {
+                break;
+            }
+            nd.rx_buffers.push(buffer);
+            post_rx(&mut nd, token);
+        }
+        print!(
+            "mac {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}...",
+            mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+        );
+        NET_DEVICES[index] = Some(nd);
+        // 8. Set the DRIVER_OK status bit. Device is now 'live'
+        virtio::driver_ok(ptr);
+        // and can be told about the receive buffers
+        if let Some(nd) = NET_DEVICES[index].as_mut() {
+            nd.rx.kick();
+        }
+
+        true
+    }
+}
+
+// Hands a receive buffer back to the device
+fn post_rx(nd: &mut NetDevice, token: usize) {
+    let buf = Buf::new(
+        nd.rx_buffers[token],
+        (nd.header_len + MAX_FRAME_SIZE) as u32,
+        true,
+    );
+    nd.rx.add_buf(&[buf], token);
+}
+
+// Numbers of the network devices that were set up, in probe order
+pub fn devices() -> Vec<usize> {
+    let mut ret = Vec::new();
+    unsafe {
+        for (index, nd) in NET_DEVICES.iter().enumerate() {
+            if nd.is_some() {
+                ret.push(index + 1);
+            }
+        }
+    }
+    ret
+}
+
+// Runs f on a device with the lock held, None if there's no such device
+fn with_device<T>(dev: usize, f: impl FnOnce(&mut NetDevice) -> T) -> Option<T> {
+    unsafe {
+        let nd = match NET_DEVICES.get_mut(dev.wrapping_sub(1)) {
+            Some(Some(nd)) => nd,
+            _ => return None,
+        };
+        Some(NET_LOCK.with(|| f(nd)))
+    }
+}
+
+pub fn mac(dev: usize) -> Option<[u8; 6]> {
+    with_device(dev, |nd| nd.mac)
+}
+
+// Devices that don't report their link status are always up
+pub fn link_up(dev: usize) -> bool {
+    with_device(dev, |nd| {
+        if nd.features & (1 << VIRTIO_NET_F_STATUS) == 0 {
+            return true;
+        }
+        let config = unsafe { nd.ptr.add(MmioOffsets::Config.scale32()) as *mut u8 };
+        let status = unsafe { (config.add(CONFIG_STATUS) as *mut u16).read_volatile() };
+        status & VIRTIO_NET_S_LINK_UP != 0
+    })
+    .unwrap_or(false)
+}
+
+// Queues an Ethernet frame, without the frame check sequence, for sending. Returns once the
+// device has it - it's freed when the device is done with it.
+pub fn send(dev: usize, frame: &[u8]) -> Result<(), NetErrors> {
+    if frame.len() < 14 || frame.len() > MAX_FRAME_SIZE {
+        return Err(NetErrors::Invalid);
+    }
+    with_device(dev, |nd| {
+        // The header and frame share an allocation, which is the token
+        let packet = kmalloc(nd.header_len + frame.len());
+        if packet.is_null() {
+            return Err(NetErrors::OutOfMemory);
+        }
+        unsafe {
+            for i in 0..nd.header_len {
+                packet.add(i).write(0);
+            }
+            copy_nonoverlapping(frame.as_ptr(), packet.add(nd.header_len), frame.len());
+        }
+        let header = Buf::new(packet, nd.header_len as u32, false);
+        let data = Buf::new(
+            unsafe { packet.add(nd.header_len) },
+            frame.len() as u32,
+            false,
+        );
+        if false == nd.tx.add_buf(&[header, data], packet as usize) {
+            kfree(packet);
+            return Err(NetErrors::QueueFull);
+        }
+        nd.tx.kick();
+        Ok(())
+    })
+    .unwrap_or(Err(NetErrors::NoDevice))
+}
+
+// The oldest frame received and not picked up yet, without the virtio header
+pub fn receive(dev: usize) -> Option<Vec<u8>> {
+    with_device(dev, |nd| nd.received.pop_front()).flatten()
+}
+
+// Frames dropped because nobody picked them up in time
+pub fn dropped(dev: usize) -> usize {
+    with_device(dev, |nd| nd.dropped).unwrap_or(0)
+}
+
+pub fn handle_interrupt(dev_id: usize) {
+    unsafe { NET_LOCK.with(|| handle_frames(dev_id)) }
+}
+
+unsafe fn handle_frames(dev_id: usize) {
+    let nd = match NET_DEVICES[dev_id].as_mut() {
+        Some(nd) => nd,
+        None => {
+            println!("Invalid network device for interrupt {}", dev_id + 1);
+            return;
+        }
+    };
+    // Sent frames can be freed
+    nd.tx.process_used(|token, _| kfree(token as *mut u8));
+
+    let mut done = Vec::new();
+    nd.rx
+        .process_used(|token, len| done.push((token, len as usize)));
+    for (token, len) in done {
+        let frame_len = len.saturating_sub(nd.header_len).min(MAX_FRAME_SIZE);
+        if nd.received.len() >= RX_QUEUE_LIMIT {
+            nd.dropped += 1;
+        } else if frame_len > 0 {
+            let start = nd.rx_buffers[token].add(nd.header_len);
+            let frame = core::slice::from_raw_parts(start, frame_len);
+            nd.received.push_back(frame.to_vec());
+        }
+        post_rx(nd, token);
+    }
+    nd.rx.kick();
+}
@@ -1,7 +1,13 @@
 use core::{mem::size_of, ptr::null_mut};
-use crate::{HEAP_SIZE, HEAP_START};
+use crate::fdt;
+use crate::lock::Mutex;
+use crate::HEAP_START;
 
 static mut ALLOC_START: usize = 0;
+// Bytes of pages handed out, from ALLOC_START. Set from the device tree by init.
+static mut HEAP_SIZE: usize = 0;
+// Every hart allocates pages, the descriptors are only touched with it held
+static mut PAGE_LOCK: Mutex = Mutex::new();
 const PAGE_ORDER: usize = 12;
 pub const PAGE_SIZE: usize = 1 << 12;
 
@@ -26,6 +32,8 @@ impl PageBits {
 
 pub struct Page {
     flags: u8,
+    // Extra references to this page, e.g. from address spaces sharing it copy-on-write
+    refs: u16,
 }
 
 impl Page {
@@ -54,6 +62,7 @@ impl Page {
 
     pub fn clear(&mut self) {
         self.flags = PageBits::Empty.val();
+        self.refs = 0;
     }
 
     pub fn set_flag(&mut self, flag: PageBits) {
@@ -68,6 +77,9 @@ impl Page {
 
 pub fn init() {
     unsafe {
+        // Everything up to the end of RAM, less room for the descriptors in front of the pages
+        let available = fdt::platform().heap_end(HEAP_START) - HEAP_START;
+        HEAP_SIZE = (available - PAGE_SIZE) / (PAGE_SIZE + size_of::<Page>()) * PAGE_SIZE;
         let num_pages = HEAP_SIZE / PAGE_SIZE;
         let ptr = HEAP_START as *mut Page;
         // Clear all pages
@@ -80,33 +92,35 @@ pub fn init() {
 
 pub fn alloc(pages: usize) -> *mut u8 {
     assert!(pages > 0);
-    unsafe {
-        let num_pages = HEAP_SIZE / PAGE_SIZE;
-        let ptr = HEAP_START as *mut Page;
-        for i in 0..num_pages - pages {
-            let mut found = false;
-            if(*ptr.add(i)).is_free() {
-                found = true;
-                // Found 1 free page - now check if we've got enough contiguous memory
-                for j in i..i + pages {
-                    if (*ptr.add(j)).is_taken() {
-                        found = false;
-                        break;
-                    }
-                }
-            }
+    unsafe { PAGE_LOCK.with(|| alloc_locked(pages)) }
+}
 
-            if found {
-                // Set all pages as taken
-                for k in i..i + pages - 1 {
-                    (*ptr.add(k)).set_flag(PageBits::Taken);
+unsafe fn alloc_locked(pages: usize) -> *mut u8 {
+    let num_pages = HEAP_SIZE / PAGE_SIZE;
+    let ptr = HEAP_START as *mut Page;
+    for i in 0..num_pages - pages {
+        let mut found = false;
+        if(*ptr.add(i)).is_free() {
+            found = true;
+            // Found 1 free page - now check if we've got enough contiguous memory
+            for j in i..i + pages {
+                if (*ptr.add(j)).is_taken() {
+                    found = false;
+                    break;
                 }
+            }
+        }
 
-                // Set last page as Last
-                (*ptr.add(i + pages - 1)).set_flag(PageBits::Taken);
-                (*ptr.add(i + pages - 1)).set_flag(PageBits::Last);
-                return (ALLOC_START + PAGE_SIZE * i) as *mut u8;
+        if found {
+            // Set all pages as taken
+            for k in i..i + pages - 1 {
+                (*ptr.add(k)).set_flag(PageBits::Taken);
             }
+
+            // Set last page as Last
+            (*ptr.add(i + pages - 1)).set_flag(PageBits::Taken);
+            (*ptr.add(i + pages - 1)).set_flag(PageBits::Last);
+            return (ALLOC_START + PAGE_SIZE * i) as *mut u8;
         }
     }
 
@@ -129,21 +143,65 @@ pub fn zalloc(pages: usize) -> *mut u8 {
     ret
 }
 
-pub fn dealloc(ptr: *mut u8) {
-    assert!(!ptr.is_null());
+// Returns the descriptor for an allocated page
+fn get_page(ptr: *mut u8) -> *mut Page {
     unsafe {
-        let addr = HEAP_START + (ptr as usize - ALLOC_START) / PAGE_SIZE;
+        let index = (ptr as usize - ALLOC_START) / PAGE_SIZE;
+        let addr = (HEAP_START as *mut Page).add(index) as usize;
         assert!(addr >= HEAP_START && addr < HEAP_START + HEAP_SIZE);
-        let mut p = addr as *mut Page;
-        while (*p).is_taken() && !(*p).is_last() {
-            (*p).clear();
-            p = p.add(1);
-        }
+        addr as *mut Page
+    }
+}
 
-        assert!((*p).is_last() == true, "Possible double-free detected!");
+pub fn dealloc(ptr: *mut u8) {
+    assert!(!ptr.is_null());
+    unsafe { PAGE_LOCK.with(|| dealloc_locked(ptr)) }
+}
 
+unsafe fn dealloc_locked(ptr: *mut u8) {
+    let mut p = get_page(ptr);
+    while (*p).is_taken() && !(*p).is_last() {
         (*p).clear();
+        p = p.add(1);
     }
+
+    assert!((*p).is_last() == true, "Possible double-free detected!");
+
+    (*p).clear();
+}
+
+// Adds a reference to a single page, so it survives until every holder releases it
+pub fn share(ptr: *mut u8) {
+    unsafe {
+        PAGE_LOCK.with(|| {
+            let p = get_page(ptr);
+            assert!((*p).is_taken(), "Sharing a free page!");
+            (*p).refs = (*p).refs.checked_add(1).expect("Too many references to a page");
+        })
+    }
+}
+
+// Drops a reference to a single page, deallocating it when it was the last one
+pub fn release(ptr: *mut u8) {
+    unsafe {
+        PAGE_LOCK.with(|| {
+            let p = get_page(ptr);
+            if (*p).refs > 0 {
+                (*p).refs -= 1;
+            } else {
+                dealloc_locked(ptr);
+            }
+        })
+    }
+}
+
+// Returns whether more than one holder references the page
+pub fn is_shared(ptr: *mut u8) -> bool {
+    unsafe { PAGE_LOCK.with(|| (*get_page(ptr)).refs > 0) }
+}
+
+pub fn heap_size() -> usize {
+    unsafe { HEAP_SIZE }
 }
 
 /// Debugging functions
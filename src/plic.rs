@@ -1,16 +1,37 @@
 // Platform Level Interrupt Controller (PLIC)
-use crate::{uart, virtio};
+use crate::{cpu, fdt, uart, virtio};
 
-const PLIC_PRIORITY: usize = 0x0c00_0000;
-const PLIC_PENDING: usize = 0x0c00_1000;
-const PLIC_INT_ENABLE: usize = 0x0c00_2000;
-const PLIC_THRESHOLD: usize = 0x0c20_0000;
-const PLIC_CLAIM: usize = 0x0c20_0004;
+// Register offsets from the PLIC's base, context 0's where it matters
+const PLIC_PRIORITY: usize = 0x0000;
+const PLIC_PENDING: usize = 0x1000;
+const PLIC_INT_ENABLE: usize = 0x2000;
+const PLIC_THRESHOLD: usize = 0x20_0000;
+const PLIC_CLAIM: usize = 0x20_0004;
+// How far apart the contexts' registers are
+const PLIC_ENABLE_STRIDE: usize = 0x80;
+const PLIC_CONTEXT_STRIDE: usize = 0x1000;
+
+fn reg(offset: usize) -> *mut u32 {
+    (fdt::platform().plic.base + offset) as *mut u32
+}
+
+// The context interrupts are taken in. QEMU gives each hart a machine mode context (2 * hart)
+// and a supervisor mode one after it - hart 0's machine mode one, or under the firmware the
+// supervisor mode one of the hart the kernel's running on.
+fn context() -> usize {
+    if cfg!(feature = "sbi") {
+        2 * cpu::hartid() + 1
+    } else {
+        0
+    }
+}
 
 // Enable an interrupt
 pub fn enable(id: u32) {
-    let enables = PLIC_INT_ENABLE as *mut u32;
-    let actual_id = 1 << id;
+    // 32 interrupts to each enable register
+    let enables = reg(PLIC_INT_ENABLE + context() * PLIC_ENABLE_STRIDE);
+    let enables = unsafe { enables.add(id as usize / 32) };
+    let actual_id = 1 << (id % 32);
     unsafe {
         enables.write_volatile(enables.read_volatile() | actual_id);
     }
@@ -19,7 +40,7 @@ pub fn enable(id: u32) {
 // Set priority of an interrupt - a number between 0-7
 pub fn set_priority(id: u32, priority: u8) {
     let actual_prior = priority & 0b111; // we only use last 3 bits
-    let prior_reg = PLIC_PRIORITY as *mut u32;
+    let prior_reg = reg(PLIC_PRIORITY);
     unsafe {
         // Offset is PLIC_PRIORITY + 4 * id (we're using u32)
         prior_reg.add(id as usize).write_volatile(actual_prior as u32);
@@ -29,7 +50,7 @@ pub fn set_priority(id: u32, priority: u8) {
 // Set threshold - minimum priority for an interrupt to trigger
 pub fn set_threshold(threshold: u8) {
     let actual_thresh = threshold & 0b111; // we only use last 3 bits
-    let thresh_reg = PLIC_THRESHOLD as *mut u32;
+    let thresh_reg = reg(PLIC_THRESHOLD + context() * PLIC_CONTEXT_STRIDE);
     unsafe {
         thresh_reg.write_volatile(actual_thresh as u32);
     }
@@ -37,7 +58,7 @@ pub fn set_threshold(threshold: u8) {
 
 // Read next pending interrupt
 pub fn next() -> Option<u32> {
-    let claim_reg = PLIC_CLAIM as *const u32;
+    let claim_reg = reg(PLIC_CLAIM + context() * PLIC_CONTEXT_STRIDE) as *const u32;
     let claim_no;
     unsafe {
         claim_no = claim_reg.read_volatile();
@@ -51,7 +72,7 @@ pub fn next() -> Option<u32> {
 
 // Mark interrupt as handled
 pub fn complete(id: u32) {
-    let complete_reg = PLIC_CLAIM as *mut u32;
+    let complete_reg = reg(PLIC_CLAIM + context() * PLIC_CONTEXT_STRIDE);
     unsafe {
         // We just write a u32 to the whole register
         complete_reg.write_volatile(id);
@@ -60,8 +81,8 @@ pub fn complete(id: u32) {
 
 // Checks if an interrupt is currently pending
 pub fn is_pending(id: u32) -> bool {
-    let pend = PLIC_PENDING as *const u32;
-    let actual_id = 1 << id;
+    let pend = unsafe { reg(PLIC_PENDING).add(id as usize / 32) as *const u32 };
+    let actual_id = 1 << (id % 32);
     let pending_ids;
     unsafe {
         pending_ids = pend.read_volatile();
@@ -71,19 +92,16 @@ pub fn is_pending(id: u32) -> bool {
 
 pub fn handle_interrupt() {
     if let Some(interrupt) = next() {
-        match interrupt {
-            1..=8 => {
-                // VIRTIO interrupt
-                // println!("virtio interrupt");
-                virtio::handle_interrupt(interrupt);
-            },
-            10 => {
-                // UART interrupt
-                uart::handle_interrupt();
-            },
-            _ => {
-                println!("Unkown external interrupt: {}", interrupt);
-            }
+        let platform = fdt::platform();
+        if interrupt == platform.uart.irq {
+            // UART interrupt
+            uart::handle_interrupt();
+        } else if let Some(slot) = platform.virtio_slot(interrupt) {
+            // VIRTIO interrupt
+            // println!("virtio interrupt");
+            virtio::handle_interrupt(slot);
+        } else {
+            println!("Unkown external interrupt: {}", interrupt);
         }
         complete(interrupt);
     }
@@ -1,22 +1,48 @@
-use crate::cpu::{CpuMode, MachineTime, Registers, TrapFrame};
+use crate::buffer::Buffer;
+use crate::cpu::{
+    build_satp, flush_asid_all_harts, satp_fence, CpuMode, MachineTime, Registers, SatpMode,
+    TrapFrame, MAX_HARTS,
+};
+use crate::elf;
+use crate::fdt;
 use crate::lock::Mutex;
-use crate::mmu::{map, unmap, EntryBits, Table};
+use crate::mmu::{
+    clone_cow, copy_from_virt, copy_to_virt, get_leaf, map, resolve_cow, unmap, virt_to_phys,
+    EntryBits, Table,
+};
 use crate::page::{alloc, dealloc, zalloc, PAGE_SIZE};
 use crate::syscall::{exit_process, yield_process};
+use crate::vfs::{self, OpenFile};
+use alloc::boxed::Box;
 use alloc::collections::vec_deque::VecDeque;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::ptr::null_mut;
 
 // Pages to allocate for stack
-const STACK_PAGES: usize = 2;
+pub const STACK_PAGES: usize = 2;
 
 // Stack address in process' virtual memory
-const STACK_ADDR: usize = 0xf_0000_0000;
+pub const STACK_ADDR: usize = 0xf_0000_0000;
 
-pub static mut PROCESS_LIST: Option<VecDeque<Process>> = None;
+// Where mmap looks for free address space, above the stack and up to the top of Sv39's lower
+// half
+pub const MMAP_ADDR: usize = 0x20_0000_0000;
+pub const MMAP_END: usize = 0x40_0000_0000;
+
+// Boxed, so a process stays put while the list is rotated or grows - get_by_pid hands out
+// pointers to them
+pub static mut PROCESS_LIST: Option<VecDeque<Box<Process>>> = None;
 pub static mut PROCESS_LIST_MUTEX: Mutex = Mutex::new();
 
 static mut NEXT_PID: u16 = 1;
 
+// Limits on what execv will copy out of the caller's memory
+pub const MAX_PATH_LEN: usize = 256;
+pub const MAX_ARGS: usize = 64;
+pub const MAX_ARG_LEN: usize = 1024;
+
 // idle process - just constantly yields
 fn idle_process() {
     loop {
@@ -25,112 +51,289 @@ fn idle_process() {
     }
 }
 
+// Runs f on the process list with PROCESS_LIST_MUTEX held. Every hart schedules from it, so
+// nothing looks at the list any other way. None if there's no list yet.
+// Can be used in interrupt context, f mustn't block or take the list again
+pub fn with_process_list<T>(f: impl FnOnce(&mut VecDeque<Box<Process>>) -> T) -> Option<T> {
+    unsafe { PROCESS_LIST_MUTEX.with(|| PROCESS_LIST.as_mut().map(f)) }
+}
+
+fn next_pid() -> u16 {
+    unsafe {
+        PROCESS_LIST_MUTEX.with(|| {
+            let pid = NEXT_PID;
+            NEXT_PID += 1;
+            pid
+        })
+    }
+}
+
+// Puts a process on the list, returns its PID or 0 if it couldn't be
+fn push_process(proc: Process) -> u16 {
+    // Failed to start process
+    try_push_process(proc).unwrap_or(0)
+}
+
+// Like push_process, but hands the process back if it couldn't be added
+fn try_push_process(proc: Process) -> Result<u16, Box<Process>> {
+    let pid = proc.pid;
+    let mut proc = Some(Box::new(proc));
+    with_process_list(|pl| pl.push_back(proc.take().unwrap()));
+    match proc {
+        None => Ok(pid),
+        Some(proc) => Err(proc),
+    }
+}
+
 pub fn init() {
     unsafe {
-        PROCESS_LIST_MUTEX.spin_lock();
-        PROCESS_LIST = Some(VecDeque::with_capacity(15));
-        PROCESS_LIST_MUTEX.unlock();
+        PROCESS_LIST_MUTEX.with(|| PROCESS_LIST = Some(VecDeque::with_capacity(15)));
+    }
+    // Every hart needs something to run when nothing else wants to
+    let harts = fdt::platform().harts.min(MAX_HARTS);
+    for _ in 0..harts {
         let pid = add_kernel_process(idle_process);
         println!("idle process is {}", pid);
-        let pl = PROCESS_LIST.take().unwrap();
-        let p = pl.front().unwrap().frame;
-        let frame = p as *const TrapFrame as usize;
-        println!("Init's frame is at 0x{:08x}", frame);
-        PROCESS_LIST.replace(pl);
-        PROCESS_LIST_MUTEX.unlock();
     }
+    let frame = with_process_list(|pl| pl.front().unwrap().frame as usize).unwrap();
+    println!("Init's frame is at 0x{:08x}", frame);
 }
 
 pub fn set_running(pid: u16) -> bool {
-    let mut retval = false;
-    unsafe {
-        if let Some(mut pl) = PROCESS_LIST.take() {
-            for proc in pl.iter_mut() {
-                if proc.pid == pid {
-                    // println!("awaking {}", pid);
-                    proc.state = ProcessState::Running;
-                    retval = true;
-                    break;
-                }
+    with_process_list(|pl| {
+        for proc in pl.iter_mut() {
+            if proc.pid == pid && proc.state != ProcessState::Dead {
+                // println!("awaking {}", pid);
+                proc.state = ProcessState::Running;
+                return true;
             }
-            PROCESS_LIST.replace(pl);
         }
-    }
-    retval
+        false
+    })
+    .unwrap_or(false)
 }
 
 pub fn set_waiting(pid: u16) -> bool {
-    let mut retval = false;
-    unsafe {
-        if let Some(mut pl) = PROCESS_LIST.take() {
-            for proc in pl.iter_mut() {
-                if proc.pid == pid {
-                    // println!("marking {} as waiting", pid);
-                    proc.state = ProcessState::Waiting;
-                    retval = true;
-                    break;
-                }
+    with_process_list(|pl| {
+        for proc in pl.iter_mut() {
+            if proc.pid == pid && proc.state != ProcessState::Dead {
+                // println!("marking {} as waiting", pid);
+                proc.state = ProcessState::Waiting;
+                return true;
             }
-            PROCESS_LIST.replace(pl);
         }
-    }
-    retval
+        false
+    })
+    .unwrap_or(false)
 }
 
 pub fn set_sleeping(pid: u16, sleep_until: MachineTime) -> bool {
-    let mut retval = false;
+    with_process_list(|pl| {
+        for proc in pl.iter_mut() {
+            if proc.pid == pid && proc.state != ProcessState::Dead {
+                proc.state = ProcessState::Sleeping;
+                proc.sleep_until = sleep_until;
+                return true;
+            }
+        }
+        false
+    })
+    .unwrap_or(false)
+}
+
+// Wait status for a process that exited normally
+pub const fn exit_status(code: usize) -> u32 {
+    ((code & 0xff) << 8) as u32
+}
+
+// Wait status for a process that was killed, e.g. by a fault
+pub const fn killed_status(signal: u32) -> u32 {
+    signal & 0x7f
+}
+
+// Signal numbers reported for processes killed by a trap
+pub const SIGILL: u32 = 4;
+pub const SIGBUS: u32 = 7;
+pub const SIGSEGV: u32 = 11;
+
+pub const WNOHANG: usize = 1;
+
+// Hands a zombie's status to a parent blocked in waitpid, returns false if the parent isn't
+// waiting for it
+fn complete_wait(parent: &mut Process, child_pid: u16, status: u32) -> bool {
+    let matches = match &parent.wait_for {
+        Some(wait_for) => wait_for.pid == -1 || wait_for.pid == child_pid as isize,
+        None => false,
+    };
+    if !matches || parent.state != ProcessState::Waiting {
+        return false;
+    }
+
+    let status_addr = parent.wait_for.take().unwrap().status_addr;
+    // The child is reaped either way, like Linux does when the status can't be stored
+    let mut ret = child_pid as usize;
+    if status_addr != 0
+        && parent.copy_to_user(status_addr, &status as *const u32 as *const u8, 4) != 4
+    {
+        ret = usize::MAX;
+    }
     unsafe {
-        if let Some(mut pl) = PROCESS_LIST.take() {
-            for proc in pl.iter_mut() {
-                if proc.pid == pid {
-                    proc.state = ProcessState::Sleeping;
-                    proc.sleep_until = sleep_until;
-                    retval = true;
-                    break;
-                }
+        (*parent.frame).regs[Registers::A0 as usize] = ret;
+    }
+    parent.state = ProcessState::Running;
+    true
+}
+
+// Ends a process. It stays around as a zombie until its parent collects the status with
+// waitpid - processes without a parent are deleted straight away.
+pub fn set_dead(pid: u16, status: u32) {
+    let files = with_process_list(|pl| {
+        let mut parent_pid = 0;
+        let mut files = Vec::new();
+        for proc in pl.iter_mut() {
+            if proc.pid == pid {
+                proc.state = ProcessState::Dead;
+                proc.exit_status = status;
+                parent_pid = proc.parent;
+                let open = core::mem::replace(&mut proc.data.files, BTreeMap::new());
+                files.extend(open.into_iter().map(|(_, file)| file));
+            } else if proc.parent == pid {
+                // Orphaned children have nobody to report to
+                proc.parent = 0;
             }
-            PROCESS_LIST.replace(pl);
         }
+
+        let mut reaped = true;
+        if parent_pid != 0 {
+            reaped = match pl.iter_mut().find(|proc| proc.pid == parent_pid) {
+                Some(parent) => complete_wait(parent, pid, status),
+                None => true,
+            };
+        }
+        if reaped {
+            if let Some(proc) = pl.iter_mut().find(|proc| proc.pid == pid) {
+                proc.parent = 0;
+            }
+        }
+
+        // Drop this process if it's been reaped, along with any orphaned zombies
+        drop_reaped(pl);
+        files
+    });
+    if let Some(files) = files {
+        // Closing files may block, so it happens in a kernel process
+        vfs::close_files(files);
     }
-    retval
 }
 
-pub fn delete_process(pid: u16) -> bool {
-    let mut retval = false;
-    unsafe {
-        if let Some(mut pl) = PROCESS_LIST.take() {
-            for i in 0..pl.len() {
-                let p = pl.get_mut(i).unwrap();
-                if (*(*p).frame).pid as u16 == pid {
-                    pl.remove(i);
-                    retval = true;
+// Drops dead processes nobody's going to collect the status of. One that a hart's still on
+// its way out of is left for that hart's scheduler.
+pub fn drop_reaped(pl: &mut VecDeque<Box<Process>>) {
+    pl.retain(|proc| {
+        !(proc.state == ProcessState::Dead && proc.parent == 0 && proc.hart.is_none())
+    });
+}
+
+// waitpid - collects the status of a dead child. Returns the value for A0 if the call
+// completes straight away, or None if the caller has been put to sleep until a child exits.
+pub fn wait_child(pid: u16, target: isize, status_addr: usize, options: usize) -> Option<usize> {
+    with_process_list(|pl| {
+        let target = if target > 0 { target } else { -1 };
+        let mut ret = Some(usize::MAX);
+        let mut zombie = None;
+        for proc in pl.iter() {
+            if proc.parent == pid && (target == -1 || proc.pid as isize == target) {
+                if proc.state == ProcessState::Dead {
+                    zombie = Some((proc.pid, proc.exit_status));
                     break;
                 }
+                // There's a live child, so we'd have something to wait for
+                ret = if options & WNOHANG != 0 {
+                    Some(0)
+                } else {
+                    None
+                };
             }
-            PROCESS_LIST.replace(pl);
         }
-    }
-    retval
+
+        if let Some((child_pid, status)) = zombie {
+            let mut stored = true;
+            if let Some(caller) = pl.iter().find(|proc| proc.pid == pid) {
+                if status_addr != 0 {
+                    stored =
+                        caller.copy_to_user(status_addr, &status as *const u32 as *const u8, 4)
+                            == 4;
+                }
+            }
+            if let Some(child) = pl.iter_mut().find(|proc| proc.pid == child_pid) {
+                child.parent = 0;
+            }
+            drop_reaped(pl);
+            ret = Some(if stored {
+                child_pid as usize
+            } else {
+                usize::MAX
+            });
+        } else if ret.is_none() {
+            if let Some(caller) = pl.iter_mut().find(|proc| proc.pid == pid) {
+                caller.state = ProcessState::Waiting;
+                caller.wait_for = Some(WaitFor {
+                    pid: target,
+                    status_addr,
+                });
+            }
+        }
+        ret
+    })
+    .unwrap_or(Some(usize::MAX))
 }
 
+// Only for a process that can't go away under the caller - the one making the syscall, or one
+// waiting on the caller. Anything else goes through with_process, or finish_syscall.
 pub unsafe fn get_by_pid(pid: u16) -> *mut Process {
-    let mut ret = null_mut();
-    if let Some(mut pl) = PROCESS_LIST.take() {
-        for i in pl.iter_mut() {
-            if (*(i.frame)).pid as u16 == pid {
-                ret = i as *mut Process;
-                break;
-            }
+    with_process_list(|pl| {
+        pl.iter_mut()
+            .find(|i| (*(i.frame)).pid as u16 == pid)
+            .map(|i| &mut **i as *mut Process)
+    })
+    .flatten()
+    .unwrap_or(null_mut())
+}
+
+// Runs f on a process with the list held, so it can't be dropped in the meantime. None if
+// there's no such process.
+// Can be used in interrupt context, f mustn't block or print
+pub fn with_process<T>(pid: u16, f: impl FnOnce(&mut Process) -> T) -> Option<T> {
+    with_process_list(|pl| {
+        pl.iter_mut()
+            .find(|proc| proc.pid == pid)
+            .map(|proc| f(proc))
+    })
+    .flatten()
+}
+
+// Ends a syscall the process was left waiting on: whatever f returns goes in its A0, and it's
+// woken. False if it's gone or dead, and then f isn't run.
+// Can be used in interrupt context, f mustn't block or print
+pub fn finish_syscall(pid: u16, f: impl FnOnce(&mut Process) -> usize) -> bool {
+    with_process(pid, |proc| {
+        if proc.state == ProcessState::Dead {
+            return false;
         }
-        PROCESS_LIST.replace(pl);
-    }
-    ret
+        let ret = f(proc);
+        unsafe {
+            (*proc.frame).regs[Registers::A0 as usize] = ret;
+        }
+        proc.state = ProcessState::Running;
+        true
+    })
+    .unwrap_or(false)
 }
 
 pub fn add_kernel_process(func: fn()) -> u16 {
     let func_addr = func as usize;
     let func_vaddr = func_addr;
-    let my_pid = unsafe { NEXT_PID };
+    let my_pid = next_pid();
     let mut ret_proc = Process {
         frame: zalloc(1) as *mut TrapFrame,
         stack: alloc(STACK_PAGES),
@@ -141,8 +344,11 @@ pub fn add_kernel_process(func: fn()) -> u16 {
         sleep_until: MachineTime::zero(),
         program: null_mut(),
         brk: 0,
+        parent: 0,
+        exit_status: 0,
+        wait_for: None,
+        hart: None,
     };
-    unsafe { NEXT_PID += 1 };
 
     // Move stack pointer to the very bottom of the allocation
     unsafe {
@@ -154,26 +360,13 @@ pub fn add_kernel_process(func: fn()) -> u16 {
         (*ret_proc.frame).pid = ret_proc.pid as usize;
     }
 
-    if let Some(mut pl) = unsafe { PROCESS_LIST.take() } {
-        pl.push_back(ret_proc);
-        unsafe {
-            PROCESS_LIST.replace(pl);
-            PROCESS_LIST_MUTEX.unlock();
-        }
-        my_pid
-    } else {
-        // Failed to start process
-        unsafe {
-            PROCESS_LIST_MUTEX.unlock();
-        }
-        0
-    }
+    push_process(ret_proc)
 }
 
 pub fn add_kernel_process_args(func: fn(args_ptr: usize), args: usize) -> u16 {
     let func_addr = func as usize;
     let func_vaddr = func_addr;
-    let my_pid = unsafe { NEXT_PID };
+    let my_pid = next_pid();
     let mut ret_proc = Process {
         frame: zalloc(1) as *mut TrapFrame,
         stack: alloc(STACK_PAGES),
@@ -184,8 +377,11 @@ pub fn add_kernel_process_args(func: fn(args_ptr: usize), args: usize) -> u16 {
         sleep_until: MachineTime::zero(),
         program: null_mut(),
         brk: 0,
+        parent: 0,
+        exit_status: 0,
+        wait_for: None,
+        hart: None,
     };
-    unsafe { NEXT_PID += 1 };
 
     // Move stack pointer to the very bottom of the allocation
     unsafe {
@@ -198,26 +394,13 @@ pub fn add_kernel_process_args(func: fn(args_ptr: usize), args: usize) -> u16 {
         (*ret_proc.frame).pid = ret_proc.pid as usize;
     }
 
-    if let Some(mut pl) = unsafe { PROCESS_LIST.take() } {
-        pl.push_back(ret_proc);
-        unsafe {
-            PROCESS_LIST.replace(pl);
-            PROCESS_LIST_MUTEX.unlock();
-        }
-        my_pid
-    } else {
-        // Failed to start process
-        unsafe {
-            PROCESS_LIST_MUTEX.unlock();
-        }
-        0
-    }
+    push_process(ret_proc)
 }
 
 pub fn add_user_process(func: fn()) -> u16 {
     let func_addr = func as usize;
     let func_vaddr = func_addr;
-    let my_pid = unsafe { NEXT_PID };
+    let my_pid = next_pid();
     let mut ret_proc = Process {
         frame: zalloc(1) as *mut TrapFrame,
         stack: alloc(STACK_PAGES),
@@ -228,8 +411,11 @@ pub fn add_user_process(func: fn()) -> u16 {
         sleep_until: MachineTime::zero(),
         program: null_mut(),
         brk: 0,
+        parent: 0,
+        exit_status: 0,
+        wait_for: None,
+        hart: None,
     };
-    unsafe { NEXT_PID += 1 };
 
     // Move stack pointer to the very bottom of the allocation
     let saddr = ret_proc.stack as usize;
@@ -288,30 +474,224 @@ pub fn add_user_process(func: fn()) -> u16 {
         0,
     );
 
+    push_process(ret_proc)
+}
+
+// Loads an ELF executable and starts it as a user process
+// don't run inside interrupt context - will block
+pub fn add_elf_process(path: &str) -> u16 {
+    match load_program(path, &[], &[]) {
+        Ok(proc) => add_process(proc).unwrap_or(0),
+        Err(e) => {
+            println!("Can't load program {}: {:?}", path, e);
+            0
+        }
+    }
+}
+
+// Reads an executable into a new process image, with argv/envp laid out on its stack
+// don't run inside interrupt context - will block
+fn load_program(path: &str, argv: &[String], envp: &[String]) -> Result<Process, elf::LoadErrors> {
+    let (_, fs, inode_num) = vfs::lookup(path).ok_or(elf::LoadErrors::NotFound)?;
+    let size = fs.stat(inode_num).ok_or(elf::LoadErrors::NotFound)?.size as u32;
+    let mut buffer = Buffer::new(size as usize);
+    if fs.read(inode_num, buffer.get_mut(), size, 0) != size {
+        return Err(elf::LoadErrors::Read);
+    }
+
+    let elf = elf::File::load(&buffer)?;
+    let proc = elf.load_proc()?;
+    let sp = elf::init_stack(unsafe { &mut *proc.root_table }, argv, envp, &elf.auxv())?;
     unsafe {
-        PROCESS_LIST_MUTEX.spin_lock();
+        (*proc.frame).regs[Registers::Sp as usize] = sp;
     }
-    if let Some(mut pl) = unsafe { PROCESS_LIST.take() } {
-        pl.push_back(ret_proc);
-        unsafe {
-            PROCESS_LIST.replace(pl);
-            PROCESS_LIST_MUTEX.unlock();
+
+    Ok(proc)
+}
+
+struct ExecArgs {
+    pub pid: u16,
+    pub path: String,
+    pub argv: Vec<String>,
+    pub envp: Vec<String>,
+}
+
+// run inside the exec process
+fn execv_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut ExecArgs) };
+    let result = load_program(&args.path, &args.argv, &args.envp);
+
+    match result {
+        Ok(mut new_proc) => {
+            // Swap the new image into the caller, keeping its PID, frame and open files.
+            // The old memory is freed when new_proc is dropped, once the list is let go of.
+            with_process(args.pid, |proc| unsafe {
+                core::mem::swap(&mut proc.root_table, &mut new_proc.root_table);
+                core::mem::swap(&mut proc.stack, &mut new_proc.stack);
+                core::mem::swap(&mut proc.program, &mut new_proc.program);
+                proc.brk = 0;
+                let frame = proc.frame;
+                (*frame).regs = (*new_proc.frame).regs;
+                (*frame).fregs = [0; 32];
+                (*frame).pc = (*new_proc.frame).pc;
+                (*frame).mode = CpuMode::User as usize;
+                (*frame).satp =
+                    build_satp(SatpMode::Sv39, args.pid as usize, proc.root_table as usize);
+            });
+            // Entries for the old table may still be cached under the same ASID, on any hart
+            // that ran it. Not under the list lock, since the other harts have to answer.
+            flush_asid_all_harts(args.pid as usize);
+            set_running(args.pid);
         }
-        my_pid
-    } else {
-        // Failed to start process
-        unsafe {
-            PROCESS_LIST_MUTEX.unlock();
+        Err(e) => {
+            println!("execv {} failed: {:?}", args.path, e);
+            finish_syscall(args.pid, |_| e.errno());
+        }
+    }
+}
+
+// called by syscall - marks current process as waiting, and spawns a new process to replace
+// its image with the program at path
+pub fn process_execv(pid: u16, path: String, argv: Vec<String>, envp: Vec<String>) {
+    let args = ExecArgs {
+        pid,
+        path,
+        argv,
+        envp,
+    };
+    let boxed_args = Box::new(args);
+    set_waiting(pid);
+    add_kernel_process_args(execv_proc, Box::into_raw(boxed_args) as usize);
+}
+
+// Duplicates a process for fork. The child shares the parent's pages copy-on-write and
+// resumes from the same point, with 0 in A0. Returns the child's PID, or 0 on failure.
+// Only processes with their own address space (e.g. loaded from ELF) can be forked, since
+// kernel processes keep physical pointers into their stacks.
+pub fn fork_process(pid: u16) -> u16 {
+    unsafe {
+        let parent = get_by_pid(pid);
+        if parent.is_null() || (*(*parent).frame).satp >> 60 == 0 {
+            return 0;
+        }
+
+        let child = Process {
+            frame: zalloc(1) as *mut TrapFrame,
+            stack: null_mut(),
+            pid: 0,
+            root_table: zalloc(1) as *mut Table,
+            state: ProcessState::Running,
+            data: (*parent).data.clone(),
+            sleep_until: MachineTime::zero(),
+            program: null_mut(),
+            brk: (*parent).brk,
+            parent: pid,
+            exit_status: 0,
+            wait_for: None,
+            hart: None,
+        };
+        *child.frame = *(*parent).frame;
+        (*child.frame).regs[Registers::A0 as usize] = 0;
+        clone_cow(&mut *(*parent).root_table, &mut *child.root_table);
+        // The parent's writable pages just became read-only, on every hart that ran it
+        flush_asid_all_harts(pid as usize);
+
+        match add_process(child) {
+            Ok(child_pid) => child_pid,
+            Err(mut child) => {
+                // Its copies of the parent's files may block to close, like in set_dead
+                let files = core::mem::replace(&mut child.data.files, BTreeMap::new());
+                vfs::close_files(files.into_iter().map(|(_, file)| file).collect());
+                0
+            }
+        }
+    }
+}
+
+// Resolves a store page fault against a copy-on-write page, and drops the stale translation so
+// the store can be retried. Returns false if the fault is genuine and the process should be
+// killed.
+pub fn handle_store_fault(pid: u16, vaddr: usize) -> bool {
+    unsafe {
+        let proc = get_by_pid(pid);
+        if proc.is_null() || (*(*proc).frame).satp >> 60 == 0 {
+            return false;
+        }
+        let table = &mut *(*proc).root_table;
+        let user_write = EntryBits::User.val() | EntryBits::Write.val();
+        let resolved = match get_leaf(table, vaddr) {
+            // Already writable - the kernel broke the sharing when it wrote into the page for a
+            // syscall, and this hart still had the read-only translation cached
+            Some(entry) if entry.get_entry() & user_write == user_write => true,
+            _ => resolve_cow(table, vaddr),
+        };
+        if resolved {
+            satp_fence(vaddr, pid as usize);
+        }
+        resolved
+    }
+}
+
+// Maps len bytes of physical memory, like a framebuffer, into a process at the first free
+// address from MMAP_ADDR. The pages aren't owned, so they outlive the process and fork shares
+// them. Returns the address, which is paddr itself for kernel processes.
+pub fn map_physical(pid: u16, paddr: usize, len: usize, writable: bool) -> Option<usize> {
+    unsafe {
+        let proc = get_by_pid(pid);
+        if proc.is_null() {
+            return None;
+        }
+        if (*(*proc).frame).satp >> 60 == 0 {
+            return Some(paddr);
+        }
+        let table = &mut *(*proc).root_table;
+        let pages = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut vaddr = MMAP_ADDR;
+        let mut free = 0;
+        while free < pages {
+            let page = vaddr + free * PAGE_SIZE;
+            if page >= MMAP_END {
+                return None;
+            }
+            if get_leaf(table, page).is_some() {
+                vaddr = page + PAGE_SIZE;
+                free = 0;
+            } else {
+                free += 1;
+            }
+        }
+        let bits = if writable {
+            EntryBits::UserReadWrite.val()
+        } else {
+            EntryBits::User.val() | EntryBits::Read.val()
+        };
+        for i in 0..pages {
+            map(table, vaddr + i * PAGE_SIZE, paddr + i * PAGE_SIZE, bits, 0);
         }
-        0
+        flush_asid_all_harts(pid as usize);
+        Some(vaddr)
+    }
+}
+
+// Gives a user process built elsewhere (e.g. by the ELF loader) a PID and schedules it
+fn add_process(mut proc: Process) -> Result<u16, Box<Process>> {
+    let my_pid = next_pid();
+    proc.pid = my_pid;
+    // A process that had the PID before may have left translations behind under the ASID
+    flush_asid_all_harts(my_pid as usize);
+    unsafe {
+        (*proc.frame).pid = my_pid as usize;
+        (*proc.frame).satp = build_satp(SatpMode::Sv39, my_pid as usize, proc.root_table as usize);
     }
+
+    try_push_process(proc)
 }
 
 fn ra_delete_proc() {
     exit_process();
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum ProcessState {
     Running,
     Sleeping,
@@ -330,27 +710,148 @@ pub struct Process {
     pub sleep_until: MachineTime,
     pub program: *mut u8,
     pub brk: usize,
+    // PID of the process that forked this one, 0 if there isn't one to report back to
+    pub parent: u16,
+    // Wait status, valid once the process is Dead
+    pub exit_status: u32,
+    // What the process is blocked on in waitpid, if anything
+    pub wait_for: Option<WaitFor>,
+    // The hart running it right now, which is the only one that may
+    pub hart: Option<usize>,
+}
+
+pub struct WaitFor {
+    // Child PID to wait for, or -1 for any child
+    pub pid: isize,
+    // Address in the waiting process' memory to write the wait status to, 0 to skip
+    pub status_addr: usize,
+}
+
+impl Process {
+    // Translates an address in this process' memory to a physical one
+    pub fn translate(&self, vaddr: usize) -> Option<usize> {
+        unsafe {
+            if (*self.frame).satp >> 60 == 0 {
+                // Running in machine mode - address is already physical
+                Some(vaddr)
+            } else {
+                virt_to_phys(&*self.root_table, vaddr)
+            }
+        }
+    }
+
+    // Copies kernel memory into this process' memory, returns the number of bytes copied
+    pub fn copy_to_user(&self, vaddr: usize, src: *const u8, len: usize) -> usize {
+        unsafe {
+            if (*self.frame).satp >> 60 == 0 {
+                core::ptr::copy(src, vaddr as *mut u8, len);
+                len
+            } else {
+                copy_to_virt(&mut *self.root_table, vaddr, src, len)
+            }
+        }
+    }
+
+    // Copies this process' memory into kernel memory, returns the number of bytes copied
+    pub fn copy_from_user(&self, dest: *mut u8, vaddr: usize, len: usize) -> usize {
+        unsafe {
+            if (*self.frame).satp >> 60 == 0 {
+                core::ptr::copy(vaddr as *const u8, dest, len);
+                len
+            } else {
+                copy_from_virt(&*self.root_table, dest, vaddr, len)
+            }
+        }
+    }
+
+    // Reads a NUL terminated string out of this process' memory
+    pub fn read_user_string(&self, vaddr: usize, max_len: usize) -> Option<String> {
+        let mut bytes = Vec::new();
+        for i in 0..max_len {
+            let c = unsafe { *(self.translate(vaddr + i)? as *const u8) };
+            if c == 0 {
+                return String::from_utf8(bytes).ok();
+            }
+            bytes.push(c);
+        }
+        None
+    }
+
+    // Reads a NULL terminated array of string pointers (e.g. argv) out of this process' memory
+    pub fn read_user_strings(&self, vaddr: usize, max_count: usize) -> Option<Vec<String>> {
+        let mut strings = Vec::new();
+        if vaddr == 0 {
+            return Some(strings);
+        }
+        for i in 0..max_count {
+            let mut ptr: usize = 0;
+            let size = core::mem::size_of::<usize>();
+            let ptr_addr = vaddr + i * size;
+            if self.copy_from_user(&mut ptr as *mut usize as *mut u8, ptr_addr, size) != size {
+                return None;
+            }
+            if ptr == 0 {
+                return Some(strings);
+            }
+            strings.push(self.read_user_string(ptr, MAX_ARG_LEN)?);
+        }
+        None
+    }
 }
 
 impl Drop for Process {
     fn drop(&mut self) {
-        // deallocate our stack
-        dealloc(self.stack);
+        // deallocate our stack - loaded programs map their stack pages into the table instead
+        if !self.stack.is_null() {
+            dealloc(self.stack);
+        }
+        if !self.program.is_null() {
+            dealloc(self.program);
+        }
         // unmap and deallocate the mmu table
         unsafe {
             unmap(&mut *self.root_table);
         }
         dealloc(self.root_table as *mut u8);
+        dealloc(self.frame as *mut u8);
     }
 }
 
 // Private data containing metadata about the process, e.g. file name or open file descriptors
+#[derive(Clone)]
 pub struct ProcessData {
     cwd_path: [u8; 128],
+    // Open files by descriptor - 0, 1 and 2 are the console
+    pub files: BTreeMap<u16, OpenFile>,
 }
 
 impl ProcessData {
     pub fn zero() -> Self {
-        ProcessData { cwd_path: [0; 128] }
+        ProcessData {
+            cwd_path: [0; 128],
+            files: BTreeMap::new(),
+        }
+    }
+
+    // Current working directory, the root if it's never been set
+    pub fn cwd(&self) -> &str {
+        let len = self
+            .cwd_path
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.cwd_path.len());
+        match core::str::from_utf8(&self.cwd_path[..len]) {
+            Ok(cwd) if !cwd.is_empty() => cwd,
+            _ => "/",
+        }
+    }
+
+    // Lowest descriptor that isn't the console or already open
+    pub fn next_fd(&self) -> u16 {
+        let mut fd = 3;
+        while self.files.contains_key(&fd) {
+            fd += 1;
+        }
+        fd
     }
 }
@@ -1,3 +1,243 @@
-pub fn setup_entropy_device(_ptr: *mut u32) -> bool {
-	false
-}
\ No newline at end of file
+// Virtio entropy device - keeps a pool of random bytes from the device and hands them out to
+// getrandom callers
+use crate::cpu::get_mtime;
+use crate::lock::Mutex;
+use crate::process;
+use crate::syscall;
+use crate::virtio::{self, Buf, Virtqueue};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::mem::size_of;
+
+// getrandom flags
+pub const GRND_NONBLOCK: usize = 1;
+pub const GRND_RANDOM: usize = 2;
+
+// Largest getrandom that's answered in full, anything above gets a short read like on Linux
+pub const MAX_GETRANDOM: usize = 256;
+// Bytes asked of the device at once
+const REQUEST_SIZE: usize = 64;
+// The pool is topped up whenever it drops below this
+const POOL_SIZE: usize = 512;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RandomErrors {
+    // No entropy device
+    NoDevice,
+    // GRND_NONBLOCK and the pool doesn't have enough yet
+    WouldBlock,
+    // Unknown flags
+    Invalid,
+    // A buffer the process can't write to
+    Fault,
+}
+
+impl RandomErrors {
+    // Negated Linux errno, which is what getrandom returns on failure
+    pub fn errno(self) -> usize {
+        let errno: isize = match self {
+            RandomErrors::NoDevice => 38,   // ENOSYS
+            RandomErrors::WouldBlock => 11, // EAGAIN
+            RandomErrors::Invalid => 22,    // EINVAL
+            RandomErrors::Fault => 14,      // EFAULT
+        };
+        -errno as usize
+    }
+}
+
+// A process blocked in getrandom
+struct Waiter {
+    pid: u16,
+    buffer: usize,
+    len: usize,
+}
+
+struct EntropyDevice {
+    vq: Virtqueue,
+    // Index of the device's MMIO slot
+    index: usize,
+    // Where the device writes, while a request is out
+    request: Box<[u8; REQUEST_SIZE]>,
+    busy: bool,
+    pool: VecDeque<u8>,
+    // Served oldest first, so a large request can't be starved by small ones
+    waiters: VecDeque<Waiter>,
+}
+
+// There's only ever any use for one
+static mut ENTROPY: Option<EntropyDevice> = None;
+// The pool and waiters are shared between getrandom and the interrupt handler, on any hart
+static mut ENTROPY_LOCK: Mutex = Mutex::new();
+
+pub fn setup_entropy_device(ptr: *mut u32) -> bool {
+    unsafe {
+        if ENTROPY.is_some() {
+            print!("already have one...");
+            return false;
+        }
+        let index = match virtio::slot(ptr) {
+            Some(index) => index,
+            None => return false,
+        };
+        // 1 - 6. Reset, acknowledge and agree on features. There are no device specific ones.
+        let features = match virtio::negotiate_features(ptr, virtio::RING_FEATURES) {
+            Some(features) => features,
+            None => return false,
+        };
+        // 7. Perform device-specific setup
+        let vq = match Virtqueue::new(ptr, 0, features) {
+            Some(vq) => vq,
+            None => return false,
+        };
+        ENTROPY.replace(EntropyDevice {
+            vq,
+            index,
+            request: Box::new([0; REQUEST_SIZE]),
+            busy: false,
+            pool: VecDeque::with_capacity(POOL_SIZE + REQUEST_SIZE),
+            waiters: VecDeque::new(),
+        });
+        // 8. Set the DRIVER_OK status bit. Device is now 'live'
+        virtio::driver_ok(ptr);
+        // Start filling the pool so it's ready by the time anyone asks
+        if let Some(dev) = ENTROPY.as_mut() {
+            refill(dev);
+        }
+        true
+    }
+}
+
+// Asks the device for more, unless it's already been asked or the pool is full
+fn refill(dev: &mut EntropyDevice) {
+    if dev.busy || (dev.pool.len() >= POOL_SIZE && dev.waiters.is_empty()) {
+        return;
+    }
+    let buf = Buf::new(dev.request.as_ptr(), REQUEST_SIZE as u32, true);
+    if dev.vq.add_buf(&[buf], 0) {
+        dev.busy = true;
+        dev.vq.kick();
+    }
+}
+
+// Copies len bytes of the pool to a process. The pool must have them.
+unsafe fn give(dev: &mut EntropyDevice, pid: u16, buffer: usize, len: usize) -> usize {
+    let proc = process::get_by_pid(pid);
+    if proc.is_null() {
+        return RandomErrors::Invalid.errno();
+    }
+    let mut bytes = [0u8; MAX_GETRANDOM];
+    for byte in bytes[..len].iter_mut() {
+        *byte = dev.pool.pop_front().unwrap();
+    }
+    let copied = (*proc).copy_to_user(buffer, bytes.as_ptr(), len);
+    // Don't leave random bytes lying around on the kernel stack
+    for byte in bytes[..len].iter_mut() {
+        core::ptr::write_volatile(byte, 0);
+    }
+    if copied == len {
+        len
+    } else {
+        RandomErrors::Fault.errno()
+    }
+}
+
+// called by syscall - fills the process' buffer from the pool and returns the number of bytes.
+// Ok(None) means the process was queued until there's enough, and A0 gets set then.
+pub fn getrandom(
+    pid: u16,
+    buffer: usize,
+    len: usize,
+    flags: usize,
+) -> Result<Option<usize>, RandomErrors> {
+    unsafe {
+        ENTROPY_LOCK.with(|| {
+            if flags & !(GRND_NONBLOCK | GRND_RANDOM) != 0 {
+                return Err(RandomErrors::Invalid);
+            }
+            let dev = ENTROPY.as_mut().ok_or(RandomErrors::NoDevice)?;
+            let len = len.min(MAX_GETRANDOM);
+            let ret = if dev.waiters.is_empty() && dev.pool.len() >= len {
+                Ok(Some(give(dev, pid, buffer, len)))
+            } else if flags & GRND_NONBLOCK != 0 {
+                Err(RandomErrors::WouldBlock)
+            } else {
+                dev.waiters.push_back(Waiter { pid, buffer, len });
+                Ok(None)
+            };
+            refill(dev);
+            ret
+        })
+    }
+}
+
+// Fills buf with random bytes for the kernel's own use, waiting for the device if needed.
+// Without a device the bytes come from the timer, which is only good for telling boots apart.
+// don't run inside interrupt context - will block
+pub fn fill(buf: &mut [u8]) {
+    let mut done = 0;
+    while done < buf.len() {
+        let len = (buf.len() - done).min(MAX_GETRANDOM);
+        let ret = syscall::getrandom(buf[done..].as_mut_ptr(), len, 0);
+        if ret == 0 || ret > MAX_GETRANDOM {
+            break;
+        }
+        done += ret;
+    }
+    if done < buf.len() {
+        let mut state = get_mtime().as_u64();
+        for chunk in buf[done..].chunks_mut(size_of::<u64>()) {
+            let bytes = splitmix64(&mut state).to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+// A random usize, see fill
+// don't run inside interrupt context - will block
+pub fn random_usize() -> usize {
+    let mut bytes = [0u8; size_of::<usize>()];
+    fill(&mut bytes);
+    usize::from_le_bytes(bytes)
+}
+
+pub fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+pub fn handle_interrupt(index: usize) {
+    unsafe { ENTROPY_LOCK.with(|| handle_entropy(index)) }
+}
+
+unsafe fn handle_entropy(index: usize) {
+    let dev = match ENTROPY.as_mut() {
+        Some(dev) if dev.index == index => dev,
+        _ => {
+            println!("Invalid entropy device for interrupt {}", index + 1);
+            return;
+        }
+    };
+    let mut written = None;
+    dev.vq.process_used(|_, len| written = Some(len as usize));
+    if let Some(len) = written {
+        dev.busy = false;
+        let len = len.min(REQUEST_SIZE);
+        dev.pool.extend(dev.request[..len].iter());
+        for byte in dev.request.iter_mut() {
+            core::ptr::write_volatile(byte, 0);
+        }
+    }
+    // Hand out what we have in order
+    while let Some(waiter) = dev.waiters.front() {
+        if dev.pool.len() < waiter.len {
+            break;
+        }
+        let waiter = dev.waiters.pop_front().unwrap();
+        let ret = give(dev, waiter.pid, waiter.buffer, waiter.len);
+        process::finish_syscall(waiter.pid, |_| ret);
+    }
+    refill(dev);
+}
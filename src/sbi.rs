@@ -0,0 +1,112 @@
+// Supervisor Binary Interface - calls down to the firmware (OpenSBI) the kernel runs on when
+// it's built with the sbi feature. The firmware keeps machine mode to itself, so the timer,
+// IPIs and starting harts all go through it, and its console stands in for a missing UART.
+use crate::{console, cpu, fdt};
+
+// Extension IDs. Console putchar and getchar are legacy extensions, which are their own
+// function.
+const EXT_CONSOLE_PUTCHAR: usize = 0x01;
+const EXT_CONSOLE_GETCHAR: usize = 0x02;
+const EXT_TIME: usize = 0x5449_4d45;
+const EXT_IPI: usize = 0x0073_5049;
+const EXT_HSM: usize = 0x0048_534d;
+
+// Function IDs
+const TIME_SET_TIMER: usize = 0;
+const IPI_SEND_IPI: usize = 0;
+const HSM_HART_START: usize = 0;
+
+pub const SBI_SUCCESS: isize = 0;
+
+extern "C" {
+    // Where started harts come in, in sboot.S
+    fn _start_hart();
+}
+
+// What every call returns in a0 and a1. Legacy extensions only return a0.
+pub struct SbiRet {
+    pub error: isize,
+    pub value: usize,
+}
+
+fn call(ext: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> SbiRet {
+    let (error, value): (usize, usize);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") fid,
+            in("a7") ext,
+        );
+    }
+    SbiRet {
+        error: error as isize,
+        value,
+    }
+}
+
+// Raises a supervisor timer interrupt once the time CSR reaches time, and clears the pending
+// one
+pub fn set_timer(time: u64) {
+    call(EXT_TIME, TIME_SET_TIMER, time as usize, 0, 0);
+}
+
+// Raises a supervisor software interrupt on every hart in hart_mask, which starts from
+// hart_mask_base
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> isize {
+    call(EXT_IPI, IPI_SEND_IPI, hart_mask, hart_mask_base, 0).error
+}
+
+// Starts a stopped hart at addr in supervisor mode, with its hart id in a0 and opaque in a1
+pub fn hart_start(hartid: usize, addr: usize, opaque: usize) -> isize {
+    call(EXT_HSM, HSM_HART_START, hartid, addr, opaque).error
+}
+
+pub fn console_putchar(c: u8) {
+    call(EXT_CONSOLE_PUTCHAR, 0, c as usize, 0, 0);
+}
+
+// None when nothing's been typed
+pub fn console_getchar() -> Option<u8> {
+    let ret = call(EXT_CONSOLE_GETCHAR, 0, 0, 0, 0).error;
+    if ret < 0 {
+        None
+    } else {
+        Some(ret as u8)
+    }
+}
+
+pub fn console_write(s: &str) {
+    for c in s.bytes() {
+        console_putchar(c);
+    }
+}
+
+// Console input when there's no UART. The firmware can't interrupt for it, so it's checked on
+// every timer tick.
+pub fn poll_console() {
+    if fdt::platform().uart.base != 0 {
+        return;
+    }
+    while let Some(c) = console_getchar() {
+        console::push_stdin(c);
+        console::echo(c);
+    }
+}
+
+// Only the hart the firmware picked comes in at _start. The others are started here and park
+// themselves the way they do in boot.S under -bios none.
+pub fn start_harts() {
+    let boot_hart = cpu::hartid();
+    for hartid in 0..fdt::platform().harts {
+        if hartid == boot_hart {
+            continue;
+        }
+        let error = hart_start(hartid, _start_hart as usize, 0);
+        if error != SBI_SUCCESS {
+            println!("Couldn't start hart {}: error {}", hartid, error);
+        }
+    }
+}
@@ -1,65 +1,99 @@
 // Process scheduler
-use crate::process::{ProcessState, PROCESS_LIST, PROCESS_LIST_MUTEX};
+use crate::cpu::{self, TrapFrame, MAX_HARTS};
+#[cfg(feature = "sbi")]
+use crate::mmu::{self, Table};
+use crate::process::{self, ProcessState};
 use crate::switch_to_user;
 
+// ASID each hart last switched to, so its TLB only has to be flushed when that changes
+static mut LAST_ASID: [usize; MAX_HARTS] = [0; MAX_HARTS];
+
 pub fn context_switch() -> ! {
     let frame = schedule();
     unsafe {
-        switch_to_user(frame);
+        #[cfg(feature = "sbi")]
+        map_trampoline(frame);
+        let hart = cpu::hartid();
+        let asid = (*(frame as *const TrapFrame)).pid;
+        let flush_asid = (LAST_ASID[hart] != asid) as usize;
+        LAST_ASID[hart] = asid;
+        switch_to_user(frame, flush_asid);
+    }
+}
+
+// A user process' table needs the trap vector and its frame, see mmu::map_trampoline
+#[cfg(feature = "sbi")]
+unsafe fn map_trampoline(frame: usize) {
+    let satp = (*(frame as *const TrapFrame)).satp;
+    if satp >> 60 != 0 {
+        // The root table's physical page number is the bottom 44 bits
+        let root = ((satp & ((1 << 44) - 1)) << 12) as *mut Table;
+        mmu::map_trampoline(&mut *root, frame);
     }
 }
 
+// Picks what this hart runs next and returns its frame. Every hart schedules from the same
+// list, so processes another hart is running are skipped - there's an idle process for each.
 pub fn schedule() -> usize {
-    unsafe {
-        let time = crate::cpu::get_mtime();
-        if PROCESS_LIST_MUTEX.try_lock() == false {
-            println!("can't get lock");
-            return 0;
+    let hart = cpu::hartid();
+    let time = cpu::get_mtime();
+    let frame_addr = process::with_process_list(|pl| {
+        // Whatever this hart was running is free to go elsewhere now
+        for prc in pl.iter_mut() {
+            if prc.hart == Some(hart) {
+                prc.hart = None;
+            }
         }
-        if let Some(mut pl) = PROCESS_LIST.take() {
-            let mut frame_addr: usize = 0;
-            let mut pid: usize = 0;
-            'procfindloop: loop {
-                pl.rotate_left(1);
-                if let Some(prc) = pl.front_mut() {
-                    match prc.state {
-                        ProcessState::Running => {
+        // and if it's dead, this may have been all that was keeping it around
+        process::drop_reaped(pl);
+
+        let mut frame_addr: usize = 0;
+        let mut pid: usize = 0;
+        'procfindloop: for _ in 0..pl.len() {
+            pl.rotate_left(1);
+            if let Some(prc) = pl.front_mut() {
+                if prc.hart.is_some() {
+                    continue;
+                }
+                match prc.state {
+                    ProcessState::Running => {
+                        prc.hart = Some(hart);
+                        frame_addr = prc.frame as usize;
+                        pid = prc.pid as usize;
+                        break 'procfindloop;
+                    }
+                    ProcessState::Sleeping => {
+                        if prc.sleep_until.as_u64() > time.as_u64() {
+                            // println!(
+                            //     "Skipping process {}, it's sleeping until {}",
+                            //     prc.pid,
+                            //     prc.sleep_until.formatted()
+                            // );
+                        } else {
+                            // println!("Awaking process {}, it's done sleeping", prc.pid);
+                            prc.state = ProcessState::Running;
+                            prc.hart = Some(hart);
                             frame_addr = prc.frame as usize;
                             pid = prc.pid as usize;
                             break 'procfindloop;
                         }
-                        ProcessState::Sleeping => {
-                            if prc.sleep_until.as_u64() > time.as_u64() {
-                                // println!(
-                                //     "Skipping process {}, it's sleeping until {}",
-                                //     prc.pid,
-                                //     prc.sleep_until.formatted()
-                                // );
-                            } else {
-                                // println!("Awaking process {}, it's done sleeping", prc.pid);
-                                prc.state = ProcessState::Running;
-                                frame_addr = prc.frame as usize;
-                                pid = prc.pid as usize;
-                                break 'procfindloop;
-                            }
-                        }
-                        _ => {
-                            // println!("Skipping process {}, it's {:?}", prc.pid, prc.state);
-                        }
+                    }
+                    _ => {
+                        // println!("Skipping process {}, it's {:?}", prc.pid, prc.state);
                     }
                 }
             }
-            PROCESS_LIST.replace(pl);
-            PROCESS_LIST_MUTEX.unlock();
-
-            // if pid > 1 {
-            //     println!("### Scheduling {} at {}", pid, time.formatted());
-            // }
-            return frame_addr;
-        } else {
-            println!("no proc");
         }
-    }
 
-    0
+        // if pid > 1 {
+        //     println!("### Scheduling {} on hart {} at {}", pid, hart, time.formatted());
+        // }
+        frame_addr
+    });
+
+    match frame_addr {
+        Some(frame_addr) if frame_addr != 0 => frame_addr,
+        // Every hart has an idle process that's always Running, so this is a bug
+        _ => panic!("Nothing to run on hart {}", hart),
+    }
 }
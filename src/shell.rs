@@ -1,7 +1,9 @@
 extern crate alloc;
 use crate::process;
 use crate::syscall;
+use alloc::format;
 use alloc::string::String;
+use alloc::vec::Vec;
 
 pub struct Shell {
     running: bool,
@@ -64,17 +66,17 @@ impl Shell {
                 }
             }
             "ps" => {
-                // task manager
-                unsafe {
-                    process::PROCESS_LIST_MUTEX.sleep_lock();
-                    if let Some(pl) = process::PROCESS_LIST.take() {
-                        println!("Task Manager");
-                        for p in pl.iter() {
-                            println!("pid {}, state {:?}", p.pid, p.state);
-                        }
-                        process::PROCESS_LIST.replace(pl);
+                // task manager - printing can wait on other harts, so not with the list held
+                let lines = process::with_process_list(|pl| {
+                    pl.iter()
+                        .map(|p| format!("pid {}, state {:?}", p.pid, p.state))
+                        .collect::<Vec<String>>()
+                });
+                if let Some(lines) = lines {
+                    println!("Task Manager");
+                    for line in lines {
+                        println!("{}", line);
                     }
-                    process::PROCESS_LIST_MUTEX.unlock();
                 }
             }
             "quit" => {
@@ -0,0 +1,464 @@
+// Socket syscalls - socket, bind, listen, accept, connect, sendto and recvfrom on top of the
+// TCP/IP stack. Sockets are ordinary descriptors, so read, write and close work on them too.
+use crate::process::{self, get_by_pid};
+use crate::syscall::{
+    sleep, SYSCALL_ACCEPT, SYSCALL_BIND, SYSCALL_CONNECT, SYSCALL_LISTEN, SYSCALL_RECVFROM,
+    SYSCALL_SENDTO, SYSCALL_SOCKET,
+};
+use crate::tcpip::{self, Ipv4, Stack, MAX_DATAGRAM, TCP_BUFFER, UNSPECIFIED};
+use crate::vfs::{DirEntry, FileSystem, OpenFile, Stat, O_NONBLOCK, O_RDWR, S_IFSOCK};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+pub const AF_INET: usize = 2;
+pub const SOCK_STREAM: usize = 1;
+pub const SOCK_DGRAM: usize = 2;
+// socket type flag
+pub const SOCK_NONBLOCK: usize = 0o4000;
+// sendto and recvfrom flag
+pub const MSG_DONTWAIT: usize = 0x40;
+
+// The mount id socket descriptors carry. Real mounts start at 1.
+pub const SOCKET_DEV: usize = 0;
+// How often a blocking read or write on a socket descriptor checks again, in ms
+const RETRY_INTERVAL: usize = 10;
+
+// struct sockaddr_in, port and address in network byte order
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SockAddrIn {
+    pub family: u16,
+    pub port: u16,
+    pub addr: [u8; 4],
+    pub zero: [u8; 8],
+}
+
+impl SockAddrIn {
+    pub fn new(addr: Ipv4, port: u16) -> Self {
+        SockAddrIn {
+            family: AF_INET as u16,
+            port: port.to_be(),
+            addr,
+            zero: [0; 8],
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SocketErrors {
+    BadFd,
+    NotSocket,
+    WouldBlock,
+    InProgress,
+    Already,
+    Invalid,
+    Fault,
+    AddrInUse,
+    AddrNotAvailable,
+    AfNotSupported,
+    NotSupported,
+    NetUnreachable,
+    ConnReset,
+    IsConnected,
+    NotConnected,
+    TimedOut,
+    Refused,
+    DestAddrRequired,
+    MessageSize,
+    BrokenPipe,
+}
+
+impl SocketErrors {
+    // Negated Linux errno, which is what the socket syscalls return on failure
+    pub fn errno(self) -> usize {
+        let errno: isize = match self {
+            SocketErrors::BadFd => 9,             // EBADF
+            SocketErrors::NotSocket => 88,        // ENOTSOCK
+            SocketErrors::WouldBlock => 11,       // EAGAIN
+            SocketErrors::InProgress => 115,      // EINPROGRESS
+            SocketErrors::Already => 114,         // EALREADY
+            SocketErrors::Invalid => 22,          // EINVAL
+            SocketErrors::Fault => 14,            // EFAULT
+            SocketErrors::AddrInUse => 98,        // EADDRINUSE
+            SocketErrors::AddrNotAvailable => 99, // EADDRNOTAVAIL
+            SocketErrors::AfNotSupported => 97,   // EAFNOSUPPORT
+            SocketErrors::NotSupported => 95,     // EOPNOTSUPP
+            SocketErrors::NetUnreachable => 101,  // ENETUNREACH
+            SocketErrors::ConnReset => 104,       // ECONNRESET
+            SocketErrors::IsConnected => 106,     // EISCONN
+            SocketErrors::NotConnected => 107,    // ENOTCONN
+            SocketErrors::TimedOut => 110,        // ETIMEDOUT
+            SocketErrors::Refused => 111,         // ECONNREFUSED
+            SocketErrors::DestAddrRequired => 89, // EDESTADDRREQ
+            SocketErrors::MessageSize => 90,      // EMSGSIZE
+            SocketErrors::BrokenPipe => 32,       // EPIPE
+        };
+        -errno as usize
+    }
+}
+
+// What a blocked process is waiting to do. Arguments are the syscall's.
+#[derive(Copy, Clone)]
+enum Op {
+    Connect,
+    Accept {
+        addr: usize,
+        addr_len: usize,
+    },
+    Send {
+        buf: usize,
+        len: usize,
+    },
+    Recv {
+        buf: usize,
+        len: usize,
+        addr: usize,
+        addr_len: usize,
+    },
+}
+
+struct Waiter {
+    pid: u16,
+    // The socket rather than the descriptor, which may be closed in the meantime
+    id: u32,
+    op: Op,
+}
+
+static mut WAITERS: Vec<Waiter> = Vec::new();
+
+// Sockets seen through the file interface. Reads and writes block, O_NONBLOCK only applies to
+// the socket syscalls.
+pub struct SocketFileSystem;
+
+impl FileSystem for SocketFileSystem {
+    fn lookup(&self, _path: &str) -> Option<u32> {
+        None
+    }
+
+    fn stat(&self, inode_num: u32) -> Option<Stat> {
+        Some(Stat {
+            ino: inode_num as u64,
+            mode: (S_IFSOCK | 0o777) as u32,
+            nlink: 1,
+            ..Stat::default()
+        })
+    }
+
+    // Returns 0 at the end of the stream or on error
+    // don't run inside interrupt context - will block
+    fn read(&self, inode_num: u32, buffer: *mut u8, size: u32, _offset: u32) -> u32 {
+        loop {
+            let ret = tcpip::with_stack(|stack| stack.recv(inode_num, size as usize));
+            match ret {
+                Some(Ok(Some((data, _, _)))) => {
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(data.as_ptr(), buffer, data.len());
+                    }
+                    return data.len() as u32;
+                }
+                Some(Ok(None)) => sleep(RETRY_INTERVAL),
+                _ => return 0,
+            };
+        }
+    }
+
+    fn readdir(&self, _inode_num: u32) -> Option<Vec<DirEntry>> {
+        None
+    }
+
+    // don't run inside interrupt context - will block
+    fn write(&self, inode_num: u32, buffer: *const u8, size: u32, _offset: u32) -> Option<u32> {
+        let data = unsafe { core::slice::from_raw_parts(buffer, size as usize) };
+        let mut done = 0;
+        loop {
+            let ret = tcpip::with_stack(|stack| stack.send(inode_num, &data[done..], None));
+            match ret? {
+                Ok(Some(len)) => done += len,
+                Ok(None) => {
+                    sleep(RETRY_INTERVAL);
+                }
+                Err(_) if done > 0 => break,
+                Err(_) => return None,
+            }
+            if done >= data.len() {
+                break;
+            }
+        }
+        Some(done as u32)
+    }
+
+    fn dup(&self, inode_num: u32) {
+        tcpip::with_stack(|stack| stack.dup(inode_num));
+    }
+
+    fn release(&self, inode_num: u32) {
+        tcpip::with_stack(|stack| stack.release(inode_num));
+    }
+}
+
+// The socket behind a descriptor, and whether it's non-blocking
+unsafe fn socket_fd(pid: u16, fd: usize) -> Result<(u32, bool), SocketErrors> {
+    if fd > u16::MAX as usize {
+        return Err(SocketErrors::BadFd);
+    }
+    let proc = get_by_pid(pid);
+    match (*proc).data.files.get(&(fd as u16)) {
+        Some(file) if file.dev == SOCKET_DEV => Ok((file.inode_num, file.flags & O_NONBLOCK != 0)),
+        Some(_) => Err(SocketErrors::NotSocket),
+        None => Err(SocketErrors::BadFd),
+    }
+}
+
+// Adds a descriptor for a socket that already holds a reference for it
+unsafe fn install(pid: u16, id: u32, flags: usize) -> usize {
+    let proc = get_by_pid(pid);
+    let fd = (*proc).data.next_fd();
+    (*proc).data.files.insert(
+        fd,
+        OpenFile {
+            fs: Arc::new(SocketFileSystem),
+            dev: SOCKET_DEV,
+            inode_num: id,
+            size: 0,
+            offset: 0,
+            flags,
+        },
+    );
+    fd as usize
+}
+
+unsafe fn read_addr(pid: u16, addr: usize, len: usize) -> Result<(Ipv4, u16), SocketErrors> {
+    if len < size_of::<SockAddrIn>() {
+        return Err(SocketErrors::Invalid);
+    }
+    let mut sa = SockAddrIn::default();
+    let dest = &mut sa as *mut SockAddrIn as *mut u8;
+    if (*get_by_pid(pid)).copy_from_user(dest, addr, size_of::<SockAddrIn>())
+        != size_of::<SockAddrIn>()
+    {
+        return Err(SocketErrors::Fault);
+    }
+    if sa.family as usize != AF_INET {
+        return Err(SocketErrors::AfNotSupported);
+    }
+    Ok((sa.addr, u16::from_be(sa.port)))
+}
+
+// Fills in the caller's sockaddr_in and its length, if it passed one
+unsafe fn write_addr(pid: u16, addr: usize, addr_len: usize, from: (Ipv4, u16)) {
+    if addr == 0 || addr_len == 0 {
+        return;
+    }
+    let proc = get_by_pid(pid);
+    let mut len = 0u32;
+    if (*proc).copy_from_user(&mut len as *mut u32 as *mut u8, addr_len, size_of::<u32>()) == 0 {
+        return;
+    }
+    let sa = SockAddrIn::new(from.0, from.1);
+    let copy = (len as usize).min(size_of::<SockAddrIn>());
+    (*proc).copy_to_user(addr, &sa as *const SockAddrIn as *const u8, copy);
+    let full = size_of::<SockAddrIn>() as u32;
+    (*proc).copy_to_user(addr_len, &full as *const u32 as *const u8, size_of::<u32>());
+}
+
+// Tries op once. None means it can't finish yet.
+unsafe fn attempt(stack: &mut Stack, pid: u16, id: u32, op: Op) -> Option<usize> {
+    let ret = match op {
+        Op::Connect => stack
+            .connect_result(id)
+            .map(|done| if done { Some(0) } else { None }),
+        Op::Accept { addr, addr_len } => stack.accept(id).map(|accepted| {
+            accepted.map(|(child, from, port)| {
+                write_addr(pid, addr, addr_len, (from, port));
+                install(pid, child, O_RDWR)
+            })
+        }),
+        Op::Send { buf, len } => {
+            // Whatever doesn't fit in the send buffer is left for the caller to try again
+            let len = if stack.is_stream(id) {
+                len.min(TCP_BUFFER)
+            } else {
+                len
+            };
+            if len > MAX_DATAGRAM && !stack.is_stream(id) {
+                return Some(SocketErrors::MessageSize.errno());
+            }
+            let mut data = Vec::new();
+            data.resize(len, 0u8);
+            if (*get_by_pid(pid)).copy_from_user(data.as_mut_ptr(), buf, len) != len {
+                Err(SocketErrors::Fault)
+            } else {
+                stack.send(id, &data, None)
+            }
+        }
+        Op::Recv {
+            buf,
+            len,
+            addr,
+            addr_len,
+        } => stack.recv(id, len).map(|received| {
+            received.map(|(data, from, port)| {
+                let copied = (*get_by_pid(pid)).copy_to_user(buf, data.as_ptr(), data.len());
+                if copied < data.len() {
+                    return SocketErrors::Fault.errno();
+                }
+                write_addr(pid, addr, addr_len, (from, port));
+                copied
+            })
+        }),
+    };
+    match ret {
+        Ok(ret) => ret,
+        Err(err) => Some(err.errno()),
+    }
+}
+
+// Queues the process until op can finish, unless the socket is non-blocking
+unsafe fn attempt_or_wait(
+    stack: &mut Stack,
+    pid: u16,
+    id: u32,
+    op: Op,
+    nonblock: bool,
+) -> Option<usize> {
+    match attempt(stack, pid, id, op) {
+        Some(ret) => Some(ret),
+        None if nonblock => Some(match op {
+            Op::Connect => SocketErrors::InProgress.errno(),
+            _ => SocketErrors::WouldBlock.errno(),
+        }),
+        None => {
+            WAITERS.push(Waiter { pid, id, op });
+            None
+        }
+    }
+}
+
+// called by syscall - returns what goes in A0, or None if the process has to wait, in which
+// case wake sets A0 later
+pub unsafe fn syscall(pid: u16, number: usize, args: [usize; 6]) -> Option<usize> {
+    let ret = tcpip::with_stack(|stack| handle(stack, pid, number, args));
+    match ret {
+        Some(Ok(ret)) => ret,
+        Some(Err(err)) => Some(err.errno()),
+        // No network device
+        None => Some(SocketErrors::AfNotSupported.errno()),
+    }
+}
+
+unsafe fn handle(
+    stack: &mut Stack,
+    pid: u16,
+    number: usize,
+    args: [usize; 6],
+) -> Result<Option<usize>, SocketErrors> {
+    if number == SYSCALL_SOCKET {
+        // socket(domain, type, protocol)
+        if args[0] != AF_INET {
+            return Err(SocketErrors::AfNotSupported);
+        }
+        let stream = match (args[1] & 0xf, args[2]) {
+            (SOCK_STREAM, 0) | (SOCK_STREAM, 6) => true,
+            (SOCK_DGRAM, 0) | (SOCK_DGRAM, 17) => false,
+            _ => return Err(SocketErrors::NotSupported),
+        };
+        let flags = if args[1] & SOCK_NONBLOCK != 0 {
+            O_RDWR | O_NONBLOCK
+        } else {
+            O_RDWR
+        };
+        let id = stack.create(stream);
+        return Ok(Some(install(pid, id, flags)));
+    }
+
+    let (id, nonblock) = socket_fd(pid, args[0])?;
+    match number {
+        SYSCALL_BIND => {
+            // bind(fd, addr, addrlen)
+            let (addr, port) = read_addr(pid, args[1], args[2])?;
+            stack.bind(id, addr, port)?;
+            Ok(Some(0))
+        }
+        SYSCALL_LISTEN => {
+            // listen(fd, backlog)
+            stack.listen(id, args[1])?;
+            Ok(Some(0))
+        }
+        SYSCALL_ACCEPT => {
+            // accept(fd, addr, addrlen)
+            let op = Op::Accept {
+                addr: args[1],
+                addr_len: args[2],
+            };
+            Ok(attempt_or_wait(stack, pid, id, op, nonblock))
+        }
+        SYSCALL_CONNECT => {
+            // connect(fd, addr, addrlen)
+            let (addr, port) = read_addr(pid, args[1], args[2])?;
+            if addr == UNSPECIFIED || port == 0 {
+                return Err(SocketErrors::Invalid);
+            }
+            if stack.connect(id, addr, port)? {
+                return Ok(Some(0));
+            }
+            Ok(attempt_or_wait(stack, pid, id, Op::Connect, nonblock))
+        }
+        SYSCALL_SENDTO => {
+            // sendto(fd, buf, len, flags, addr, addrlen) - the address only matters for UDP
+            let (buf, len, flags) = (args[1], args[2], args[3]);
+            if args[4] != 0 && !stack.is_stream(id) {
+                let to = read_addr(pid, args[4], args[5])?;
+                if len > MAX_DATAGRAM {
+                    return Err(SocketErrors::MessageSize);
+                }
+                let mut data = Vec::new();
+                data.resize(len, 0u8);
+                if (*get_by_pid(pid)).copy_from_user(data.as_mut_ptr(), buf, len) != len {
+                    return Err(SocketErrors::Fault);
+                }
+                // UDP never waits
+                return Ok(stack.send(id, &data, Some(to))?.or(Some(0)));
+            }
+            let nonblock = nonblock || flags & MSG_DONTWAIT != 0;
+            Ok(attempt_or_wait(
+                stack,
+                pid,
+                id,
+                Op::Send { buf, len },
+                nonblock,
+            ))
+        }
+        SYSCALL_RECVFROM => {
+            // recvfrom(fd, buf, len, flags, addr, addrlen)
+            let nonblock = nonblock || args[3] & MSG_DONTWAIT != 0;
+            let op = Op::Recv {
+                buf: args[1],
+                len: args[2],
+                addr: args[4],
+                addr_len: args[5],
+            };
+            Ok(attempt_or_wait(stack, pid, id, op, nonblock))
+        }
+        _ => Err(SocketErrors::Invalid),
+    }
+}
+
+// Retries every blocked socket call, waking the processes whose calls finished. Called by the
+// network process after each poll. The waiters are only touched with the stack's lock held.
+pub fn wake() {
+    tcpip::with_stack(|stack| unsafe {
+        let waiters = core::mem::replace(&mut WAITERS, Vec::new());
+        for waiter in waiters {
+            if get_by_pid(waiter.pid).is_null() {
+                continue;
+            }
+            match attempt(stack, waiter.pid, waiter.id, waiter.op) {
+                Some(ret) => {
+                    process::finish_syscall(waiter.pid, |_| ret);
+                }
+                None => WAITERS.push(waiter),
+            }
+        }
+    });
+}
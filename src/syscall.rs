@@ -1,10 +1,18 @@
-use crate::block;
+use crate::block::{self, BlockErrors};
 use crate::console;
 use crate::cpu::TrapFrame;
 use crate::cpu::{get_mtime, MachineTime, Registers};
 use crate::fs;
 use crate::mmu::virt_to_phys;
-use crate::process::{delete_process, get_by_pid, set_sleeping, set_waiting};
+use crate::page::PAGE_SIZE;
+use crate::process::{
+    exit_status, fork_process, get_by_pid, process_execv, set_dead, set_running, set_sleeping,
+    set_waiting, wait_child, MAX_ARGS, MAX_PATH_LEN,
+};
+use crate::random;
+use crate::socket::{self, SockAddrIn};
+use crate::vfs;
+use core::mem::size_of;
 
 pub const SYSCALL_EXIT: usize = 93;
 pub const SYSCALL_EXIT_GROUP: usize = 94;
@@ -12,13 +20,38 @@ pub const SYSCALL_YIELD: usize = 1;
 pub const SYSCALL_PUTCHAR: usize = 2;
 pub const SYSCALL_DUMP_REGISTERS: usize = 8; // TODO
 pub const SYSCALL_SLEEP: usize = 10;
-pub const SYSCALL_EXECV: usize = 11; // TODO
+pub const SYSCALL_EXECV: usize = 11;
+pub const SYSCALL_FORK: usize = 220; // clone, without any flags
+pub const SYSCALL_MMAP: usize = 222;
 pub const SYSCALL_WAIT: usize = 3;
 pub const SYSCALL_TEST: usize = 99;
+pub const SYSCALL_MKDIRAT: usize = 34;
+pub const SYSCALL_FALLOCATE: usize = 47;
+pub const SYSCALL_UNLINKAT: usize = 35;
+pub const SYSCALL_OPENAT: usize = 56;
+pub const SYSCALL_CLOSE: usize = 57;
+pub const SYSCALL_GETDENTS64: usize = 61;
+pub const SYSCALL_LSEEK: usize = 62;
 pub const SYSCALL_SYS_READ: usize = 63;
 pub const SYSCALL_SYS_WRITE: usize = 64;
+pub const SYSCALL_FSTAT: usize = 80;
+pub const SYSCALL_SYNC: usize = 81;
+pub const SYSCALL_FSYNC: usize = 82;
 pub const SYSCALL_GET_PID: usize = 172;
+pub const SYSCALL_SOCKET: usize = 198;
+pub const SYSCALL_BIND: usize = 200;
+pub const SYSCALL_LISTEN: usize = 201;
+pub const SYSCALL_ACCEPT: usize = 202;
+pub const SYSCALL_CONNECT: usize = 203;
+pub const SYSCALL_SENDTO: usize = 206;
+pub const SYSCALL_RECVFROM: usize = 207;
+pub const SYSCALL_GETRANDOM: usize = 278;
 pub const SYSCALL_BLOCK_READ: usize = 180;
+pub const SYSCALL_BLOCK_WRITE: usize = 181;
+pub const SYSCALL_BLOCK_FLUSH: usize = 182;
+pub const SYSCALL_BLOCK_DISCARD: usize = 183;
+pub const SYSCALL_BLOCK_WRITE_ZEROES: usize = 184;
+pub const SYSCALL_BLOCK_INFO: usize = 185;
 pub const SYSCALL_GET_TIME: usize = 1000;
 pub const SYSCALL_GET_INODE: usize = 1001;
 
@@ -50,6 +83,10 @@ pub fn exit_process() -> usize {
     do_make_syscall(SYSCALL_EXIT, 0, 0, 0, 0, 0, 0)
 }
 
+pub fn exit_with(code: usize) -> usize {
+    do_make_syscall(SYSCALL_EXIT, code, 0, 0, 0, 0, 0)
+}
+
 pub fn yield_process() -> usize {
     do_make_syscall(SYSCALL_YIELD, 0, 0, 0, 0, 0, 0)
 }
@@ -58,8 +95,23 @@ pub fn sleep(period: usize) -> usize {
     do_make_syscall(SYSCALL_SLEEP, period, 0, 0, 0, 0, 0)
 }
 
+// Waits for any child to exit
 pub fn wait_process() -> usize {
-    do_make_syscall(SYSCALL_WAIT, 0, 0, 0, 0, 0, 0)
+    waitpid(-1, core::ptr::null_mut(), 0)
+}
+
+// pid is -1 for any child. Returns the reaped child's PID, 0 if WNOHANG is set and no child
+// has exited yet, or -1 if there are no matching children.
+pub fn waitpid(pid: isize, status: *mut u32, options: usize) -> usize {
+    do_make_syscall(
+        SYSCALL_WAIT,
+        pid as usize,
+        status as usize,
+        options,
+        0,
+        0,
+        0,
+    )
 }
 
 pub fn putchar(c: char) -> usize {
@@ -74,16 +126,195 @@ pub fn sys_read(fd: u16, buf: *const u8, size: usize) -> usize {
     do_make_syscall(SYSCALL_SYS_READ, fd as usize, buf as usize, size, 0, 0, 0)
 }
 
+// Opens a NUL terminated path relative to the working directory, returns the descriptor.
+// mode is only used when O_CREAT creates the file.
+pub fn open(path: *const u8, flags: usize, mode: u16) -> usize {
+    do_make_syscall(
+        SYSCALL_OPENAT,
+        vfs::AT_FDCWD as usize,
+        path as usize,
+        flags,
+        mode as usize,
+        0,
+        0,
+    )
+}
+
+pub fn unlink(path: *const u8) -> usize {
+    do_make_syscall(
+        SYSCALL_UNLINKAT,
+        vfs::AT_FDCWD as usize,
+        path as usize,
+        0,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn mkdir(path: *const u8, mode: u16) -> usize {
+    do_make_syscall(
+        SYSCALL_MKDIRAT,
+        vfs::AT_FDCWD as usize,
+        path as usize,
+        mode as usize,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn rmdir(path: *const u8) -> usize {
+    do_make_syscall(
+        SYSCALL_UNLINKAT,
+        vfs::AT_FDCWD as usize,
+        path as usize,
+        vfs::AT_REMOVEDIR,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn close(fd: u16) -> usize {
+    do_make_syscall(SYSCALL_CLOSE, fd as usize, 0, 0, 0, 0, 0)
+}
+
+// Fills buf with linux_dirent64 records, returns the bytes used or 0 at the end of the directory
+pub fn getdents64(fd: u16, buf: *mut u8, size: usize) -> usize {
+    do_make_syscall(SYSCALL_GETDENTS64, fd as usize, buf as usize, size, 0, 0, 0)
+}
+
+pub fn fstat(fd: u16, stat: *mut vfs::Stat) -> usize {
+    do_make_syscall(SYSCALL_FSTAT, fd as usize, stat as usize, 0, 0, 0, 0)
+}
+
+// Writes every cached block back to disk
+pub fn sync() -> usize {
+    do_make_syscall(SYSCALL_SYNC, 0, 0, 0, 0, 0, 0)
+}
+
+// Makes a file's changes durable. Returns 0 or a negated errno.
+pub fn fsync(fd: u16) -> usize {
+    do_make_syscall(SYSCALL_FSYNC, fd as usize, 0, 0, 0, 0, 0)
+}
+
+// Only FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE is supported. Returns 0 or a negated errno.
+pub fn fallocate(fd: u16, mode: usize, offset: usize, len: usize) -> usize {
+    do_make_syscall(SYSCALL_FALLOCATE, fd as usize, mode, offset, len, 0, 0)
+}
+
+// Returns the new offset
+pub fn lseek(fd: u16, offset: isize, whence: usize) -> usize {
+    do_make_syscall(SYSCALL_LSEEK, fd as usize, offset as usize, whence, 0, 0, 0)
+}
+
 pub fn sys_write(fd: u16, buf: *const u8, size: usize) -> usize {
     do_make_syscall(SYSCALL_SYS_WRITE, fd as usize, buf as usize, size, 0, 0, 0)
 }
 
+// Maps an open file's memory, e.g. /dev/fb0, shared. addr is only a hint and is ignored.
+// Returns the address, or usize::MAX (MAP_FAILED).
+pub fn mmap(addr: usize, len: usize, prot: usize, flags: usize, fd: u16, offset: usize) -> usize {
+    do_make_syscall(SYSCALL_MMAP, addr, len, prot, flags, fd as usize, offset)
+}
+
+// Returns the number of bytes written to buf, or a negated errno. Blocks until the entropy
+// pool has enough unless flags has random::GRND_NONBLOCK.
+pub fn getrandom(buf: *mut u8, len: usize, flags: usize) -> usize {
+    do_make_syscall(SYSCALL_GETRANDOM, buf as usize, len, flags, 0, 0, 0)
+}
+
+// The socket calls return a negated errno on failure, like Linux
+pub fn socket(domain: usize, kind: usize, protocol: usize) -> usize {
+    do_make_syscall(SYSCALL_SOCKET, domain, kind, protocol, 0, 0, 0)
+}
+
+pub fn bind(fd: u16, addr: *const SockAddrIn) -> usize {
+    do_make_syscall(
+        SYSCALL_BIND,
+        fd as usize,
+        addr as usize,
+        size_of::<SockAddrIn>(),
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn listen(fd: u16, backlog: usize) -> usize {
+    do_make_syscall(SYSCALL_LISTEN, fd as usize, backlog, 0, 0, 0, 0)
+}
+
+// Waits for a connection and returns its descriptor. addr and addr_len may be null.
+pub fn accept(fd: u16, addr: *mut SockAddrIn, addr_len: *mut u32) -> usize {
+    do_make_syscall(
+        SYSCALL_ACCEPT,
+        fd as usize,
+        addr as usize,
+        addr_len as usize,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn connect(fd: u16, addr: *const SockAddrIn) -> usize {
+    do_make_syscall(
+        SYSCALL_CONNECT,
+        fd as usize,
+        addr as usize,
+        size_of::<SockAddrIn>(),
+        0,
+        0,
+        0,
+    )
+}
+
+// addr is only used by unconnected UDP sockets, and may be null otherwise
+pub fn sendto(fd: u16, buf: *const u8, len: usize, flags: usize, addr: *const SockAddrIn) -> usize {
+    let addr_len = if addr.is_null() {
+        0
+    } else {
+        size_of::<SockAddrIn>()
+    };
+    do_make_syscall(
+        SYSCALL_SENDTO,
+        fd as usize,
+        buf as usize,
+        len,
+        flags,
+        addr as usize,
+        addr_len,
+    )
+}
+
+// Returns 0 once a stream's peer has closed its end. addr and addr_len may be null.
+pub fn recvfrom(
+    fd: u16,
+    buf: *mut u8,
+    len: usize,
+    flags: usize,
+    addr: *mut SockAddrIn,
+    addr_len: *mut u32,
+) -> usize {
+    do_make_syscall(
+        SYSCALL_RECVFROM,
+        fd as usize,
+        buf as usize,
+        len,
+        flags,
+        addr as usize,
+        addr_len as usize,
+    )
+}
+
 pub fn get_pid() -> u16 {
     do_make_syscall(SYSCALL_GET_PID, 0, 0, 0, 0, 0, 0) as u16
 }
 
-pub fn read_block(dev: usize, buf: *const u8, size: u32, offset: u32) {
-    do_make_syscall(
+pub fn read_block(dev: usize, buf: *const u8, size: u32, offset: u64) -> Result<(), BlockErrors> {
+    let ret = do_make_syscall(
         SYSCALL_BLOCK_READ,
         dev,
         buf as usize,
@@ -92,6 +323,79 @@ pub fn read_block(dev: usize, buf: *const u8, size: u32, offset: u32) {
         0,
         0,
     );
+    block::from_syscall_ret(ret)
+}
+
+// path, argv and envp are NUL terminated, argv and envp are NULL terminated arrays.
+// Only returns if the program couldn't be started, with a negated errno.
+pub fn execv(path: *const u8, argv: *const *const u8, envp: *const *const u8) -> usize {
+    do_make_syscall(
+        SYSCALL_EXECV,
+        path as usize,
+        argv as usize,
+        envp as usize,
+        0,
+        0,
+        0,
+    )
+}
+
+// Returns the child's PID to the parent and 0 to the child
+pub fn fork() -> usize {
+    do_make_syscall(SYSCALL_FORK, 0, 0, 0, 0, 0, 0)
+}
+
+pub fn write_block(dev: usize, buf: *const u8, size: u32, offset: u64) -> Result<(), BlockErrors> {
+    let ret = do_make_syscall(
+        SYSCALL_BLOCK_WRITE,
+        dev,
+        buf as usize,
+        size as usize,
+        offset as usize,
+        0,
+        0,
+    );
+    block::from_syscall_ret(ret)
+}
+
+// Waits for the device's write cache to reach storage
+pub fn flush_block(dev: usize) -> Result<(), BlockErrors> {
+    let ret = do_make_syscall(SYSCALL_BLOCK_FLUSH, dev, 0, 0, 0, 0, 0);
+    block::from_syscall_ret(ret)
+}
+
+// One discard request - see block::discard_range for any size
+pub fn discard_block(dev: usize, offset: u64, size: u64) -> Result<(), BlockErrors> {
+    let ret = do_make_syscall(
+        SYSCALL_BLOCK_DISCARD,
+        dev,
+        offset as usize,
+        size as usize,
+        0,
+        0,
+        0,
+    );
+    block::from_syscall_ret(ret)
+}
+
+// One write-zeroes request - see block::write_zeroes_range for any size
+pub fn write_zeroes_block(dev: usize, offset: u64, size: u64) -> Result<(), BlockErrors> {
+    let ret = do_make_syscall(
+        SYSCALL_BLOCK_WRITE_ZEROES,
+        dev,
+        offset as usize,
+        size as usize,
+        0,
+        0,
+        0,
+    );
+    block::from_syscall_ret(ret)
+}
+
+// Size, read-only status and the rest of what the device reported at probe time
+pub fn block_info(dev: usize, info: *mut block::BlockInfo) -> Result<(), BlockErrors> {
+    let ret = do_make_syscall(SYSCALL_BLOCK_INFO, dev, info as usize, 0, 0, 0, 0);
+    block::from_syscall_ret(ret)
 }
 
 pub fn get_time() -> MachineTime {
@@ -111,6 +415,18 @@ pub fn get_inode(dev: usize, node: u32, buffer: *mut u8, size: u32, offset: u32)
     ) as u32
 }
 
+// Register holding the file descriptor for syscalls that take one
+fn fd_register(syscall_number: usize) -> Option<usize> {
+    match syscall_number {
+        SYSCALL_SYS_READ | SYSCALL_SYS_WRITE | SYSCALL_CLOSE | SYSCALL_GETDENTS64
+        | SYSCALL_FSTAT | SYSCALL_FSYNC | SYSCALL_FALLOCATE | SYSCALL_LSEEK => {
+            Some(Registers::A0 as usize)
+        }
+        SYSCALL_MMAP => Some(Registers::A4 as usize),
+        _ => None,
+    }
+}
+
 pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) -> bool {
     let syscall_number = (*frame).regs[Registers::A7 as usize];
     let pid = (*frame).pid as u16;
@@ -118,10 +434,19 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) -> bool {
     // Advance process' program counter
     (*frame).pc = mepc + 4;
 
+    // Descriptors are u16, so anything bigger can't be open - it mustn't wrap onto one that is
+    if let Some(reg) = fd_register(syscall_number) {
+        if (*frame).regs[reg] > u16::MAX as usize {
+            (*frame).regs[Registers::A0 as usize] = usize::MAX;
+            return false;
+        }
+    }
+
     match syscall_number {
         SYSCALL_EXIT | SYSCALL_EXIT_GROUP => {
-            // Exit process
-            delete_process(pid);
+            // Exit process - it hangs around until the parent collects the exit code
+            let code = (*frame).regs[Registers::A0 as usize];
+            set_dead(pid, exit_status(code));
             return true;
         }
         SYSCALL_YIELD => {
@@ -145,10 +470,45 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) -> bool {
             return true;
         }
         SYSCALL_WAIT => {
-            // Wait - set as waiting and context switch
-            println!("{} wants to wait", pid);
-            set_waiting(pid);
-            return true;
+            // waitpid(pid, &status, options) - reap a dead child, or wait for one to exit
+            let target = (*frame).regs[Registers::A0 as usize] as isize;
+            let status_addr = (*frame).regs[Registers::A1 as usize];
+            let options = (*frame).regs[Registers::A2 as usize];
+            match wait_child(pid, target, status_addr, options) {
+                Some(ret) => {
+                    (*frame).regs[Registers::A0 as usize] = ret;
+                }
+                None => {
+                    // Woken up by the child exiting, which fills in A0
+                    return true;
+                }
+            }
+        }
+        SYSCALL_EXECV => {
+            // execv - replace the process' image, keeping its PID and open files
+            let proc = get_by_pid(pid);
+            let path =
+                (*proc).read_user_string((*frame).regs[Registers::A0 as usize], MAX_PATH_LEN);
+            let argv = (*proc).read_user_strings((*frame).regs[Registers::A1 as usize], MAX_ARGS);
+            let envp = (*proc).read_user_strings((*frame).regs[Registers::A2 as usize], MAX_ARGS);
+            match (path, argv, envp) {
+                (Some(path), Some(argv), Some(envp)) => {
+                    process_execv(pid, path, argv, envp);
+                    return true;
+                }
+                _ => {
+                    (*frame).regs[Registers::A0 as usize] = -14isize as usize; // EFAULT
+                }
+            }
+        }
+        SYSCALL_FORK => {
+            // fork - the child gets a copy of this frame, with the program counter already advanced
+            let child_pid = fork_process(pid);
+            (*frame).regs[Registers::A0 as usize] = if child_pid > 0 {
+                child_pid as usize
+            } else {
+                usize::MAX
+            };
         }
         SYSCALL_PUTCHAR => {
             // putchar
@@ -183,19 +543,11 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) -> bool {
                     } else {
                         //
                         for i in inb.drain(0..num_elements) {
-                            let paddr = if (*frame).satp >> 60 == 0 {
-                                // Running in machine mode - address is already physical
-                                Some(buf as usize)
-                            } else {
-                                // Running in user/supervisor mode - need to lookup physical address
-                                let table = ((*proc).root_table).as_mut().unwrap();
-                                virt_to_phys(table, buf as usize)
-                            };
-                            if paddr.is_none() {
+                            // copy_to_user looks up the physical address and breaks copy-on-write
+                            if (*proc).copy_to_user(buf as usize, &i as *const u8, 1) == 0 {
+                                ret = usize::MAX;
                                 break;
                             }
-                            let buf_ptr = paddr.unwrap() as *mut u8;
-                            buf_ptr.write_volatile(i);
                             buf = buf.add(1);
                             ret += 1;
                         }
@@ -205,8 +557,163 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) -> bool {
                 console::IN_LOCK.unlock();
                 (*frame).regs[Registers::A0 as usize] = ret;
                 return reschedule;
+            } else if let Some(file) = (*get_by_pid(pid)).data.files.get(&fd) {
+                if file.flags & vfs::O_ACCMODE == vfs::O_WRONLY {
+                    (*frame).regs[Registers::A0 as usize] = usize::MAX;
+                } else {
+                    // open file - read it from a kernel process
+                    vfs::process_file_read(pid, fd, buf as usize, size as u32);
+                    return true;
+                }
+            } else {
+                (*frame).regs[Registers::A0 as usize] = usize::MAX;
+            }
+        }
+        SYSCALL_OPENAT => {
+            // openat(dirfd, path, flags, mode) - only relative to the working directory
+            let dirfd = (*frame).regs[Registers::A0 as usize] as isize;
+            let proc = get_by_pid(pid);
+            let path =
+                (*proc).read_user_string((*frame).regs[Registers::A1 as usize], MAX_PATH_LEN);
+            let flags = (*frame).regs[Registers::A2 as usize];
+            let mode = vfs::S_IFREG | ((*frame).regs[Registers::A3 as usize] as u16 & 0o777);
+            match path {
+                Some(path) if dirfd == vfs::AT_FDCWD || path.starts_with('/') => {
+                    let path = vfs::absolute_path((*proc).data.cwd(), &path);
+                    vfs::process_open(pid, path, flags, mode);
+                    return true;
+                }
+                _ => {
+                    (*frame).regs[Registers::A0 as usize] = usize::MAX;
+                }
+            }
+        }
+        SYSCALL_MKDIRAT | SYSCALL_UNLINKAT => {
+            // mkdirat(dirfd, path, mode) / unlinkat(dirfd, path, flags)
+            let dirfd = (*frame).regs[Registers::A0 as usize] as isize;
+            let proc = get_by_pid(pid);
+            let path =
+                (*proc).read_user_string((*frame).regs[Registers::A1 as usize], MAX_PATH_LEN);
+            let arg = (*frame).regs[Registers::A2 as usize];
+            match path {
+                Some(path) if dirfd == vfs::AT_FDCWD || path.starts_with('/') => {
+                    let path = vfs::absolute_path((*proc).data.cwd(), &path);
+                    let op = if syscall_number == SYSCALL_MKDIRAT {
+                        vfs::PathOp::Mkdir(arg as u16 & 0o777)
+                    } else if arg & vfs::AT_REMOVEDIR != 0 {
+                        vfs::PathOp::Rmdir
+                    } else {
+                        vfs::PathOp::Unlink
+                    };
+                    vfs::process_path_op(pid, path, op);
+                    return true;
+                }
+                _ => {
+                    (*frame).regs[Registers::A0 as usize] = usize::MAX;
+                }
             }
         }
+        SYSCALL_CLOSE => {
+            // close(fd)
+            let fd = (*frame).regs[Registers::A0 as usize] as u16;
+            let proc = get_by_pid(pid);
+            match (*proc).data.files.remove(&fd) {
+                Some(file) => {
+                    // Releasing the inode may block
+                    vfs::process_close(pid, file);
+                    return true;
+                }
+                None => {
+                    (*frame).regs[Registers::A0 as usize] = usize::MAX;
+                }
+            }
+        }
+        SYSCALL_GETDENTS64 | SYSCALL_FSTAT => {
+            // getdents64(fd, buf, size) / fstat(fd, statbuf)
+            let fd = (*frame).regs[Registers::A0 as usize] as u16;
+            let buf = (*frame).regs[Registers::A1 as usize];
+            let size = (*frame).regs[Registers::A2 as usize];
+            if (*get_by_pid(pid)).data.files.contains_key(&fd) {
+                if syscall_number == SYSCALL_GETDENTS64 {
+                    vfs::process_getdents(pid, fd, buf, size as u32);
+                } else {
+                    vfs::process_fstat(pid, fd, buf);
+                }
+                return true;
+            }
+            (*frame).regs[Registers::A0 as usize] = usize::MAX;
+        }
+        SYSCALL_SYNC => {
+            // sync - write back from a kernel process
+            vfs::process_sync(pid);
+            return true;
+        }
+        SYSCALL_FSYNC | SYSCALL_FALLOCATE => {
+            // fsync(fd) / fallocate(fd, mode, offset, len)
+            let fd = (*frame).regs[Registers::A0 as usize] as u16;
+            let mode = (*frame).regs[Registers::A1 as usize];
+            let offset = (*frame).regs[Registers::A2 as usize];
+            let len = (*frame).regs[Registers::A3 as usize];
+            match (*get_by_pid(pid)).data.files.get(&fd) {
+                Some(_) if syscall_number == SYSCALL_FSYNC => {
+                    vfs::process_fsync(pid, fd);
+                    return true;
+                }
+                Some(file) if file.flags & vfs::O_ACCMODE != vfs::O_RDONLY => {
+                    vfs::process_fallocate(pid, fd, mode, offset, len);
+                    return true;
+                }
+                _ => (*frame).regs[Registers::A0 as usize] = usize::MAX,
+            }
+        }
+        SYSCALL_MMAP => {
+            // mmap(addr, len, prot, flags, fd, offset) - only shared mappings of whole pages
+            let len = (*frame).regs[Registers::A1 as usize];
+            let prot = (*frame).regs[Registers::A2 as usize];
+            let flags = (*frame).regs[Registers::A3 as usize];
+            let fd = (*frame).regs[Registers::A4 as usize] as u16;
+            let offset = (*frame).regs[Registers::A5 as usize];
+            let len = (len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            let writable = prot & vfs::PROT_WRITE != 0;
+            match (*get_by_pid(pid)).data.files.get(&fd) {
+                Some(file)
+                    if flags & vfs::MAP_SHARED != 0
+                        && len > 0
+                        && len <= u32::MAX as usize
+                        && offset % PAGE_SIZE == 0
+                        && offset <= u32::MAX as usize
+                        && !(writable && file.flags & vfs::O_ACCMODE == vfs::O_RDONLY) =>
+                {
+                    vfs::process_mmap(pid, fd, offset as u32, len as u32, writable);
+                    return true;
+                }
+                _ => (*frame).regs[Registers::A0 as usize] = usize::MAX,
+            }
+        }
+        SYSCALL_LSEEK => {
+            // lseek(fd, offset, whence)
+            let fd = (*frame).regs[Registers::A0 as usize] as u16;
+            let offset = (*frame).regs[Registers::A1 as usize] as isize;
+            let whence = (*frame).regs[Registers::A2 as usize];
+            let proc = get_by_pid(pid);
+            let mut ret = usize::MAX;
+            if let Some(file) = (*proc).data.files.get_mut(&fd) {
+                let base = match whence {
+                    vfs::SEEK_SET => Some(0),
+                    vfs::SEEK_CUR => Some(file.offset as isize),
+                    vfs::SEEK_END => Some(file.size as isize),
+                    _ => None,
+                };
+                if let Some(base) = base {
+                    let new_offset = base + offset;
+                    if new_offset >= 0 && new_offset <= u32::MAX as isize {
+                        file.offset = new_offset as u32;
+                        ret = new_offset as usize;
+                    }
+                }
+            }
+            (*frame).regs[Registers::A0 as usize] = ret;
+        }
         SYSCALL_SYS_WRITE => {
             // sys_write
             let fd = (*frame).regs[Registers::A0 as usize] as u16;
@@ -231,21 +738,115 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) -> bool {
                     }
                 }
                 (*frame).regs[Registers::A0 as usize] = iter as usize;
+            } else if let Some(file) = (*get_by_pid(pid)).data.files.get(&fd) {
+                if file.flags & vfs::O_ACCMODE == vfs::O_RDONLY {
+                    (*frame).regs[Registers::A0 as usize] = usize::MAX;
+                } else {
+                    // open file - write it from a kernel process
+                    vfs::process_file_write(pid, fd, buf as usize, size as u32);
+                    return true;
+                }
+            } else {
+                (*frame).regs[Registers::A0 as usize] = usize::MAX;
             }
         }
         SYSCALL_GET_PID => {
             // get pid
             (*frame).regs[Registers::A0 as usize] = pid as usize;
         }
-        SYSCALL_BLOCK_READ => {
-            // read block
+        SYSCALL_GETRANDOM => {
+            // getrandom(buf, len, flags) - waits for the entropy device if the pool runs dry
+            let buf = (*frame).regs[Registers::A0 as usize];
+            let len = (*frame).regs[Registers::A1 as usize];
+            let flags = (*frame).regs[Registers::A2 as usize];
+            // Waiting before it's queued - another hart can take the completion and wake it
+            // before this one gets any further
+            set_waiting(pid);
+            match random::getrandom(pid, buf, len, flags) {
+                Ok(Some(bytes)) => {
+                    set_running(pid);
+                    (*frame).regs[Registers::A0 as usize] = bytes;
+                }
+                Ok(None) => return true,
+                Err(err) => {
+                    set_running(pid);
+                    (*frame).regs[Registers::A0 as usize] = err.errno();
+                }
+            }
+        }
+        SYSCALL_SOCKET | SYSCALL_BIND | SYSCALL_LISTEN | SYSCALL_ACCEPT | SYSCALL_CONNECT
+        | SYSCALL_SENDTO | SYSCALL_RECVFROM => {
+            // socket calls - the ones that block are finished by the network process
+            let mut args = [0; 6];
+            for (i, arg) in args.iter_mut().enumerate() {
+                *arg = (*frame).regs[Registers::A0 as usize + i];
+            }
             set_waiting(pid);
+            match socket::syscall(pid, syscall_number, args) {
+                Some(ret) => {
+                    set_running(pid);
+                    (*frame).regs[Registers::A0 as usize] = ret;
+                }
+                None => return true,
+            }
+        }
+        SYSCALL_BLOCK_READ | SYSCALL_BLOCK_WRITE => {
+            // read / write block - the result is set when the request completes
             let dev = (*frame).regs[Registers::A0 as usize];
-            let buffer = (*frame).regs[Registers::A1 as usize] as *mut u8;
+            let buffer = (*frame).regs[Registers::A1 as usize];
             let size = (*frame).regs[Registers::A2 as usize] as u32;
             let offset = (*frame).regs[Registers::A3 as usize] as u64;
-            block::block_op(dev, buffer, size, offset, false, pid);
-            return true;
+            let write = syscall_number == SYSCALL_BLOCK_WRITE;
+            set_waiting(pid);
+            let queued = if (*frame).satp >> 60 == 0 {
+                // A kernel process' buffer is already physical
+                block::block_op(dev, buffer as *mut u8, size, offset, write, pid)
+            } else {
+                block::user_op(dev, buffer, size, offset, write, pid)
+            };
+            match queued {
+                Ok(()) => return true,
+                Err(err) => {
+                    set_running(pid);
+                    (*frame).regs[Registers::A0 as usize] = err.errno();
+                }
+            }
+        }
+        SYSCALL_BLOCK_FLUSH | SYSCALL_BLOCK_DISCARD | SYSCALL_BLOCK_WRITE_ZEROES => {
+            // flush / discard / write zeroes - the result is set when the request completes
+            let dev = (*frame).regs[Registers::A0 as usize];
+            let offset = (*frame).regs[Registers::A1 as usize] as u64;
+            let size = (*frame).regs[Registers::A2 as usize] as u64;
+            set_waiting(pid);
+            let queued = match syscall_number {
+                SYSCALL_BLOCK_FLUSH => block::flush(dev, pid),
+                SYSCALL_BLOCK_DISCARD => block::discard(dev, offset, size, pid),
+                _ => block::write_zeroes(dev, offset, size, pid),
+            };
+            match queued {
+                Ok(()) => return true,
+                Err(err) => {
+                    set_running(pid);
+                    (*frame).regs[Registers::A0 as usize] = err.errno();
+                }
+            }
+        }
+        SYSCALL_BLOCK_INFO => {
+            // block info(dev, info)
+            let dev = (*frame).regs[Registers::A0 as usize];
+            let buf = (*frame).regs[Registers::A1 as usize];
+            let size = size_of::<block::BlockInfo>();
+            (*frame).regs[Registers::A0 as usize] = match block::info(dev) {
+                Some(info) => {
+                    let src = &info as *const block::BlockInfo as *const u8;
+                    if (*get_by_pid(pid)).copy_to_user(buf, src, size) == size {
+                        0
+                    } else {
+                        BlockErrors::Fault.errno()
+                    }
+                }
+                None => BlockErrors::NoDevice.errno(),
+            };
         }
         SYSCALL_GET_TIME => {
             // get time
@@ -0,0 +1,1551 @@
+// TCP/IP stack - ARP, IPv4 configured by DHCP, ICMP echo, UDP and TCP on the first network
+// device. A kernel process polls the device and runs the timers, and the socket syscalls reach
+// in through socket.rs. Everything here runs with the stack's lock held, so neither sees the
+// other half done.
+use crate::cpu::{get_mtime, MTIMER_TICKS_PER_MS};
+use crate::lock::Mutex;
+use crate::net;
+use crate::process;
+use crate::random;
+use crate::socket::{self, SocketErrors};
+use crate::syscall::sleep;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+pub type Ipv4 = [u8; 4];
+pub type Mac = [u8; 6];
+
+pub const UNSPECIFIED: Ipv4 = [0; 4];
+pub const BROADCAST: Ipv4 = [255; 4];
+const BROADCAST_MAC: Mac = [0xff; 6];
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const PROTO_ICMP: u8 = 1;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+const ETH_HEADER: usize = 14;
+const IP_HEADER: usize = 20;
+const UDP_HEADER: usize = 8;
+const TCP_HEADER: usize = 20;
+// Largest UDP payload that fits in one frame - there's no fragmentation
+pub const MAX_DATAGRAM: usize = net::MAX_FRAME_SIZE - ETH_HEADER - IP_HEADER - UDP_HEADER;
+const TCP_MSS: usize = net::MAX_FRAME_SIZE - ETH_HEADER - IP_HEADER - TCP_HEADER;
+// Until the peer says otherwise
+const TCP_DEFAULT_MSS: usize = 536;
+
+// TCP flags
+const FIN: u8 = 1;
+const SYN: u8 = 2;
+const RST: u8 = 4;
+const PSH: u8 = 8;
+const ACK: u8 = 16;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_NAK: u8 = 6;
+
+// Used when DHCP gets no answer - QEMU user-mode networking's defaults
+const STATIC_CONFIG: Config = Config {
+    addr: [10, 0, 2, 15],
+    netmask: [255, 255, 255, 0],
+    gateway: [10, 0, 2, 2],
+};
+
+// Timers, in ms
+const POLL_INTERVAL: usize = 10;
+const ARP_RETRY: u64 = 1000;
+const ARP_GIVE_UP: u64 = 3000;
+const DHCP_RETRY: u64 = 2000;
+const DHCP_ATTEMPTS: u32 = 3;
+const TCP_RTO: u64 = 1000;
+const TCP_MAX_RTO: u64 = 16000;
+const TCP_MAX_RETRIES: u32 = 6;
+const TCP_TIME_WAIT: u64 = 2000;
+// Only closed sockets get to FIN-WAIT-2, so nobody would ever notice it hanging
+const TCP_FIN_WAIT_2: u64 = 30000;
+
+// Per socket limits
+pub const TCP_BUFFER: usize = 16384;
+const UDP_QUEUE: usize = 32;
+const MAX_BACKLOG: usize = 16;
+// Packets waiting for an ARP reply
+const ARP_QUEUE: usize = 64;
+
+const EPHEMERAL_PORTS: u16 = 49152;
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Config {
+    pub addr: Ipv4,
+    pub netmask: Ipv4,
+    pub gateway: Ipv4,
+}
+
+impl Config {
+    fn on_link(&self, addr: Ipv4) -> bool {
+        (0..4).all(|i| addr[i] & self.netmask[i] == self.addr[i] & self.netmask[i])
+    }
+
+    fn broadcast(&self) -> Ipv4 {
+        let mut ret = self.addr;
+        for i in 0..4 {
+            ret[i] |= !self.netmask[i];
+        }
+        ret
+    }
+}
+
+enum Dhcp {
+    Discover,
+    // Address offered and the server that offered it
+    Request(Ipv4, Ipv4),
+    // The lease isn't renewed - the address is kept until reboot
+    Done,
+}
+
+// An IP packet on its way out. send_ip adds the IP header and the UDP or TCP checksum.
+struct Packet {
+    dst: Ipv4,
+    proto: u8,
+    payload: Vec<u8>,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+struct Segment<'a> {
+    src: Ipv4,
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    data: &'a [u8],
+}
+
+pub struct Tcp {
+    pub state: TcpState,
+    local_port: u16,
+    pub remote: (Ipv4, u16),
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: u32,
+    rcv_nxt: u32,
+    mss: usize,
+    // Bytes from snd_una on, sent or not
+    send_buf: VecDeque<u8>,
+    recv_buf: VecDeque<u8>,
+    // The user closed it, a FIN goes out after the data
+    fin_queued: bool,
+    fin_sent: bool,
+    fin_received: bool,
+    // Something arrived that needs acknowledging
+    ack_pending: bool,
+    retransmit_at: Option<u64>,
+    rto: u64,
+    retries: u32,
+    // When TIME-WAIT or FIN-WAIT-2 ends
+    linger_until: u64,
+    // Why the connection failed, reported once by the next call
+    error: Option<SocketErrors>,
+    // Listening sockets: connections that finished the handshake, waiting for accept
+    backlog: VecDeque<u32>,
+    backlog_max: usize,
+    // The listening socket a connection came in through, until it's accepted
+    parent: Option<u32>,
+}
+
+pub struct Udp {
+    // Where send goes without an address, and the only peer received from
+    remote: Option<(Ipv4, u16)>,
+    // Sender address, port and payload
+    queue: VecDeque<(Ipv4, u16, Vec<u8>)>,
+}
+
+pub enum Kind {
+    Udp(Udp),
+    Tcp(Tcp),
+}
+
+pub struct Socket {
+    // Descriptors referring to it
+    refs: usize,
+    // Bound address and port, port 0 until it's bound
+    local: (Ipv4, u16),
+    kind: Kind,
+}
+
+pub struct Stack {
+    dev: usize,
+    mac: Mac,
+    // None until DHCP finishes or gives up
+    config: Option<Config>,
+    dhcp: Dhcp,
+    dhcp_xid: u32,
+    dhcp_attempts: u32,
+    dhcp_next: u64,
+    arp: BTreeMap<Ipv4, Mac>,
+    // Packets waiting for the next hop's address: next hop, IP packet, when it was queued
+    arp_pending: Vec<(Ipv4, Vec<u8>, u64)>,
+    arp_retry_at: u64,
+    ip_id: u16,
+    sockets: BTreeMap<u32, Socket>,
+    next_socket: u32,
+    next_port: u16,
+    // For initial sequence numbers and ports
+    seed: u64,
+    out: Vec<Packet>,
+}
+
+static mut STACK: Option<Stack> = None;
+// Also covers the sockets' waiters, see socket::wake
+static mut STACK_LOCK: Mutex = Mutex::new();
+
+// Brings the stack up on the first network device, if there is one
+pub fn init() {
+    let dev = match net::devices().first() {
+        Some(dev) => *dev,
+        None => return,
+    };
+    let seed = get_mtime().as_u64();
+    unsafe {
+        STACK.replace(Stack {
+            dev,
+            mac: net::mac(dev).unwrap(),
+            config: None,
+            dhcp: Dhcp::Discover,
+            dhcp_xid: seed as u32,
+            dhcp_attempts: 0,
+            dhcp_next: 0,
+            arp: BTreeMap::new(),
+            arp_pending: Vec::new(),
+            arp_retry_at: 0,
+            ip_id: 0,
+            sockets: BTreeMap::new(),
+            next_socket: 1,
+            next_port: EPHEMERAL_PORTS,
+            seed,
+            out: Vec::new(),
+        });
+    }
+    process::add_kernel_process(net_proc);
+}
+
+// Runs f on the stack with its lock held, None if there's no network. Sends whatever f queued.
+pub fn with_stack<T>(f: impl FnOnce(&mut Stack) -> T) -> Option<T> {
+    unsafe {
+        STACK_LOCK.with(|| {
+            let stack = STACK.as_mut()?;
+            let ret = f(stack);
+            stack.flush();
+            Some(ret)
+        })
+    }
+}
+
+// Kernel process that moves frames between the device and the stack and runs the timers
+fn net_proc() {
+    let mut seed = [0u8; 8];
+    random::fill(&mut seed);
+    with_stack(|stack| {
+        stack.seed ^= u64::from_le_bytes(seed);
+        stack.dhcp_xid = random::splitmix64(&mut stack.seed) as u32;
+        stack.next_port = EPHEMERAL_PORTS + (random::splitmix64(&mut stack.seed) % 16384) as u16;
+    });
+    loop {
+        with_stack(|stack| stack.poll());
+        // Anyone blocked on a socket may be able to go on
+        socket::wake();
+        sleep(POLL_INTERVAL);
+    }
+}
+
+fn now_ms() -> u64 {
+    get_mtime().as_u64() / MTIMER_TICKS_PER_MS
+}
+
+fn be16(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+fn be32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn ipv4(data: &[u8], at: usize) -> Ipv4 {
+    [data[at], data[at + 1], data[at + 2], data[at + 3]]
+}
+
+// Internet checksum of data, continuing from sum
+fn checksum(data: &[u8], mut sum: u32) -> u16 {
+    for pair in data.chunks(2) {
+        let word = if pair.len() == 2 {
+            be16(pair, 0)
+        } else {
+            (pair[0] as u16) << 8
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// Checksum of a UDP or TCP segment, including the pseudo header
+fn l4_checksum(src: Ipv4, dst: Ipv4, proto: u8, data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for addr in [src, dst].iter() {
+        sum += be16(addr, 0) as u32 + be16(addr, 2) as u32;
+    }
+    sum += proto as u32 + data.len() as u32;
+    checksum(data, sum)
+}
+
+// a < b, for sequence numbers that wrap
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+fn udp_packet(src_port: u16, dst_port: u16, data: &[u8]) -> Vec<u8> {
+    let mut p = Vec::with_capacity(UDP_HEADER + data.len());
+    p.extend_from_slice(&src_port.to_be_bytes());
+    p.extend_from_slice(&dst_port.to_be_bytes());
+    p.extend_from_slice(&((UDP_HEADER + data.len()) as u16).to_be_bytes());
+    // checksum, filled in by send_ip
+    p.extend_from_slice(&[0, 0]);
+    p.extend_from_slice(data);
+    p
+}
+
+fn tcp_packet(
+    src_port: u16,
+    dst: (Ipv4, u16),
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    data: &[u8],
+) -> Packet {
+    // SYNs say how large a segment we take
+    let options: &[u8] = if flags & SYN != 0 {
+        &[2, 4, (TCP_MSS >> 8) as u8, TCP_MSS as u8]
+    } else {
+        &[]
+    };
+    let header_len = TCP_HEADER + options.len();
+    let mut p = Vec::with_capacity(header_len + data.len());
+    p.extend_from_slice(&src_port.to_be_bytes());
+    p.extend_from_slice(&dst.1.to_be_bytes());
+    p.extend_from_slice(&seq.to_be_bytes());
+    p.extend_from_slice(&ack.to_be_bytes());
+    p.push(((header_len / 4) as u8) << 4);
+    p.push(flags);
+    p.extend_from_slice(&window.to_be_bytes());
+    // checksum, filled in by send_ip, and the urgent pointer
+    p.extend_from_slice(&[0, 0, 0, 0]);
+    p.extend_from_slice(options);
+    p.extend_from_slice(data);
+    Packet {
+        dst: dst.0,
+        proto: PROTO_TCP,
+        payload: p,
+    }
+}
+
+// What to send back for a segment nobody wants
+fn reset_for(seg: &Segment) -> Packet {
+    let dst = (seg.src, seg.src_port);
+    if seg.flags & ACK != 0 {
+        tcp_packet(seg.dst_port, dst, seg.ack, 0, RST, 0, &[])
+    } else {
+        let len =
+            seg.data.len() as u32 + (seg.flags & SYN != 0) as u32 + (seg.flags & FIN != 0) as u32;
+        let ack = seg.seq.wrapping_add(len);
+        tcp_packet(seg.dst_port, dst, 0, ack, RST | ACK, 0, &[])
+    }
+}
+
+impl Tcp {
+    fn new() -> Self {
+        Tcp {
+            state: TcpState::Closed,
+            local_port: 0,
+            remote: (UNSPECIFIED, 0),
+            iss: 0,
+            snd_una: 0,
+            snd_nxt: 0,
+            snd_wnd: 0,
+            rcv_nxt: 0,
+            mss: TCP_DEFAULT_MSS,
+            send_buf: VecDeque::new(),
+            recv_buf: VecDeque::new(),
+            fin_queued: false,
+            fin_sent: false,
+            fin_received: false,
+            ack_pending: false,
+            retransmit_at: None,
+            rto: TCP_RTO,
+            retries: 0,
+            linger_until: 0,
+            error: None,
+            backlog: VecDeque::new(),
+            backlog_max: 0,
+            parent: None,
+        }
+    }
+
+    fn window(&self) -> u16 {
+        (TCP_BUFFER - self.recv_buf.len()).min(0xffff) as u16
+    }
+
+    // A segment to the peer, acknowledging everything received so far
+    fn segment(&self, flags: u8, seq: u32, data: &[u8]) -> Packet {
+        let ack = if flags & ACK != 0 { self.rcv_nxt } else { 0 };
+        tcp_packet(
+            self.local_port,
+            self.remote,
+            seq,
+            ack,
+            flags,
+            self.window(),
+            data,
+        )
+    }
+
+    // Bytes of send_buf that have been sent. Only meaningful once the SYNs are through.
+    fn in_flight(&self) -> usize {
+        let unacked = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        // The FIN takes a sequence number but isn't in send_buf
+        if self.fin_sent && unacked > 0 {
+            unacked - 1
+        } else {
+            unacked
+        }
+    }
+
+    fn arm(&mut self, now: u64) {
+        if self.retransmit_at.is_none() {
+            self.retransmit_at = Some(now + self.rto);
+        }
+    }
+
+    // Drops the connection, letting the user know why
+    fn reset(&mut self, err: Option<SocketErrors>) {
+        self.state = TcpState::Closed;
+        self.error = err;
+        self.retransmit_at = None;
+        self.send_buf.clear();
+    }
+
+    fn linger(&mut self, state: TcpState, now: u64) {
+        self.state = state;
+        self.linger_until = now
+            + if state == TcpState::TimeWait {
+                TCP_TIME_WAIT
+            } else {
+                TCP_FIN_WAIT_2
+            };
+    }
+
+    // Sends whatever the window allows, then the FIN if the user is done, then a bare ACK if
+    // nothing else carried one
+    fn output(&mut self, now: u64, out: &mut Vec<Packet>) {
+        let sending = match self.state {
+            TcpState::Established
+            | TcpState::CloseWait
+            | TcpState::FinWait1
+            | TcpState::Closing
+            | TcpState::LastAck => true,
+            TcpState::FinWait2 | TcpState::TimeWait => false,
+            _ => return,
+        };
+        if sending && !self.fin_sent {
+            loop {
+                let sent = self.in_flight();
+                // With nothing in flight a closed window still gets a byte, to find out
+                // when it opens
+                let window = if sent == 0 {
+                    (self.snd_wnd as usize).max(1)
+                } else {
+                    (self.snd_wnd as usize).saturating_sub(sent)
+                };
+                let len = (self.send_buf.len() - sent).min(window).min(self.mss);
+                if len == 0 {
+                    break;
+                }
+                let data: Vec<u8> = self.send_buf.iter().skip(sent).take(len).cloned().collect();
+                out.push(self.segment(ACK | PSH, self.snd_nxt, &data));
+                self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+                self.ack_pending = false;
+                self.arm(now);
+            }
+            if self.fin_queued && self.in_flight() == self.send_buf.len() {
+                out.push(self.segment(FIN | ACK, self.snd_nxt, &[]));
+                self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                self.fin_sent = true;
+                self.ack_pending = false;
+                self.arm(now);
+                self.state = match self.state {
+                    TcpState::Established => TcpState::FinWait1,
+                    TcpState::CloseWait => TcpState::LastAck,
+                    // a retransmitted FIN
+                    state => state,
+                };
+            }
+        }
+        if self.ack_pending {
+            out.push(self.segment(ACK, self.snd_nxt, &[]));
+            self.ack_pending = false;
+        }
+    }
+
+    fn timer(&mut self, now: u64, out: &mut Vec<Packet>) {
+        if self.state == TcpState::TimeWait || self.state == TcpState::FinWait2 {
+            if now >= self.linger_until {
+                self.state = TcpState::Closed;
+            }
+            return;
+        }
+        match self.retransmit_at {
+            Some(at) if now >= at => {}
+            _ => return,
+        }
+        self.retransmit_at = None;
+        self.retries += 1;
+        if self.retries > TCP_MAX_RETRIES {
+            self.reset(Some(SocketErrors::TimedOut));
+            return;
+        }
+        self.rto = (self.rto * 2).min(TCP_MAX_RTO);
+        match self.state {
+            TcpState::SynSent => out.push(self.segment(SYN, self.iss, &[])),
+            TcpState::SynReceived => out.push(self.segment(SYN | ACK, self.iss, &[])),
+            _ => {
+                // Go back to the oldest unacknowledged byte, output sends it all again
+                self.snd_nxt = self.snd_una;
+                self.fin_sent = false;
+            }
+        }
+        self.arm(now);
+    }
+
+    // Handles a segment for this connection. Returns true when a connection that came in
+    // through a listening socket finishes its handshake.
+    fn input(&mut self, seg: &Segment, now: u64, out: &mut Vec<Packet>) -> bool {
+        if self.state == TcpState::SynSent {
+            self.syn_sent_input(seg, now, out);
+            return false;
+        }
+
+        // Anything already received is trimmed off, anything past rcv_nxt is dropped - there's
+        // no reordering
+        let mut data = seg.data;
+        let mut fin = seg.flags & FIN != 0;
+        let len = data.len() as u32 + fin as u32;
+        let behind = self.rcv_nxt.wrapping_sub(seg.seq);
+        let acceptable = if len == 0 {
+            seg.seq == self.rcv_nxt
+        } else {
+            seg.seq == self.rcv_nxt
+                || (seq_lt(seg.seq, self.rcv_nxt)
+                    && seq_lt(self.rcv_nxt, seg.seq.wrapping_add(len)))
+        };
+        if !acceptable {
+            if seg.flags & RST == 0 {
+                self.ack_pending = true;
+                self.output(now, out);
+            }
+            return false;
+        }
+        if seq_lt(seg.seq, self.rcv_nxt) {
+            data = &data[(behind as usize).min(data.len())..];
+        }
+        if seg.flags & RST != 0 {
+            let err = match self.state {
+                TcpState::SynReceived => None,
+                _ => Some(SocketErrors::ConnReset),
+            };
+            self.reset(err);
+            return false;
+        }
+        if seg.flags & SYN != 0 {
+            // A SYN inside the window means the peer lost track of the connection
+            out.push(self.segment(RST, self.snd_nxt, &[]));
+            self.reset(Some(SocketErrors::ConnReset));
+            return false;
+        }
+        if seg.flags & ACK == 0 {
+            return false;
+        }
+
+        let mut established = false;
+        if self.state == TcpState::SynReceived {
+            if seg.ack != self.snd_nxt {
+                out.push(reset_for(seg));
+                return false;
+            }
+            self.state = TcpState::Established;
+            self.snd_una = seg.ack;
+            self.retransmit_at = None;
+            self.retries = 0;
+            self.rto = TCP_RTO;
+            established = true;
+        }
+        if seq_lt(self.snd_una, seg.ack) && seq_le(seg.ack, self.snd_nxt) {
+            let acked = seg.ack.wrapping_sub(self.snd_una) as usize;
+            let fin_acked = self.fin_sent && seg.ack == self.snd_nxt;
+            let data_acked = if fin_acked { acked - 1 } else { acked };
+            self.send_buf.drain(..data_acked.min(self.send_buf.len()));
+            self.snd_una = seg.ack;
+            self.retries = 0;
+            self.rto = TCP_RTO;
+            self.retransmit_at = if self.snd_una == self.snd_nxt {
+                None
+            } else {
+                Some(now + self.rto)
+            };
+            if fin_acked {
+                match self.state {
+                    TcpState::FinWait1 => self.linger(TcpState::FinWait2, now),
+                    TcpState::Closing => self.linger(TcpState::TimeWait, now),
+                    TcpState::LastAck => self.state = TcpState::Closed,
+                    _ => {}
+                }
+            }
+        } else if seq_lt(self.snd_nxt, seg.ack) {
+            // Acknowledges something we never sent
+            self.ack_pending = true;
+            self.output(now, out);
+            return established;
+        }
+        if seq_le(self.snd_una, seg.ack) {
+            self.snd_wnd = seg.window as u32;
+        }
+
+        let receiving = match self.state {
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 => true,
+            _ => false,
+        };
+        if receiving && !data.is_empty() {
+            let take = data.len().min(TCP_BUFFER - self.recv_buf.len());
+            self.recv_buf.extend(data[..take].iter());
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(take as u32);
+            // The FIN comes after the bytes we had no room for
+            fin &= take == data.len();
+            self.ack_pending = true;
+        }
+        if receiving && fin {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+            self.ack_pending = true;
+            match self.state {
+                TcpState::Established => self.state = TcpState::CloseWait,
+                TcpState::FinWait1 if self.snd_una == self.snd_nxt => {
+                    self.linger(TcpState::TimeWait, now)
+                }
+                TcpState::FinWait1 => self.state = TcpState::Closing,
+                _ => self.linger(TcpState::TimeWait, now),
+            }
+        }
+        self.output(now, out);
+        established
+    }
+
+    fn syn_sent_input(&mut self, seg: &Segment, now: u64, out: &mut Vec<Packet>) {
+        let ack_ok = seg.ack == self.iss.wrapping_add(1);
+        if seg.flags & ACK != 0 && !ack_ok {
+            if seg.flags & RST == 0 {
+                out.push(reset_for(seg));
+            }
+            return;
+        }
+        if seg.flags & RST != 0 {
+            if seg.flags & ACK != 0 {
+                self.reset(Some(SocketErrors::Refused));
+            }
+            return;
+        }
+        if seg.flags & SYN != 0 && seg.flags & ACK != 0 {
+            self.rcv_nxt = seg.seq.wrapping_add(1);
+            self.snd_una = seg.ack;
+            self.snd_wnd = seg.window as u32;
+            if let Some(mss) = seg.mss {
+                self.mss = (mss as usize).min(TCP_MSS);
+            }
+            self.state = TcpState::Established;
+            self.retransmit_at = None;
+            self.retries = 0;
+            self.rto = TCP_RTO;
+            self.ack_pending = true;
+            self.output(now, out);
+        }
+    }
+}
+
+impl Socket {
+    // Nothing left to do for it once nobody refers to it
+    fn finished(&self) -> bool {
+        match &self.kind {
+            Kind::Udp(_) => true,
+            Kind::Tcp(tcp) => tcp.state == TcpState::Closed,
+        }
+    }
+}
+
+impl Stack {
+    fn poll(&mut self) {
+        let now = now_ms();
+        while let Some(frame) = net::receive(self.dev) {
+            self.input(&frame, now);
+        }
+        self.timers(now);
+    }
+
+    // Sends everything queued in out
+    fn flush(&mut self) {
+        for packet in core::mem::replace(&mut self.out, Vec::new()) {
+            self.send_ip(packet);
+        }
+    }
+
+    fn send_frame(&self, dst: Mac, ethertype: u16, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(ETH_HEADER + payload.len());
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&self.mac);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        // A full queue is just another lost packet
+        let _ = net::send(self.dev, &frame);
+    }
+
+    fn send_arp(&self, op: u16, dst: Mac, target_mac: Mac, target: Ipv4) {
+        let addr = self.config.map_or(UNSPECIFIED, |config| config.addr);
+        let mut p = Vec::with_capacity(28);
+        p.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+        p.extend_from_slice(&op.to_be_bytes());
+        p.extend_from_slice(&self.mac);
+        p.extend_from_slice(&addr);
+        p.extend_from_slice(&target_mac);
+        p.extend_from_slice(&target);
+        self.send_frame(dst, ETHERTYPE_ARP, &p);
+    }
+
+    fn send_ip(&mut self, packet: Packet) {
+        let Packet {
+            dst,
+            proto,
+            mut payload,
+        } = packet;
+        let src = self.config.map_or(UNSPECIFIED, |config| config.addr);
+        let at = match proto {
+            PROTO_UDP => Some(6),
+            PROTO_TCP => Some(16),
+            _ => None,
+        };
+        if let Some(at) = at {
+            let mut sum = l4_checksum(src, dst, proto, &payload);
+            // 0 means no checksum for UDP
+            if sum == 0 && proto == PROTO_UDP {
+                sum = 0xffff;
+            }
+            payload[at..at + 2].copy_from_slice(&sum.to_be_bytes());
+        }
+
+        let mut ip = Vec::with_capacity(IP_HEADER + payload.len());
+        ip.extend_from_slice(&[0x45, 0]);
+        ip.extend_from_slice(&((IP_HEADER + payload.len()) as u16).to_be_bytes());
+        ip.extend_from_slice(&self.ip_id.to_be_bytes());
+        // Don't fragment, TTL 64
+        ip.extend_from_slice(&[0x40, 0, 64, proto, 0, 0]);
+        ip.extend_from_slice(&src);
+        ip.extend_from_slice(&dst);
+        let sum = checksum(&ip, 0);
+        ip[10..12].copy_from_slice(&sum.to_be_bytes());
+        ip.extend_from_slice(&payload);
+        self.ip_id = self.ip_id.wrapping_add(1);
+
+        let hop = match self.config {
+            Some(config) if dst != BROADCAST && dst != config.broadcast() => {
+                if config.on_link(dst) {
+                    dst
+                } else {
+                    config.gateway
+                }
+            }
+            _ => {
+                self.send_frame(BROADCAST_MAC, ETHERTYPE_IPV4, &ip);
+                return;
+            }
+        };
+        match self.arp.get(&hop) {
+            Some(mac) => self.send_frame(*mac, ETHERTYPE_IPV4, &ip),
+            None if self.arp_pending.len() < ARP_QUEUE => {
+                let asked = self.arp_pending.iter().any(|pending| pending.0 == hop);
+                self.arp_pending.push((hop, ip, now_ms()));
+                if !asked {
+                    self.send_arp(ARP_REQUEST, BROADCAST_MAC, [0; 6], hop);
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn input(&mut self, frame: &[u8], now: u64) {
+        if frame.len() < ETH_HEADER {
+            return;
+        }
+        let payload = &frame[ETH_HEADER..];
+        match be16(frame, 12) {
+            ETHERTYPE_ARP => self.arp_input(payload),
+            ETHERTYPE_IPV4 => self.ip_input(payload, now),
+            _ => {}
+        }
+    }
+
+    fn arp_input(&mut self, p: &[u8]) {
+        if p.len() < 28 || be16(p, 0) != 1 || be16(p, 2) != ETHERTYPE_IPV4 || p[4] != 6 || p[5] != 4
+        {
+            return;
+        }
+        let mut sender_mac = [0u8; 6];
+        sender_mac.copy_from_slice(&p[8..14]);
+        let sender = ipv4(p, 14);
+        let target = ipv4(p, 24);
+        let for_us = self.config.map_or(false, |config| config.addr == target);
+        if for_us || self.arp.contains_key(&sender) {
+            self.arp.insert(sender, sender_mac);
+            // Send what was waiting for it
+            let pending = core::mem::replace(&mut self.arp_pending, Vec::new());
+            for (hop, ip, queued) in pending {
+                if hop == sender {
+                    self.send_frame(sender_mac, ETHERTYPE_IPV4, &ip);
+                } else {
+                    self.arp_pending.push((hop, ip, queued));
+                }
+            }
+        }
+        if be16(p, 6) == ARP_REQUEST && for_us {
+            self.send_arp(ARP_REPLY, sender_mac, sender_mac, sender);
+        }
+    }
+
+    fn ip_input(&mut self, p: &[u8], now: u64) {
+        if p.len() < IP_HEADER || p[0] >> 4 != 4 {
+            return;
+        }
+        let header_len = (p[0] & 0xf) as usize * 4;
+        let total = be16(p, 2) as usize;
+        if header_len < IP_HEADER
+            || total < header_len
+            || total > p.len()
+            || checksum(&p[..header_len], 0) != 0
+        {
+            return;
+        }
+        // No reassembly
+        if be16(p, 6) & 0x3fff != 0 {
+            return;
+        }
+        let src = ipv4(p, 12);
+        let dst = ipv4(p, 16);
+        let accept = match self.config {
+            Some(config) => dst == config.addr || dst == BROADCAST || dst == config.broadcast(),
+            // DHCP offers may go to the address being offered
+            None => true,
+        };
+        if !accept {
+            return;
+        }
+        let data = &p[header_len..total];
+        match p[9] {
+            PROTO_ICMP => self.icmp_input(src, data),
+            PROTO_UDP => self.udp_input(src, dst, data),
+            PROTO_TCP => self.tcp_input(src, dst, data, now),
+            _ => {}
+        }
+    }
+
+    fn icmp_input(&mut self, src: Ipv4, data: &[u8]) {
+        if data.len() < 8 || data[0] != ICMP_ECHO_REQUEST || checksum(data, 0) != 0 {
+            return;
+        }
+        if self.config.is_none() {
+            return;
+        }
+        let mut reply = data.to_vec();
+        reply[0] = ICMP_ECHO_REPLY;
+        reply[2..4].copy_from_slice(&[0, 0]);
+        let sum = checksum(&reply, 0);
+        reply[2..4].copy_from_slice(&sum.to_be_bytes());
+        self.out.push(Packet {
+            dst: src,
+            proto: PROTO_ICMP,
+            payload: reply,
+        });
+    }
+
+    fn udp_input(&mut self, src: Ipv4, dst: Ipv4, data: &[u8]) {
+        if data.len() < UDP_HEADER {
+            return;
+        }
+        let len = be16(data, 4) as usize;
+        if len < UDP_HEADER || len > data.len() {
+            return;
+        }
+        let data = &data[..len];
+        if be16(data, 6) != 0 && l4_checksum(src, dst, PROTO_UDP, data) != 0 {
+            return;
+        }
+        let src_port = be16(data, 0);
+        let dst_port = be16(data, 2);
+        let payload = &data[UDP_HEADER..];
+        if dst_port == DHCP_CLIENT_PORT {
+            self.dhcp_input(payload);
+            return;
+        }
+        for sock in self.sockets.values_mut() {
+            if sock.local.1 != dst_port || (sock.local.0 != UNSPECIFIED && sock.local.0 != dst) {
+                continue;
+            }
+            if let Kind::Udp(udp) = &mut sock.kind {
+                if udp.remote.map_or(true, |remote| remote == (src, src_port)) {
+                    if udp.queue.len() < UDP_QUEUE {
+                        udp.queue.push_back((src, src_port, payload.to_vec()));
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    fn tcp_input(&mut self, src: Ipv4, dst: Ipv4, data: &[u8], now: u64) {
+        if data.len() < TCP_HEADER || l4_checksum(src, dst, PROTO_TCP, data) != 0 {
+            return;
+        }
+        let header_len = (data[12] >> 4) as usize * 4;
+        if header_len < TCP_HEADER || header_len > data.len() {
+            return;
+        }
+        // The only option we care about is the MSS
+        let mut mss = None;
+        let mut options = &data[TCP_HEADER..header_len];
+        while let Some(&kind) = options.first() {
+            match kind {
+                0 => break,
+                1 => options = &options[1..],
+                _ => {
+                    let len = *options.get(1).unwrap_or(&0) as usize;
+                    if len < 2 || len > options.len() {
+                        break;
+                    }
+                    // An MSS of 0 would stall sending, so it counts as no MSS at all
+                    if kind == 2 && len == 4 && be16(options, 2) != 0 {
+                        mss = Some(be16(options, 2));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+        let seg = Segment {
+            src,
+            src_port: be16(data, 0),
+            dst_port: be16(data, 2),
+            seq: be32(data, 4),
+            ack: be32(data, 8),
+            flags: data[13],
+            window: be16(data, 14),
+            mss,
+            data: &data[header_len..],
+        };
+
+        // A connection first, then a listener
+        let mut listener = None;
+        let mut connection = None;
+        for (id, sock) in self.sockets.iter() {
+            if let Kind::Tcp(tcp) = &sock.kind {
+                if sock.local.1 != seg.dst_port {
+                    continue;
+                }
+                match tcp.state {
+                    TcpState::Closed => {}
+                    TcpState::Listen => listener = Some(*id),
+                    _ if tcp.remote == (src, seg.src_port) => connection = Some(*id),
+                    _ => {}
+                }
+            }
+        }
+        match (connection, listener) {
+            (Some(id), _) => {
+                let tcp = match self.sockets.get_mut(&id).map(|sock| &mut sock.kind) {
+                    Some(Kind::Tcp(tcp)) => tcp,
+                    _ => return,
+                };
+                if tcp.input(&seg, now, &mut self.out) {
+                    let parent = tcp.parent;
+                    self.accepted(parent, id);
+                }
+            }
+            (None, Some(id)) => self.listen_input(id, dst, &seg, now),
+            (None, None) if seg.flags & RST == 0 => self.out.push(reset_for(&seg)),
+            _ => {}
+        }
+    }
+
+    // A SYN for a listening socket starts a new connection
+    fn listen_input(&mut self, id: u32, dst: Ipv4, seg: &Segment, now: u64) {
+        if seg.flags & RST != 0 {
+            return;
+        }
+        if seg.flags & ACK != 0 || seg.flags & SYN == 0 {
+            self.out.push(reset_for(seg));
+            return;
+        }
+        let half_open = self
+            .sockets
+            .values()
+            .filter(|sock| match &sock.kind {
+                Kind::Tcp(tcp) => tcp.parent == Some(id) && tcp.state == TcpState::SynReceived,
+                _ => false,
+            })
+            .count();
+        let (backlog, backlog_max) = match self.sockets.get(&id).map(|sock| &sock.kind) {
+            Some(Kind::Tcp(tcp)) => (tcp.backlog.len(), tcp.backlog_max),
+            _ => return,
+        };
+        // The client tries again later
+        if half_open + backlog >= backlog_max {
+            return;
+        }
+        let mut tcp = Tcp::new();
+        tcp.state = TcpState::SynReceived;
+        tcp.local_port = seg.dst_port;
+        tcp.remote = (seg.src, seg.src_port);
+        tcp.iss = self.iss();
+        tcp.snd_una = tcp.iss;
+        tcp.snd_nxt = tcp.iss.wrapping_add(1);
+        tcp.snd_wnd = seg.window as u32;
+        tcp.rcv_nxt = seg.seq.wrapping_add(1);
+        if let Some(mss) = seg.mss {
+            tcp.mss = (mss as usize).min(TCP_MSS);
+        }
+        tcp.parent = Some(id);
+        self.out.push(tcp.segment(SYN | ACK, tcp.iss, &[]));
+        tcp.arm(now);
+        let child = self.next_socket;
+        self.next_socket += 1;
+        self.sockets.insert(
+            child,
+            Socket {
+                refs: 0,
+                local: (dst, seg.dst_port),
+                kind: Kind::Tcp(tcp),
+            },
+        );
+    }
+
+    // A connection finished its handshake, and waits for its listener's accept
+    fn accepted(&mut self, parent: Option<u32>, id: u32) {
+        let listening = parent.and_then(|parent| self.sockets.get_mut(&parent));
+        if let Some(Socket {
+            kind: Kind::Tcp(listener),
+            ..
+        }) = listening
+        {
+            if listener.state == TcpState::Listen {
+                listener.backlog.push_back(id);
+                return;
+            }
+        }
+        if let Some(Socket {
+            kind: Kind::Tcp(tcp),
+            ..
+        }) = self.sockets.get_mut(&id)
+        {
+            self.out.push(tcp.segment(RST, tcp.snd_nxt, &[]));
+            tcp.reset(None);
+        }
+    }
+
+    fn timers(&mut self, now: u64) {
+        self.dhcp_timer(now);
+
+        if now >= self.arp_retry_at {
+            self.arp_retry_at = now + ARP_RETRY;
+            self.arp_pending
+                .retain(|pending| now < pending.2 + ARP_GIVE_UP);
+            let mut hops: Vec<Ipv4> = self.arp_pending.iter().map(|pending| pending.0).collect();
+            hops.dedup();
+            for hop in hops {
+                self.send_arp(ARP_REQUEST, BROADCAST_MAC, [0; 6], hop);
+            }
+        }
+
+        for sock in self.sockets.values_mut() {
+            if let Kind::Tcp(tcp) = &mut sock.kind {
+                tcp.timer(now, &mut self.out);
+                tcp.output(now, &mut self.out);
+            }
+        }
+
+        // Forget sockets nobody refers to once they're done
+        let done: Vec<u32> = self
+            .sockets
+            .iter()
+            .filter(|(_, sock)| sock.refs == 0 && sock.finished())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in done {
+            self.sockets.remove(&id);
+            for sock in self.sockets.values_mut() {
+                if let Kind::Tcp(tcp) = &mut sock.kind {
+                    tcp.backlog.retain(|child| *child != id);
+                }
+            }
+        }
+    }
+
+    fn dhcp_timer(&mut self, now: u64) {
+        if let Dhcp::Done = self.dhcp {
+            return;
+        }
+        if now < self.dhcp_next {
+            return;
+        }
+        if self.dhcp_attempts >= DHCP_ATTEMPTS {
+            println!("net: no answer from DHCP, using a static address");
+            self.configure(STATIC_CONFIG);
+            return;
+        }
+        self.dhcp_attempts += 1;
+        self.dhcp_next = now + DHCP_RETRY;
+        let message = match self.dhcp {
+            Dhcp::Request(addr, server) => self.dhcp_message(DHCP_REQUEST, Some((addr, server))),
+            _ => self.dhcp_message(DHCP_DISCOVER, None),
+        };
+        self.out.push(Packet {
+            dst: BROADCAST,
+            proto: PROTO_UDP,
+            payload: udp_packet(DHCP_CLIENT_PORT, DHCP_SERVER_PORT, &message),
+        });
+    }
+
+    fn dhcp_message(&self, message_type: u8, request: Option<(Ipv4, Ipv4)>) -> Vec<u8> {
+        let mut m = Vec::with_capacity(300);
+        // BOOTREQUEST over Ethernet
+        m.extend_from_slice(&[1, 1, 6, 0]);
+        m.extend_from_slice(&self.dhcp_xid.to_be_bytes());
+        // Ask for broadcast replies, since we can't take unicast ones yet
+        m.extend_from_slice(&[0, 0, 0x80, 0]);
+        m.resize(28, 0);
+        m.extend_from_slice(&self.mac);
+        m.resize(236, 0);
+        m.extend_from_slice(&DHCP_MAGIC);
+        m.extend_from_slice(&[53, 1, message_type]);
+        if let Some((addr, server)) = request {
+            m.extend_from_slice(&[50, 4]);
+            m.extend_from_slice(&addr);
+            m.extend_from_slice(&[54, 4]);
+            m.extend_from_slice(&server);
+        }
+        // Ask for the netmask and router
+        m.extend_from_slice(&[55, 2, 1, 3, 255]);
+        // Some servers ignore anything shorter than a BOOTP message
+        m.resize(300, 0);
+        m
+    }
+
+    fn dhcp_input(&mut self, p: &[u8]) {
+        if p.len() < 240 || p[0] != 2 || be32(p, 4) != self.dhcp_xid || p[236..240] != DHCP_MAGIC {
+            return;
+        }
+        let offered = ipv4(p, 16);
+        let mut message_type = None;
+        let mut netmask = None;
+        let mut router = None;
+        let mut server = None;
+        let mut options = &p[240..];
+        while options.len() >= 2 && options[0] != 255 {
+            if options[0] == 0 {
+                options = &options[1..];
+                continue;
+            }
+            let len = options[1] as usize;
+            if options.len() < 2 + len {
+                break;
+            }
+            let value = &options[2..2 + len];
+            match (options[0], len) {
+                (53, 1) => message_type = Some(value[0]),
+                (1, 4) => netmask = Some(ipv4(value, 0)),
+                (3, _) if len >= 4 => router = Some(ipv4(value, 0)),
+                (54, 4) => server = Some(ipv4(value, 0)),
+                _ => {}
+            }
+            options = &options[2 + len..];
+        }
+        match (message_type, &self.dhcp, server) {
+            (Some(DHCP_OFFER), Dhcp::Discover, Some(server)) => {
+                self.dhcp = Dhcp::Request(offered, server);
+                self.dhcp_attempts = 0;
+                self.dhcp_next = 0;
+            }
+            (Some(DHCP_ACK), Dhcp::Request(addr, _), _) if *addr == offered => {
+                self.configure(Config {
+                    addr: offered,
+                    netmask: netmask.unwrap_or([255, 255, 255, 0]),
+                    gateway: router.unwrap_or(UNSPECIFIED),
+                });
+            }
+            (Some(DHCP_NAK), Dhcp::Request(..), _) => {
+                self.dhcp = Dhcp::Discover;
+                self.dhcp_attempts = 0;
+                self.dhcp_next = 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn configure(&mut self, config: Config) {
+        self.config = Some(config);
+        self.dhcp = Dhcp::Done;
+        let (a, g) = (config.addr, config.gateway);
+        println!(
+            "net: address {}.{}.{}.{}, gateway {}.{}.{}.{}",
+            a[0], a[1], a[2], a[3], g[0], g[1], g[2], g[3]
+        );
+    }
+
+    fn iss(&mut self) -> u32 {
+        // Random, plus a clock ticking every 4us like RFC 793 has it
+        (random::splitmix64(&mut self.seed) as u32).wrapping_add((now_ms() * 250) as u32)
+    }
+
+    fn port_in_use(&self, port: u16, stream: bool) -> bool {
+        self.sockets.values().any(|sock| {
+            sock.local.1 == port
+                && match &sock.kind {
+                    Kind::Tcp(tcp) => stream && tcp.state != TcpState::Closed,
+                    Kind::Udp(_) => !stream,
+                }
+        })
+    }
+
+    fn ephemeral_port(&mut self, stream: bool) -> Option<u16> {
+        for _ in EPHEMERAL_PORTS..=u16::MAX {
+            let port = self.next_port;
+            self.next_port = if port == u16::MAX {
+                EPHEMERAL_PORTS
+            } else {
+                port + 1
+            };
+            if !self.port_in_use(port, stream) {
+                return Some(port);
+            }
+        }
+        None
+    }
+
+    fn socket(&mut self, id: u32) -> Result<&mut Socket, SocketErrors> {
+        self.sockets.get_mut(&id).ok_or(SocketErrors::BadFd)
+    }
+
+    pub fn is_stream(&self, id: u32) -> bool {
+        match self.sockets.get(&id).map(|sock| &sock.kind) {
+            Some(Kind::Tcp(_)) => true,
+            _ => false,
+        }
+    }
+
+    // Sockets start out with one reference, for the descriptor they're created for
+    pub fn create(&mut self, stream: bool) -> u32 {
+        let id = self.next_socket;
+        self.next_socket += 1;
+        let kind = if stream {
+            Kind::Tcp(Tcp::new())
+        } else {
+            Kind::Udp(Udp {
+                remote: None,
+                queue: VecDeque::new(),
+            })
+        };
+        self.sockets.insert(
+            id,
+            Socket {
+                refs: 1,
+                local: (UNSPECIFIED, 0),
+                kind,
+            },
+        );
+        id
+    }
+
+    pub fn dup(&mut self, id: u32) {
+        if let Ok(sock) = self.socket(id) {
+            sock.refs += 1;
+        }
+    }
+
+    // Drops a reference. The last one closes the socket, though a connection stays around
+    // until its data and FIN are through.
+    pub fn release(&mut self, id: u32) {
+        let now = now_ms();
+        let sock = match self.sockets.get_mut(&id) {
+            Some(sock) => sock,
+            None => return,
+        };
+        sock.refs = sock.refs.saturating_sub(1);
+        if sock.refs > 0 {
+            return;
+        }
+        let tcp = match &mut sock.kind {
+            Kind::Tcp(tcp) => tcp,
+            Kind::Udp(_) => return,
+        };
+        match tcp.state {
+            TcpState::Listen => {
+                tcp.state = TcpState::Closed;
+                // Connections nobody accepted are reset
+                for sock in self.sockets.values_mut() {
+                    if let Kind::Tcp(child) = &mut sock.kind {
+                        if child.parent == Some(id) && sock.refs == 0 {
+                            self.out.push(child.segment(RST, child.snd_nxt, &[]));
+                            child.reset(None);
+                        }
+                    }
+                }
+            }
+            TcpState::SynSent => tcp.reset(None),
+            TcpState::SynReceived | TcpState::Established | TcpState::CloseWait => {
+                tcp.fin_queued = true;
+                tcp.output(now, &mut self.out);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn bind(&mut self, id: u32, addr: Ipv4, port: u16) -> Result<(), SocketErrors> {
+        let stream = self.is_stream(id);
+        if addr != UNSPECIFIED && self.config.map_or(true, |config| config.addr != addr) {
+            return Err(SocketErrors::AddrNotAvailable);
+        }
+        if self.socket(id)?.local.1 != 0 {
+            return Err(SocketErrors::Invalid);
+        }
+        let port = match port {
+            0 => self.ephemeral_port(stream).ok_or(SocketErrors::AddrInUse)?,
+            port if self.port_in_use(port, stream) => return Err(SocketErrors::AddrInUse),
+            port => port,
+        };
+        self.socket(id)?.local = (addr, port);
+        Ok(())
+    }
+
+    fn bind_any(&mut self, id: u32) -> Result<(), SocketErrors> {
+        if self.socket(id)?.local.1 == 0 {
+            self.bind(id, UNSPECIFIED, 0)?;
+        }
+        Ok(())
+    }
+
+    pub fn listen(&mut self, id: u32, backlog: usize) -> Result<(), SocketErrors> {
+        self.bind_any(id)?;
+        match &mut self.socket(id)?.kind {
+            Kind::Tcp(tcp) if tcp.state == TcpState::Closed || tcp.state == TcpState::Listen => {
+                tcp.state = TcpState::Listen;
+                tcp.backlog_max = backlog.max(1).min(MAX_BACKLOG);
+                Ok(())
+            }
+            Kind::Tcp(_) => Err(SocketErrors::Invalid),
+            Kind::Udp(_) => Err(SocketErrors::NotSupported),
+        }
+    }
+
+    // Ok(false) means a TCP handshake has started, see connect_result
+    pub fn connect(&mut self, id: u32, addr: Ipv4, port: u16) -> Result<bool, SocketErrors> {
+        if self.config.is_none() {
+            return Err(SocketErrors::NetUnreachable);
+        }
+        self.bind_any(id)?;
+        let iss = self.iss();
+        let now = now_ms();
+        // Borrowed apart from self.out
+        let sock = self.sockets.get_mut(&id).ok_or(SocketErrors::BadFd)?;
+        let local_port = sock.local.1;
+        match &mut sock.kind {
+            Kind::Udp(udp) => {
+                udp.remote = Some((addr, port));
+                Ok(true)
+            }
+            Kind::Tcp(tcp) => match tcp.state {
+                TcpState::Closed if tcp.remote.1 == 0 => {
+                    tcp.local_port = local_port;
+                    tcp.remote = (addr, port);
+                    tcp.iss = iss;
+                    tcp.snd_una = iss;
+                    tcp.snd_nxt = iss.wrapping_add(1);
+                    tcp.state = TcpState::SynSent;
+                    self.out.push(tcp.segment(SYN, iss, &[]));
+                    tcp.arm(now);
+                    Ok(false)
+                }
+                TcpState::Listen => Err(SocketErrors::Invalid),
+                TcpState::SynSent => Err(SocketErrors::Already),
+                _ => Err(SocketErrors::IsConnected),
+            },
+        }
+    }
+
+    // Whether a connect that started a handshake has finished
+    pub fn connect_result(&mut self, id: u32) -> Result<bool, SocketErrors> {
+        match &mut self.socket(id)?.kind {
+            Kind::Tcp(tcp) => match tcp.state {
+                TcpState::SynSent => Ok(false),
+                TcpState::Closed => Err(tcp.error.take().unwrap_or(SocketErrors::Refused)),
+                _ => Ok(true),
+            },
+            Kind::Udp(_) => Ok(true),
+        }
+    }
+
+    // The oldest connection waiting on a listening socket, with the peer's address
+    pub fn accept(&mut self, id: u32) -> Result<Option<(u32, Ipv4, u16)>, SocketErrors> {
+        let child = match &mut self.socket(id)?.kind {
+            Kind::Tcp(tcp) if tcp.state == TcpState::Listen => match tcp.backlog.pop_front() {
+                Some(child) => child,
+                None => return Ok(None),
+            },
+            Kind::Tcp(_) => return Err(SocketErrors::Invalid),
+            Kind::Udp(_) => return Err(SocketErrors::NotSupported),
+        };
+        let sock = self.socket(child)?;
+        sock.refs = 1;
+        match &mut sock.kind {
+            Kind::Tcp(tcp) => {
+                tcp.parent = None;
+                Ok(Some((child, tcp.remote.0, tcp.remote.1)))
+            }
+            Kind::Udp(_) => Err(SocketErrors::Invalid),
+        }
+    }
+
+    // Returns the number of bytes taken, None if there's no room yet
+    pub fn send(
+        &mut self,
+        id: u32,
+        data: &[u8],
+        to: Option<(Ipv4, u16)>,
+    ) -> Result<Option<usize>, SocketErrors> {
+        let now = now_ms();
+        if !self.is_stream(id) {
+            if data.len() > MAX_DATAGRAM {
+                return Err(SocketErrors::MessageSize);
+            }
+            if self.config.is_none() {
+                return Err(SocketErrors::NetUnreachable);
+            }
+            self.bind_any(id)?;
+        }
+        let sock = self.sockets.get_mut(&id).ok_or(SocketErrors::BadFd)?;
+        let local_port = sock.local.1;
+        match &mut sock.kind {
+            Kind::Udp(udp) => {
+                let (dst, port) = to.or(udp.remote).ok_or(SocketErrors::DestAddrRequired)?;
+                self.out.push(Packet {
+                    dst,
+                    proto: PROTO_UDP,
+                    payload: udp_packet(local_port, port, data),
+                });
+                Ok(Some(data.len()))
+            }
+            Kind::Tcp(tcp) => {
+                if let Some(err) = tcp.error.take() {
+                    return Err(err);
+                }
+                match tcp.state {
+                    TcpState::Established | TcpState::CloseWait if !tcp.fin_queued => {}
+                    TcpState::SynSent | TcpState::SynReceived => return Ok(None),
+                    TcpState::Closed | TcpState::Listen => return Err(SocketErrors::NotConnected),
+                    _ => return Err(SocketErrors::BrokenPipe),
+                }
+                let len = data.len().min(TCP_BUFFER - tcp.send_buf.len());
+                if len == 0 && !data.is_empty() {
+                    return Ok(None);
+                }
+                tcp.send_buf.extend(data[..len].iter());
+                tcp.output(now, &mut self.out);
+                Ok(Some(len))
+            }
+        }
+    }
+
+    // Up to max bytes and who sent them, None if nothing has arrived yet. An empty Vec means
+    // the peer closed its end.
+    pub fn recv(
+        &mut self,
+        id: u32,
+        max: usize,
+    ) -> Result<Option<(Vec<u8>, Ipv4, u16)>, SocketErrors> {
+        let now = now_ms();
+        let sock = self.sockets.get_mut(&id).ok_or(SocketErrors::BadFd)?;
+        match &mut sock.kind {
+            Kind::Udp(udp) => Ok(udp.queue.pop_front().map(|(src, port, mut data)| {
+                // The rest of the datagram is lost, like on Linux
+                data.truncate(max);
+                (data, src, port)
+            })),
+            Kind::Tcp(tcp) => {
+                if !tcp.recv_buf.is_empty() {
+                    let was_full = (tcp.window() as usize) < tcp.mss;
+                    let len = max.min(tcp.recv_buf.len());
+                    let data: Vec<u8> = tcp.recv_buf.drain(..len).collect();
+                    // Tell the peer there's room again
+                    if was_full {
+                        tcp.ack_pending = true;
+                        tcp.output(now, &mut self.out);
+                    }
+                    return Ok(Some((data, tcp.remote.0, tcp.remote.1)));
+                }
+                if let Some(err) = tcp.error.take() {
+                    return Err(err);
+                }
+                if tcp.fin_received {
+                    return Ok(Some((Vec::new(), tcp.remote.0, tcp.remote.1)));
+                }
+                match tcp.state {
+                    TcpState::Closed | TcpState::Listen => Err(SocketErrors::NotConnected),
+                    _ => Ok(None),
+                }
+            }
+        }
+    }
+}
@@ -1,83 +1,470 @@
 use crate::block::SECTOR_SIZE;
-use crate::process::{add_kernel_process, add_user_process};
+use crate::input::InputEvent;
+use crate::process::{add_elf_process, add_kernel_process, add_user_process};
+use crate::socket::{SockAddrIn, AF_INET, SOCK_STREAM};
 use crate::syscall::{
-    exit_process, get_inode, get_pid, /*get_time, putchar,*/ read_block, sleep, sys_write,
-    test_syscall, /*wait_process,*/ yield_process,
+    block_info, close, connect, execv, exit_process, fallocate, fstat, fsync, get_pid, getdents64,
+    getrandom, lseek, mkdir, mmap, open, /*get_time, putchar,*/ read_block, recvfrom, rmdir,
+    sendto, sleep, socket, sync, sys_read, sys_write, test_syscall, unlink,
+    /*wait_process,*/ yield_process,
 };
-use crate::{block, kmem, shell};
+use crate::{block, cpu, fdt, fs, kmem, net, shell, virtio};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 pub fn init_processes() {
     // add_user_process(process_that_exits);
     // add_user_process(process_2);
     // add_user_process(process_sleepy);
     // add_kernel_process(kernel_block_process);
+    // add_kernel_process(block_backlog_tester);
     // add_kernel_process(process_shell);
     // add_user_process(user_proc);
+    // add_kernel_process(elf_tester);
+    // add_kernel_process(exec_tester);
+    // add_kernel_process(file_tester);
+    // add_kernel_process(write_tester);
+    // add_kernel_process(minix_versions_tester);
+    // add_kernel_process(random_tester);
+    // add_kernel_process(net_tester);
+    // add_kernel_process(socket_tester);
+    // add_kernel_process(input_tester);
+    // add_kernel_process(fb_tester);
+    // add_kernel_process(hvc_tester);
+    // add_kernel_process(fdt_tester);
+    // add_kernel_process(smp_tester);
+    // #[cfg(feature = "sbi")]
+    // add_kernel_process(sbi_tester);
     add_kernel_process(minix_tester);
 }
 
+pub fn elf_tester() {
+    let pid = add_elf_process("/bin/hello");
+    if pid > 0 {
+        println!("started program as process {}", pid);
+    } else {
+        println!("couldn't start program");
+    }
+}
+
 pub fn process_that_exits() {
     let s = "Welcome to the process that exits!\r\n";
     sys_write(1, s.as_ptr(), s.len());
     println!("exiting now");
 }
 
+pub fn exec_tester() {
+    // Kernel process replaces itself with a user program
+    let path = "/bin/hello\0";
+    let arg0 = "hello\0";
+    let arg1 = "world\0";
+    let argv = [arg0.as_ptr(), arg1.as_ptr(), core::ptr::null()];
+    let envp = [core::ptr::null()];
+    execv(path.as_ptr(), argv.as_ptr(), envp.as_ptr());
+    println!("execv failed");
+}
+
+pub fn file_tester() {
+    let fd = open("/hello.txt\0".as_ptr(), crate::vfs::O_RDONLY, 0);
+    if fd == usize::MAX {
+        println!("couldn't open /hello.txt");
+        return;
+    }
+    let fd = fd as u16;
+    let mut buffer = [0u8; 64];
+    let bytes_read = sys_read(fd, buffer.as_mut_ptr(), buffer.len());
+    println!("read {} bytes from fd {}", bytes_read, fd);
+    for i in 0..bytes_read {
+        print!("{}", buffer[i] as char);
+    }
+    println!();
+
+    // Read the last few bytes again
+    let offset = lseek(fd, -4, crate::vfs::SEEK_END);
+    let bytes_read = sys_read(fd, buffer.as_mut_ptr(), buffer.len());
+    println!("read {} bytes from offset {}", bytes_read, offset);
+    println!("close returned {}", close(fd));
+}
+
+// Reads hello.txt from every mounted disk. Attach a v1 and a v2 image as extra drives, e.g. made
+// with mkfs.minix -1 and mkfs.minix -2, to check both layouts.
+pub fn minix_versions_tester() {
+    for (i, bdev) in block::devices().iter().enumerate() {
+        let root = if i == 0 {
+            String::from("/")
+        } else {
+            format!("/mnt/disk{}/", bdev)
+        };
+        let layout = match fs::MinixFileSystem::new(*bdev).layout() {
+            Some(layout) => layout,
+            None => {
+                println!("block device {} isn't a Minix disk", bdev);
+                continue;
+            }
+        };
+        println!(
+            "{}: {:?}, zones of {} bytes, names up to {}",
+            root, layout.version, layout.zone_size, layout.name_len
+        );
+        let path = format!("{}hello.txt\0", root);
+        let fd = open(path.as_ptr(), crate::vfs::O_RDONLY, 0);
+        if fd == usize::MAX {
+            println!("couldn't open {}", &path[..path.len() - 1]);
+            continue;
+        }
+        let mut buffer = [0u8; 64];
+        let bytes_read = sys_read(fd as u16, buffer.as_mut_ptr(), buffer.len());
+        if bytes_read == usize::MAX {
+            println!("reading {} failed", &path[..path.len() - 1]);
+        } else {
+            for i in 0..bytes_read {
+                print!("{}", buffer[i] as char);
+            }
+            println!();
+        }
+        close(fd as u16);
+    }
+}
+
+pub fn random_tester() {
+    let mut buffer = [0u8; 16];
+    let ret = getrandom(buffer.as_mut_ptr(), buffer.len(), 0);
+    print!("getrandom returned {}:", ret as isize);
+    for byte in buffer.iter() {
+        print!(" {:02x}", byte);
+    }
+    println!();
+
+    // Same again through the device node
+    let fd = open("/dev/urandom\0".as_ptr(), crate::vfs::O_RDONLY, 0);
+    if fd == usize::MAX {
+        println!("couldn't open /dev/urandom");
+        return;
+    }
+    let bytes_read = sys_read(fd as u16, buffer.as_mut_ptr(), buffer.len());
+    print!("read {} bytes from /dev/urandom:", bytes_read);
+    for byte in buffer.iter() {
+        print!(" {:02x}", byte);
+    }
+    println!();
+    close(fd as u16);
+}
+
+// Asks QEMU's user-mode network gateway for its MAC address and prints what comes back
+pub fn net_tester() {
+    let dev = match net::devices().first() {
+        Some(dev) => *dev,
+        None => {
+            println!("no network device");
+            return;
+        }
+    };
+    let mac = net::mac(dev).unwrap();
+    let mut frame = Vec::new();
+    // Ethernet: broadcast, from us, ARP
+    frame.extend_from_slice(&[0xff; 6]);
+    frame.extend_from_slice(&mac);
+    frame.extend_from_slice(&[0x08, 0x06]);
+    // ARP request for 10.0.2.2 from 10.0.2.15
+    frame.extend_from_slice(&[0, 1, 0x08, 0, 6, 4, 0, 1]);
+    frame.extend_from_slice(&mac);
+    frame.extend_from_slice(&[10, 0, 2, 15]);
+    frame.extend_from_slice(&[0; 6]);
+    frame.extend_from_slice(&[10, 0, 2, 2]);
+    println!(
+        "link {}, send returned {:?}",
+        if net::link_up(dev) { "up" } else { "down" },
+        net::send(dev, &frame)
+    );
+    for _ in 0..20 {
+        while let Some(frame) = net::receive(dev) {
+            print!("received {} bytes:", frame.len());
+            for byte in frame.iter().take(42) {
+                print!(" {:02x}", byte);
+            }
+            println!();
+        }
+        sleep(100);
+    }
+}
+
+// Fetches / from a web server on the host, through QEMU's user-mode network gateway
+pub fn socket_tester() {
+    let fd = socket(AF_INET, SOCK_STREAM, 0);
+    if fd as isize <= 0 {
+        println!("socket returned {}", fd as isize);
+        return;
+    }
+    let fd = fd as u16;
+    let addr = SockAddrIn::new([10, 0, 2, 2], 80);
+    let ret = connect(fd, &addr);
+    println!("connect returned {}", ret as isize);
+    if ret == 0 {
+        let request = b"GET / HTTP/1.0\r\n\r\n";
+        let sent = sendto(fd, request.as_ptr(), request.len(), 0, core::ptr::null());
+        println!("sent {}", sent as isize);
+        let mut buffer = [0u8; 256];
+        loop {
+            let ret = recvfrom(
+                fd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                0,
+                core::ptr::null_mut(),
+                core::ptr::null_mut(),
+            );
+            if ret == 0 || ret > buffer.len() {
+                println!();
+                println!("recvfrom returned {}", ret as isize);
+                break;
+            }
+            for byte in buffer[..ret].iter() {
+                print!("{}", *byte as char);
+            }
+        }
+    }
+    close(fd);
+}
+
+// Prints the first events from each input device - move the mouse over the window or type
+pub fn input_tester() {
+    for path in ["/dev/input/event0\0", "/dev/input/event1\0"].iter() {
+        let fd = open(path.as_ptr(), crate::vfs::O_RDONLY, 0);
+        if fd == usize::MAX {
+            println!("couldn't open {}", path.trim_end_matches('\0'));
+            continue;
+        }
+        let mut events = [InputEvent::default(); 8];
+        let size = events.len() * size_of::<InputEvent>();
+        let bytes_read = sys_read(fd as u16, events.as_mut_ptr() as *const u8, size);
+        for event in events.iter().take(bytes_read / size_of::<InputEvent>()) {
+            println!(
+                "{}: {}.{:06} type {} code {} value {}",
+                path.trim_end_matches('\0'),
+                event.sec,
+                event.usec,
+                event.kind,
+                event.code,
+                event.value
+            );
+        }
+        close(fd as u16);
+    }
+}
+
+// Draws a band of colour across the top of the display through a mapping of /dev/fb0
+pub fn fb_tester() {
+    let fd = open("/dev/fb0\0".as_ptr(), crate::vfs::O_RDWR, 0);
+    if fd == usize::MAX {
+        println!("couldn't open /dev/fb0");
+        return;
+    }
+    let len = 64 * 1024;
+    let prot = crate::vfs::PROT_READ | crate::vfs::PROT_WRITE;
+    let addr = mmap(0, len, prot, crate::vfs::MAP_SHARED, fd as u16, 0);
+    if addr == usize::MAX {
+        println!("mmap failed");
+    } else {
+        let fb = addr as *mut u32;
+        for i in 0..len / 4 {
+            // Red across, green down
+            let pixel = ((i % 256) << 16 | (i / 256 % 256) << 8) as u32;
+            unsafe {
+                fb.add(i).write_volatile(pixel);
+            }
+        }
+        println!("drew {} pixels at {:#x}", len / 4, addr);
+    }
+    close(fd as u16);
+}
+
+// Echoes back whatever comes in on the second virtio console port, e.g. with
+// -device virtserialport,chardev=...,nr=1 - run until 'q' is sent
+pub fn hvc_tester() {
+    let fd = open("/dev/hvc1\0".as_ptr(), crate::vfs::O_RDWR, 0);
+    if fd == usize::MAX {
+        println!("couldn't open /dev/hvc1");
+        return;
+    }
+    let greeting = "echo on hvc1, q to quit\r\n";
+    sys_write(fd as u16, greeting.as_ptr(), greeting.len());
+    let mut buf = [0u8; 64];
+    loop {
+        let bytes_read = sys_read(fd as u16, buf.as_mut_ptr(), buf.len());
+        if bytes_read == 0 || bytes_read == usize::MAX || buf[..bytes_read].contains(&b'q') {
+            break;
+        }
+        sys_write(fd as u16, buf.as_ptr(), bytes_read);
+    }
+    close(fd as u16);
+}
+
+// Prints what was found in the device tree, or the defaults if there wasn't one
+pub fn fdt_tester() {
+    let platform = fdt::platform();
+    println!(
+        "memory: {} MiB at {:#x}, {} harts",
+        platform.memory.size >> 20,
+        platform.memory.base,
+        platform.harts
+    );
+    println!(
+        "uart: {:#x} irq {}, plic: {:#x}, clint: {:#x}",
+        platform.uart.base, platform.uart.irq, platform.plic.base, platform.clint.base
+    );
+    for (slot, virtio) in platform.virtio().iter().enumerate() {
+        println!("virtio {}: {:#x} irq {}", slot, virtio.base, virtio.irq);
+    }
+}
+
+// Harts a worker has been seen running on, one bit each
+static SMP_HARTS_SEEN: AtomicUsize = AtomicUsize::new(0);
+static SMP_WORKERS_DONE: AtomicUsize = AtomicUsize::new(0);
+const SMP_WORKERS: usize = 8;
+
+fn smp_worker() {
+    for _ in 0..20 {
+        SMP_HARTS_SEEN.fetch_or(1 << cpu::hartid(), Ordering::SeqCst);
+        let mut i: usize = 0;
+        while i < 5_000_000 {
+            i = core::hint::black_box(i) + 1;
+        }
+        yield_process();
+    }
+    SMP_WORKERS_DONE.fetch_add(1, Ordering::SeqCst);
+}
+
+// Starts more busy processes than there are harts and checks every hart ran some of them
+pub fn smp_tester() {
+    for _ in 0..SMP_WORKERS {
+        add_kernel_process(smp_worker);
+    }
+    while SMP_WORKERS_DONE.load(Ordering::SeqCst) < SMP_WORKERS {
+        sleep(100);
+    }
+    let harts = fdt::platform().harts.min(cpu::MAX_HARTS);
+    let seen = SMP_HARTS_SEEN.load(Ordering::SeqCst);
+    println!(
+        "{} workers ran on {} of {} harts (mask {:#x})",
+        SMP_WORKERS,
+        seen.count_ones(),
+        harts,
+        seen
+    );
+}
+
+// Talks to the firmware directly - its console, and starting a hart that's already running
+#[cfg(feature = "sbi")]
+pub fn sbi_tester() {
+    crate::sbi::console_write("written through the firmware's console\r\n");
+    // SBI_ERR_ALREADY_AVAILABLE is -6
+    let error = crate::sbi::hart_start(cpu::hartid(), 0, 0);
+    println!("starting this hart again returned {}", error);
+}
+
+pub fn write_tester() {
+    let flags = crate::vfs::O_RDWR | crate::vfs::O_CREAT | crate::vfs::O_TRUNC;
+    println!("mkdir returned {}", mkdir("/tmp\0".as_ptr(), 0o755));
+    let fd = open("/tmp/written.txt\0".as_ptr(), flags, 0o644);
+    if fd == usize::MAX {
+        println!("couldn't create /tmp/written.txt");
+        return;
+    }
+    let fd = fd as u16;
+    let s = "Written by the kernel\n";
+    println!("wrote {} bytes", sys_write(fd, s.as_ptr(), s.len()));
+    println!("fsync returned {}", fsync(fd));
+
+    // "Written" should read back as zeroes, the rest is untouched
+    let mode = crate::vfs::FALLOC_FL_PUNCH_HOLE | crate::vfs::FALLOC_FL_KEEP_SIZE;
+    println!("fallocate returned {}", fallocate(fd, mode, 0, 7));
+
+    // Read it back from the start
+    lseek(fd, 0, crate::vfs::SEEK_SET);
+    let mut buffer = [0u8; 64];
+    let bytes_read = sys_read(fd, buffer.as_mut_ptr(), buffer.len());
+    for i in 0..bytes_read {
+        if buffer[i] == 0 {
+            print!(".");
+        } else {
+            print!("{}", buffer[i] as char);
+        }
+    }
+    close(fd);
+
+    println!("unlink returned {}", unlink("/tmp/written.txt\0".as_ptr()));
+    println!("rmdir returned {}", rmdir("/tmp\0".as_ptr()));
+    println!("sync returned {}", sync());
+}
+
 pub fn minix_tester() {
-    let buffer = kmem::kmalloc(1024);
-    let root_dir = crate::fs::MinixFileSystem::get_inode(3, 1);
+    let fd = open("/\0".as_ptr(), crate::vfs::O_RDONLY, 0);
+    if fd == usize::MAX {
+        println!("couldn't open root dir - invalid disk");
+        return;
+    }
+    let fd = fd as u16;
+    let mut stat = crate::vfs::Stat::default();
+    fstat(fd, &mut stat);
     println!(
-        "root dir: {}",
-        if root_dir.unwrap().mode & crate::fs::S_IFDIR != 0 {
+        "root dir: {}, inode {} on mount {}",
+        if stat.mode as u16 & crate::vfs::S_IFMT == crate::vfs::S_IFDIR {
             "Directory"
         } else {
             "File"
-        }
+        },
+        stat.ino,
+        stat.dev
     );
-    let bytes_read = get_inode(3, 1, buffer, 1024, 0);
-    if bytes_read > 0 {
-        println!("bytes read: {}", bytes_read);
-        print!("'");
-        for i in 0..bytes_read as usize {
-            print!("{}", unsafe { buffer.add(i).read() });
-        }
-        println!("'");
-
-        let dir_entry = buffer as *const crate::fs::DirEntry;
-        unsafe {
-            for i in 0..bytes_read as usize / 64 {
-                let curr = dir_entry.add(i);
-                println!("inode: {}", (*curr).inode);
-                for j in 0..60 {
-                    let c = (*curr).name[j];
+
+    let buffer = kmem::kmalloc(1024);
+    loop {
+        let bytes_read = getdents64(fd, buffer, 1024);
+        if bytes_read == 0 || bytes_read == usize::MAX {
+            break;
+        }
+        let mut offset = 0;
+        while offset < bytes_read {
+            unsafe {
+                let curr = buffer.add(offset) as *const crate::vfs::LinuxDirent64;
+                let name = buffer.add(offset + size_of::<crate::vfs::LinuxDirent64>());
+                print!("inode: {} ", (*curr).ino);
+                for j in 0.. {
+                    let c = name.add(j).read();
                     if c == 0 {
                         break;
                     }
                     print!("{}", c as char);
                 }
                 println!();
+                offset += (*curr).reclen as usize;
             }
         }
-    } else {
-        println!("couldn't retrieve inode - invalid disk");
-    }
-
-    let bytes_read = get_inode(3, 2, buffer, 100, 0);
-    if bytes_read > 0 {
-        println!("bytes read: {}", bytes_read);
-        print!("'");
-        for i in 0..bytes_read as usize {
-            print!("{}", unsafe { buffer.add(i).read() as char });
-        }
-        println!("'");
-    } else {
-        println!("couldn't retrieve inode - invalid disk");
     }
     kmem::kfree(buffer);
+    close(fd);
 }
 
 pub fn user_proc() {
+    let mut info = block::BlockInfo::default();
+    match block_info(3, &mut info) {
+        Ok(()) => println!(
+            "disk 3: {} bytes, block size {}{}",
+            info.capacity,
+            info.blk_size,
+            if info.read_only { ", read-only" } else { "" }
+        ),
+        Err(err) => println!("block info failed: {:?}", err),
+    }
     let buffer = kmem::kmalloc(1024);
-    read_block(3, buffer, SECTOR_SIZE, 1024);
+    if let Err(err) = read_block(3, buffer, SECTOR_SIZE, 1024) {
+        println!("reading blocks failed: {:?}", err);
+        kmem::kfree(buffer);
+        return;
+    }
     println!("done from reading blocks");
 
     unsafe {
@@ -113,7 +500,11 @@ pub fn kernel_block_process() {
     unsafe { buffer.write_volatile(1) }
     let s = "reading block\r\n";
     sys_write(1, s.as_ptr(), s.len());
-    block::process_read(pid, 3, buffer, SECTOR_SIZE, 1024);
+    if let Err(err) = block::process_read(pid, 3, buffer, SECTOR_SIZE, 1024) {
+        println!("reading block failed: {:?}", err);
+        kmem::kfree(buffer);
+        return;
+    }
     let s = "we're back from reading the block\r\n";
     sys_write(1, s.as_ptr(), s.len());
 
@@ -145,13 +536,67 @@ pub fn kernel_block_process() {
 
     let s = "writing block\r\n";
     sys_write(1, s.as_ptr(), s.len());
-    block::process_write(pid, 3, buffer, SECTOR_SIZE, 0);
+    if let Err(err) = block::process_write(pid, 3, buffer, SECTOR_SIZE, 0) {
+        println!("writing block failed: {:?}", err);
+    }
     let s = "we're back from writing to the block\r\n";
     sys_write(1, s.as_ptr(), s.len());
 
     kmem::kfree(buffer);
 }
 
+// More requests than the ring has descriptors, even with one per request, so some have to wait
+// in the backlog
+const BACKLOG_REQUESTS: usize = 3 * virtio::VIRTIO_RING_SIZE;
+static BACKLOG_GO: AtomicBool = AtomicBool::new(false);
+static BACKLOG_READY: AtomicUsize = AtomicUsize::new(0);
+static BACKLOG_DONE: AtomicUsize = AtomicUsize::new(0);
+static BACKLOG_FAILED: AtomicUsize = AtomicUsize::new(0);
+
+fn backlog_worker() {
+    let dev = block::devices()[0];
+    let buffer = kmem::kmalloc(SECTOR_SIZE as usize);
+    BACKLOG_READY.fetch_add(1, Ordering::SeqCst);
+    // Hold back until every worker is ready, so the requests all arrive together
+    while !BACKLOG_GO.load(Ordering::SeqCst) {
+        yield_process();
+    }
+    let offset = (get_pid() as u64 % 64) * SECTOR_SIZE as u64;
+    if let Err(err) = read_block(dev, buffer, SECTOR_SIZE, offset) {
+        println!("backlog read failed: {:?}", err);
+        BACKLOG_FAILED.fetch_add(1, Ordering::SeqCst);
+    }
+    kmem::kfree(buffer);
+    BACKLOG_DONE.fetch_add(1, Ordering::SeqCst);
+}
+
+// Floods the first block device with reads and checks every one of them completes
+pub fn block_backlog_tester() {
+    if block::devices().is_empty() {
+        println!("no block device to test");
+        return;
+    }
+    let mut workers = 0;
+    for _ in 0..BACKLOG_REQUESTS {
+        if add_kernel_process(backlog_worker) != 0 {
+            workers += 1;
+        }
+    }
+    while BACKLOG_READY.load(Ordering::SeqCst) < workers {
+        sleep(10);
+    }
+    BACKLOG_GO.store(true, Ordering::SeqCst);
+    while BACKLOG_DONE.load(Ordering::SeqCst) < workers {
+        sleep(100);
+    }
+    println!(
+        "{} block requests for a ring of {}: all completed, {} failed",
+        workers,
+        virtio::VIRTIO_RING_SIZE,
+        BACKLOG_FAILED.load(Ordering::SeqCst)
+    );
+}
+
 pub fn process_2() {
     let mut i: usize = 0;
     loop {
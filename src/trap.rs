@@ -1,7 +1,8 @@
 // trap.rs
 // Trap routines
-use crate::{plic, process};
-use crate::cpu::{TrapFrame, get_mtime, set_next_minterrupt};
+use crate::{cpu, plic, process};
+use crate::process::{killed_status, SIGBUS, SIGILL, SIGSEGV};
+use crate::cpu::{CpuMode, TrapFrame, clear_software_interrupt, get_mtime, set_next_minterrupt};
 use crate::syscall::do_syscall;
 use crate::scheduler::context_switch;
 
@@ -21,20 +22,30 @@ extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status
     let cause_num = cause & 0xfff;
     let mut return_pc = epc;
     if is_async {
-        // async trap
+        // async trap - the supervisor versions (1, 5 and 9) when running under the firmware
         match cause_num {
-            3 => {
-                // Machine software
-                println!("Machine software interrupt CPU#{}", hart);
+            1 | 3 => {
+                // Machine software - the boot hart waking this one up, from then on it
+                // schedules like every other hart. After that it's another hart asking for a
+                // TLB flush, and whatever was running carries on.
+                clear_software_interrupt(hart);
+                cpu::handle_tlb_flush();
+                if cpu::set_online() {
+                    schedule_scheduler();
+                    context_switch();
+                }
             },
-            7 => {
+            5 | 7 => {
                 // Context switch machine timer
+                #[cfg(feature = "sbi")]
+                crate::sbi::poll_console();
                 schedule_scheduler();
                 context_switch();
             },
-            11 => {
+            9 | 11 => {
                 // External interrupt from PLIC
                 // println!("plic interrupt");
+                // Each driver takes its own lock, the other harts may be using the device
                 plic::handle_interrupt();
             },
             _ => {
@@ -48,10 +59,20 @@ extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status
             2 => {
 				// Illegal instruction
                 println!("Illegal instruction CPU#{}, PID {}, PC 0x{:08x}, MEPC 0x{:08x}\n", hart, (*frame).pid, (*frame).pc, epc);
-                process::delete_process((*frame).pid as u16);
+                process::set_dead((*frame).pid as u16, killed_status(SIGILL));
                 schedule_scheduler();
                 context_switch();
             },
+			// Under the firmware an ecall from supervisor mode goes to it, so kernel processes
+			// make system calls with a breakpoint instead
+			3 if cfg!(feature = "sbi") && (*frame).mode != CpuMode::User as usize => unsafe {
+                let switch_required = do_syscall(return_pc, frame);
+                return_pc += 4;
+                if switch_required == true {
+                    schedule_scheduler();
+                    context_switch();
+                }
+			},
             3 => {
                 // Breakpoint
                 println!("breakpoint\r\n");
@@ -61,28 +82,28 @@ extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status
             4 => {
 				// Load address misaligned
                 println!("Load address misaligned CPU#{}, PID {}, PC 0x{:08x}, MEPC 0x{:08x}\n", hart, (*frame).pid, (*frame).pc, epc);
-                process::delete_process((*frame).pid as u16);
+                process::set_dead((*frame).pid as u16, killed_status(SIGBUS));
                 schedule_scheduler();
                 context_switch();
             },
             5 => {
 				// Load access fault
                 println!("Load access fault CPU#{}, PID {}, PC 0x{:08x}, MEPC 0x{:08x}\n", hart, (*frame).pid, (*frame).pc, epc);
-                process::delete_process((*frame).pid as u16);
+                process::set_dead((*frame).pid as u16, killed_status(SIGSEGV));
                 schedule_scheduler();
                 context_switch();
             },
             6 => {
 				// Store/AMO address misaligned
                 println!("Store/AMO address misaligned CPU#{}, PID {}, PC 0x{:08x}, MEPC 0x{:08x}\n", hart, (*frame).pid, (*frame).pc, epc);
-                process::delete_process((*frame).pid as u16);
+                process::set_dead((*frame).pid as u16, killed_status(SIGBUS));
                 schedule_scheduler();
                 context_switch();
             },
             7 => {
 				// Store/AMO access fault
                 println!("Store/AMO access fault CPU#{}, PID {}, PC 0x{:08x}, MEPC 0x{:08x}\n", hart, (*frame).pid, (*frame).pc, epc);
-                process::delete_process((*frame).pid as u16);
+                process::set_dead((*frame).pid as u16, killed_status(SIGSEGV));
                 schedule_scheduler();
                 context_switch();
 			},
@@ -99,23 +120,27 @@ extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status
 			12 => {
 				// Instruction page fault
 				println!("Instruction page fault CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
-                process::delete_process((*frame).pid as u16);
+                process::set_dead((*frame).pid as u16, killed_status(SIGSEGV));
                 schedule_scheduler();
                 context_switch();
 			},
 			13 => {
 				// Load page fault
 				println!("Load page fault CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
-                process::delete_process((*frame).pid as u16);
+                process::set_dead((*frame).pid as u16, killed_status(SIGSEGV));
                 schedule_scheduler();
                 context_switch();
 			},
 			15 => {
 				// Store page fault
-				println!("Store page fault CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
-                process::delete_process((*frame).pid as u16);
-                schedule_scheduler();
-                context_switch();
+				if process::handle_store_fault((*frame).pid as u16, tval) {
+					// Copy-on-write page has been copied, retry the store
+				} else {
+					println!("Store page fault CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
+					process::set_dead((*frame).pid as u16, killed_status(SIGSEGV));
+					schedule_scheduler();
+					context_switch();
+				}
 			},
 			_ => {
 				panic!("Unhandled sync trap CPU#{} -> {}\n", hart, cause_num);
@@ -0,0 +1,395 @@
+// Virtio console - serial ports to the host, each one a TTY at /dev/hvcN. The kernel log goes
+// to the console port as well as the UART, the other ports are free for processes.
+use crate::console;
+use crate::cpu::{get_mtime, MTIMER_TICKS_PER_MS};
+use crate::devfs::{self, Device};
+use crate::kmem::{kfree, kmalloc};
+use crate::syscall::sleep;
+use crate::vfs::S_IFCHR;
+use crate::virtio::{self, Buf, MmioOffsets, Virtqueue};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::copy_nonoverlapping;
+
+// Feature bits
+const VIRTIO_CONSOLE_F_MULTIPORT: u32 = 1;
+
+// Config space offsets, in bytes
+const CONFIG_MAX_NR_PORTS: usize = 4;
+
+// Control queues, only there with VIRTIO_CONSOLE_F_MULTIPORT
+const CONTROL_RX_QUEUE: u32 = 2;
+const CONTROL_TX_QUEUE: u32 = 3;
+
+// Control events
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+// Ports set up, however many the device offers
+const MAX_PORTS: usize = 8;
+// Receive buffers kept posted on each port
+const RX_BUFFERS: usize = 4;
+const RX_BUFFER_SIZE: usize = 256;
+// Bytes that haven't been read yet, per port. Anything over is dropped.
+const RX_QUEUE_LIMIT: usize = 4096;
+// Control messages kept posted, each big enough for a port name
+const CONTROL_BUFFERS: usize = 8;
+const CONTROL_BUFFER_SIZE: usize = 64;
+// How long probing waits for the device to announce its ports, in ms
+const PORT_WAIT: u64 = 50;
+// How often a blocked read or write checks again, in ms
+const RETRY_INTERVAL: usize = 10;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct ControlMsg {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+struct Port {
+    rx: Virtqueue,
+    tx: Virtqueue,
+    rx_buffers: Box<[[u8; RX_BUFFER_SIZE]; RX_BUFFERS]>,
+    received: VecDeque<u8>,
+    // The device said the port exists
+    added: bool,
+    // The port the kernel log goes to
+    console: bool,
+    // Given by the host, e.g. org.qemu.guest_agent.0
+    name: String,
+}
+
+struct ConsoleDevice {
+    index: usize,
+    ports: Vec<Port>,
+    control_rx: Option<Virtqueue>,
+    control_tx: Option<Virtqueue>,
+    control_buffers: Box<[[u8; CONTROL_BUFFER_SIZE]; CONTROL_BUFFERS]>,
+}
+
+// Ports of one device are enough TTYs
+static mut CONSOLE_DEVICE: Option<ConsoleDevice> = None;
+
+// Port 0 uses the first two queues, the control queues come next and then the other ports
+fn rx_queue(id: usize) -> u32 {
+    if id == 0 {
+        0
+    } else {
+        2 + 2 * id as u32
+    }
+}
+
+pub fn setup_console_device(ptr: *mut u32) -> bool {
+    unsafe {
+        if CONSOLE_DEVICE.is_some() {
+            print!("already have one...");
+            return false;
+        }
+        let index = match virtio::slot(ptr) {
+            Some(index) => index,
+            None => return false,
+        };
+        // 1 - 6. Reset, acknowledge and agree on features
+        let supported = virtio::RING_FEATURES | (1 << VIRTIO_CONSOLE_F_MULTIPORT);
+        let features = match virtio::negotiate_features(ptr, supported) {
+            Some(features) => features,
+            None => return false,
+        };
+        let multiport = features & (1 << VIRTIO_CONSOLE_F_MULTIPORT) != 0;
+
+        // 7. Perform device-specific setup. Every port's queues are set up now, since that
+        // has to happen before DRIVER_OK, even though the device only adds ports later.
+        let num_ports = if multiport {
+            let config = ptr.add(MmioOffsets::Config.scale32()) as *mut u8;
+            let max = (config.add(CONFIG_MAX_NR_PORTS) as *mut u32).read_volatile();
+            (max as usize).max(1).min(MAX_PORTS)
+        } else {
+            1
+        };
+        let mut cd = ConsoleDevice {
+            index,
+            ports: Vec::with_capacity(num_ports),
+            control_rx: None,
+            control_tx: None,
+            control_buffers: Box::new([[0; CONTROL_BUFFER_SIZE]; CONTROL_BUFFERS]),
+        };
+        for id in 0..num_ports {
+            let rx = Virtqueue::new(ptr, rx_queue(id), features);
+            let tx = Virtqueue::new(ptr, rx_queue(id) + 1, features);
+            let (rx, tx) = match (rx, tx) {
+                (Some(rx), Some(tx)) => (rx, tx),
+                _ => return false,
+            };
+            cd.ports.push(Port {
+                rx,
+                tx,
+                rx_buffers: Box::new([[0; RX_BUFFER_SIZE]; RX_BUFFERS]),
+                received: VecDeque::new(),
+                // Without multiport there's just the one, which is the console
+                added: !multiport,
+                console: !multiport,
+                name: String::new(),
+            });
+            for token in 0..RX_BUFFERS {
+                post_rx(&mut cd.ports[id], token);
+            }
+        }
+        if multiport {
+            cd.control_rx = Virtqueue::new(ptr, CONTROL_RX_QUEUE, features);
+            cd.control_tx = Virtqueue::new(ptr, CONTROL_TX_QUEUE, features);
+            if cd.control_rx.is_none() || cd.control_tx.is_none() {
+                return false;
+            }
+            for token in 0..CONTROL_BUFFERS {
+                post_control(&mut cd, token);
+            }
+        }
+
+        // 8. Set the DRIVER_OK status bit. Device is now 'live'
+        virtio::driver_ok(ptr);
+        for port in cd.ports.iter_mut() {
+            port.rx.kick();
+        }
+        if multiport {
+            if let Some(vq) = cd.control_rx.as_mut() {
+                vq.kick();
+            }
+            // The device answers with a DEVICE_ADD for every port. Interrupts aren't on yet,
+            // so wait for those here - /dev can't change once probing is done. Ports added
+            // later still work, they just don't get a node.
+            send_control(&mut cd, 0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+            let deadline = get_mtime().as_u64() + PORT_WAIT * MTIMER_TICKS_PER_MS;
+            while get_mtime().as_u64() < deadline {
+                process_control(&mut cd);
+            }
+        }
+
+        for (id, port) in cd.ports.iter().enumerate().filter(|(_, port)| port.added) {
+            devfs::register(Device {
+                name: "hvc",
+                minor: Some(id),
+                mode: S_IFCHR | 0o666,
+                read: read_port,
+                write: Some(write_port),
+                mmap: None,
+            });
+            if port.name.is_empty() {
+                print!("hvc{}", id);
+            } else {
+                print!("hvc{} ({})", id, port.name);
+            }
+            print!("{}...", if port.console { ", console" } else { "" });
+        }
+        CONSOLE_DEVICE = Some(cd);
+        true
+    }
+}
+
+fn post_rx(port: &mut Port, token: usize) {
+    let buf = Buf::new(port.rx_buffers[token].as_ptr(), RX_BUFFER_SIZE as u32, true);
+    port.rx.add_buf(&[buf], token);
+}
+
+fn post_control(cd: &mut ConsoleDevice, token: usize) {
+    let buf = Buf::new(
+        cd.control_buffers[token].as_ptr(),
+        CONTROL_BUFFER_SIZE as u32,
+        true,
+    );
+    if let Some(vq) = cd.control_rx.as_mut() {
+        vq.add_buf(&[buf], token);
+    }
+}
+
+// Queues bytes on a transmit queue. The copy is freed when the device is done with it.
+fn send(vq: &mut Virtqueue, bytes: *const u8, len: usize) -> bool {
+    // Reclaim what the device finished, the log is written before interrupts are on
+    vq.process_used(|token, _| kfree(token as *mut u8));
+    let copy = kmalloc(len);
+    if copy.is_null() {
+        return false;
+    }
+    unsafe {
+        copy_nonoverlapping(bytes, copy, len);
+    }
+    if false == vq.add_buf(&[Buf::new(copy, len as u32, false)], copy as usize) {
+        kfree(copy);
+        return false;
+    }
+    vq.kick();
+    true
+}
+
+fn send_control(cd: &mut ConsoleDevice, id: u32, event: u16, value: u16) {
+    let msg = ControlMsg { id, event, value };
+    if let Some(vq) = cd.control_tx.as_mut() {
+        send(
+            vq,
+            &msg as *const ControlMsg as *const u8,
+            size_of::<ControlMsg>(),
+        );
+    }
+}
+
+fn control(cd: &mut ConsoleDevice, msg: ControlMsg, extra: &[u8]) {
+    let id = msg.id as usize;
+    if id >= cd.ports.len() {
+        if msg.event == VIRTIO_CONSOLE_DEVICE_ADD {
+            // Tell the device we can't use it
+            send_control(cd, msg.id, VIRTIO_CONSOLE_PORT_READY, 0);
+        }
+        return;
+    }
+    match msg.event {
+        VIRTIO_CONSOLE_DEVICE_ADD => {
+            cd.ports[id].added = true;
+            send_control(cd, msg.id, VIRTIO_CONSOLE_PORT_READY, 1);
+            // Nothing opens ports as such, so they're open from the start
+            send_control(cd, msg.id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+        }
+        VIRTIO_CONSOLE_DEVICE_REMOVE => {
+            cd.ports[id].added = false;
+            cd.ports[id].console = false;
+        }
+        VIRTIO_CONSOLE_CONSOLE_PORT => cd.ports[id].console = true,
+        VIRTIO_CONSOLE_PORT_NAME => {
+            let name = extra.split(|&b| b == 0).next().unwrap_or(&[]);
+            cd.ports[id].name = name.iter().map(|&b| b as char).collect();
+        }
+        // Whether the host end is connected and resizes don't matter to us
+        _ => {}
+    }
+}
+
+// Frees sent control messages and handles the ones received
+fn process_control(cd: &mut ConsoleDevice) {
+    if let Some(vq) = cd.control_tx.as_mut() {
+        vq.process_used(|token, _| kfree(token as *mut u8));
+    }
+    let mut done = Vec::new();
+    if let Some(vq) = cd.control_rx.as_mut() {
+        vq.process_used(|token, len| done.push((token, len as usize)));
+    }
+    for &(token, len) in done.iter() {
+        let buffer = cd.control_buffers[token];
+        let len = len.min(CONTROL_BUFFER_SIZE);
+        if len >= size_of::<ControlMsg>() {
+            let msg = unsafe { (buffer.as_ptr() as *const ControlMsg).read_unaligned() };
+            control(cd, msg, &buffer[size_of::<ControlMsg>()..len]);
+        }
+        post_control(cd, token);
+    }
+    if !done.is_empty() {
+        if let Some(vq) = cd.control_rx.as_mut() {
+            vq.kick();
+        }
+    }
+}
+
+// Runs f on port id with the output lock held, since print! writes to the console port, None if
+// the port isn't there
+fn with_port<T>(id: usize, f: impl FnOnce(&mut Port) -> T) -> Option<T> {
+    console::with_output(|| unsafe {
+        CONSOLE_DEVICE
+            .as_mut()?
+            .ports
+            .get_mut(id)
+            .filter(|port| port.added)
+            .map(f)
+    })
+}
+
+// Sends part of the kernel log to the console port, if there is one. Dropped if the port can't
+// take it right now. Called by print! with the output lock held.
+pub fn write_log(s: &str) {
+    unsafe {
+        if let Some(cd) = CONSOLE_DEVICE.as_mut() {
+            if let Some(port) = cd.ports.iter_mut().find(|port| port.added && port.console) {
+                send(&mut port.tx, s.as_ptr(), s.len());
+            }
+        }
+    }
+}
+
+// Returns what's arrived, waiting until there's at least a byte
+// don't run inside interrupt context - will block
+fn read_port(id: usize, buffer: *mut u8, size: u32, _offset: u32) -> u32 {
+    loop {
+        let count = with_port(id, |port| {
+            let count = (size as usize).min(port.received.len());
+            for (i, byte) in port.received.drain(..count).enumerate() {
+                unsafe {
+                    buffer.add(i).write(byte);
+                }
+            }
+            count
+        });
+        match count {
+            Some(0) if size > 0 => {
+                sleep(RETRY_INTERVAL);
+            }
+            Some(count) => return count as u32,
+            None => return 0,
+        }
+    }
+}
+
+// Waits for room on the transmit queue
+// don't run inside interrupt context - will block
+fn write_port(id: usize, buffer: *const u8, size: u32, _offset: u32) -> Option<u32> {
+    if size == 0 {
+        return Some(0);
+    }
+    loop {
+        match with_port(id, |port| send(&mut port.tx, buffer, size as usize)) {
+            Some(true) => return Some(size),
+            Some(false) => {
+                sleep(RETRY_INTERVAL);
+            }
+            None => return None,
+        }
+    }
+}
+
+pub fn handle_interrupt(index: usize) {
+    console::with_output(|| unsafe {
+        let cd = match CONSOLE_DEVICE.as_mut() {
+            Some(cd) if cd.index == index => cd,
+            _ => {
+                println!("Invalid console device for interrupt {}", index + 1);
+                return;
+            }
+        };
+        process_control(cd);
+        for port in cd.ports.iter_mut() {
+            port.tx.process_used(|token, _| kfree(token as *mut u8));
+
+            let mut done = [(0usize, 0usize); RX_BUFFERS];
+            let mut count = 0;
+            port.rx.process_used(|token, len| {
+                done[count] = (token, len as usize);
+                count += 1;
+            });
+            for &(token, len) in done[..count].iter() {
+                let len = len.min(RX_BUFFER_SIZE);
+                let room = RX_QUEUE_LIMIT.saturating_sub(port.received.len());
+                let bytes = port.rx_buffers[token];
+                port.received.extend(bytes[..len.min(room)].iter());
+                post_rx(port, token);
+            }
+            if count > 0 {
+                port.rx.kick();
+            }
+        }
+    });
+}
@@ -0,0 +1,935 @@
+// Virtual file system - the interface every filesystem implements, and the table of where
+// each one is mounted
+use crate::bcache;
+use crate::block::{self, BlockErrors};
+use crate::buffer::Buffer;
+use crate::devfs::DevFileSystem;
+use crate::fs::MinixFileSystem;
+use crate::lock::Mutex;
+use crate::process;
+use crate::syscall::sleep;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+// open flags
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+pub const O_ACCMODE: usize = 3;
+pub const O_CREAT: usize = 0o100;
+pub const O_TRUNC: usize = 0o1000;
+pub const O_APPEND: usize = 0o2000;
+pub const O_NONBLOCK: usize = 0o4000;
+
+// unlinkat flag to remove a directory instead of a file
+pub const AT_REMOVEDIR: usize = 0x200;
+
+// fallocate modes - only punching holes is supported, which has to keep the size
+pub const FALLOC_FL_KEEP_SIZE: usize = 1;
+pub const FALLOC_FL_PUNCH_HOLE: usize = 2;
+
+// Most bytes one read or write syscall moves, since they go through a kernel buffer - asking for
+// more gets a short count
+pub const MAX_FILE_IO: u32 = 32 * 1024;
+
+// mmap protection and flags - only shared mappings of files are supported
+pub const PROT_READ: usize = 1;
+pub const PROT_WRITE: usize = 2;
+pub const MAP_SHARED: usize = 1;
+
+// lseek whence
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+// openat's dirfd for paths relative to the working directory
+pub const AT_FDCWD: isize = -100;
+
+// mode types
+pub const S_IFMT: u16 = 0o170_000;
+pub const S_IFDIR: u16 = 0o040_000;
+pub const S_IFCHR: u16 = 0o020_000;
+pub const S_IFREG: u16 = 0o100_000;
+pub const S_IFSOCK: u16 = 0o140_000;
+
+// getdents64 entry type when the filesystem doesn't say
+pub const DT_UNKNOWN: u8 = 0;
+
+// Same layout as the Linux struct stat, so fstat can copy it straight to the user
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct Stat {
+    // Mount the file lives on
+    pub dev: u64,
+    pub ino: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u64,
+    pub pad1: u64,
+    pub size: i64,
+    pub blksize: i32,
+    pub pad2: i32,
+    pub blocks: i64,
+    pub atime: i64,
+    pub atime_nsec: u64,
+    pub mtime: i64,
+    pub mtime_nsec: u64,
+    pub ctime: i64,
+    pub ctime_nsec: u64,
+    pub unused: [u32; 2],
+}
+
+pub struct DirEntry {
+    pub inode: u32,
+    pub name: String,
+}
+
+// Header of each record getdents64 hands out, followed by the NUL terminated name
+#[repr(C)]
+pub struct LinuxDirent64 {
+    pub ino: u64,
+    pub off: i64,
+    pub reclen: u16,
+    pub file_type: u8,
+}
+
+// Paths passed in are relative to the filesystem's mount point and always start with '/'.
+// Everything here may block, so only call it from a kernel process.
+pub trait FileSystem {
+    fn lookup(&self, path: &str) -> Option<u32>;
+    fn stat(&self, inode_num: u32) -> Option<Stat>;
+    fn read(&self, inode_num: u32, buffer: *mut u8, size: u32, offset: u32) -> u32;
+    fn readdir(&self, inode_num: u32) -> Option<Vec<DirEntry>>;
+
+    // Block device the filesystem is stored on, if it has one
+    fn bdev(&self) -> Option<usize> {
+        None
+    }
+
+    // Open files hold a reference to their inode, so the filesystem can keep it in memory.
+    // open may block, dup and release are called wherever files are copied or dropped.
+    fn open(&self, _inode_num: u32) -> bool {
+        true
+    }
+
+    fn dup(&self, _inode_num: u32) {}
+
+    // Never called in interrupt context, so it may block
+    fn release(&self, _inode_num: u32) {}
+
+    // Writes back anything kept in memory
+    fn sync(&self) -> Result<(), BlockErrors> {
+        Ok(())
+    }
+
+    // Makes one file's changes durable, the whole filesystem's unless it can do better
+    fn fsync(&self, _inode_num: u32) -> Result<(), BlockErrors> {
+        self.sync()
+    }
+
+    // Read-only filesystems can leave the rest out
+
+    // Returns the bytes written, None if the filesystem can't be written
+    fn write(&self, _inode_num: u32, _buffer: *const u8, _size: u32, _offset: u32) -> Option<u32> {
+        None
+    }
+
+    fn create(&self, _path: &str, _mode: u16) -> Option<u32> {
+        None
+    }
+
+    fn truncate(&self, _inode_num: u32) -> bool {
+        false
+    }
+
+    fn unlink(&self, _path: &str) -> bool {
+        false
+    }
+
+    fn mkdir(&self, _path: &str, _mode: u16) -> bool {
+        false
+    }
+
+    fn rmdir(&self, _path: &str) -> bool {
+        false
+    }
+
+    // Frees the storage behind a byte range of a file, which then reads as zeroes. The size
+    // stays the same.
+    fn punch_hole(&self, _inode_num: u32, _offset: u32, _len: u32) -> Result<(), BlockErrors> {
+        Err(BlockErrors::Unsupported)
+    }
+
+    // Physical address of len bytes of the file from offset, for files that are memory, like a
+    // framebuffer. Those can be mapped into a process with mmap.
+    fn mmap(&self, _inode_num: u32, _offset: u32, _len: u32) -> Option<usize> {
+        None
+    }
+}
+
+pub struct Mount {
+    pub id: usize,
+    // Absolute path the filesystem's root appears at
+    pub path: String,
+    pub fs: Arc<dyn FileSystem>,
+}
+
+static mut MOUNTS: Option<Vec<Mount>> = None;
+static mut MOUNTS_MUTEX: Mutex = Mutex::new();
+static mut NEXT_MOUNT_ID: usize = 1;
+
+// How often changes are written back, in ms
+pub const WRITEBACK_INTERVAL: usize = 5000;
+
+// Mounts the first block device at / and any others under /mnt, and the device nodes at /dev
+pub fn init() {
+    unsafe {
+        MOUNTS.replace(Vec::new());
+    }
+    process::add_kernel_process(writeback_proc);
+    for (i, bdev) in block::devices().iter().enumerate() {
+        let path = if i == 0 {
+            String::from("/")
+        } else {
+            format!("/mnt/disk{}", bdev)
+        };
+        let fs = Arc::new(MinixFileSystem::new(*bdev));
+        if mount(&path, fs).is_some() {
+            println!("mounted block device {} at {}", bdev, path);
+        }
+    }
+    mount("/dev", Arc::new(DevFileSystem::new()));
+}
+
+// Attaches a filesystem at an absolute path, returns the mount's id
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Option<usize> {
+    unsafe {
+        MOUNTS_MUTEX.spin_lock();
+        let mut ret = None;
+        if let Some(mut mounts) = MOUNTS.take() {
+            if !mounts.iter().any(|mount| mount.path == path) {
+                let id = NEXT_MOUNT_ID;
+                NEXT_MOUNT_ID += 1;
+                mounts.push(Mount {
+                    id,
+                    path: String::from(path),
+                    fs,
+                });
+                ret = Some(id);
+            }
+            MOUNTS.replace(mounts);
+        }
+        MOUNTS_MUTEX.unlock();
+        ret
+    }
+}
+
+// Detaches whatever is mounted at path and writes it back. Files that are still open keep it
+// alive until closed.
+// don't run inside interrupt context - will block
+pub fn unmount(path: &str) -> bool {
+    let mut removed = None;
+    unsafe {
+        MOUNTS_MUTEX.spin_lock();
+        if let Some(mut mounts) = MOUNTS.take() {
+            if let Some(index) = mounts.iter().position(|mount| mount.path == path) {
+                removed = Some(mounts.remove(index));
+            }
+            MOUNTS.replace(mounts);
+        }
+        MOUNTS_MUTEX.unlock();
+    }
+    match removed {
+        Some(mount) => {
+            if let Err(err) = mount.fs.sync().and(bcache::sync()) {
+                println!("Unmounting {} lost changes: {:?}", path, err);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+// Finds the filesystem an absolute path lives on - the mount with the longest matching path.
+// Returns the mount's id and filesystem, and the path relative to the filesystem's root.
+pub fn resolve(path: &str) -> Option<(usize, Arc<dyn FileSystem>, String)> {
+    unsafe {
+        MOUNTS_MUTEX.spin_lock();
+        let mut ret = None;
+        if let Some(mounts) = MOUNTS.take() {
+            let mut best: Option<&Mount> = None;
+            for mount in mounts.iter() {
+                let matches = mount.path == "/"
+                    || path == mount.path
+                    || (path.starts_with(&mount.path) && path[mount.path.len()..].starts_with('/'));
+                if matches && best.map_or(true, |best| mount.path.len() > best.path.len()) {
+                    best = Some(mount);
+                }
+            }
+            if let Some(mount) = best {
+                let rest = if mount.path == "/" {
+                    path
+                } else {
+                    &path[mount.path.len()..]
+                };
+                let relative = if rest.is_empty() {
+                    String::from("/")
+                } else {
+                    String::from(rest)
+                };
+                ret = Some((mount.id, mount.fs.clone(), relative));
+            }
+            MOUNTS.replace(mounts);
+        }
+        MOUNTS_MUTEX.unlock();
+        ret
+    }
+}
+
+// Finds the filesystem mounted from a block device
+pub fn mounted_bdev(bdev: usize) -> Option<Arc<dyn FileSystem>> {
+    unsafe {
+        MOUNTS_MUTEX.spin_lock();
+        let mut ret = None;
+        if let Some(mounts) = MOUNTS.take() {
+            ret = mounts
+                .iter()
+                .find(|mount| mount.fs.bdev() == Some(bdev))
+                .map(|mount| mount.fs.clone());
+            MOUNTS.replace(mounts);
+        }
+        MOUNTS_MUTEX.unlock();
+        ret
+    }
+}
+
+// Writes back every mounted filesystem, the block cache and the devices' caches. Everything is tried even if
+// something fails, and the first error is returned.
+// don't run inside interrupt context - will block
+pub fn sync() -> Result<(), BlockErrors> {
+    let mut filesystems = Vec::new();
+    unsafe {
+        MOUNTS_MUTEX.spin_lock();
+        if let Some(mounts) = MOUNTS.take() {
+            for mount in mounts.iter() {
+                filesystems.push(mount.fs.clone());
+            }
+            MOUNTS.replace(mounts);
+        }
+        MOUNTS_MUTEX.unlock();
+    }
+    let mut ret = Ok(());
+    for fs in filesystems.iter() {
+        ret = ret.and(fs.sync());
+    }
+    ret = ret.and(bcache::sync());
+    // and out of the devices' own caches
+    for dev in block::devices() {
+        ret = ret.and(block::flush_dev(dev));
+    }
+    ret
+}
+
+// Kernel process that periodically writes back changes
+fn writeback_proc() {
+    loop {
+        sleep(WRITEBACK_INTERVAL);
+        if let Err(err) = sync() {
+            println!("Write-back failed: {:?}", err);
+        }
+    }
+}
+
+// Looks up an absolute path, returns the mount id, filesystem and inode
+// don't run inside interrupt context - will block
+pub fn lookup(path: &str) -> Option<(usize, Arc<dyn FileSystem>, u32)> {
+    let (dev, fs, relative) = resolve(path)?;
+    let inode_num = fs.lookup(&relative)?;
+    Some((dev, fs, inode_num))
+}
+
+// An entry in a process' file descriptor table. Holds a reference to the inode for as long
+// as it lives, so it must not be dropped in interrupt context - see close_files.
+pub struct OpenFile {
+    pub fs: Arc<dyn FileSystem>,
+    // Mount the file was opened through
+    pub dev: usize,
+    pub inode_num: u32,
+    // Size as of the last access
+    pub size: u32,
+    // Byte offset, or the next entry for directories
+    pub offset: u32,
+    pub flags: usize,
+}
+
+impl Clone for OpenFile {
+    fn clone(&self) -> Self {
+        self.fs.dup(self.inode_num);
+        OpenFile {
+            fs: self.fs.clone(),
+            dev: self.dev,
+            inode_num: self.inode_num,
+            size: self.size,
+            offset: self.offset,
+            flags: self.flags,
+        }
+    }
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        self.fs.release(self.inode_num);
+    }
+}
+
+// Turns a path into an absolute one without "." or ".." components
+pub fn absolute_path(cwd: &str, path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    let full = if path.starts_with('/') {
+        path.split('/').collect::<Vec<&str>>()
+    } else {
+        cwd.split('/').chain(path.split('/')).collect()
+    };
+    for name in full {
+        match name {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(name),
+        }
+    }
+
+    let mut ret = String::from("/");
+    ret.push_str(&components.join("/"));
+    ret
+}
+
+struct OpenArgs {
+    pub pid: u16,
+    pub path: String,
+    pub flags: usize,
+    // Mode bits for a file created by O_CREAT
+    pub mode: u16,
+}
+
+// Finds or creates the file, truncating it if asked to
+fn open_file(path: &str, flags: usize, mode: u16) -> Option<OpenFile> {
+    let (dev, fs, relative) = resolve(path)?;
+    let mut inode_num = fs.lookup(&relative);
+    if inode_num.is_none() && flags & O_CREAT != 0 {
+        // Someone else may have created it in the meantime
+        inode_num = fs.create(&relative, mode).or_else(|| fs.lookup(&relative));
+    }
+    let inode_num = inode_num?;
+    if !fs.open(inode_num) {
+        return None;
+    }
+    // Dropping it from here on releases the inode
+    let mut file = OpenFile {
+        fs,
+        dev,
+        inode_num,
+        size: 0,
+        offset: 0,
+        flags,
+    };
+    let stat = file.fs.stat(inode_num)?;
+    file.size = stat.size as u32;
+
+    if flags & O_ACCMODE != O_RDONLY {
+        // Directories can only be opened for reading
+        if stat.mode as u16 & S_IFMT == S_IFDIR {
+            return None;
+        }
+        if flags & O_TRUNC != 0 {
+            if !file.fs.truncate(inode_num) {
+                return None;
+            }
+            file.size = 0;
+        }
+    }
+
+    Some(file)
+}
+
+// run inside the open process
+fn open_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut OpenArgs) };
+    let mut file = open_file(&args.path, args.flags, args.mode);
+
+    process::finish_syscall(args.pid, |proc| match file.take() {
+        Some(file) => {
+            let fd = proc.data.next_fd();
+            proc.data.files.insert(fd, file);
+            fd as usize
+        }
+        None => usize::MAX,
+    });
+    // If the caller has gone away the file is released here, with the process list let go of
+}
+
+// called by syscall - marks current process as waiting, and spawns a new process to resolve
+// the path and add it to the process' descriptor table
+pub fn process_open(pid: u16, path: String, flags: usize, mode: u16) {
+    let args = OpenArgs {
+        pid,
+        path,
+        flags,
+        mode,
+    };
+    let boxed_args = Box::new(args);
+    process::set_waiting(pid);
+    process::add_kernel_process_args(open_proc, Box::into_raw(boxed_args) as usize);
+}
+
+struct FileArgs {
+    pub pid: u16,
+    pub fd: u16,
+    pub buffer: usize,
+    pub size: u32,
+}
+
+// run inside the read process
+fn file_read_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut FileArgs) };
+    let ptr = unsafe { process::get_by_pid(args.pid) };
+    if ptr.is_null() {
+        return;
+    }
+    let file = unsafe { (*ptr).data.files.get(&args.fd).cloned() };
+    let mut buffer = Buffer::new(args.size as usize);
+    let bytes = match file {
+        Some(file) => {
+            let bytes = file
+                .fs
+                .read(file.inode_num, buffer.get_mut(), args.size, file.offset);
+            Some(bytes)
+        }
+        None => None,
+    };
+
+    process::finish_syscall(args.pid, |proc| match bytes {
+        Some(bytes) => {
+            let copied = proc.copy_to_user(args.buffer, buffer.get(), bytes as usize);
+            if copied < bytes as usize {
+                // Part of the buffer can't be written
+                return usize::MAX;
+            }
+            if let Some(file) = proc.data.files.get_mut(&args.fd) {
+                file.offset += copied as u32;
+            }
+            copied
+        }
+        None => usize::MAX,
+    });
+}
+
+// called by syscall - marks current process as waiting, and spawns a new process to read from
+// the open file into the process' buffer
+pub fn process_file_read(pid: u16, fd: u16, buffer: usize, size: u32) {
+    let args = FileArgs {
+        pid,
+        fd,
+        buffer,
+        size: size.min(MAX_FILE_IO),
+    };
+    let boxed_args = Box::new(args);
+    process::set_waiting(pid);
+    process::add_kernel_process_args(file_read_proc, Box::into_raw(boxed_args) as usize);
+}
+
+// run inside the write process
+fn file_write_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut FileArgs) };
+    let ptr = unsafe { process::get_by_pid(args.pid) };
+    if ptr.is_null() {
+        return;
+    }
+    let mut buffer = Buffer::new(args.size as usize);
+    let size =
+        unsafe { (*ptr).copy_from_user(buffer.get_mut(), args.buffer, args.size as usize) } as u32;
+    let file = unsafe { (*ptr).data.files.get(&args.fd).cloned() };
+
+    let written = match file {
+        Some(file) => {
+            let offset = if file.flags & O_APPEND != 0 {
+                // Another descriptor may have grown the file since we last looked
+                file.fs
+                    .stat(file.inode_num)
+                    .map_or(file.size, |stat| stat.size as u32)
+            } else {
+                file.offset
+            };
+            file.fs
+                .write(file.inode_num, buffer.get(), size, offset)
+                .map(|bytes| (bytes, offset))
+        }
+        None => None,
+    };
+
+    process::finish_syscall(args.pid, |proc| match written {
+        Some((bytes, offset)) => {
+            if let Some(file) = proc.data.files.get_mut(&args.fd) {
+                file.offset = offset + bytes;
+                if file.offset > file.size {
+                    file.size = file.offset;
+                }
+            }
+            bytes as usize
+        }
+        None => usize::MAX,
+    });
+}
+
+// called by syscall - marks current process as waiting, and spawns a new process to write the
+// process' buffer to the open file
+pub fn process_file_write(pid: u16, fd: u16, buffer: usize, size: u32) {
+    let args = FileArgs {
+        pid,
+        fd,
+        buffer,
+        size: size.min(MAX_FILE_IO),
+    };
+    let boxed_args = Box::new(args);
+    process::set_waiting(pid);
+    process::add_kernel_process_args(file_write_proc, Box::into_raw(boxed_args) as usize);
+}
+
+// run inside the stat process
+fn fstat_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut FileArgs) };
+    let ptr = unsafe { process::get_by_pid(args.pid) };
+    if ptr.is_null() {
+        return;
+    }
+    let file = unsafe { (*ptr).data.files.get(&args.fd).cloned() };
+    let stat = file.and_then(|file| {
+        file.fs.stat(file.inode_num).map(|mut stat| {
+            stat.dev = file.dev as u64;
+            stat
+        })
+    });
+
+    let size = size_of::<Stat>();
+    process::finish_syscall(args.pid, |proc| match stat {
+        Some(stat) => {
+            if let Some(file) = proc.data.files.get_mut(&args.fd) {
+                file.size = stat.size as u32;
+            }
+            let src = &stat as *const Stat as *const u8;
+            if proc.copy_to_user(args.buffer, src, size) == size {
+                0
+            } else {
+                usize::MAX
+            }
+        }
+        None => usize::MAX,
+    });
+}
+
+// called by syscall - marks current process as waiting, and spawns a new process to fill in
+// the process' struct stat
+pub fn process_fstat(pid: u16, fd: u16, buffer: usize) {
+    let args = FileArgs {
+        pid,
+        fd,
+        buffer,
+        size: size_of::<Stat>() as u32,
+    };
+    let boxed_args = Box::new(args);
+    process::set_waiting(pid);
+    process::add_kernel_process_args(fstat_proc, Box::into_raw(boxed_args) as usize);
+}
+
+// Packs directory entries into getdents64 records, as many as fit. Returns the records, how
+// many entries they hold and whether any are left over.
+fn pack_dirents(entries: &[DirEntry], first: u32, size: usize) -> (Vec<u8>, u32, bool) {
+    let mut ret = Vec::new();
+    let mut count = 0;
+    for entry in entries.iter().skip(first as usize) {
+        // Header, name and NUL, padded to 8 bytes
+        let reclen = (size_of::<LinuxDirent64>() + entry.name.len() + 1 + 7) & !7;
+        if ret.len() + reclen > size {
+            break;
+        }
+        count += 1;
+        let header = LinuxDirent64 {
+            ino: entry.inode as u64,
+            off: (first + count) as i64,
+            reclen: reclen as u16,
+            file_type: DT_UNKNOWN,
+        };
+        let start = ret.len();
+        ret.resize(start + reclen, 0);
+        unsafe {
+            (ret.as_mut_ptr().add(start) as *mut LinuxDirent64).write_unaligned(header);
+        }
+        let name_start = start + size_of::<LinuxDirent64>();
+        ret[name_start..name_start + entry.name.len()].copy_from_slice(entry.name.as_bytes());
+    }
+    let left_over = ((first + count) as usize) < entries.len();
+    (ret, count, left_over)
+}
+
+// run inside the getdents process
+fn getdents_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut FileArgs) };
+    let ptr = unsafe { process::get_by_pid(args.pid) };
+    if ptr.is_null() {
+        return;
+    }
+    let file = unsafe { (*ptr).data.files.get(&args.fd).cloned() };
+    let packed = file.and_then(|file| {
+        let entries = file.fs.readdir(file.inode_num)?;
+        Some(pack_dirents(&entries, file.offset, args.size as usize))
+    });
+
+    process::finish_syscall(args.pid, |proc| match packed {
+        // The buffer is too small for the next entry
+        Some((_, 0, true)) => usize::MAX,
+        Some((records, count, _)) => {
+            let copied = proc.copy_to_user(args.buffer, records.as_ptr(), records.len());
+            if copied < records.len() {
+                return usize::MAX;
+            }
+            if let Some(file) = proc.data.files.get_mut(&args.fd) {
+                file.offset += count;
+            }
+            copied
+        }
+        None => usize::MAX,
+    });
+}
+
+// called by syscall - marks current process as waiting, and spawns a new process to read the
+// open directory's entries into the process' buffer
+pub fn process_getdents(pid: u16, fd: u16, buffer: usize, size: u32) {
+    let args = FileArgs {
+        pid,
+        fd,
+        buffer,
+        size,
+    };
+    let boxed_args = Box::new(args);
+    process::set_waiting(pid);
+    process::add_kernel_process_args(getdents_proc, Box::into_raw(boxed_args) as usize);
+}
+
+#[derive(Copy, Clone)]
+pub enum PathOp {
+    Unlink,
+    Mkdir(u16),
+    Rmdir,
+}
+
+struct PathArgs {
+    pub pid: u16,
+    pub path: String,
+    pub op: PathOp,
+}
+
+// run inside the path process
+fn path_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut PathArgs) };
+    let ok = match resolve(&args.path) {
+        // Mount points can't be removed or created over
+        Some((_, _, relative)) if relative == "/" => false,
+        Some((_, fs, relative)) => match args.op {
+            PathOp::Unlink => fs.unlink(&relative),
+            PathOp::Mkdir(mode) => fs.mkdir(&relative, mode),
+            PathOp::Rmdir => fs.rmdir(&relative),
+        },
+        None => false,
+    };
+
+    process::finish_syscall(args.pid, |_| if ok { 0 } else { usize::MAX });
+}
+
+// called by syscall - marks current process as waiting, and spawns a new process to create or
+// remove the named file or directory
+pub fn process_path_op(pid: u16, path: String, op: PathOp) {
+    let args = PathArgs { pid, path, op };
+    let boxed_args = Box::new(args);
+    process::set_waiting(pid);
+    process::add_kernel_process_args(path_proc, Box::into_raw(boxed_args) as usize);
+}
+
+struct CloseArgs {
+    pub pid: u16,
+    pub files: Vec<OpenFile>,
+}
+
+// run inside the close process
+fn close_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut CloseArgs) };
+    let pid = args.pid;
+    // Dropping the files releases their inodes
+    drop(args);
+
+    if pid != 0 {
+        process::finish_syscall(pid, |_| 0);
+    }
+}
+
+// called by syscall - marks current process as waiting, and spawns a new process to release
+// the file it took out of its descriptor table
+pub fn process_close(pid: u16, file: OpenFile) {
+    let mut files = Vec::new();
+    files.push(file);
+    let args = CloseArgs { pid, files };
+    let boxed_args = Box::new(args);
+    process::set_waiting(pid);
+    process::add_kernel_process_args(close_proc, Box::into_raw(boxed_args) as usize);
+}
+
+// Releases files from interrupt context, e.g. those of a process that died, in a new process
+pub fn close_files(files: Vec<OpenFile>) {
+    if files.is_empty() {
+        return;
+    }
+    let args = CloseArgs { pid: 0, files };
+    let boxed_args = Box::new(args);
+    process::add_kernel_process_args(close_proc, Box::into_raw(boxed_args) as usize);
+}
+
+struct SyncArgs {
+    pub pid: u16,
+}
+
+// run inside the sync process
+fn sync_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut SyncArgs) };
+    let ret = block::to_syscall_ret(sync());
+
+    process::finish_syscall(args.pid, |_| ret);
+}
+
+// called by syscall - marks current process as waiting, and spawns a new process to write
+// back every filesystem
+pub fn process_sync(pid: u16) {
+    let args = SyncArgs { pid };
+    let boxed_args = Box::new(args);
+    process::set_waiting(pid);
+    process::add_kernel_process_args(sync_proc, Box::into_raw(boxed_args) as usize);
+}
+
+struct FsyncArgs {
+    pub pid: u16,
+    pub fd: u16,
+    // fallocate only
+    pub mode: usize,
+    pub offset: usize,
+    pub len: usize,
+}
+
+// run inside the fsync or fallocate process
+fn fsync_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut FsyncArgs) };
+    fsync_or_fallocate(args, true);
+}
+
+fn fallocate_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut FsyncArgs) };
+    fsync_or_fallocate(args, false);
+}
+
+fn fsync_or_fallocate(args: Box<FsyncArgs>, fsync: bool) {
+    let ptr = unsafe { process::get_by_pid(args.pid) };
+    if ptr.is_null() {
+        return;
+    }
+    let file = unsafe { (*ptr).data.files.get(&args.fd).cloned() };
+    let result = match file {
+        Some(file) if fsync => file.fs.fsync(file.inode_num),
+        Some(file) if args.mode == FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE => {
+            // Files are never bigger than this anyway
+            let offset = args.offset.min(u32::MAX as usize) as u32;
+            let len = args.len.min(u32::MAX as usize) as u32;
+            file.fs.punch_hole(file.inode_num, offset, len)
+        }
+        Some(_) => Err(BlockErrors::Unsupported),
+        None => Err(BlockErrors::Invalid),
+    };
+
+    process::finish_syscall(args.pid, |_| block::to_syscall_ret(result));
+}
+
+// called by syscall - marks current process as waiting, and spawns a new process to make the
+// open file's changes durable
+pub fn process_fsync(pid: u16, fd: u16) {
+    let args = FsyncArgs {
+        pid,
+        fd,
+        mode: 0,
+        offset: 0,
+        len: 0,
+    };
+    let boxed_args = Box::new(args);
+    process::set_waiting(pid);
+    process::add_kernel_process_args(fsync_proc, Box::into_raw(boxed_args) as usize);
+}
+
+// called by syscall - marks current process as waiting, and spawns a new process to change the
+// open file's allocation
+pub fn process_fallocate(pid: u16, fd: u16, mode: usize, offset: usize, len: usize) {
+    let args = FsyncArgs {
+        pid,
+        fd,
+        mode,
+        offset,
+        len,
+    };
+    let boxed_args = Box::new(args);
+    process::set_waiting(pid);
+    process::add_kernel_process_args(fallocate_proc, Box::into_raw(boxed_args) as usize);
+}
+
+struct MmapArgs {
+    pub pid: u16,
+    pub fd: u16,
+    pub offset: u32,
+    pub len: u32,
+    pub writable: bool,
+}
+
+// run inside the mmap process
+fn mmap_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut MmapArgs) };
+    unsafe {
+        let ptr = process::get_by_pid(args.pid);
+        if ptr.is_null() {
+            return;
+        }
+        let file = (*ptr).data.files.get(&args.fd).cloned();
+        let paddr = file.and_then(|file| file.fs.mmap(file.inode_num, args.offset, args.len));
+
+        let ret = paddr
+            .and_then(|paddr| {
+                process::map_physical(args.pid, paddr, args.len as usize, args.writable)
+            })
+            .unwrap_or(usize::MAX);
+        process::finish_syscall(args.pid, |_| ret);
+    }
+}
+
+// called by syscall - marks current process as waiting, and spawns a new process to map the
+// open file's memory into it
+pub fn process_mmap(pid: u16, fd: u16, offset: u32, len: u32, writable: bool) {
+    let args = MmapArgs {
+        pid,
+        fd,
+        offset,
+        len,
+        writable,
+    };
+    let boxed_args = Box::new(args);
+    process::set_waiting(pid);
+    process::add_kernel_process_args(mmap_proc, Box::into_raw(boxed_args) as usize);
+}
@@ -1,7 +1,15 @@
 use crate::{block, block::{setup_block_device}};
-use crate::page::{PAGE_SIZE};
+use crate::fdt;
+use crate::kmem::{kfree, kmalloc};
+use crate::page::{zalloc, PAGE_SIZE};
+use crate::gpu;
+use crate::input;
+use crate::net;
 use crate::random;
+use crate::vconsole;
+use alloc::vec::Vec;
 use core::mem::size_of;
+use core::sync::atomic::{fence, Ordering};
 
 // Flags
 pub const VIRTIO_DESC_F_NEXT: u16 = 1;
@@ -14,6 +22,14 @@ pub const VIRTIO_USED_F_NO_NOTIFY: u16 = 1;
 
 pub const VIRTIO_RING_SIZE: usize = 1 << 7;
 
+// Device independent feature bits
+pub const VIRTIO_F_INDIRECT_DESC: u32 = 28;
+pub const VIRTIO_F_EVENT_IDX: u32 = 29;
+pub const VIRTIO_F_VERSION_1: u32 = 32;
+
+// Ring features Virtqueue knows how to use. Drivers add them to what they support.
+pub const RING_FEATURES: u64 = (1 << VIRTIO_F_INDIRECT_DESC) | (1 << VIRTIO_F_EVENT_IDX);
+
 #[repr(C)]
 pub struct Descriptor {
 	pub addr:  u64,
@@ -55,6 +71,7 @@ pub struct Queue {
 }
 
 #[repr(usize)]
+#[derive(Copy, Clone)]
 pub enum MmioOffsets {
     MagicValue = 0x000,
     Version = 0x004,
@@ -70,11 +87,20 @@ pub enum MmioOffsets {
     QueueNum = 0x038,
     QueueAlign = 0x03c,
     QueuePfn = 0x040,
-    QueueNotify = 0x050,
-    InterruptStatus = 0x060,
-    InterruptAck = 0x064,
-    Status = 0x070,
-    Config = 0x100,
+    // Version 2 only, from here on except where noted
+    QueueReady = 0x044,
+    QueueNotify = 0x050, // both
+    InterruptStatus = 0x060, // both
+    InterruptAck = 0x064, // both
+    Status = 0x070, // both
+    QueueDescLow = 0x080,
+    QueueDescHigh = 0x084,
+    QueueDriverLow = 0x090,
+    QueueDriverHigh = 0x094,
+    QueueDeviceLow = 0x0a0,
+    QueueDeviceHigh = 0x0a4,
+    ConfigGeneration = 0x0fc,
+    Config = 0x100, // both
 }
 
 #[repr(usize)]
@@ -143,11 +169,12 @@ impl StatusField {
     }
 }
 
-pub const MMIO_VIRTIO_START: usize = 0x1000_1000;
-pub const MMIO_VIRTIO_END: usize = 0x1000_8000;
-pub const MMIO_VIRTIO_STRIDE: usize = 0x1000;
 pub const MMIO_VIRTIO_MAGIC: u32 = 0x74_72_69_76; // 'triv' (i.e. 'virt' in little-endian)
 
+// Register layouts, read from MmioOffsets::Version
+pub const MMIO_VERSION_LEGACY: u32 = 1;
+pub const MMIO_VERSION_MODERN: u32 = 2;
+
 pub struct VirtioDevice {
     pub devtype: DeviceTypes,
 }
@@ -162,10 +189,355 @@ impl VirtioDevice {
     }
 }
 
+// Index of the device's MMIO slot, which the drivers keep their devices by. None if it isn't
+// one of the platform's slots.
+pub fn slot(ptr: *mut u32) -> Option<usize> {
+    fdt::platform()
+        .virtio()
+        .iter()
+        .position(|slot| slot.base == ptr as usize)
+}
+
+// Which register layout the device uses, one of the MMIO_VERSION values
+pub unsafe fn version(ptr: *mut u32) -> u32 {
+    ptr.add(MmioOffsets::Version.scale32()).read_volatile()
+}
+
+// Steps 1 to 6 of device initialization: reset, acknowledge, and agree on features. supported is
+// what the driver understands, VIRTIO_F_VERSION_1 is added for modern devices. Returns the
+// negotiated features, or None after marking the device failed.
+pub unsafe fn negotiate_features(ptr: *mut u32, supported: u64) -> Option<u64> {
+    let version = version(ptr);
+    if version != MMIO_VERSION_LEGACY && version != MMIO_VERSION_MODERN {
+        print!("unknown version {}...", version);
+        return None;
+    }
+    // 1. Reset by writing 0 to status
+    ptr.add(MmioOffsets::Status.scale32()).write_volatile(0);
+    // 2. Set ACKNOWLEDGE status bit
+    let mut status_bits = StatusField::Acknowledge.val32();
+    ptr.add(MmioOffsets::Status.scale32())
+        .write_volatile(status_bits);
+    // 3. Set the DRIVER status bit
+    status_bits |= StatusField::Driver.val32();
+    ptr.add(MmioOffsets::Status.scale32())
+        .write_volatile(status_bits);
+    // 4. Read device feature bits, write subset of feature bits understood by OS and driver to the device
+    let mut supported = supported;
+    if version == MMIO_VERSION_MODERN {
+        supported |= 1 << VIRTIO_F_VERSION_1;
+    }
+    let host_features = read_host_features(ptr);
+    let guest_features = host_features & supported;
+    if version == MMIO_VERSION_MODERN && guest_features & (1 << VIRTIO_F_VERSION_1) == 0 {
+        print!("no VIRTIO_F_VERSION_1...");
+        ptr.add(MmioOffsets::Status.scale32())
+            .write_volatile(StatusField::Failed.val32());
+        return None;
+    }
+    write_guest_features(ptr, guest_features);
+    // 5. Set the FEATURES_OK status bit
+    status_bits |= StatusField::FeaturesOk.val32();
+    ptr.add(MmioOffsets::Status.scale32())
+        .write_volatile(status_bits);
+    // 6. Re-read status to ensure FEATURES_OK is still set.
+    // Otherwise, it doesn't support our features.
+    let status_ok = ptr.add(MmioOffsets::Status.scale32()).read_volatile();
+    if false == StatusField::features_ok(status_ok) {
+        print!("features fail...");
+        ptr.add(MmioOffsets::Status.scale32())
+            .write_volatile(StatusField::Failed.val32());
+        return None;
+    }
+    Some(guest_features)
+}
+
+// All 64 feature bits the device offers, 32 at a time
+unsafe fn read_host_features(ptr: *mut u32) -> u64 {
+    let mut features = 0;
+    for sel in 0..2 {
+        ptr.add(MmioOffsets::HostFeaturesSel.scale32()).write_volatile(sel);
+        let word = ptr.add(MmioOffsets::HostFeatures.scale32()).read_volatile();
+        features |= (word as u64) << (32 * sel);
+    }
+    features
+}
+
+unsafe fn write_guest_features(ptr: *mut u32, features: u64) {
+    for sel in 0..2 {
+        ptr.add(MmioOffsets::GuestFeaturesSel.scale32()).write_volatile(sel);
+        ptr.add(MmioOffsets::GuestFeatures.scale32())
+            .write_volatile((features >> (32 * sel)) as u32);
+    }
+}
+
+// Hands queue number idx to the device. Legacy devices find the rings from the queue's page
+// number, modern ones are told where each part is.
+pub unsafe fn setup_queue(ptr: *mut u32, idx: u32, queue: *mut Queue) -> bool {
+    ptr.add(MmioOffsets::QueueSel.scale32()).write_volatile(idx);
+    let qnmax = ptr.add(MmioOffsets::QueueNumMax.scale32()).read_volatile();
+    // Max sure the queue size is valid
+    if VIRTIO_RING_SIZE as u32 > qnmax {
+        print!("queue size fail...");
+        return false;
+    }
+    // Set queue num
+    ptr.add(MmioOffsets::QueueNum.scale32()).write_volatile(VIRTIO_RING_SIZE as u32);
+    if version(ptr) == MMIO_VERSION_LEGACY {
+        ptr.add(MmioOffsets::GuestPageSize.scale32()).write_volatile(PAGE_SIZE as u32);
+        ptr.add(MmioOffsets::QueuePfn.scale32())
+            .write_volatile((queue as usize / PAGE_SIZE) as u32);
+    } else {
+        let desc = &(*queue).desc as *const _ as u64;
+        let driver = &(*queue).avail as *const _ as u64;
+        let device = &(*queue).used as *const _ as u64;
+        let parts = [
+            (MmioOffsets::QueueDescLow, MmioOffsets::QueueDescHigh, desc),
+            (MmioOffsets::QueueDriverLow, MmioOffsets::QueueDriverHigh, driver),
+            (MmioOffsets::QueueDeviceLow, MmioOffsets::QueueDeviceHigh, device),
+        ];
+        for (low, high, addr) in parts.iter() {
+            ptr.add(low.scale32()).write_volatile(*addr as u32);
+            ptr.add(high.scale32()).write_volatile((*addr >> 32) as u32);
+        }
+        ptr.add(MmioOffsets::QueueReady.scale32()).write_volatile(1);
+    }
+    true
+}
+
+// Step 8 of device initialization: set the DRIVER_OK status bit. Device is now 'live'
+pub unsafe fn driver_ok(ptr: *mut u32) {
+    let status_bits = ptr.add(MmioOffsets::Status.scale32()).read_volatile();
+    ptr.add(MmioOffsets::Status.scale32())
+        .write_volatile(status_bits | StatusField::DriverOk.val32());
+}
+
+// Bumped by modern devices whenever the config space changes. Reads of fields wider than 32 bits
+// are retried until it stays the same. Always 0 for legacy devices.
+pub unsafe fn config_generation(ptr: *mut u32) -> u32 {
+    if version(ptr) == MMIO_VERSION_LEGACY {
+        return 0;
+    }
+    ptr.add(MmioOffsets::ConfigGeneration.scale32()).read_volatile()
+}
+
+// One buffer of a chain. write is true for buffers the device writes into.
+#[derive(Copy, Clone)]
+pub struct Buf {
+    pub addr: u64,
+    pub len: u32,
+    pub write: bool,
+}
+
+impl Buf {
+    pub fn new<T>(ptr: *const T, len: u32, write: bool) -> Self {
+        Buf { addr: ptr as u64, len, write }
+    }
+}
+
+// A split virtqueue and the driver's bookkeeping for it. Chains are identified by a token the
+// driver picks, usually a pointer to its request.
+pub struct Virtqueue {
+    queue: *mut Queue,
+    dev: *mut u32,
+    // Queue number on the device
+    idx: u32,
+    // Descriptors that aren't part of a chain on the ring
+    free_desc: Vec<u16>,
+    // Token of each chain in flight, by head descriptor
+    tokens: [usize; VIRTIO_RING_SIZE],
+    // used.idx keeps counting past the ring size, so this does too
+    ack_used_idx: u16,
+    // avail.idx when the device was last notified
+    kicked_idx: u16,
+    indirect: bool,
+    event_idx: bool,
+}
+
+impl Virtqueue {
+    // Allocates queue number idx and hands it to the device. features are the negotiated ones,
+    // which decide whether indirect descriptors and event indices get used.
+    pub unsafe fn new(ptr: *mut u32, idx: u32, features: u64) -> Option<Self> {
+        let num_pages = (size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
+        let queue = zalloc(num_pages) as *mut Queue;
+        if queue.is_null() || false == setup_queue(ptr, idx, queue) {
+            return None;
+        }
+        Some(Virtqueue {
+            queue,
+            dev: ptr,
+            idx,
+            free_desc: (0..VIRTIO_RING_SIZE as u16).collect(),
+            tokens: [0; VIRTIO_RING_SIZE],
+            ack_used_idx: 0,
+            kicked_idx: 0,
+            indirect: features & (1 << VIRTIO_F_INDIRECT_DESC) != 0,
+            event_idx: features & (1 << VIRTIO_F_EVENT_IDX) != 0,
+        })
+    }
+
+    // Descriptors a chain of this many buffers takes on the ring
+    pub fn descriptors_needed(&self, bufs: usize) -> usize {
+        if self.indirect && bufs > 1 {
+            1
+        } else {
+            bufs
+        }
+    }
+
+    pub fn free_descriptors(&self) -> usize {
+        self.free_desc.len()
+    }
+
+    // Puts a chain on the available ring. The device doesn't look at it until kick. Returns
+    // false if there aren't enough free descriptors, the caller can retry after poll_used.
+    pub fn add_buf(&mut self, chain: &[Buf], token: usize) -> bool {
+        if chain.is_empty() || self.free_desc.len() < self.descriptors_needed(chain.len()) {
+            return false;
+        }
+        unsafe {
+            let head = if self.indirect && chain.len() > 1 {
+                match self.add_indirect(chain) {
+                    Some(head) => head,
+                    // Out of memory, fall back to the ring if it has room
+                    None if self.free_desc.len() >= chain.len() => self.add_direct(chain),
+                    None => return false,
+                }
+            } else {
+                self.add_direct(chain)
+            };
+            self.tokens[head as usize] = token;
+            let avail = &mut (*self.queue).avail;
+            avail.ring[avail.idx as usize % VIRTIO_RING_SIZE] = head;
+            // The device must see the ring entry before the index moves past it
+            fence(Ordering::SeqCst);
+            avail.idx = avail.idx.wrapping_add(1);
+        }
+        true
+    }
+
+    // Builds the chain in ring descriptors, back to front so each one knows the next
+    unsafe fn add_direct(&mut self, chain: &[Buf]) -> u16 {
+        let mut next = 0;
+        let mut flags = 0;
+        for buf in chain.iter().rev() {
+            let idx = self.free_desc.pop().unwrap();
+            (*self.queue).desc[idx as usize] = Descriptor {
+                addr: buf.addr,
+                len: buf.len,
+                flags: flags | if buf.write { VIRTIO_DESC_F_WRITE } else { 0 },
+                next,
+            };
+            next = idx;
+            flags = VIRTIO_DESC_F_NEXT;
+        }
+        next
+    }
+
+    // Builds the chain in a table of its own that a single ring descriptor points to
+    unsafe fn add_indirect(&mut self, chain: &[Buf]) -> Option<u16> {
+        let table = kmalloc(size_of::<Descriptor>() * chain.len()) as *mut Descriptor;
+        if table.is_null() {
+            return None;
+        }
+        for (i, buf) in chain.iter().enumerate() {
+            let last = i + 1 == chain.len();
+            table.add(i).write(Descriptor {
+                addr: buf.addr,
+                len: buf.len,
+                flags: if last { 0 } else { VIRTIO_DESC_F_NEXT }
+                    | if buf.write { VIRTIO_DESC_F_WRITE } else { 0 },
+                next: if last { 0 } else { i as u16 + 1 },
+            });
+        }
+        let idx = self.free_desc.pop().unwrap();
+        (*self.queue).desc[idx as usize] = Descriptor {
+            addr: table as u64,
+            len: (size_of::<Descriptor>() * chain.len()) as u32,
+            flags: VIRTIO_DESC_F_INDIRECT,
+            next: 0,
+        };
+        Some(idx)
+    }
+
+    // Tells the device about chains added since the last kick, unless it said it doesn't need
+    // to hear about them
+    pub fn kick(&mut self) {
+        unsafe {
+            // Our avail.idx must be visible before we read what the device wants
+            fence(Ordering::SeqCst);
+            let new_idx = (*self.queue).avail.idx;
+            let old_idx = self.kicked_idx;
+            self.kicked_idx = new_idx;
+            let notify = if self.event_idx {
+                // used.event is where the device keeps avail_event
+                let event = (*self.queue).used.event;
+                new_idx.wrapping_sub(event).wrapping_sub(1) < new_idx.wrapping_sub(old_idx)
+            } else {
+                (*self.queue).used.flags & VIRTIO_USED_F_NO_NOTIFY == 0
+            };
+            if notify {
+                self.dev
+                    .add(MmioOffsets::QueueNotify.scale32())
+                    .write_volatile(self.idx);
+            }
+        }
+    }
+
+    // Takes the next finished chain off the used ring and frees its descriptors. Returns its
+    // token and the number of bytes the device wrote.
+    pub fn poll_used(&mut self) -> Option<(usize, u32)> {
+        unsafe {
+            if self.ack_used_idx == (*self.queue).used.idx {
+                return None;
+            }
+            // Don't read the element before the index that covers it
+            fence(Ordering::SeqCst);
+            let elem = &(*self.queue).used.ring[self.ack_used_idx as usize % VIRTIO_RING_SIZE];
+            let (head, len) = (elem.id as u16, elem.len);
+            self.ack_used_idx = self.ack_used_idx.wrapping_add(1);
+            if self.event_idx {
+                // avail.event is where we keep used_event: interrupt once the next one's done
+                (*self.queue).avail.event = self.ack_used_idx;
+                // The device may have moved used.idx before it saw the new event, so the next
+                // check has to read it after the write is visible
+                fence(Ordering::SeqCst);
+            }
+            self.free_chain(head);
+            Some((self.tokens[head as usize], len))
+        }
+    }
+
+    // Calls f with the token and written length of every finished chain. Drivers call this from
+    // their interrupt handler.
+    pub fn process_used(&mut self, mut f: impl FnMut(usize, u32)) {
+        while let Some((token, len)) = self.poll_used() {
+            f(token, len);
+        }
+    }
+
+    // Puts every descriptor of a chain back on the free list
+    unsafe fn free_chain(&mut self, head: u16) {
+        let mut idx = head;
+        loop {
+            self.free_desc.push(idx);
+            let desc = &(*self.queue).desc[idx as usize];
+            if desc.flags & VIRTIO_DESC_F_INDIRECT != 0 {
+                kfree(desc.addr as *mut u8);
+            }
+            if desc.flags & VIRTIO_DESC_F_NEXT == 0 {
+                break;
+            }
+            idx = desc.next;
+        }
+    }
+}
+
 static mut VIRTIO_DEVICES: [Option<VirtioDevice>; 8] = [None, None, None, None, None, None, None, None];
 
 pub fn probe() {
-    for addr in (MMIO_VIRTIO_START..=MMIO_VIRTIO_END).step_by(MMIO_VIRTIO_STRIDE) {
+    for (index, slot) in fdt::platform().virtio().iter().enumerate() {
+        let addr = slot.base;
         print!("Virtio probing 0x{:08x}...", addr);
         let magicvalue;
         let deviceid;
@@ -184,7 +556,14 @@ pub fn probe() {
                 1 => {
                     // Network device
                     print!("network device...");
-                    println!("setup failed.");
+                    if false == net::setup_network_device(ptr) {
+                        println!("setup failed.");
+                    } else {
+                        unsafe {
+                            VIRTIO_DEVICES[index] = Some(VirtioDevice::new_with(DeviceTypes::Network));
+                        }
+                        println!("setup succeeded!");
+                    }
                 },
                 2 => {
                     // Block device
@@ -192,28 +571,58 @@ pub fn probe() {
                     if false == setup_block_device(ptr) {
                         println!("setup failed.");
                     } else {
-                        let index = (addr - MMIO_VIRTIO_START) >> 12;
                         unsafe {
                             VIRTIO_DEVICES[index] = Some(VirtioDevice::new_with(DeviceTypes::Block));
                         }
                         println!("setup succeeded!");
                     }
                 },
+                3 => {
+                    // Console device
+                    print!("console device...");
+                    if false == vconsole::setup_console_device(ptr) {
+                        println!("setup failed.");
+                    } else {
+                        unsafe {
+                            VIRTIO_DEVICES[index] = Some(VirtioDevice::new_with(DeviceTypes::Console));
+                        }
+                        println!("setup succeeded!");
+                    }
+                },
                 4 => {
                     print!("random number generator...");
                     if false == random::setup_entropy_device(ptr) {
                         println!("setup failed.");
+                    } else {
+                        unsafe {
+                            VIRTIO_DEVICES[index] = Some(VirtioDevice::new_with(DeviceTypes::Entropy));
+                        }
+                        println!("setup succeeded!");
                     }
                 },
                 16 => {
                     // GPU device
                     print!("GPU device...");
-                    println!("setup failed.");
+                    if false == gpu::setup_gpu_device(ptr) {
+                        println!("setup failed.");
+                    } else {
+                        unsafe {
+                            VIRTIO_DEVICES[index] = Some(VirtioDevice::new_with(DeviceTypes::Gpu));
+                        }
+                        println!("setup succeeded!");
+                    }
                 },
                 18 => {
                     // Input device
                     print!("input device...");
-                    println!("setup failed.");
+                    if false == input::setup_input_device(ptr) {
+                        println!("setup failed.");
+                    } else {
+                        unsafe {
+                            VIRTIO_DEVICES[index] = Some(VirtioDevice::new_with(DeviceTypes::Input));
+                        }
+                        println!("setup succeeded!");
+                    }
                 },
                 _ => println!("unknown device type."),
             }
@@ -222,14 +631,32 @@ pub fn probe() {
     }
 }
 
-pub fn handle_interrupt(interrupt: u32) {
-    let index = interrupt as usize - 1;
+pub fn handle_interrupt(index: usize) {
     unsafe {
         if let Some(vd) = &VIRTIO_DEVICES[index] {
+            // The interrupt stays raised until it's acknowledged
+            let ptr = fdt::platform().virtio()[index].base as *mut u32;
+            let status = ptr.add(MmioOffsets::InterruptStatus.scale32()).read_volatile();
+            ptr.add(MmioOffsets::InterruptAck.scale32()).write_volatile(status);
             match vd.devtype {
                 DeviceTypes::Block => {
                     block::handle_interrupt(index);
                 },
+                DeviceTypes::Network => {
+                    net::handle_interrupt(index);
+                },
+                DeviceTypes::Console => {
+                    vconsole::handle_interrupt(index);
+                },
+                DeviceTypes::Entropy => {
+                    random::handle_interrupt(index);
+                },
+                DeviceTypes::Gpu => {
+                    gpu::handle_interrupt(index);
+                },
+                DeviceTypes::Input => {
+                    input::handle_interrupt(index);
+                },
                 _ => {
                     println!("Invalid device generated interrupt!");
                 },